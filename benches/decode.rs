@@ -0,0 +1,114 @@
+//! Compares the owned `BencodeParser` with the borrowed decoder.
+//!
+//! Run with `cargo bench --bench decode`.
+use il_pleut::decoder::{BencodeRef, Decoder};
+use il_pleut::parser::{BencodeParser, BencodeValue, bencode_encode};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Runs `f` repeatedly for about a second and prints the mean time per run.
+fn bench(name: &str, bytes: usize, mut f: impl FnMut()) {
+    // Warm up caches and the allocator
+    for _ in 0..3 {
+        f();
+    }
+    let mut runs = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(1) {
+        f();
+        runs += 1;
+    }
+    let per_run = start.elapsed() / runs;
+    let throughput = bytes as f64 / per_run.as_secs_f64() / (1024.0 * 1024.0);
+    println!("{:<40} {:>12?} {:>10.1} MiB/s", name, per_run, throughput);
+}
+
+fn string(s: &str) -> BencodeValue {
+    BencodeValue::String(s.as_bytes().to_vec())
+}
+
+fn dict(entries: Vec<(&str, BencodeValue)>) -> BencodeValue {
+    BencodeValue::Dictionary(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect::<HashMap<_, _>>(),
+    )
+}
+
+/// A multi-file torrent with `count` files in nested directories.
+fn many_files_torrent(count: usize) -> Vec<u8> {
+    let files = (0..count)
+        .map(|i| {
+            dict(vec![
+                ("length", BencodeValue::Integer(1000 + i as i64)),
+                (
+                    "path",
+                    BencodeValue::List(vec![
+                        string(&format!("dir{}", i / 100)),
+                        string(&format!("file-{}.dat", i)),
+                    ]),
+                ),
+            ])
+        })
+        .collect();
+    let info = dict(vec![
+        ("name", string("many")),
+        ("piece length", BencodeValue::Integer(1 << 20)),
+        ("pieces", BencodeValue::String(vec![0xab; 20 * count / 10])),
+        ("files", BencodeValue::List(files)),
+    ]);
+    bencode_encode(&dict(vec![
+        ("announce", string("http://tracker.example/announce")),
+        ("info", info),
+    ]))
+}
+
+/// A DHT `get_peers` response with a few compact nodes.
+fn dht_response() -> Vec<u8> {
+    bencode_encode(&dict(vec![
+        ("t", string("aa")),
+        ("y", string("r")),
+        (
+            "r",
+            dict(vec![
+                ("id", BencodeValue::String(vec![7; 20])),
+                ("token", string("aoeusnth")),
+                ("nodes", BencodeValue::String(vec![1; 26 * 8])),
+            ]),
+        ),
+    ]))
+}
+
+fn count_events(data: &[u8]) -> usize {
+    let mut decoder = Decoder::new(data);
+    let mut events = 0;
+    while decoder.next_event().expect("valid input").is_some() {
+        events += 1;
+    }
+    events
+}
+
+fn main() {
+    let inputs = [
+        ("50k files", many_files_torrent(50_000)),
+        ("DHT response", dht_response()),
+    ];
+    for (label, data) in &inputs {
+        println!("{} ({} bytes)", label, data.len());
+        bench("  BencodeParser::parse", data.len(), || {
+            black_box(BencodeParser::new(black_box(data)).parse().unwrap());
+        });
+        bench("  BencodeParser::parse with spans", data.len(), || {
+            let mut parser = BencodeParser::new(black_box(data)).with_spans();
+            black_box(parser.parse().unwrap());
+        });
+        bench("  BencodeRef::parse", data.len(), || {
+            black_box(BencodeRef::parse(black_box(data)).unwrap());
+        });
+        bench("  Decoder events", data.len(), || {
+            black_box(count_events(black_box(data)));
+        });
+    }
+}
@@ -0,0 +1,1043 @@
+/// Serde support for bencode.
+///
+/// Rust types map onto bencode the way the BitTorrent specs use it: structs
+/// and maps become dictionaries, sequences become lists, integers and `bool`
+/// become integers, and strings become byte strings. `None` and `()` are left
+/// out of dictionaries. Bencode has no floats.
+///
+/// Serde treats `Vec<u8>` as a sequence of numbers. Use [`ByteString`] or
+/// `#[serde(with = "bencode::byte_string")]` to write byte strings; when
+/// reading, a byte string is accepted wherever a sequence of bytes is.
+use crate::parser::{BencodeParser, BencodeValue, PathSegment, bencode_encode};
+use serde::de::{self, DeserializeOwned, IntoDeserializer};
+use serde::ser::{self, Serialize};
+use serde::{Deserialize, Deserializer as _};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct BencodeError {
+    pub message: String,
+    /// Where in the value the error happened, outermost first.
+    pub path: Vec<PathSegment>,
+}
+
+impl BencodeError {
+    fn new(message: impl Into<String>) -> Self {
+        BencodeError {
+            message: message.into(),
+            path: Vec::new(),
+        }
+    }
+
+    /// Records that the error happened inside `segment`.
+    fn within(mut self, segment: PathSegment) -> Self {
+        self.path.insert(0, segment);
+        self
+    }
+}
+
+impl std::fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Bencode error: {}", self.message)?;
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        write!(f, " at ")?;
+        for (i, segment) in self.path.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{}", String::from_utf8_lossy(key))?,
+                PathSegment::Key(key) => write!(f, ".{}", String::from_utf8_lossy(key))?,
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BencodeError {}
+
+impl de::Error for BencodeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        BencodeError::new(msg.to_string())
+    }
+}
+
+impl ser::Error for BencodeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        BencodeError::new(msg.to_string())
+    }
+}
+
+/// Decodes bencoded `data` into a `T`.
+pub fn from_bytes<T: DeserializeOwned>(data: &[u8]) -> Result<T, BencodeError> {
+    let value = BencodeParser::new(data)
+        .parse()
+        .map_err(|e| BencodeError::new(e.message))?;
+    from_value(&value)
+}
+
+/// Reads a `T` out of a parsed value. `T` may borrow strings and byte
+/// strings from `value`.
+pub fn from_value<'de, T: Deserialize<'de>>(value: &'de BencodeValue) -> Result<T, BencodeError> {
+    T::deserialize(Deserializer::new(value))
+}
+
+/// Encodes `value` as bencode.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, BencodeError> {
+    Ok(bencode_encode(&to_value(value)?))
+}
+
+/// Converts `value` into a [`BencodeValue`].
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<BencodeValue, BencodeError> {
+    value
+        .serialize(Serializer)?
+        .ok_or_else(|| BencodeError::new("Nothing to encode"))
+}
+
+/// A byte string that serializes as one, unlike `Vec<u8>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ByteString(pub Vec<u8>);
+
+impl std::ops::Deref for ByteString {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for ByteString {
+    fn from(bytes: Vec<u8>) -> Self {
+        ByteString(bytes)
+    }
+}
+
+impl Serialize for ByteString {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteString {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_byte_buf(BytesVisitor)
+            .map(ByteString)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> de::Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a byte string")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Vec<u8>, E> {
+        Ok(v.as_bytes().to_vec())
+    }
+
+    // Formats without byte strings, such as JSON, write a list of numbers
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+/// `#[serde(with = "...")]` helpers for byte fields of other types, such as
+/// `Vec<u8>` and `[u8; 20]`.
+pub mod byte_string {
+    use super::BytesVisitor;
+    use serde::{Deserializer, Serializer, de};
+
+    pub fn serialize<T: AsRef<[u8]>, S: Serializer>(
+        bytes: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes.as_ref())
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: TryFrom<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+        let length = bytes.len();
+        T::try_from(bytes)
+            .map_err(|_| de::Error::invalid_length(length, &"a byte string of the right length"))
+    }
+
+    /// The same for optional fields; combine with `#[serde(default)]`.
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        #[derive(Deserialize)]
+        struct Bytes(#[serde(with = "super")] Vec<u8>);
+
+        pub fn serialize<T: AsRef<[u8]>, S: Serializer>(
+            bytes: &Option<T>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match bytes {
+                Some(bytes) => {
+                    serializer.serialize_some(&super::super::ByteString(bytes.as_ref().to_vec()))
+                }
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+        where
+            T: TryFrom<Vec<u8>>,
+            D: Deserializer<'de>,
+        {
+            Option::<Bytes>::deserialize(deserializer)?
+                .map(|Bytes(bytes)| {
+                    let length = bytes.len();
+                    T::try_from(bytes).map_err(|_| {
+                        serde::de::Error::invalid_length(
+                            length,
+                            &"a byte string of the right length",
+                        )
+                    })
+                })
+                .transpose()
+        }
+    }
+}
+
+/// `#[serde(deserialize_with = "...")]` helpers for fields that must not
+/// fail the whole value when malformed. Combine with `#[serde(default)]`.
+pub mod lenient {
+    use crate::parser::BencodeValue;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Deserializer};
+
+    /// The field's value, or its default when it has the wrong type or is
+    /// out of range.
+    pub fn or_default<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: DeserializeOwned + Default,
+        D: Deserializer<'de>,
+    {
+        let value = BencodeValue::deserialize(deserializer)?;
+        Ok(super::from_value(&value).unwrap_or_default())
+    }
+
+    /// Text decoded lossily. Anything but a byte string reads as empty.
+    pub fn text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+        let value = BencodeValue::deserialize(deserializer)?;
+        let bytes = value.as_bytes().unwrap_or(b"");
+        Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+}
+
+impl Serialize for BencodeValue {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::SerializeMap;
+        match self {
+            BencodeValue::String(bytes) => serializer.serialize_bytes(bytes),
+            BencodeValue::Integer(i) => serializer.serialize_i64(*i),
+            BencodeValue::List(list) => serializer.collect_seq(list),
+            BencodeValue::Dictionary(dict) => {
+                let mut keys: Vec<&Vec<u8>> = dict.keys().collect();
+                keys.sort();
+                let mut map = serializer.serialize_map(Some(keys.len()))?;
+                for key in keys {
+                    map.serialize_entry(&ByteString(key.clone()), &dict[key])?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BencodeValue {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> de::Visitor<'de> for ValueVisitor {
+    type Value = BencodeValue;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a bencode value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<BencodeValue, E> {
+        Ok(BencodeValue::Integer(v as i64))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<BencodeValue, E> {
+        Ok(BencodeValue::Integer(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<BencodeValue, E> {
+        i64::try_from(v)
+            .map(BencodeValue::Integer)
+            .map_err(|_| E::custom(format!("integer {} is too large for bencode", v)))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<BencodeValue, E> {
+        Ok(BencodeValue::String(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<BencodeValue, E> {
+        Ok(BencodeValue::String(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<BencodeValue, E> {
+        Ok(BencodeValue::String(v.as_bytes().to_vec()))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<BencodeValue, A::Error> {
+        let mut list = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            list.push(item);
+        }
+        Ok(BencodeValue::List(list))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<BencodeValue, A::Error> {
+        let mut dict = HashMap::new();
+        while let Some((ByteString(key), value)) = map.next_entry()? {
+            dict.insert(key, value);
+        }
+        Ok(BencodeValue::Dictionary(dict))
+    }
+}
+
+/// A parsed value, or a dictionary key, being deserialized.
+#[derive(Clone, Copy)]
+enum Node<'de> {
+    Bytes(&'de [u8]),
+    Integer(i64),
+    List(&'de [BencodeValue]),
+    Dictionary(&'de HashMap<Vec<u8>, BencodeValue>),
+}
+
+/// Reads Rust values out of a [`BencodeValue`].
+pub struct Deserializer<'de> {
+    node: Node<'de>,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn new(value: &'de BencodeValue) -> Self {
+        let node = match value {
+            BencodeValue::String(bytes) => Node::Bytes(bytes),
+            BencodeValue::Integer(i) => Node::Integer(*i),
+            BencodeValue::List(list) => Node::List(list),
+            BencodeValue::Dictionary(dict) => Node::Dictionary(dict),
+        };
+        Deserializer { node }
+    }
+
+    fn unexpected(&self) -> de::Unexpected<'de> {
+        match self.node {
+            Node::Bytes(bytes) => de::Unexpected::Bytes(bytes),
+            Node::Integer(i) => de::Unexpected::Signed(i),
+            Node::List(_) => de::Unexpected::Seq,
+            Node::Dictionary(_) => de::Unexpected::Map,
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'de> {
+    type Error = BencodeError;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self.node {
+            Node::Bytes(bytes) => visitor.visit_borrowed_bytes(bytes),
+            Node::Integer(i) => visitor.visit_i64(i),
+            Node::List(list) => visitor.visit_seq(ListAccess {
+                items: list.iter().enumerate(),
+            }),
+            Node::Dictionary(dict) => {
+                let mut entries: Vec<_> = dict.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                visitor.visit_map(DictAccess {
+                    entries: entries.into_iter(),
+                    value: None,
+                })
+            }
+        }
+    }
+
+    fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self.node {
+            Node::Integer(0) => visitor.visit_bool(false),
+            Node::Integer(1) => visitor.visit_bool(true),
+            _ => Err(de::Error::invalid_type(self.unexpected(), &"0 or 1")),
+        }
+    }
+
+    fn deserialize_str<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self.node {
+            Node::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => visitor.visit_borrowed_str(text),
+                Err(_) => Err(BencodeError::new("Invalid UTF-8 in string")),
+            },
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        // Absent keys are the only way to say `None`
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, BencodeError> {
+        match self.node {
+            // Lets `Vec<u8>` and `[u8; N]` read byte strings
+            Node::Bytes(bytes) => {
+                let mut seq = de::value::SeqDeserializer::new(bytes.iter().copied());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: de::Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        match self.node {
+            Node::Bytes(variant) => visitor.visit_enum(Enum {
+                variant,
+                value: None,
+            }),
+            Node::Dictionary(dict) if dict.len() == 1 => {
+                let (variant, value) = dict.iter().next().expect("one entry");
+                visitor.visit_enum(Enum {
+                    variant,
+                    value: Some(value),
+                })
+            }
+            _ => Err(de::Error::invalid_type(
+                self.unexpected(),
+                &"a variant name or a dictionary with one key",
+            )),
+        }
+    }
+
+    fn deserialize_ignored_any<V: de::Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf
+        unit unit_struct map struct identifier
+    }
+}
+
+struct ListAccess<'de> {
+    items: std::iter::Enumerate<std::slice::Iter<'de, BencodeValue>>,
+}
+
+impl<'de> de::SeqAccess<'de> for ListAccess<'de> {
+    type Error = BencodeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, BencodeError> {
+        match self.items.next() {
+            Some((index, item)) => seed
+                .deserialize(Deserializer::new(item))
+                .map(Some)
+                .map_err(|e| e.within(PathSegment::Index(index))),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct DictAccess<'de> {
+    entries: std::vec::IntoIter<(&'de Vec<u8>, &'de BencodeValue)>,
+    value: Option<(&'de Vec<u8>, &'de BencodeValue)>,
+}
+
+impl<'de> de::MapAccess<'de> for DictAccess<'de> {
+    type Error = BencodeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, BencodeError> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some((key, value));
+        let key_node = Deserializer {
+            node: Node::Bytes(key),
+        };
+        seed.deserialize(key_node)
+            .map(Some)
+            .map_err(|e| e.within(PathSegment::Key(key.clone())))
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, BencodeError> {
+        let (key, value) = self
+            .value
+            .take()
+            .ok_or_else(|| BencodeError::new("Value requested before its key"))?;
+        seed.deserialize(Deserializer::new(value))
+            .map_err(|e| e.within(PathSegment::Key(key.clone())))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// An enum, written as its variant name or `{variant: contents}`.
+struct Enum<'de> {
+    variant: &'de [u8],
+    value: Option<&'de BencodeValue>,
+}
+
+impl<'de> de::EnumAccess<'de> for Enum<'de> {
+    type Error = BencodeError;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), BencodeError> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Enum<'de> {
+    type Error = BencodeError;
+
+    fn unit_variant(self) -> Result<(), BencodeError> {
+        match self.value {
+            None => Ok(()),
+            Some(_) => Err(BencodeError::new("Unit variant must not have contents")),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, BencodeError> {
+        seed.deserialize(self.contents()?)
+            .map_err(|e| e.within(PathSegment::Key(self.variant.to_vec())))
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        self.contents()?
+            .deserialize_seq(visitor)
+            .map_err(|e| e.within(PathSegment::Key(self.variant.to_vec())))
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, BencodeError> {
+        self.contents()?
+            .deserialize_map(visitor)
+            .map_err(|e| e.within(PathSegment::Key(self.variant.to_vec())))
+    }
+}
+
+impl<'de> Enum<'de> {
+    fn contents(&self) -> Result<Deserializer<'de>, BencodeError> {
+        self.value
+            .map(Deserializer::new)
+            .ok_or_else(|| BencodeError::new("Variant is missing its contents"))
+    }
+}
+
+/// Turns Rust values into [`BencodeValue`]s.
+///
+/// Produces `None` for values bencode cannot express in place, such as
+/// `Option::None`; dictionaries drop such entries and lists reject them.
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeVariant<SerializeList>;
+    type SerializeMap = SerializeDict;
+    type SerializeStruct = SerializeDict;
+    type SerializeStructVariant = SerializeVariant<SerializeDict>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BencodeValue::Integer(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, BencodeError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, BencodeError> {
+        let v = i64::try_from(v)
+            .map_err(|_| BencodeError::new(format!("Integer {} is too large for bencode", v)))?;
+        self.serialize_i64(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, BencodeError> {
+        Err(BencodeError::new("Bencode has no floating point numbers"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, BencodeError> {
+        Err(BencodeError::new("Bencode has no floating point numbers"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, BencodeError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, BencodeError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, BencodeError> {
+        Ok(Some(BencodeValue::String(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, BencodeError> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, BencodeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, BencodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, BencodeError> {
+        let mut dict = HashMap::new();
+        if let Some(value) = value.serialize(Serializer)? {
+            dict.insert(variant.as_bytes().to_vec(), value);
+        }
+        Ok(Some(BencodeValue::Dictionary(dict)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, BencodeError> {
+        Ok(SerializeList {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, BencodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeList>, BencodeError> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeDict, BencodeError> {
+        Ok(SerializeDict {
+            entries: HashMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeDict, BencodeError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeDict>, BencodeError> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+pub struct SerializeList {
+    items: Vec<BencodeValue>,
+}
+
+impl SerializeList {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        let index = self.items.len();
+        let item = value
+            .serialize(Serializer)
+            .and_then(|item| item.ok_or_else(|| BencodeError::new("Lists cannot hold None")))
+            .map_err(|e| e.within(PathSegment::Index(index)))?;
+        self.items.push(item);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<BencodeValue>, BencodeError> {
+        Ok(Some(BencodeValue::List(self.items)))
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+pub struct SerializeDict {
+    entries: HashMap<Vec<u8>, BencodeValue>,
+    key: Option<Vec<u8>>,
+}
+
+impl SerializeDict {
+    fn insert<T: Serialize + ?Sized>(
+        &mut self,
+        key: Vec<u8>,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        match value.serialize(Serializer) {
+            Ok(Some(value)) => {
+                self.entries.insert(key, value);
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e.within(PathSegment::Key(key))),
+        }
+    }
+
+    fn finish(self) -> Result<Option<BencodeValue>, BencodeError> {
+        Ok(Some(BencodeValue::Dictionary(self.entries)))
+    }
+}
+
+impl ser::SerializeMap for SerializeDict {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), BencodeError> {
+        match key.serialize(Serializer)? {
+            Some(BencodeValue::String(key)) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(BencodeError::new("Dictionary keys must be strings")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| BencodeError::new("Value serialized before its key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeDict {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        self.finish()
+    }
+}
+
+/// A tuple or struct variant, written as `{variant: contents}`.
+pub struct SerializeVariant<T> {
+    variant: &'static str,
+    inner: T,
+}
+
+impl<T> SerializeVariant<T> {
+    fn wrap(variant: &str, contents: Option<BencodeValue>) -> Option<BencodeValue> {
+        let mut dict = HashMap::new();
+        if let Some(contents) = contents {
+            dict.insert(variant.as_bytes().to_vec(), contents);
+        }
+        Some(BencodeValue::Dictionary(dict))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeList> {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), BencodeError> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        Ok(Self::wrap(self.variant, self.inner.finish()?))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeDict> {
+    type Ok = Option<BencodeValue>;
+    type Error = BencodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), BencodeError> {
+        self.inner.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, BencodeError> {
+        Ok(Self::wrap(self.variant, self.inner.finish()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Action {
+        Ping,
+        Get { key: String },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        #[serde(rename = "t")]
+        transaction: ByteString,
+        #[serde(with = "byte_string")]
+        id: [u8; 4],
+        port: Option<u16>,
+        token: Option<String>,
+        nodes: Vec<u32>,
+        action: Action,
+        seen: bool,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let message = Message {
+            transaction: ByteString(vec![0xff, 0]),
+            id: *b"abcd",
+            port: Some(6881),
+            token: None,
+            nodes: vec![1, 2],
+            action: Action::Get {
+                key: "k".to_string(),
+            },
+            seen: true,
+        };
+        let bytes = to_bytes(&message).unwrap();
+        assert_eq!(
+            bytes,
+            b"d6:actiond3:Getd3:key1:kee2:id4:abcd5:nodesli1ei2ee4:porti6881e4:seeni1e1:t2:\xff\x00e"
+        );
+        assert_eq!(from_bytes::<Message>(&bytes).unwrap(), message);
+        assert_eq!(to_bytes(&Action::Ping).unwrap(), b"4:Ping");
+
+        let value = from_bytes::<BencodeValue>(&bytes).unwrap();
+        assert_eq!(to_bytes(&value).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_errors_name_the_path() {
+        let err =
+            from_bytes::<Message>(b"d6:action4:Ping2:id4:abcd5:nodesli1e3:twoe4:seeni0e1:t0:e")
+                .unwrap_err();
+        assert_eq!(
+            err.path,
+            vec![PathSegment::Key(b"nodes".to_vec()), PathSegment::Index(1)]
+        );
+        assert!(err.to_string().ends_with(" at nodes[1]"), "{}", err);
+
+        let err =
+            from_bytes::<Message>(b"d6:action4:Ping2:id3:abc5:nodesle4:seeni0e1:t0:e").unwrap_err();
+        assert!(err.to_string().ends_with(" at id"), "{}", err);
+
+        let err = to_bytes(&vec![Some(1), None]).unwrap_err();
+        assert_eq!(err.path, vec![PathSegment::Index(1)]);
+    }
+}
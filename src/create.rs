@@ -0,0 +1,356 @@
+/// Creates `.torrent` files from a file or directory on disk.
+use crate::parser::{BencodeValue, TorrentFileInfo, TorrentFiles, TorrentInfo, bencode_encode};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+/// Aim for roughly this many pieces when picking a piece length.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+#[derive(Debug)]
+pub struct CreateError {
+    pub message: String,
+}
+
+impl std::fmt::Display for CreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Create error: {}", self.message)
+    }
+}
+
+impl std::error::Error for CreateError {}
+
+impl From<io::Error> for CreateError {
+    fn from(err: io::Error) -> Self {
+        CreateError {
+            message: format!("IO error: {}", err),
+        }
+    }
+}
+
+/// Settings for a new torrent. Only `path` and at least one tracker are
+/// required; everything else has a sensible default.
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    pub path: PathBuf,
+    /// Piece length in bytes, or `None` to pick one from the total size.
+    pub piece_length: Option<u32>,
+    /// Tracker tiers, in order. The first URL of the first tier becomes `announce`.
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub private: bool,
+    pub web_seeds: Vec<String>,
+    /// Number of hashing threads, or `None` to use every CPU core.
+    pub threads: Option<usize>,
+}
+
+/// The result of [`create_torrent`].
+#[derive(Debug, Clone)]
+pub struct CreatedTorrent {
+    pub info: TorrentInfo,
+    pub info_hash: [u8; 20],
+    /// The bencoded `.torrent` file.
+    pub data: Vec<u8>,
+}
+
+/// Picks a power-of-two piece length giving roughly `TARGET_PIECE_COUNT`
+/// pieces, clamped to 16 KiB..16 MiB.
+pub fn auto_piece_length(total_size: u64) -> u32 {
+    let ideal = (total_size / TARGET_PIECE_COUNT).max(1);
+    ideal
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH as u64, MAX_PIECE_LENGTH as u64) as u32
+}
+
+/// Hashes `options.path` and builds a bencoded torrent for it.
+pub fn create_torrent(options: &CreateOptions) -> Result<CreatedTorrent, CreateError> {
+    let first_tracker = options
+        .trackers
+        .iter()
+        .flatten()
+        .next()
+        .ok_or_else(|| CreateError {
+            message: "At least one tracker URL is required".to_string(),
+        })?
+        .clone();
+
+    let name = options
+        .path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| CreateError {
+            message: format!("Cannot derive a name from '{}'", options.path.display()),
+        })?
+        .to_string();
+
+    let metadata = fs::metadata(&options.path)?;
+    let (disk_files, files) = if metadata.is_dir() {
+        let mut disk_files = Vec::new();
+        collect_files(&options.path, &mut disk_files)?;
+        disk_files.sort();
+        if disk_files.is_empty() {
+            return Err(CreateError {
+                message: format!("Directory '{}' contains no files", options.path.display()),
+            });
+        }
+
+        let mut files = Vec::new();
+        let mut sized_files = Vec::new();
+        for file in disk_files {
+            let length = fs::metadata(&file)?.len();
+            let path = file
+                .strip_prefix(&options.path)
+                .expect("collected files live under the root")
+                .components()
+                .map(|c| {
+                    c.as_os_str()
+                        .to_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| CreateError {
+                            message: format!("Path '{}' is not valid UTF-8", file.display()),
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            files.push(TorrentFileInfo {
+                path,
+                length,
+                ..Default::default()
+            });
+            sized_files.push((file, length));
+        }
+        (sized_files, TorrentFiles::Multiple { files })
+    } else {
+        let length = metadata.len();
+        (
+            vec![(options.path.clone(), length)],
+            TorrentFiles::Single { length },
+        )
+    };
+
+    let total_size: u64 = disk_files.iter().map(|(_, length)| length).sum();
+    let piece_length = options
+        .piece_length
+        .unwrap_or_else(|| auto_piece_length(total_size));
+    if piece_length == 0 {
+        return Err(CreateError {
+            message: "Piece length must be greater than zero".to_string(),
+        });
+    }
+
+    let threads = options.threads.unwrap_or_else(|| {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let pieces = hash_pieces(&disk_files, piece_length, threads)?;
+
+    let info = TorrentInfo {
+        name,
+        raw_name: None,
+        name_utf8: None,
+        piece_length,
+        pieces,
+        files,
+        meta_version: None,
+        file_tree: Vec::new(),
+        private: options.private,
+    };
+
+    let info_value = info.to_bencode();
+    let info_hash: [u8; 20] = Sha1::digest(bencode_encode(&info_value)).into();
+
+    let mut root = HashMap::new();
+    root.insert(b"announce".to_vec(), string_value(&first_tracker));
+    if options.trackers.iter().map(Vec::len).sum::<usize>() > 1 {
+        let tiers = options
+            .trackers
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|tier| BencodeValue::List(tier.iter().map(|url| string_value(url)).collect()))
+            .collect();
+        root.insert(b"announce-list".to_vec(), BencodeValue::List(tiers));
+    }
+    if let Some(ref comment) = options.comment {
+        root.insert(b"comment".to_vec(), string_value(comment));
+    }
+    if let Some(ref created_by) = options.created_by {
+        root.insert(b"created by".to_vec(), string_value(created_by));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    root.insert(b"creation date".to_vec(), BencodeValue::Integer(now as i64));
+    if !options.web_seeds.is_empty() {
+        let seeds = options
+            .web_seeds
+            .iter()
+            .map(|url| string_value(url))
+            .collect();
+        root.insert(b"url-list".to_vec(), BencodeValue::List(seeds));
+    }
+    root.insert(b"info".to_vec(), info_value);
+
+    Ok(CreatedTorrent {
+        info,
+        info_hash,
+        data: bencode_encode(&BencodeValue::Dictionary(root)),
+    })
+}
+
+fn string_value(s: &str) -> BencodeValue {
+    BencodeValue::String(s.as_bytes().to_vec())
+}
+
+/// Recursively lists regular files under `dir`. Symlinks are not followed.
+fn collect_files(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), out)?;
+        } else if file_type.is_file() {
+            out.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Hashes the concatenation of `files` in `piece_length` pieces, spreading
+/// the pieces over `threads` worker threads.
+fn hash_pieces(
+    files: &[(PathBuf, u64)],
+    piece_length: u32,
+    threads: usize,
+) -> Result<Vec<[u8; 20]>, CreateError> {
+    let total_size: u64 = files.iter().map(|(_, length)| length).sum();
+    let num_pieces = total_size.div_ceil(piece_length as u64) as usize;
+    let threads = threads.clamp(1, num_pieces.max(1));
+    let pieces_per_thread = num_pieces.div_ceil(threads).max(1);
+
+    let mut pieces = vec![[0u8; 20]; num_pieces];
+    thread::scope(|scope| {
+        let workers: Vec<_> = pieces
+            .chunks_mut(pieces_per_thread)
+            .enumerate()
+            .map(|(chunk_index, chunk)| {
+                scope.spawn(move || -> io::Result<()> {
+                    let first_piece = chunk_index * pieces_per_thread;
+                    let mut buf = vec![0u8; piece_length as usize];
+                    for (i, hash) in chunk.iter_mut().enumerate() {
+                        let offset = (first_piece + i) as u64 * piece_length as u64;
+                        let len = (total_size - offset).min(piece_length as u64) as usize;
+                        read_range(files, offset, &mut buf[..len])?;
+                        *hash = Sha1::digest(&buf[..len]).into();
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().expect("hashing thread panicked")?;
+        }
+        Ok::<(), io::Error>(())
+    })?;
+
+    Ok(pieces)
+}
+
+/// Fills `buf` with the bytes at `offset` in the concatenation of `files`.
+fn read_range(files: &[(PathBuf, u64)], mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+    for (path, length) in files {
+        if buf.is_empty() {
+            break;
+        }
+        if offset >= *length {
+            offset -= length;
+            continue;
+        }
+        let take = (*length - offset).min(buf.len() as u64) as usize;
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf[..take])?;
+        buf = &mut buf[take..];
+        offset = 0;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_torrent_file;
+
+    #[test]
+    fn test_auto_piece_length() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(1024 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(auto_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_create_directory_torrent_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("bundle");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("README"), vec![b'r'; 40_000]).unwrap();
+        fs::write(root.join("bin").join("tool"), vec![b't'; 10_000]).unwrap();
+
+        let options = CreateOptions {
+            path: root.clone(),
+            piece_length: Some(16 * 1024),
+            trackers: vec![
+                vec!["http://tracker.example/announce".to_string()],
+                vec!["http://backup.example/announce".to_string()],
+            ],
+            comment: Some("nightly build".to_string()),
+            private: true,
+            threads: Some(3),
+            ..Default::default()
+        };
+        let created = create_torrent(&options).unwrap();
+
+        // 50 000 bytes in 16 KiB pieces
+        assert_eq!(created.info.pieces.len(), 4);
+        let mut concatenated = vec![b'r'; 40_000];
+        concatenated.extend(vec![b't'; 10_000]);
+        let last_piece: [u8; 20] = Sha1::digest(&concatenated[3 * 16384..]).into();
+        assert_eq!(created.info.pieces[3], last_piece);
+
+        let torrent_path = dir.path().join("bundle.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+        let parsed = parse_torrent_file(torrent_path.to_str().unwrap()).unwrap();
+        assert_eq!(parsed.info_hash, created.info_hash);
+        assert_eq!(parsed.info.name, "bundle");
+        assert!(parsed.info.private);
+        assert_eq!(parsed.announce, "http://tracker.example/announce");
+        assert_eq!(parsed.announce_list.unwrap().len(), 2);
+        match parsed.info.files {
+            TorrentFiles::Multiple { files } => {
+                assert_eq!(files[0].path, vec!["README".to_string()]);
+                assert_eq!(files[1].path, vec!["bin".to_string(), "tool".to_string()]);
+            }
+            TorrentFiles::Single { .. } => panic!("expected a multi-file torrent"),
+        }
+    }
+
+    #[test]
+    fn test_create_requires_tracker() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file.bin");
+        fs::write(&file, b"data").unwrap();
+
+        let options = CreateOptions {
+            path: file,
+            ..Default::default()
+        };
+        assert!(create_torrent(&options).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+/// Zero-copy bencode decoding: a pull decoder that yields one event at a time,
+/// and [`BencodeRef`], a borrowed value tree built on top of it.
+///
+/// Unlike [`BencodeParser`](crate::parser::BencodeParser), nothing here copies
+/// strings or hashes keys, and nesting is tracked on the heap rather than the
+/// call stack. Both enforce the same depth limit.
+use crate::parser::{BencodeValue, ParseError, ParseMode};
+use std::ops::Range;
+
+/// Nesting allowed by default. Torrents need a handful of levels (the v2
+/// `file tree` one per directory), DHT messages fewer.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError {
+    pub message: String,
+    /// Byte offset of the offending token.
+    pub offset: usize,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Decode error: {} at byte {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for ParseError {
+    fn from(err: DecodeError) -> Self {
+        ParseError {
+            message: format!("{} at byte {}", err.message, err.offset),
+        }
+    }
+}
+
+/// One token of bencoded input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event<'a> {
+    Integer(i64),
+    /// A byte string; inside a dictionary, every other one is a key.
+    Bytes(&'a [u8]),
+    ListStart,
+    DictStart,
+    /// Closes the innermost list or dictionary.
+    End,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame<'a> {
+    dict: bool,
+    /// In a dictionary, whether the next token is a key.
+    expect_key: bool,
+    /// The dictionary's previous key, to check the order in strict mode.
+    last_key: Option<&'a [u8]>,
+}
+
+/// Pull decoder over a single bencoded value.
+///
+/// Each call to [`Decoder::next_event`] reads one token and checks it is
+/// allowed where it appears: keys must be byte strings, every key needs a
+/// value, and containers must be closed. Once the root value is complete it
+/// returns `None`; anything after it is left unread (see
+/// [`Decoder::position`]).
+///
+/// In [`ParseMode::Strict`] non-canonical integers, string lengths and key
+/// orders are errors too, and so is data after the root value. The lenient default accepts them silently; use
+/// [`BencodeParser`](crate::parser::BencodeParser) to collect warnings.
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    stack: Vec<Frame<'a>>,
+    max_depth: usize,
+    mode: ParseMode,
+    done: bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder {
+            data,
+            position: 0,
+            stack: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            mode: ParseMode::Lenient,
+            done: false,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Rejects lists and dictionaries nested deeper than `max_depth`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Offset of the next unread byte.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of lists and dictionaries currently open.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn error(&self, offset: usize, message: impl Into<String>) -> DecodeError {
+        DecodeError {
+            message: message.into(),
+            offset,
+        }
+    }
+
+    pub fn next_event(&mut self) -> Result<Option<Event<'a>>, DecodeError> {
+        if self.done {
+            return Ok(None);
+        }
+        let start = self.position;
+        let byte = match self.data.get(start) {
+            Some(&byte) => byte,
+            None if self.stack.is_empty() => {
+                return Err(self.error(start, "Unexpected end of data"));
+            }
+            None => return Err(self.error(start, "Unterminated list or dictionary")),
+        };
+        let frame = self.stack.last().copied();
+        let expect_key = frame.is_some_and(|frame| frame.dict && frame.expect_key);
+
+        let event = match byte {
+            b'e' if frame.is_some() => {
+                if frame.is_some_and(|frame| frame.dict && !frame.expect_key) {
+                    return Err(self.error(start, "Dictionary key without a value"));
+                }
+                self.position += 1;
+                self.stack.pop();
+                Event::End
+            }
+            _ if expect_key && !byte.is_ascii_digit() => {
+                return Err(self.error(start, "Dictionary key must be a string"));
+            }
+            b'i' => Event::Integer(self.read_integer()?),
+            b'0'..=b'9' if expect_key => {
+                let key = self.read_bytes()?;
+                self.check_key_order(start, key)?;
+                Event::Bytes(key)
+            }
+            b'0'..=b'9' => Event::Bytes(self.read_bytes()?),
+            b'l' | b'd' => {
+                if self.stack.len() >= self.max_depth {
+                    return Err(self.error(
+                        start,
+                        format!("Nesting deeper than {} levels", self.max_depth),
+                    ));
+                }
+                self.position += 1;
+                self.stack.push(Frame {
+                    dict: byte == b'd',
+                    expect_key: true,
+                    last_key: None,
+                });
+                return Ok(Some(if byte == b'd' {
+                    Event::DictStart
+                } else {
+                    Event::ListStart
+                }));
+            }
+            _ => {
+                return Err(self.error(start, format!("Unexpected character: {}", byte as char)));
+            }
+        };
+
+        // A key, a value or a whole container was read
+        match self.stack.last_mut() {
+            Some(frame) if frame.dict => frame.expect_key = !frame.expect_key,
+            Some(_) => {}
+            None => {
+                self.done = true;
+                if self.strict() && self.position < self.data.len() {
+                    return Err(self.error(self.position, "Trailing data after value"));
+                }
+            }
+        }
+        Ok(Some(event))
+    }
+
+    fn strict(&self) -> bool {
+        self.mode == ParseMode::Strict
+    }
+
+    fn check_key_order(&mut self, start: usize, key: &'a [u8]) -> Result<(), DecodeError> {
+        let frame = self
+            .stack
+            .last_mut()
+            .expect("keys are read inside a dictionary");
+        let previous = frame.last_key.replace(key);
+        if !self.strict() {
+            return Ok(());
+        }
+        match previous {
+            Some(previous) if key == previous => Err(self.error(start, "Duplicate dictionary key")),
+            Some(previous) if key < previous => {
+                Err(self.error(start, "Dictionary keys not sorted"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn read_integer(&mut self) -> Result<i64, DecodeError> {
+        let start = self.position;
+        let digits = start + 1;
+        let end = self.data[digits..]
+            .iter()
+            .position(|&b| b == b'e')
+            .map(|length| digits + length)
+            .ok_or_else(|| self.error(start, "Unterminated integer"))?;
+        let text = &self.data[digits..end];
+        let value = std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.parse::<i64>().ok())
+            .ok_or_else(|| self.error(start, "Invalid integer"))?;
+        if self.strict() {
+            // Only `0` may start with a zero, and there is no `+` or `-0`
+            let magnitude = text.strip_prefix(b"-").unwrap_or(text);
+            let canonical = !text.starts_with(b"+")
+                && (magnitude == b"0" && text.len() == 1 || !magnitude.starts_with(b"0"));
+            if !canonical {
+                return Err(self.error(start, "Non-canonical integer"));
+            }
+        }
+        self.position = end + 1;
+        Ok(value)
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.position;
+        let colon = self.data[start..]
+            .iter()
+            .position(|&b| b == b':')
+            .map(|length| start + length)
+            .ok_or_else(|| self.error(start, "Unterminated string length"))?;
+        let text = &self.data[start..colon];
+        let length = std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.parse::<usize>().ok())
+            .ok_or_else(|| self.error(start, "Invalid string length"))?;
+        if self.strict() && text.len() > 1 && text[0] == b'0' {
+            return Err(self.error(start, "Non-canonical string length"));
+        }
+        let begin = colon + 1;
+        if length > self.data.len() - begin {
+            return Err(self.error(start, "String longer than remaining data"));
+        }
+        self.position = begin + length;
+        Ok(&self.data[begin..self.position])
+    }
+}
+
+/// A bencoded value borrowing from the input.
+///
+/// Dictionaries keep their entries in input order, duplicates included, and
+/// every value remembers the bytes it was decoded from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BencodeRef<'a> {
+    pub kind: BencodeKind<'a>,
+    /// The value's original encoding.
+    pub raw: &'a [u8],
+    /// Where `raw` starts in the input.
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BencodeKind<'a> {
+    Bytes(&'a [u8]),
+    Integer(i64),
+    List(Vec<BencodeRef<'a>>),
+    Dict(Vec<(&'a [u8], BencodeRef<'a>)>),
+}
+
+/// A list or dictionary still being read.
+enum Partial<'a> {
+    List(Vec<BencodeRef<'a>>),
+    Dict(Vec<(&'a [u8], BencodeRef<'a>)>, Option<&'a [u8]>),
+}
+
+impl<'a> BencodeRef<'a> {
+    /// Decodes `data`, which must hold exactly one value.
+    pub fn parse(data: &'a [u8]) -> Result<Self, DecodeError> {
+        Self::decode(Decoder::new(data))
+    }
+
+    /// Decodes the rest of the input with a configured decoder, e.g. one
+    /// in strict mode or with another depth limit.
+    pub fn decode(mut decoder: Decoder<'a>) -> Result<Self, DecodeError> {
+        let data = decoder.data;
+        let mut open: Vec<(usize, Partial<'a>)> = Vec::new();
+        loop {
+            let start = decoder.position();
+            let event = decoder
+                .next_event()?
+                .expect("the decoder is not done before the root value ends");
+            let (kind, start) = match event {
+                Event::Integer(value) => (BencodeKind::Integer(value), start),
+                Event::Bytes(bytes) => {
+                    if let Some((_, Partial::Dict(_, key @ None))) = open.last_mut() {
+                        *key = Some(bytes);
+                        continue;
+                    }
+                    (BencodeKind::Bytes(bytes), start)
+                }
+                Event::ListStart => {
+                    open.push((start, Partial::List(Vec::new())));
+                    continue;
+                }
+                Event::DictStart => {
+                    open.push((start, Partial::Dict(Vec::new(), None)));
+                    continue;
+                }
+                Event::End => match open.pop().expect("the decoder matches every End") {
+                    (start, Partial::List(items)) => (BencodeKind::List(items), start),
+                    (start, Partial::Dict(entries, _)) => (BencodeKind::Dict(entries), start),
+                },
+            };
+
+            let value = BencodeRef {
+                kind,
+                raw: &data[start..decoder.position()],
+                offset: start,
+            };
+            match open.last_mut() {
+                None => {
+                    if decoder.position() != data.len() {
+                        return Err(DecodeError {
+                            message: "Trailing data after value".to_string(),
+                            offset: decoder.position(),
+                        });
+                    }
+                    return Ok(value);
+                }
+                Some((_, Partial::List(items))) => items.push(value),
+                Some((_, Partial::Dict(entries, key))) => {
+                    let key = key.take().expect("the decoder reads a key first");
+                    entries.push((key, value));
+                }
+            }
+        }
+    }
+
+    /// Byte range of the value in the input.
+    pub fn span(&self) -> Range<usize> {
+        self.offset..self.offset + self.raw.len()
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self.kind {
+            BencodeKind::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The byte string, if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&'a str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self.kind {
+            BencodeKind::Integer(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeRef<'a>]> {
+        match self.kind {
+            BencodeKind::List(ref items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&[(&'a [u8], BencodeRef<'a>)]> {
+        match self.kind {
+            BencodeKind::Dict(ref entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// The first value stored under `key`, if this is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeRef<'a>> {
+        self.as_dict()?
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Copies the value into an owned [`BencodeValue`]. Later duplicate
+    /// keys win, as with [`BencodeParser`](crate::parser::BencodeParser).
+    pub fn to_value(&self) -> BencodeValue {
+        match self.kind {
+            BencodeKind::Bytes(bytes) => BencodeValue::String(bytes.to_vec()),
+            BencodeKind::Integer(value) => BencodeValue::Integer(value),
+            BencodeKind::List(ref items) => {
+                BencodeValue::List(items.iter().map(BencodeRef::to_value).collect())
+            }
+            BencodeKind::Dict(ref entries) => BencodeValue::Dictionary(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.to_vec(), value.to_value()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::BencodeParser;
+
+    #[test]
+    fn test_events() {
+        let mut decoder = Decoder::new(b"d3:cowl3:mooi-4ee4:spami0ee");
+        let mut events = Vec::new();
+        while let Some(event) = decoder.next_event().unwrap() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                Event::DictStart,
+                Event::Bytes(b"cow"),
+                Event::ListStart,
+                Event::Bytes(b"moo"),
+                Event::Integer(-4),
+                Event::End,
+                Event::Bytes(b"spam"),
+                Event::Integer(0),
+                Event::End,
+            ]
+        );
+        assert_eq!(decoder.depth(), 0);
+
+        let error = |data: &[u8]| {
+            let mut decoder = Decoder::new(data);
+            loop {
+                match decoder.next_event() {
+                    Ok(Some(_)) => {}
+                    Ok(None) => panic!("{:?} decoded", data),
+                    Err(e) => return e,
+                }
+            }
+        };
+        assert_eq!(
+            error(b"di1e1:xe").message,
+            "Dictionary key must be a string"
+        );
+        assert_eq!(error(b"d1:ke").offset, 4);
+        assert_eq!(error(b"l1:a").message, "Unterminated list or dictionary");
+        assert_eq!(error(b"5:abc").message, "String longer than remaining data");
+        assert_eq!(error(b"i12").message, "Unterminated integer");
+    }
+
+    #[test]
+    fn test_borrowed_values_keep_order_and_spans() {
+        let data = b"d4:zetai1e5:alphal1:x1:yee";
+        let value = BencodeRef::parse(data).unwrap();
+        let keys: Vec<&[u8]> = value.as_dict().unwrap().iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![b"zeta".as_slice(), b"alpha".as_slice()]);
+
+        let alpha = value.get(b"alpha").unwrap();
+        assert_eq!(alpha.raw, b"l1:x1:ye");
+        assert_eq!(alpha.span(), 17..25);
+        assert_eq!(alpha.as_list().unwrap()[1].as_str(), Some("y"));
+        assert_eq!(value.get(b"zeta").unwrap().as_integer(), Some(1));
+        assert_eq!(value.span(), 0..data.len());
+
+        assert!(BencodeRef::parse(b"i1ei2e").is_err());
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let deep = format!("{}{}", "l".repeat(100_000), "e".repeat(100_000));
+        let err = BencodeRef::parse(deep.as_bytes()).unwrap_err();
+        assert_eq!(err.offset, DEFAULT_MAX_DEPTH);
+
+        let nested = b"llleee";
+        assert!(BencodeRef::decode(Decoder::new(nested).with_max_depth(3)).is_ok());
+        assert!(BencodeRef::decode(Decoder::new(nested).with_max_depth(2)).is_err());
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let strict = |data: &'static [u8]| {
+            BencodeRef::decode(Decoder::new(data).with_mode(ParseMode::Strict))
+        };
+        for (data, message, offset) in [
+            (b"i-0e".as_slice(), "Non-canonical integer", 0),
+            (b"li03ee", "Non-canonical integer", 1),
+            (b"i+3e", "Non-canonical integer", 0),
+            (b"l03:abce", "Non-canonical string length", 1),
+            (b"d1:bi1e1:ai2ee", "Dictionary keys not sorted", 7),
+            (b"d1:ai1e1:ai2ee", "Duplicate dictionary key", 7),
+        ] {
+            let err = strict(data).unwrap_err();
+            assert_eq!((err.message.as_str(), err.offset), (message, offset));
+            assert!(BencodeRef::parse(data).is_ok());
+        }
+        assert!(strict(b"d1:ai-10e1:bli0e0:d1:xi0e1:yi0eeee").is_ok());
+
+        // Lenient decoders stop after the root value; strict ones reject the rest
+        let mut lenient = Decoder::new(b"i1ei2e");
+        assert_eq!(lenient.next_event().unwrap(), Some(Event::Integer(1)));
+        assert_eq!(lenient.next_event().unwrap(), None);
+        let err = Decoder::new(b"i1ei2e")
+            .with_mode(ParseMode::Strict)
+            .next_event()
+            .unwrap_err();
+        assert_eq!(
+            (err.message.as_str(), err.offset),
+            ("Trailing data after value", 3)
+        );
+    }
+
+    #[test]
+    fn test_matches_owned_parser() {
+        let data = std::fs::read("samples/ubuntu-25.04-desktop-amd64.iso.torrent").unwrap();
+        let owned = BencodeParser::new(&data).parse().unwrap();
+        let borrowed = BencodeRef::parse(&data).unwrap();
+        assert_eq!(borrowed.to_value(), owned);
+
+        let mut parser = BencodeParser::new(&data).with_spans();
+        parser.parse().unwrap();
+        let info = parser
+            .span(&[crate::parser::PathSegment::Key(b"info".to_vec())])
+            .unwrap();
+        assert_eq!(borrowed.get(b"info").unwrap().span(), info);
+    }
+}
@@ -0,0 +1,413 @@
+/// Finds peers for torrents through the mainline DHT (BEP 5).
+///
+/// We take part as a read-only node (BEP 43): our queries carry `ro=1`, so
+/// other nodes leave us out of their routing tables and we never have to
+/// answer queries. A lookup starts at the bootstrap nodes and keeps asking
+/// the nodes closest to the info hash that it has not asked yet, collecting
+/// the peers they know. Announcing then tells the closest nodes that we
+/// have the torrent, using the tokens they handed out.
+///
+/// Private torrents (BEP 27) must never be looked up or announced here;
+/// callers check [`PeerSources::dht`](crate::peer_manager::PeerSources).
+use crate::bencode::{self, ByteString, byte_string};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// Well-known nodes to start lookups from.
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Time a node gets to answer one query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Nodes asked at once in each step of a lookup.
+const ALPHA: usize = 8;
+/// Closest nodes a lookup tries to reach, and announces to.
+const K: usize = 8;
+/// Queries one lookup sends at most.
+const MAX_QUERIES: usize = 64;
+/// Bytes per node in a compact `nodes` string: id, IPv4 address, port.
+const COMPACT_NODE_LEN: usize = 26;
+
+#[derive(Debug)]
+pub struct DhtError {
+    pub message: String,
+}
+
+impl std::fmt::Display for DhtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "DHT error: {}", self.message)
+    }
+}
+
+impl std::error::Error for DhtError {}
+
+impl From<io::Error> for DhtError {
+    fn from(err: io::Error) -> Self {
+        DhtError {
+            message: format!("IO error: {}", err),
+        }
+    }
+}
+
+/// Arguments of the queries we send. Fields a query does not use are left
+/// out.
+#[derive(Debug, Serialize)]
+struct QueryArgs {
+    #[serde(with = "byte_string")]
+    id: [u8; 20],
+    #[serde(with = "byte_string")]
+    info_hash: [u8; 20],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none", with = "byte_string::option")]
+    token: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Query<'a> {
+    #[serde(with = "byte_string")]
+    t: [u8; 2],
+    y: &'a str,
+    q: &'a str,
+    a: QueryArgs,
+    /// We are a read-only node (BEP 43).
+    ro: i64,
+}
+
+/// Any message a node sends us; only responses are looked at.
+#[derive(Debug, Deserialize)]
+struct Message {
+    #[serde(with = "byte_string")]
+    t: Vec<u8>,
+    #[serde(default)]
+    y: ByteString,
+    #[serde(default, deserialize_with = "bencode::lenient::or_default")]
+    r: Option<Response>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Response {
+    #[serde(default, with = "byte_string::option")]
+    id: Option<[u8; 20]>,
+    #[serde(default, with = "byte_string::option")]
+    token: Option<Vec<u8>>,
+    /// Compact node info of nodes closer to the target.
+    #[serde(default, with = "byte_string::option")]
+    nodes: Option<Vec<u8>>,
+    /// Compact addresses of peers in the swarm.
+    #[serde(default, deserialize_with = "bencode::lenient::or_default")]
+    values: Vec<ByteString>,
+}
+
+/// What a lookup found: peers, and the closest nodes that answered with
+/// the token needed to announce to them.
+#[derive(Debug, Default)]
+pub struct Lookup {
+    pub peers: Vec<SocketAddr>,
+    closest: Vec<(SocketAddr, Vec<u8>)>,
+}
+
+type Pending = Mutex<HashMap<[u8; 2], oneshot::Sender<Response>>>;
+
+/// A DHT client on one UDP socket. Lookups can run concurrently.
+pub struct Dht {
+    socket: Arc<UdpSocket>,
+    node_id: [u8; 20],
+    bootstrap: Vec<String>,
+    next_transaction: AtomicU16,
+    /// Queries waiting for an answer, by transaction id.
+    pending: Arc<Pending>,
+    receiver: JoinHandle<()>,
+}
+
+impl Dht {
+    /// Binds the UDP socket and starts reading answers. Lookups start from
+    /// `bootstrap` (`host:port` strings).
+    pub async fn bind(addr: SocketAddr, bootstrap: Vec<String>) -> Result<Self, DhtError> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let pending: Arc<Pending> = Arc::default();
+        let receiver = tokio::spawn(receive(socket.clone(), pending.clone()));
+        Ok(Dht {
+            socket,
+            node_id: rand::random(),
+            bootstrap,
+            next_transaction: AtomicU16::new(rand::random()),
+            pending,
+            receiver,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Looks up the peers of a torrent.
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Lookup {
+        let mut candidates: Vec<(Option<[u8; 20]>, SocketAddr)> = Vec::new();
+        for host in &self.bootstrap {
+            if let Ok(addrs) = tokio::net::lookup_host(host.as_str()).await {
+                candidates.extend(addrs.filter(SocketAddr::is_ipv4).map(|addr| (None, addr)));
+            }
+        }
+
+        let mut lookup = Lookup::default();
+        let mut answered: Vec<([u8; 20], SocketAddr, Vec<u8>)> = Vec::new();
+        let mut queried = HashSet::new();
+        while queried.len() < MAX_QUERIES {
+            // Closest first; bootstrap nodes, whose id we do not know, lead
+            candidates.sort_by_key(|(id, _)| id.map(|id| distance(&id, &info_hash)));
+            let next: Vec<SocketAddr> = candidates
+                .iter()
+                .map(|&(_, addr)| addr)
+                .filter(|addr| !queried.contains(addr))
+                .take(ALPHA.min(MAX_QUERIES - queried.len()))
+                .collect();
+            if next.is_empty() || self.reached_closest(&candidates, &queried) {
+                break;
+            }
+            queried.extend(next.iter().copied());
+
+            let queries = next.iter().map(|&addr| async move {
+                let args = self.args(info_hash, None, None);
+                (addr, self.query(addr, "get_peers", args).await)
+            });
+            for (addr, response) in join_all(queries).await {
+                let Some(response) = response else { continue };
+                for value in &response.values {
+                    if let Some(peer) = compact_peer(value)
+                        && !lookup.peers.contains(&peer)
+                    {
+                        lookup.peers.push(peer);
+                    }
+                }
+                for (id, node) in compact_nodes(response.nodes.as_deref().unwrap_or_default()) {
+                    if !candidates.iter().any(|&(_, known)| known == node) {
+                        candidates.push((Some(id), node));
+                    }
+                }
+                if let (Some(id), Some(token)) = (response.id, response.token) {
+                    answered.push((id, addr, token));
+                }
+            }
+        }
+
+        answered.sort_by_key(|(id, _, _)| distance(id, &info_hash));
+        lookup.closest = answered
+            .into_iter()
+            .take(K)
+            .map(|(_, addr, token)| (addr, token))
+            .collect();
+        lookup
+    }
+
+    /// Looks up the peers of a torrent, then tells the closest nodes that
+    /// we accept connections for it on `port`.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddr> {
+        let lookup = self.get_peers(info_hash).await;
+        let announces = lookup.closest.iter().map(|(addr, token)| {
+            let args = self.args(info_hash, Some(port), Some(token.clone()));
+            self.query(*addr, "announce_peer", args)
+        });
+        join_all(announces).await;
+        lookup.peers
+    }
+
+    /// Whether the `K` closest known nodes have all been asked already.
+    fn reached_closest(
+        &self,
+        candidates: &[(Option<[u8; 20]>, SocketAddr)],
+        queried: &HashSet<SocketAddr>,
+    ) -> bool {
+        let known: Vec<_> = candidates.iter().filter(|(id, _)| id.is_some()).collect();
+        !known.is_empty() && known.iter().take(K).all(|(_, addr)| queried.contains(addr))
+    }
+
+    fn args(&self, info_hash: [u8; 20], port: Option<u16>, token: Option<Vec<u8>>) -> QueryArgs {
+        QueryArgs {
+            id: self.node_id,
+            info_hash,
+            port,
+            token,
+        }
+    }
+
+    /// Sends a query and waits for its response. `None` on timeout or if
+    /// the query could not be sent.
+    async fn query(&self, addr: SocketAddr, method: &str, args: QueryArgs) -> Option<Response> {
+        let t = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let query = Query {
+            t,
+            y: "q",
+            q: method,
+            a: args,
+            ro: 1,
+        };
+        let packet = bencode::to_bytes(&query).ok()?;
+        let (sender, answer) = oneshot::channel();
+        self.pending.lock().unwrap().insert(t, sender);
+        let response = match self.socket.send_to(&packet, addr).await {
+            Ok(_) => timeout(QUERY_TIMEOUT, answer).await.ok()?.ok(),
+            Err(_) => None,
+        };
+        self.pending.lock().unwrap().remove(&t);
+        response
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+/// Hands each response to the query waiting for it; anything else,
+/// including queries from nodes that ignore `ro`, is dropped.
+async fn receive(socket: Arc<UdpSocket>, pending: Arc<Pending>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Ok(message) = bencode::from_bytes::<Message>(&buf[..len]) else {
+            continue;
+        };
+        let (Ok(t), Some(response)) = (<[u8; 2]>::try_from(message.t.as_slice()), message.r) else {
+            continue;
+        };
+        if message.y.as_slice() != b"r" {
+            continue;
+        }
+        if let Some(sender) = pending.lock().unwrap().remove(&t) {
+            let _ = sender.send(response);
+        }
+    }
+}
+
+/// XOR distance between two ids, comparable as bytes.
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+/// A peer in compact form: IPv4 address and port.
+fn compact_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let bytes: [u8; 6] = bytes.try_into().ok()?;
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+    (port != 0).then_some(SocketAddr::new(IpAddr::V4(ip), port))
+}
+
+fn compact_nodes(bytes: &[u8]) -> Vec<([u8; 20], SocketAddr)> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|node| {
+            let id: [u8; 20] = node[..20].try_into().unwrap();
+            Some((id, compact_peer(&node[20..])?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{BencodeParser, BencodeValue, bencode_encode};
+
+    /// A DHT node on localhost that answers `get_peers` with `peers` and
+    /// `nodes`, and reports the ports of `announce_peer` queries.
+    async fn fake_node(
+        id: [u8; 20],
+        peers: Vec<SocketAddr>,
+        nodes: Vec<([u8; 20], SocketAddr)>,
+    ) -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<i64>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (announced, receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let query = BencodeParser::new(&buf[..len]).parse().unwrap();
+                let dict = |value: &BencodeValue, key: &str| match value {
+                    BencodeValue::Dictionary(dict) => dict.get(key.as_bytes()).cloned(),
+                    _ => None,
+                };
+                let t = dict(&query, "t").unwrap();
+                let args = dict(&query, "a").unwrap();
+                assert_eq!(dict(&query, "ro"), Some(BencodeValue::Integer(1)));
+
+                let mut r = HashMap::new();
+                r.insert(b"id".to_vec(), BencodeValue::String(id.to_vec()));
+                if dict(&query, "q") == Some(BencodeValue::String(b"announce_peer".to_vec())) {
+                    assert_eq!(
+                        dict(&args, "token"),
+                        Some(BencodeValue::String(b"tk".to_vec()))
+                    );
+                    if let Some(BencodeValue::Integer(port)) = dict(&args, "port") {
+                        announced.send(port).unwrap();
+                    }
+                } else {
+                    r.insert(b"token".to_vec(), BencodeValue::String(b"tk".to_vec()));
+                    let values = peers.iter().map(|peer| {
+                        let SocketAddr::V4(peer) = peer else { panic!() };
+                        let mut bytes = peer.ip().octets().to_vec();
+                        bytes.extend(peer.port().to_be_bytes());
+                        BencodeValue::String(bytes)
+                    });
+                    r.insert(b"values".to_vec(), BencodeValue::List(values.collect()));
+                    let mut compact = Vec::new();
+                    for (node_id, node) in &nodes {
+                        let SocketAddr::V4(node) = node else { panic!() };
+                        compact.extend(node_id);
+                        compact.extend(node.ip().octets());
+                        compact.extend(node.port().to_be_bytes());
+                    }
+                    r.insert(b"nodes".to_vec(), BencodeValue::String(compact));
+                }
+                let mut response = HashMap::new();
+                response.insert(b"t".to_vec(), t);
+                response.insert(b"y".to_vec(), BencodeValue::String(b"r".to_vec()));
+                response.insert(b"r".to_vec(), BencodeValue::Dictionary(r));
+                let packet = bencode_encode(&BencodeValue::Dictionary(response));
+                socket.send_to(&packet, from).await.unwrap();
+            }
+        });
+        (addr, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_lookup_follows_nodes_and_announces() {
+        let info_hash = [0xAA; 20];
+        let far_peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let near_peer: SocketAddr = "10.0.0.2:51413".parse().unwrap();
+        // The bootstrap node only knows a node closer to the info hash
+        let (near, mut near_announces) = fake_node([0xAB; 20], vec![near_peer], vec![]).await;
+        let (bootstrap, mut bootstrap_announces) =
+            fake_node([0x01; 20], vec![far_peer], vec![([0xAB; 20], near)]).await;
+
+        let dht = Dht::bind("127.0.0.1:0".parse().unwrap(), vec![bootstrap.to_string()])
+            .await
+            .unwrap();
+        let peers = dht.announce(info_hash, 7000).await;
+        assert_eq!(peers, vec![far_peer, near_peer]);
+        assert_eq!(near_announces.recv().await, Some(7000));
+        assert_eq!(bootstrap_announces.recv().await, Some(7000));
+
+        assert_eq!(compact_nodes(&[0u8; 25]), Vec::new());
+        assert_eq!(compact_peer(&[127, 0, 0, 1, 0, 0]), None);
+    }
+}
@@ -1,16 +1,27 @@
-use crate::parser::{TorrentFile, TorrentFiles};
+use crate::merkle;
+use crate::parser::TorrentFile;
 use crate::peer_manager::PeerClient;
+use crate::peer_scoring::PeerScores;
+use crate::picker::{PickStrategy, PiecePicker};
+use crate::priority::{self, FilePriorities, FilePriority};
+use crate::resume::ResumeData;
+use crate::storage::{Storage, StorageError};
+use crate::stream::PieceProgress;
 use crate::ui::UIEvent;
-use crate::wire::PeerMessage;
-use sha1::{Digest, Sha1};
+use crate::web_seed::WebSeed;
+use crate::wire::{HashRequest, PeerMessage};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
 
-const BLOCK_SIZE: u32 = 16384; // 16KB standard block size
+pub const BLOCK_SIZE: u32 = 16384; // 16KB standard block size
 
 #[derive(Debug)]
 pub struct DownloadError {
@@ -33,18 +44,27 @@ impl From<io::Error> for DownloadError {
     }
 }
 
+impl From<StorageError> for DownloadError {
+    fn from(err: StorageError) -> Self {
+        DownloadError {
+            message: err.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct Block {
-    index: u32,
     begin: u32,
+    peer: IpAddr,
     data: Vec<u8>,
 }
 
+/// The blocks of one piece as a peer sends them. Only the blocks we
+/// request, [`BLOCK_SIZE`] long and aligned to it, are accepted.
 #[derive(Debug)]
 struct PieceBuffer {
     blocks: HashMap<u32, Block>, // key is begin offset
     total_size: u32,
-    received_size: u32,
 }
 
 impl PieceBuffer {
@@ -52,73 +72,194 @@ impl PieceBuffer {
         PieceBuffer {
             blocks: HashMap::new(),
             total_size: piece_size,
-            received_size: 0,
         }
     }
 
-    fn add_block(&mut self, block: Block) -> bool {
-        if !self.blocks.contains_key(&block.begin) {
-            self.received_size += block.data.len() as u32;
-            self.blocks.insert(block.begin, block);
+    /// Adds a block, returning whether the piece is now complete. A block
+    /// that does not match one we requested is a protocol error.
+    fn add_block(&mut self, block: Block) -> Result<bool, DownloadError> {
+        if self.block_length(block.begin) != Some(block.data.len() as u32) {
+            return Err(DownloadError {
+                message: format!(
+                    "Peer sent a block we did not request ({} bytes at offset {})",
+                    block.data.len(),
+                    block.begin
+                ),
+            });
+        }
+        self.blocks.entry(block.begin).or_insert(block);
+        Ok(self.is_complete())
+    }
+
+    /// Length of the block requested at `begin`, if one was.
+    fn block_length(&self, begin: u32) -> Option<u32> {
+        if !begin.is_multiple_of(BLOCK_SIZE) || begin >= self.total_size {
+            return None;
         }
-        self.is_complete()
+        Some(std::cmp::min(BLOCK_SIZE, self.total_size - begin))
     }
 
     fn is_complete(&self) -> bool {
-        self.received_size >= self.total_size
+        self.blocks.len() as u32 == self.total_size.div_ceil(BLOCK_SIZE)
     }
 
     fn assemble(&self) -> Vec<u8> {
         let mut piece_data = vec![0u8; self.total_size as usize];
-
         for block in self.blocks.values() {
             let start = block.begin as usize;
-            let end = start + block.data.len();
-            if end <= piece_data.len() {
-                piece_data[start..end].copy_from_slice(&block.data);
-            }
+            piece_data[start..start + block.data.len()].copy_from_slice(&block.data);
         }
-
         piece_data
     }
+
+    /// Lists `(begin, peer, data)` for every block, for peer scoring.
+    fn contributions(&self) -> Vec<(u32, IpAddr, &[u8])> {
+        self.blocks
+            .values()
+            .map(|block| (block.begin, block.peer, block.data.as_slice()))
+            .collect()
+    }
 }
 
 pub struct Downloader {
     torrent: TorrentFile,
-    output_file: File,
-    completed_pieces: Vec<bool>,
-    current_piece_buffer: Option<(u32, PieceBuffer)>,
-    peer_bitfield: Option<Vec<u8>>,
-    peer_choked: bool,
+    /// Shared by every connection and web seed feeding this download.
+    state: Mutex<DownloadState>,
+    /// Woken when a piece is verified or given back, for connections
+    /// waiting on pieces another one is fetching.
+    piece_released: Notify,
     ui_sender: Option<Sender<UIEvent>>,
     stop_signal: Option<Arc<AtomicBool>>,
+    stream: Option<PieceProgress>,
+    priorities: FilePriorities,
+    uploaded: AtomicU64,
+}
+
+struct DownloadState {
+    storage: Storage,
+    completed_pieces: Vec<bool>,
+    /// Verified pieces in the order they were verified, so connections
+    /// can tell their peer about the new ones.
+    verified: Vec<u32>,
+    /// Pieces some connection or web seed is fetching right now.
+    in_flight: Vec<bool>,
+    peer_scores: PeerScores,
+    picker: PiecePicker,
+    /// [`FilePriorities::version`] the picker was last updated for.
+    priorities_version: u64,
+}
+
+/// A piece taken by one source. Dropping it gives the piece back unless it
+/// was verified, so a failed or cancelled fetch leaves it for the others.
+struct Claim<'a> {
+    downloader: &'a Downloader,
+    piece: u32,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.downloader.lock().in_flight[self.piece as usize] = false;
+        self.downloader.piece_released.notify_waiters();
+    }
+}
+
+/// How long a source with nothing left to fetch waits for pieces other
+/// sources are fetching before checking again.
+const RELEASE_WAIT: Duration = Duration::from_secs(1);
+/// How long a peer may take to send every block of a piece we asked for.
+const PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a peer that wants nothing from us may keep us choked.
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest block a peer may ask for; longer requests are ignored.
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// A piece being fetched from one peer.
+struct PieceDownload<'a> {
+    claim: Claim<'a>,
+    buffer: PieceBuffer,
+    deadline: Instant,
+}
+
+/// One peer connection's side of the exchange.
+struct Connection<'a> {
+    downloader: &'a Downloader,
+    /// Pieces the peer has, most significant bit first. Counted in the
+    /// picker's availability while the connection lasts.
+    bitfield: Vec<u8>,
+    /// Whether the peer is choking us, and since when.
+    choked: bool,
+    choked_since: Instant,
+    /// Whether we are choking the peer.
+    choking: bool,
+    peer_interested: bool,
+    /// Pieces tried from this peer, so a bad one is not retried from it.
+    attempted: Vec<bool>,
+    /// How many of [`DownloadState::verified`] the peer has been told about.
+    announced: usize,
+    current: Option<PieceDownload<'a>>,
+}
+
+impl Connection<'_> {
+    fn has_all(&self, num_pieces: usize) -> bool {
+        (0..num_pieces as u32).all(|i| has_piece(Some(&self.bitfield), i))
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.downloader
+            .lock()
+            .picker
+            .remove_bitfield(&self.bitfield);
+    }
+}
+
+async fn send(peer: &mut PeerClient, message: PeerMessage) -> Result<(), DownloadError> {
+    peer.send_message(message).await.map_err(|e| DownloadError {
+        message: format!("Failed to send message: {}", e),
+    })
 }
 
 impl Downloader {
-    pub fn new(torrent: TorrentFile, output_path: &str) -> Result<Self, DownloadError> {
-        // Create or truncate the output file
-        let output_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(output_path)?;
-
-        // Pre-allocate file size for single file torrents
-        if let TorrentFiles::Single { length } = &torrent.info.files {
-            output_file.set_len(*length)?;
-        }
+    /// Creates a downloader writing the torrent's files under `output_dir`.
+    pub fn new(torrent: TorrentFile, output_dir: &Path) -> Result<Self, DownloadError> {
+        Downloader::new_with_priorities(torrent, output_dir, FilePriorities::default())
+    }
 
-        let num_pieces = torrent.info.pieces.len();
+    /// Like [`Downloader::new`], downloading files by priority. Skipped
+    /// files are not created; priorities may change while downloading.
+    pub fn new_with_priorities(
+        torrent: TorrentFile,
+        output_dir: &Path,
+        priorities: FilePriorities,
+    ) -> Result<Self, DownloadError> {
+        let current = priorities.to_vec();
+        let skipped: Vec<bool> = current
+            .iter()
+            .map(|&priority| priority == FilePriority::Skip)
+            .collect();
+        let storage = Storage::new_skipping(&torrent, output_dir, &skipped)?;
+        let num_pieces = torrent.num_pieces();
+        let mut picker = PiecePicker::new(num_pieces, PickStrategy::default());
+        picker.set_priorities(priority::piece_priorities(&torrent, &current));
 
         Ok(Downloader {
             torrent,
-            output_file,
-            completed_pieces: vec![false; num_pieces],
-            current_piece_buffer: None,
-            peer_bitfield: None,
-            peer_choked: true,
+            state: Mutex::new(DownloadState {
+                storage,
+                completed_pieces: vec![false; num_pieces],
+                verified: Vec::new(),
+                in_flight: vec![false; num_pieces],
+                peer_scores: PeerScores::default(),
+                picker,
+                priorities_version: priorities.version(),
+            }),
+            piece_released: Notify::new(),
             ui_sender: None,
             stop_signal: None,
+            stream: None,
+            priorities,
+            uploaded: AtomicU64::new(0),
         })
     }
 
@@ -132,262 +273,847 @@ impl Downloader {
         self
     }
 
-    pub fn download(&mut self, peer: &mut PeerClient) -> Result<(), DownloadError> {
-        if let Some(ref sender) = self.ui_sender {
-            let _ = sender.send(UIEvent::DownloadStarted);
-        }
+    pub fn with_peer_scores(mut self, peer_scores: PeerScores) -> Self {
+        self.state.get_mut().unwrap().peer_scores = peer_scores;
+        self
+    }
 
-        // Wait for bitfield and initial messages
-        self.handle_initial_messages(peer)?;
+    pub fn with_strategy(mut self, strategy: PickStrategy) -> Self {
+        self.state.get_mut().unwrap().picker.set_strategy(strategy);
+        self
+    }
 
-        if self.peer_choked {
-            return Err(DownloadError {
-                message: "Peer never unchoked us".to_string(),
-            });
-        }
+    /// Starts the playhead at `piece`, for [`PickStrategy::Streaming`].
+    pub fn with_playhead(mut self, piece: u32) -> Self {
+        self.state.get_mut().unwrap().picker.set_playhead(piece);
+        self
+    }
 
-        // Download pieces in order
-        for piece_index in 0..self.torrent.info.pieces.len() {
-            // Check if we should stop
-            if let Some(ref stop_signal) = self.stop_signal {
-                if stop_signal.load(Ordering::Relaxed) {
-                    return Err(DownloadError {
-                        message: "Download stopped by user".to_string(),
-                    });
-                }
+    /// Reports verified pieces to stream readers, and follows their
+    /// playhead when the strategy is [`PickStrategy::Streaming`].
+    pub fn with_stream(mut self, progress: PieceProgress) -> Self {
+        let state = self.state.get_mut().unwrap();
+        for (index, done) in state.completed_pieces.iter().enumerate() {
+            if *done {
+                progress.piece_verified(index as u32);
             }
+        }
+        self.stream = Some(progress);
+        self
+    }
 
-            if self.can_download_piece(piece_index as u32) {
-                self.download_piece(peer, piece_index as u32)?;
-            } else {
-                return Err(DownloadError {
-                    message: format!("Peer doesn't have piece {}", piece_index),
-                });
+    /// Skips the pieces a fast-resume file records as verified. The caller
+    /// checks [`ResumeData::matches`] first; data for another torrent is
+    /// ignored.
+    pub fn with_resume(mut self, resume: &ResumeData) -> Self {
+        if resume.info_hash == self.torrent.info_hash {
+            let state = self.state.get_mut().unwrap();
+            for index in 0..state.completed_pieces.len() {
+                if resume.has_piece(index) {
+                    state.mark_completed(index as u32);
+                }
             }
         }
+        self
+    }
 
-        if let Some(ref sender) = self.ui_sender {
-            let _ = sender.send(UIEvent::DownloadComplete);
-        }
+    fn lock(&self) -> MutexGuard<'_, DownloadState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.lock().peer_scores.is_banned(ip)
+    }
+
+    /// Whether every piece of the files not skipped is verified.
+    pub fn is_complete(&self) -> bool {
+        let (completed, total) = self.get_progress();
+        completed == total
+    }
+
+    /// Downloads every missing piece the peer has, uploading to it in turn.
+    ///
+    /// Any number of peers and web seeds can feed the downloader at once;
+    /// each piece is fetched by one of them at a time. Pieces that fail
+    /// verification are left for a later attempt. Succeeds once every
+    /// wanted piece has been verified.
+    pub async fn download(&self, peer: &mut PeerClient) -> Result<(), DownloadError> {
+        self.send_ui(UIEvent::DownloadStarted);
+        self.exchange(peer, true).await?;
+        self.send_ui(UIEvent::DownloadComplete);
         Ok(())
     }
 
-    fn handle_initial_messages(&mut self, peer: &mut PeerClient) -> Result<(), DownloadError> {
-        // Send interested message
-        peer.send_message(&PeerMessage::Interested)
-            .map_err(|e| DownloadError {
-                message: format!("Failed to send interested: {}", e),
-            })?;
-
-        // Handle initial messages
-        let mut messages_received = 0;
-        while messages_received < 10 {
-            // Limit to avoid infinite loop
-            match peer.receive_message() {
-                Ok(msg) => {
-                    messages_received += 1;
-                    match msg {
-                        PeerMessage::Bitfield(bits) => {
-                            self.peer_bitfield = Some(bits);
-                        }
-                        PeerMessage::Unchoke => {
-                            self.peer_choked = false;
-                            break; // Ready to start downloading
-                        }
-                        PeerMessage::Choke => {
-                            self.peer_choked = true;
-                        }
-                        PeerMessage::Have(piece_index) => {
-                            self.update_peer_has_piece(piece_index);
-                        }
-                        PeerMessage::KeepAlive => {
-                            // Ignore keep-alive messages
-                        }
-                        _other => {
-                            // Ignore other messages during initialization
-                        }
-                    }
-                }
+    /// Uploads verified pieces to the peer until it goes away or turns out
+    /// to have everything.
+    pub async fn seed(&self, peer: &mut PeerClient) -> Result<(), DownloadError> {
+        self.exchange(peer, false).await
+    }
+
+    /// Bytes of piece data sent to peers so far.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Runs one peer connection: answers its requests for pieces we have
+    /// and, when `download` is set, fetches the pieces we miss from it.
+    async fn exchange(&self, peer: &mut PeerClient, download: bool) -> Result<(), DownloadError> {
+        if self.is_banned(&peer.addr.ip()) {
+            return Err(DownloadError {
+                message: format!("Peer {} is banned", peer.addr.ip()),
+            });
+        }
+        let num_pieces = self.torrent.num_pieces();
+        peer.set_piece_count(num_pieces);
+
+        let (bitfield, announced) = self.our_bitfield();
+        if announced > 0 {
+            send(peer, PeerMessage::Bitfield(bitfield)).await?;
+        }
+        let mut connection = Connection {
+            downloader: self,
+            bitfield: vec![0; num_pieces.div_ceil(8)],
+            choked: true,
+            choked_since: Instant::now(),
+            choking: true,
+            peer_interested: false,
+            attempted: vec![false; num_pieces],
+            announced,
+            current: None,
+        };
+        if download && !self.is_complete() {
+            send(peer, PeerMessage::Interested).await?;
+        }
+
+        loop {
+            self.check_stopped()?;
+            for piece in self.verified_since(&mut connection.announced) {
+                send(peer, PeerMessage::Have(piece)).await?;
+            }
+            if !download && connection.has_all(num_pieces) {
+                // Two seeds have nothing to say to each other
+                return Ok(());
+            }
+            if download && !self.next_request(peer, &mut connection).await? {
+                return Ok(());
+            }
+
+            let deadline = Instant::now() + RELEASE_WAIT;
+            let message = match peer.receive_message_before(deadline).await {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                // A peer we only upload to may leave whenever it likes
+                Err(_) if !download => return Ok(()),
                 Err(e) => {
                     return Err(DownloadError {
                         message: format!("Failed to receive message: {}", e),
                     });
                 }
-            }
+            };
+            self.handle_message(peer, &mut connection, message).await?;
         }
-
-        Ok(())
     }
 
-    fn can_download_piece(&self, piece_index: u32) -> bool {
-        if let Some(ref bitfield) = self.peer_bitfield {
-            let byte_index = (piece_index / 8) as usize;
-            let bit_index = 7 - (piece_index % 8);
-
-            if byte_index < bitfield.len() {
-                return (bitfield[byte_index] >> bit_index) & 1 == 1;
+    /// Asks the peer for the next piece if it is not already sending one.
+    /// Returns `false` once there is nothing left to do with this peer.
+    async fn next_request<'a>(
+        &'a self,
+        peer: &mut PeerClient,
+        connection: &mut Connection<'a>,
+    ) -> Result<bool, DownloadError> {
+        if let Some(ref current) = connection.current {
+            if Instant::now() > current.deadline {
+                return Err(DownloadError {
+                    message: format!("Failed to download complete piece {}", current.claim.piece),
+                });
             }
+            return Ok(true);
         }
-        false
-    }
-
-    fn update_peer_has_piece(&mut self, piece_index: u32) {
-        if let Some(ref mut bitfield) = self.peer_bitfield {
-            let byte_index = (piece_index / 8) as usize;
-            let bit_index = 7 - (piece_index % 8);
-
-            if byte_index < bitfield.len() {
-                bitfield[byte_index] |= 1 << bit_index;
+        if connection.choked {
+            if !connection.peer_interested
+                && connection.choked_since.elapsed() > UNCHOKE_TIMEOUT
+                && !self.is_complete()
+            {
+                return Err(DownloadError {
+                    message: "Peer never unchoked us".to_string(),
+                });
             }
+            return Ok(true);
         }
-    }
 
-    fn download_piece(
-        &mut self,
-        peer: &mut PeerClient,
-        piece_index: u32,
-    ) -> Result<(), DownloadError> {
-        let piece_size = self.get_piece_size(piece_index);
-        let mut piece_buffer = PieceBuffer::new(piece_size);
+        let claim = {
+            let (bitfield, attempted) = (&connection.bitfield, &connection.attempted);
+            let allowed = |i: u32| !attempted[i as usize] && has_piece(Some(bitfield), i);
+            match self.claim(allowed)? {
+                Some(claim) => claim,
+                // Stay while the peer still wants pieces from us
+                None if self.is_complete() => return Ok(connection.peer_interested),
+                None if connection.peer_interested || self.any_in_flight(allowed) => {
+                    return Ok(true);
+                }
+                None => {
+                    let (completed, total) = self.get_progress();
+                    return Err(DownloadError {
+                        message: format!(
+                            "{} pieces still missing after peer {}",
+                            total - completed,
+                            peer.addr
+                        ),
+                    });
+                }
+            }
+        };
 
-        // Request all blocks for this piece
-        let num_blocks = (piece_size + BLOCK_SIZE - 1) / BLOCK_SIZE; // Ceiling division
-        for block_index in 0..num_blocks {
-            let begin = block_index * BLOCK_SIZE;
+        let piece_index = claim.piece;
+        connection.attempted[piece_index as usize] = true;
+        let piece_size = self.get_piece_size(piece_index);
+        for begin in (0..piece_size).step_by(BLOCK_SIZE as usize) {
             let length = std::cmp::min(BLOCK_SIZE, piece_size - begin);
-
             let request = PeerMessage::Request {
                 index: piece_index,
                 begin,
                 length,
             };
-
-            peer.send_message(&request).map_err(|e| DownloadError {
-                message: format!("Failed to send request: {}", e),
-            })?;
+            send(peer, request).await?;
         }
+        connection.current = Some(PieceDownload {
+            claim,
+            buffer: PieceBuffer::new(piece_size),
+            deadline: Instant::now() + PIECE_TIMEOUT,
+        });
+        Ok(true)
+    }
 
-        // Receive blocks until piece is complete
-        let mut blocks_received = 0;
-        while !piece_buffer.is_complete() && blocks_received < num_blocks * 2 {
-            // Check if we should stop
-            if let Some(ref stop_signal) = self.stop_signal {
-                if stop_signal.load(Ordering::Relaxed) {
-                    return Err(DownloadError {
-                        message: "Download stopped by user".to_string(),
-                    });
+    async fn handle_message<'a>(
+        &'a self,
+        peer: &mut PeerClient,
+        connection: &mut Connection<'a>,
+        message: PeerMessage,
+    ) -> Result<(), DownloadError> {
+        match message {
+            PeerMessage::Bitfield(mut bits) => {
+                bits.resize(connection.bitfield.len(), 0);
+                let mut state = self.lock();
+                // Replaces whatever the peer announced before
+                state.picker.remove_bitfield(&connection.bitfield);
+                state.picker.add_bitfield(&bits);
+                connection.bitfield = bits;
+            }
+            // A repeated Have must not count the peer twice
+            PeerMessage::Have(piece_index)
+                if !has_piece(Some(&connection.bitfield), piece_index) =>
+            {
+                self.lock().picker.add_have(piece_index);
+                set_has_piece(Some(&mut connection.bitfield), piece_index);
+            }
+            PeerMessage::Choke => {
+                connection.choked = true;
+                connection.choked_since = Instant::now();
+                // Requests are dropped on choke; the piece goes back
+                if let Some(current) = connection.current.take() {
+                    connection.attempted[current.claim.piece as usize] = false;
                 }
             }
-
-            match peer.receive_message() {
-                Ok(PeerMessage::Piece {
-                    index,
-                    begin,
-                    block,
-                }) => {
-                    if index == piece_index {
-                        let block = Block {
+            PeerMessage::Unchoke => connection.choked = false,
+            PeerMessage::Interested => {
+                connection.peer_interested = true;
+                if connection.choking {
+                    send(peer, PeerMessage::Unchoke).await?;
+                    connection.choking = false;
+                }
+            }
+            PeerMessage::NotInterested => connection.peer_interested = false,
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                if connection.choking || length > MAX_REQUEST_LENGTH || !self.has_verified(index) {
+                    return Ok(());
+                }
+                let block = self
+                    .lock()
+                    .storage
+                    .read_block(&self.torrent, index, begin, length);
+                // A request we cannot read is dropped, like one for a piece we lack
+                if let Ok(block) = block {
+                    send(
+                        peer,
+                        PeerMessage::Piece {
                             index,
                             begin,
+                            block,
+                        },
+                    )
+                    .await?;
+                    self.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+                }
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // Blocks of a piece given back on choke may still arrive
+                let complete = match connection.current {
+                    Some(ref mut current) if current.claim.piece == index => {
+                        current.buffer.add_block(Block {
+                            begin,
+                            peer: peer.addr.ip(),
                             data: block,
-                        };
-
-                        if piece_buffer.add_block(block) {
-                            break; // Piece is complete
-                        }
+                        })?
                     }
-                    blocks_received += 1;
-                }
-                Ok(PeerMessage::Choke) => {
-                    return Err(DownloadError {
-                        message: "Peer choked us during download".to_string(),
-                    });
-                }
-                Ok(PeerMessage::KeepAlive) => {
-                    // Ignore keep-alive
-                }
-                Ok(_other) => {
-                    // Ignore other messages during piece download
+                    _ => false,
+                };
+                if complete {
+                    let current = connection.current.take().unwrap();
+                    self.finish_piece(peer.addr, current)?;
                 }
-                Err(e) => {
-                    return Err(DownloadError {
-                        message: format!("Failed to receive piece data: {}", e),
-                    });
+            }
+            PeerMessage::HashRequest(request) => {
+                send(peer, answer_hash_request(&self.torrent, request)).await?;
+            }
+            _other => {
+                // Keep-alives, cancels (blocks go out at once) and the rest
+            }
+        }
+        Ok(())
+    }
+
+    /// Downloads missing pieces from a web seed, alongside any peers.
+    ///
+    /// The data goes through the same hash check as peer data. A mirror that
+    /// serves a bad piece is dropped rather than retried.
+    pub async fn download_from_web_seed(&self, seed: &WebSeed) -> Result<(), DownloadError> {
+        self.send_ui(UIEvent::DownloadStarted);
+
+        loop {
+            self.check_stopped()?;
+            let released = self.piece_released.notified();
+            let claim = match self.claim(|_| true)? {
+                Some(claim) => claim,
+                None if self.is_complete() => break,
+                None => {
+                    let _ = tokio::time::timeout(RELEASE_WAIT, released).await;
+                    continue;
                 }
+            };
+
+            let data = seed
+                .fetch_piece(&self.torrent, claim.piece)
+                .await
+                .map_err(|e| DownloadError {
+                    message: e.to_string(),
+                })?;
+            if !self.verify_and_write_piece(claim.piece, &data)? {
+                return Err(DownloadError {
+                    message: format!(
+                        "Piece {} from web seed {} failed its hash check",
+                        claim.piece, seed.url
+                    ),
+                });
             }
         }
 
-        if !piece_buffer.is_complete() {
+        self.send_ui(UIEvent::DownloadComplete);
+        Ok(())
+    }
+
+    fn check_stopped(&self) -> Result<(), DownloadError> {
+        if let Some(ref stop_signal) = self.stop_signal
+            && stop_signal.load(Ordering::Relaxed)
+        {
             return Err(DownloadError {
-                message: format!("Failed to download complete piece {}", piece_index),
+                message: "Download stopped by user".to_string(),
             });
         }
-
-        // Verify and write piece
-        let piece_data = piece_buffer.assemble();
-        self.verify_and_write_piece(piece_index, piece_data)?;
-
         Ok(())
     }
 
-    fn get_piece_size(&self, piece_index: u32) -> u32 {
-        let total_size = self.torrent.total_size();
-        let piece_length = self.torrent.info.piece_length;
-
-        if piece_index == (self.torrent.info.pieces.len() - 1) as u32 {
-            // Last piece might be smaller
-            let remaining = total_size % piece_length as u64;
-            if remaining == 0 {
-                piece_length
-            } else {
-                remaining as u32
+    fn send_ui(&self, event: UIEvent) {
+        if let Some(ref sender) = self.ui_sender {
+            let _ = sender.send(event);
+        }
+    }
+
+    /// Takes the next missing piece `allowed` accepts that no other source
+    /// is fetching. Pieces stream readers wait for come first, then the
+    /// rest follows their playhead.
+    fn claim(&self, allowed: impl Fn(u32) -> bool) -> Result<Option<Claim<'_>>, DownloadError> {
+        let mut state = self.lock();
+        state.refresh_priorities(&self.torrent, &self.priorities)?;
+        if let Some(ref stream) = self.stream {
+            if let Some(playhead) = stream.playhead() {
+                state.picker.set_playhead(playhead);
             }
-        } else {
-            piece_length
+            state.picker.set_urgent(stream.requested());
         }
+        let DownloadState {
+            picker, in_flight, ..
+        } = &mut *state;
+        let piece = picker.pick(|i| !in_flight[i as usize] && allowed(i));
+        Ok(piece.map(|piece| {
+            in_flight[piece as usize] = true;
+            Claim {
+                downloader: self,
+                piece,
+            }
+        }))
     }
 
-    fn verify_and_write_piece(
-        &mut self,
-        piece_index: u32,
-        data: Vec<u8>,
-    ) -> Result<(), DownloadError> {
-        // Verify SHA-1 hash
-        let mut hasher = Sha1::new();
-        hasher.update(&data);
-        let hash: [u8; 20] = hasher.finalize().into();
+    /// Whether another source is fetching a piece `allowed` accepts.
+    fn any_in_flight(&self, allowed: impl Fn(u32) -> bool) -> bool {
+        let state = self.lock();
+        (0..state.in_flight.len() as u32).any(|i| state.in_flight[i as usize] && allowed(i))
+    }
+
+    /// Checks and writes a piece a peer sent in full, scoring the peers
+    /// that sent its blocks.
+    fn finish_piece(&self, addr: SocketAddr, current: PieceDownload) -> Result<(), DownloadError> {
+        let piece_index = current.claim.piece;
+        let piece_data = current.buffer.assemble();
+        if self.verify_and_write_piece(piece_index, &piece_data)? {
+            let banned = self
+                .lock()
+                .peer_scores
+                .record_piece_passed(piece_index, &piece_data);
+            self.report_bans(banned);
+            return Ok(());
+        }
 
-        let expected_hash = &self.torrent.info.pieces[piece_index as usize];
+        // The piece stays missing and will be picked again later
+        drop(current.claim);
+        let banned = self
+            .lock()
+            .peer_scores
+            .record_hash_failure(piece_index, &current.buffer.contributions());
+        self.send_ui(UIEvent::PieceFailed(piece_index, addr));
+        self.report_bans(banned);
 
-        if hash != *expected_hash {
+        if self.is_banned(&addr.ip()) {
             return Err(DownloadError {
-                message: format!("Piece {} failed hash verification", piece_index),
+                message: format!("Peer {} banned after repeated hash failures", addr),
             });
         }
+        Ok(())
+    }
 
-        // Write to file at correct offset
-        let offset = piece_index as u64 * self.torrent.info.piece_length as u64;
-        self.output_file.seek(SeekFrom::Start(offset))?;
-        self.output_file.write_all(&data)?;
-        self.output_file.flush()?;
+    /// Our bitfield, and how many verified pieces it covers.
+    fn our_bitfield(&self) -> (Vec<u8>, usize) {
+        let state = self.lock();
+        let mut bitfield = vec![0u8; state.completed_pieces.len().div_ceil(8)];
+        for (index, _) in state
+            .completed_pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, done)| **done)
+        {
+            bitfield[index / 8] |= 0x80 >> (index % 8);
+        }
+        (bitfield, state.verified.len())
+    }
 
-        // Mark piece as completed
-        self.completed_pieces[piece_index as usize] = true;
+    /// Pieces verified since a connection last looked, moving `announced` on.
+    fn verified_since(&self, announced: &mut usize) -> Vec<u32> {
+        let state = self.lock();
+        let pieces = state.verified[*announced..].to_vec();
+        *announced = state.verified.len();
+        pieces
+    }
 
-        let completed = self.completed_pieces.iter().filter(|&&x| x).count();
-        let total = self.completed_pieces.len();
+    fn has_verified(&self, piece_index: u32) -> bool {
+        let state = self.lock();
+        state
+            .completed_pieces
+            .get(piece_index as usize)
+            .copied()
+            .unwrap_or(false)
+    }
 
-        // Send progress update to UI
-        if let Some(ref sender) = self.ui_sender {
-            let _ = sender.send(UIEvent::PieceCompleted(piece_index, completed, total));
+    fn report_bans(&self, banned: Vec<IpAddr>) {
+        for ip in banned {
+            self.send_ui(UIEvent::PeerBanned(ip));
         }
+    }
 
-        Ok(())
+    fn get_piece_size(&self, piece_index: u32) -> u32 {
+        self.torrent.piece_size(piece_index)
+    }
+
+    /// Writes the piece if it matches its hashes. Returns `false` if the
+    /// hash check failed.
+    fn verify_and_write_piece(&self, piece_index: u32, data: &[u8]) -> Result<bool, DownloadError> {
+        if !self.torrent.verify_piece(piece_index, data) {
+            return Ok(false);
+        }
+
+        let (completed, total) = {
+            let mut state = self.lock();
+
+            state
+                .storage
+                .write_piece(&self.torrent, piece_index, data)?;
+
+            state.mark_completed(piece_index);
+            let (completed, total) = state.picker.progress();
+            if completed == total {
+                state.storage.finalize()?;
+            }
+            (completed, total)
+        };
+        if let Some(ref stream) = self.stream {
+            stream.piece_verified(piece_index);
+        }
+
+        // Send progress update to UI
+        self.send_ui(UIEvent::PieceCompleted(piece_index, completed, total));
+        Ok(true)
     }
 
+    /// Verified and total pieces, counting only those of files not skipped.
     pub fn get_progress(&self) -> (usize, usize) {
-        let completed = self.completed_pieces.iter().filter(|&&x| x).count();
-        let total = self.completed_pieces.len();
-        (completed, total)
+        self.lock().picker.progress()
+    }
+}
+
+impl DownloadState {
+    /// Picks up priority changes made since the last pick, creating the
+    /// files that are no longer skipped.
+    fn refresh_priorities(
+        &mut self,
+        torrent: &TorrentFile,
+        priorities: &FilePriorities,
+    ) -> Result<(), DownloadError> {
+        let version = priorities.version();
+        if version == self.priorities_version {
+            return Ok(());
+        }
+        self.priorities_version = version;
+        let priorities = priorities.to_vec();
+        for (index, &priority) in priorities.iter().enumerate() {
+            if priority != FilePriority::Skip {
+                self.storage.unskip(index)?;
+            }
+        }
+        self.picker
+            .set_priorities(priority::piece_priorities(torrent, &priorities));
+        Ok(())
+    }
+
+    fn mark_completed(&mut self, piece_index: u32) {
+        if !self.completed_pieces[piece_index as usize] {
+            self.completed_pieces[piece_index as usize] = true;
+            self.verified.push(piece_index);
+        }
+        self.picker.mark_done(piece_index);
+    }
+}
+
+/// `hashes` from the torrent's piece layers, or a reject for hashes we
+/// cannot prove (the block layer, or a file without a piece layer).
+fn answer_hash_request(torrent: &TorrentFile, request: HashRequest) -> PeerMessage {
+    let hashes = torrent
+        .piece_layers
+        .get(&request.pieces_root)
+        .and_then(|layer| {
+            merkle::hashes_from_piece_layer(
+                layer,
+                torrent.info.piece_length,
+                request.base_layer,
+                request.index,
+                request.length,
+                request.proof_layers,
+            )
+        });
+    match hashes {
+        Some(hashes) => PeerMessage::Hashes { request, hashes },
+        None => PeerMessage::HashReject(request),
+    }
+}
+
+/// Whether a peer's bitfield (most significant bit first) has the piece.
+fn has_piece(bitfield: Option<&[u8]>, piece_index: u32) -> bool {
+    let byte_index = (piece_index / 8) as usize;
+    let bit_index = 7 - (piece_index % 8);
+    bitfield
+        .and_then(|bitfield| bitfield.get(byte_index))
+        .is_some_and(|byte| (byte >> bit_index) & 1 == 1)
+}
+
+fn set_has_piece(bitfield: Option<&mut [u8]>, piece_index: u32) {
+    let byte_index = (piece_index / 8) as usize;
+    let bit_index = 7 - (piece_index % 8);
+    if let Some(byte) = bitfield.and_then(|bitfield| bitfield.get_mut(byte_index)) {
+        *byte |= 1 << bit_index;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use crate::peer_manager::PeerConfig;
+    use crate::web_seed::WebSeedKind;
+    use crate::wire::{Handshake, MessageCodec};
+    use futures::{SinkExt, StreamExt};
+    use std::fs;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+
+    /// A four-piece single-file torrent and its content.
+    fn four_piece_torrent(dir: &std::path::Path) -> (TorrentFile, Vec<u8>) {
+        let content: Vec<u8> = (0..4 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("data.bin");
+        fs::write(&path, &content).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path,
+            piece_length: Some(PIECE_LENGTH as u32),
+            trackers: vec![vec!["http://tracker.invalid/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.join("data.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+        (
+            parse_torrent_file(torrent_path.to_str().unwrap()).unwrap(),
+            content,
+        )
+    }
+
+    /// A web seed serving `content` with `Range` requests after `delay`.
+    /// Ranges starting at or past `available` get a 404. Returns the seed
+    /// and the range starts it served.
+    async fn serve_web_seed(
+        content: Vec<u8>,
+        available: usize,
+        delay: Duration,
+    ) -> (WebSeed, Arc<Mutex<Vec<usize>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let log = served.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&head).to_lowercase();
+                let (start, end) = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(start, end)| (start.parse().unwrap(), end.parse::<usize>().unwrap()))
+                    .unwrap();
+                tokio::time::sleep(delay).await;
+                let response = if start < available {
+                    log.lock().unwrap().push(start);
+                    let body = &content[start..=end];
+                    let mut response = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(body);
+                    response
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_vec()
+                };
+                let _ = socket.write_all(&response).await;
+            }
+        });
+        let url = format!("http://{}/data.bin", addr);
+        let seed = WebSeed::new(&url, WebSeedKind::Url, reqwest::Client::new());
+        (seed, served)
+    }
+
+    /// A peer that has every piece of `content`. Resolves to the pieces it
+    /// was asked for, once the connection closes.
+    async fn serve_peer(
+        torrent: &TorrentFile,
+        content: Vec<u8>,
+    ) -> (SocketAddr, tokio::task::JoinHandle<Vec<u32>>) {
+        serve_peer_at([127, 0, 0, 1], torrent, content).await
+    }
+
+    /// Like [`serve_peer`], on its own loopback IP so it can be banned
+    /// apart from other peers.
+    async fn serve_peer_at(
+        ip: [u8; 4],
+        torrent: &TorrentFile,
+        content: Vec<u8>,
+    ) -> (SocketAddr, tokio::task::JoinHandle<Vec<u32>>) {
+        let listener = TcpListener::bind(SocketAddr::from((ip, 0))).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let info_hash = torrent.info_hash;
+        let num_pieces = torrent.num_pieces();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            socket.read_exact(&mut handshake).await.unwrap();
+            let reply = Handshake::new(info_hash, [2u8; 20]).serialize();
+            socket.write_all(&reply).await.unwrap();
+
+            let mut framed = Framed::new(socket, MessageCodec::new());
+            let bitfield = vec![0xFFu8 << (8 - num_pieces); 1];
+            framed.send(PeerMessage::Bitfield(bitfield)).await.unwrap();
+            framed.send(PeerMessage::Unchoke).await.unwrap();
+
+            let mut requested = Vec::new();
+            while let Some(Ok(message)) = framed.next().await {
+                if let PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } = message
+                {
+                    if begin == 0 {
+                        requested.push(index);
+                    }
+                    let start = index as usize * PIECE_LENGTH + begin as usize;
+                    let block = content[start..start + length as usize].to_vec();
+                    let piece = PeerMessage::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    if framed.send(piece).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            requested
+        });
+        (addr, server)
+    }
+
+    async fn connect(addr: SocketAddr, torrent: &TorrentFile) -> PeerClient {
+        PeerClient::connect(addr, torrent.info_hash, [1u8; 20], PeerConfig::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_web_seed_and_peer_fetch_different_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, content) = four_piece_torrent(dir.path());
+        // The mirror only has the first half, so the peer must fetch the rest
+        let (seed, served) =
+            serve_web_seed(content.clone(), 2 * PIECE_LENGTH, Duration::ZERO).await;
+        let (addr, peer_server) = serve_peer(&torrent, content.clone()).await;
+
+        let output = dir.path().join("out");
+        let downloader = Downloader::new(torrent.clone(), &output).unwrap();
+        let mut peer = connect(addr, &torrent).await;
+        let (seed_result, peer_result) = tokio::join!(
+            downloader.download_from_web_seed(&seed),
+            downloader.download(&mut peer)
+        );
+        assert!(seed_result.is_err());
+        peer_result.unwrap();
+        drop(peer);
+
+        assert!(downloader.is_complete());
+        assert_eq!(fs::read(output.join("data.bin")).unwrap(), content);
+        let requested = peer_server.await.unwrap();
+        assert!(requested.contains(&2) && requested.contains(&3));
+        // Each piece came from exactly one source
+        let from_seed: Vec<u32> = served
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&start| (start / PIECE_LENGTH) as u32)
+            .collect();
+        let mut all: Vec<u32> = from_seed.iter().chain(&requested).copied().collect();
+        all.sort();
+        assert_eq!(all, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_peer_takes_piece_a_web_seed_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, content) = four_piece_torrent(dir.path());
+        let mut corrupt = content.clone();
+        corrupt[100] ^= 0xFF;
+        // The mirror holds piece 0 until the peer has fetched everything
+        // else, then serves it corrupt
+        let (seed, _) = serve_web_seed(corrupt, content.len(), Duration::from_millis(300)).await;
+        let (addr, peer_server) = serve_peer(&torrent, content.clone()).await;
+
+        let output = dir.path().join("out");
+        let downloader = Downloader::new(torrent.clone(), &output).unwrap();
+        let fetch_from_peer = async {
+            // Let the web seed claim piece 0 first
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut peer = connect(addr, &torrent).await;
+            downloader.download(&mut peer).await
+        };
+        let (seed_result, peer_result) =
+            tokio::join!(downloader.download_from_web_seed(&seed), fetch_from_peer);
+        assert!(seed_result.unwrap_err().message.contains("hash check"));
+        peer_result.unwrap();
+
+        assert_eq!(fs::read(output.join("data.bin")).unwrap(), content);
+        let mut requested = peer_server.await.unwrap();
+        requested.sort();
+        assert_eq!(requested, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_smart_ban_bans_sender_of_failed_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, content) = four_piece_torrent(dir.path());
+        let mut corrupt = content.clone();
+        corrupt[100] ^= 0xFF;
+        let (bad_addr, _) = serve_peer_at([127, 0, 0, 2], &torrent, corrupt).await;
+        let (good_addr, _) = serve_peer_at([127, 0, 0, 3], &torrent, content.clone()).await;
+
+        // One strike is not enough to ban, so only smart-ban can
+        let output = dir.path().join("out");
+        let downloader = Downloader::new(torrent.clone(), &output)
+            .unwrap()
+            .with_peer_scores(PeerScores::new(10).with_smart_ban(true));
+        let mut bad = connect(bad_addr, &torrent).await;
+        // Piece 0 fails and is not retried from the same peer
+        assert!(downloader.download(&mut bad).await.is_err());
+        assert!(!downloader.is_banned(&bad_addr.ip()));
+
+        let mut good = connect(good_addr, &torrent).await;
+        downloader.download(&mut good).await.unwrap();
+        assert!(downloader.is_complete());
+        assert_eq!(fs::read(output.join("data.bin")).unwrap(), content);
+        assert!(downloader.is_banned(&bad_addr.ip()));
+        assert!(!downloader.is_banned(&good_addr.ip()));
+    }
+
+    #[test]
+    fn test_piece_buffer_accepts_only_requested_blocks() {
+        let block = |begin: u32, len: usize| Block {
+            begin,
+            peer: IpAddr::from([10, 0, 0, 1]),
+            data: vec![1; len],
+        };
+        let mut buffer = PieceBuffer::new(BLOCK_SIZE + 100);
+        // Oversized, misaligned, short and out of range
+        assert!(buffer.add_block(block(0, 2 * BLOCK_SIZE as usize)).is_err());
+        assert!(buffer.add_block(block(1, 100)).is_err());
+        assert!(buffer.add_block(block(BLOCK_SIZE, 99)).is_err());
+        assert!(buffer.add_block(block(2 * BLOCK_SIZE, 100)).is_err());
+
+        assert!(!buffer.add_block(block(0, BLOCK_SIZE as usize)).unwrap());
+        // A repeated block does not count twice
+        assert!(!buffer.add_block(block(0, BLOCK_SIZE as usize)).unwrap());
+        assert!(buffer.add_block(block(BLOCK_SIZE, 100)).unwrap());
+        assert_eq!(buffer.assemble().len(), BLOCK_SIZE as usize + 100);
     }
 }
@@ -0,0 +1,299 @@
+/// Changes the metadata around a torrent's `info` dictionary (trackers,
+/// comment, web seeds, ...) without touching the dictionary itself.
+use crate::parser::{BencodeParser, BencodeValue, ParseError, PathSegment, bencode_encode};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub struct EditError {
+    pub message: String,
+}
+
+impl std::fmt::Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Edit error: {}", self.message)
+    }
+}
+
+impl std::error::Error for EditError {}
+
+impl From<ParseError> for EditError {
+    fn from(err: ParseError) -> Self {
+        EditError {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// A loaded torrent being edited.
+///
+/// The `info` dictionary is written back byte for byte as it was read, even
+/// when it is not canonical bencode, so the info hash stays the same. Only
+/// edits to the dictionary itself (such as [`TorrentEditor::set_private`])
+/// re-encode it, and [`TorrentEditor::changes_info_hash`] reports them.
+#[derive(Debug, Clone)]
+pub struct TorrentEditor {
+    /// Every top-level key, including ones this client does not know.
+    root: HashMap<Vec<u8>, BencodeValue>,
+    original_info: Vec<u8>,
+    /// The edited `info` dictionary, once something in it was changed.
+    edited_info: Option<BencodeValue>,
+}
+
+impl TorrentEditor {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, EditError> {
+        let mut parser = BencodeParser::new(data).with_spans();
+        let root = match parser.parse()? {
+            BencodeValue::Dictionary(root) => root,
+            _ => {
+                return Err(EditError {
+                    message: "Torrent is not a dictionary".to_string(),
+                });
+            }
+        };
+        let info_span = parser
+            .span(&[PathSegment::Key(b"info".to_vec())])
+            .ok_or_else(|| EditError {
+                message: "Missing 'info' dictionary".to_string(),
+            })?;
+        if !matches!(
+            root.get(b"info".as_slice()),
+            Some(BencodeValue::Dictionary(_))
+        ) {
+            return Err(EditError {
+                message: "'info' is not a dictionary".to_string(),
+            });
+        }
+
+        Ok(TorrentEditor {
+            root,
+            original_info: data[info_span].to_vec(),
+            edited_info: None,
+        })
+    }
+
+    /// Replaces every tracker. `announce` becomes the first URL, and
+    /// `announce-list` holds the tiers when there is more than one URL.
+    /// Empty URLs are dropped, and no tiers removes both keys.
+    pub fn set_trackers(&mut self, tiers: &[Vec<String>]) {
+        let tiers: Vec<Vec<&String>> = tiers
+            .iter()
+            .map(|tier| tier.iter().filter(|url| !url.is_empty()).collect())
+            .filter(|tier: &Vec<&String>| !tier.is_empty())
+            .collect();
+        match tiers.first() {
+            Some(first) => {
+                self.root
+                    .insert(b"announce".to_vec(), string_value(first[0]));
+            }
+            None => {
+                self.root.remove(b"announce".as_slice());
+            }
+        }
+        if tiers.iter().map(|tier| tier.len()).sum::<usize>() > 1 {
+            let tiers = tiers
+                .iter()
+                .map(|tier| BencodeValue::List(tier.iter().map(|url| string_value(url)).collect()))
+                .collect();
+            self.root
+                .insert(b"announce-list".to_vec(), BencodeValue::List(tiers));
+        } else {
+            self.root.remove(b"announce-list".as_slice());
+        }
+    }
+
+    /// Sets or, with `None`, removes the comment. A `comment.utf-8` left by
+    /// another client goes too, so it cannot contradict the new comment.
+    pub fn set_comment(&mut self, comment: Option<&str>) {
+        self.set_string(b"comment", comment);
+    }
+
+    /// Sets or, with `None`, removes the `created by` field, like
+    /// [`TorrentEditor::set_comment`].
+    pub fn set_created_by(&mut self, created_by: Option<&str>) {
+        self.set_string(b"created by", created_by);
+    }
+
+    /// Replaces the BEP 19 web seeds (`url-list`); an empty list removes them.
+    pub fn set_web_seeds(&mut self, urls: &[String]) {
+        if urls.is_empty() {
+            self.root.remove(b"url-list".as_slice());
+        } else {
+            let urls = urls.iter().map(|url| string_value(url)).collect();
+            self.root
+                .insert(b"url-list".to_vec(), BencodeValue::List(urls));
+        }
+    }
+
+    /// Sets the BEP 27 `private` flag. It lives in the `info` dictionary, so
+    /// this changes the info hash whenever the flag actually flips.
+    pub fn set_private(&mut self, private: bool) {
+        let current = match self.info().get(b"private".as_slice()) {
+            Some(BencodeValue::Integer(flag)) => *flag == 1,
+            _ => false,
+        };
+        if current == private {
+            return;
+        }
+        let mut info = self.info().clone();
+        if private {
+            info.insert(b"private".to_vec(), BencodeValue::Integer(1));
+        } else {
+            info.remove(b"private".as_slice());
+        }
+        self.edited_info = Some(BencodeValue::Dictionary(info));
+    }
+
+    fn set_string(&mut self, key: &[u8], value: Option<&str>) {
+        self.root.remove([key, b".utf-8"].concat().as_slice());
+        match value {
+            Some(value) => {
+                self.root.insert(key.to_vec(), string_value(value));
+            }
+            None => {
+                self.root.remove(key);
+            }
+        }
+    }
+
+    fn info(&self) -> &HashMap<Vec<u8>, BencodeValue> {
+        let info = self
+            .edited_info
+            .as_ref()
+            .unwrap_or_else(|| &self.root[b"info".as_slice()]);
+        match info {
+            BencodeValue::Dictionary(info) => info,
+            _ => unreachable!("from_bytes checks that info is a dictionary"),
+        }
+    }
+
+    fn info_bytes(&self) -> Vec<u8> {
+        match self.edited_info {
+            Some(ref info) => bencode_encode(info),
+            None => self.original_info.clone(),
+        }
+    }
+
+    /// The v1 info hash of the torrent as loaded.
+    pub fn original_info_hash(&self) -> [u8; 20] {
+        Sha1::digest(&self.original_info).into()
+    }
+
+    /// The v1 info hash of the torrent as it would be written.
+    pub fn info_hash(&self) -> [u8; 20] {
+        Sha1::digest(self.info_bytes()).into()
+    }
+
+    /// Whether the edits so far put the torrent in a different swarm.
+    pub fn changes_info_hash(&self) -> bool {
+        self.info_bytes() != self.original_info
+    }
+
+    /// Encodes the edited torrent, splicing in the `info` bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut keys: Vec<&Vec<u8>> = self.root.keys().collect();
+        keys.sort();
+        let mut data = b"d".to_vec();
+        for key in keys {
+            data.extend_from_slice(&bencode_encode(&BencodeValue::String(key.clone())));
+            if key == b"info" {
+                data.extend_from_slice(&self.info_bytes());
+            } else {
+                data.extend_from_slice(&bencode_encode(&self.root[key]));
+            }
+        }
+        data.push(b'e');
+        data
+    }
+}
+
+fn string_value(s: &str) -> BencodeValue {
+    BencodeValue::String(s.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_torrent_file;
+    use std::fs;
+
+    // Keys out of order and an unknown key: canonical re-encoding would
+    // change these bytes
+    const TORRENT: &[u8] = b"d8:announce14:http://old/ann7:comment3:old4:infod\
+        4:name3:abc12:piece lengthi16384e6:lengthi3e6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+        1:xi1ee5:nodesl9:127.0.0.1ee";
+
+    #[test]
+    fn test_edit_keeps_info_bytes() {
+        let mut editor = TorrentEditor::from_bytes(TORRENT).unwrap();
+        let hash = editor.original_info_hash();
+        editor.set_trackers(&[
+            vec!["http://new/a".to_string(), "http://new/b".to_string()],
+            vec!["udp://backup:80".to_string()],
+        ]);
+        editor.set_comment(None);
+        editor.set_web_seeds(&["http://mirror/".to_string()]);
+        assert!(!editor.changes_info_hash());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("edited.torrent");
+        fs::write(&path, editor.to_bytes()).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(torrent.info_hash, hash);
+        assert_eq!(torrent.announce, "http://new/a");
+        assert_eq!(torrent.announce_list.unwrap().len(), 2);
+        assert_eq!(torrent.comment, None);
+        assert_eq!(torrent.web_seeds, vec!["http://mirror/"]);
+        // Unknown keys survive
+        let needle = b"5:nodesl9:127.0.0.1e";
+        assert!(editor.to_bytes().windows(needle.len()).any(|w| w == needle));
+    }
+
+    #[test]
+    fn test_clear_trackers() {
+        let mut editor = TorrentEditor::from_bytes(TORRENT).unwrap();
+        editor.set_trackers(&[]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trackerless.torrent");
+        fs::write(&path, editor.to_bytes()).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(torrent.announce, "");
+        assert_eq!(torrent.announce_list, None);
+        assert_eq!(torrent.info_hash, editor.original_info_hash());
+    }
+
+    #[test]
+    fn test_empty_tracker_urls_are_dropped() {
+        let mut editor = TorrentEditor::from_bytes(TORRENT).unwrap();
+        editor.set_trackers(&[
+            vec!["".to_string()],
+            vec!["http://a/".to_string(), "".to_string()],
+        ]);
+        let needle = b"8:announce9:http://a/";
+        assert!(editor.to_bytes().windows(needle.len()).any(|w| w == needle));
+        assert!(!editor.root.contains_key(b"announce-list".as_slice()));
+    }
+
+    #[test]
+    fn test_comment_replaces_utf8_variant() {
+        let mut data = TORRENT[..TORRENT.len() - 1].to_vec();
+        data.extend_from_slice(b"13:comment.utf-83:olde");
+        let mut editor = TorrentEditor::from_bytes(&data).unwrap();
+        editor.set_comment(Some("new"));
+        assert!(!editor.root.contains_key(b"comment.utf-8".as_slice()));
+        assert_eq!(editor.root[b"comment".as_slice()], string_value("new"));
+    }
+
+    #[test]
+    fn test_private_flag_changes_hash() {
+        let mut editor = TorrentEditor::from_bytes(TORRENT).unwrap();
+        editor.set_private(false);
+        assert!(!editor.changes_info_hash());
+        editor.set_private(true);
+        assert!(editor.changes_info_hash());
+        assert_ne!(editor.info_hash(), editor.original_info_hash());
+
+        assert!(TorrentEditor::from_bytes(b"d8:announce1:xe").is_err());
+    }
+}
@@ -0,0 +1,30 @@
+//! Il Pleut - A minimal BitTorrent client
+
+pub mod bencode;
+pub mod create;
+pub mod decoder;
+pub mod dht;
+pub mod download;
+pub mod edit;
+pub mod magnet;
+pub mod merkle;
+pub mod metadata;
+pub mod parser;
+pub mod peer_manager;
+pub mod peer_scoring;
+pub mod picker;
+pub mod priority;
+pub mod rate_limit;
+pub mod resume;
+pub mod rpc;
+pub mod sanitize;
+pub mod serve;
+pub mod session;
+pub mod storage;
+pub mod stream;
+pub mod summary;
+pub mod tracker;
+pub mod ui;
+pub mod verify;
+pub mod web_seed;
+pub mod wire;
@@ -0,0 +1,378 @@
+/// Magnet links (BEP 9), including v2 `btmh` hashes (BEP 52).
+use crate::edit::{EditError, TorrentEditor};
+use crate::merkle::Hash256;
+use crate::parser::TorrentFile;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+#[derive(Debug)]
+pub struct MagnetError {
+    pub message: String,
+}
+
+impl std::fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Magnet error: {}", self.message)
+    }
+}
+
+impl std::error::Error for MagnetError {}
+
+impl From<EditError> for MagnetError {
+    fn from(err: EditError) -> Self {
+        MagnetError {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Multihash prefix of a SHA-256 digest: function 0x12, length 0x20.
+const SHA256_MULTIHASH: &str = "1220";
+
+/// Everything but RFC 3986 unreserved characters is escaped in query values.
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MagnetLink {
+    /// v1 info hash (`xt=urn:btih:`).
+    pub info_hash: Option<[u8; 20]>,
+    /// v2 info hash (`xt=urn:btmh:`).
+    pub info_hash_v2: Option<Hash256>,
+    /// Suggested name (`dn`).
+    pub display_name: Option<String>,
+    /// Trackers (`tr`), in the order given.
+    pub trackers: Vec<String>,
+    /// Web seeds (`ws`).
+    pub web_seeds: Vec<String>,
+    /// Total size in bytes (`xl`).
+    pub exact_length: Option<u64>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let query = uri.strip_prefix("magnet:?").ok_or_else(|| MagnetError {
+            message: "Not a magnet link (must start with 'magnet:?')".to_string(),
+        })?;
+
+        let mut link = MagnetLink::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = decode_component(value);
+            // Numbered keys such as `tr.1` repeat a parameter
+            let key = key.split('.').next().unwrap_or(key);
+            match key {
+                "xt" => link.parse_exact_topic(&value)?,
+                "dn" => link.display_name = Some(value),
+                "tr" => link.trackers.push(value),
+                "ws" => link.web_seeds.push(value),
+                "xl" => {
+                    link.exact_length = Some(value.parse().map_err(|_| MagnetError {
+                        message: format!("Invalid length '{}'", value),
+                    })?)
+                }
+                _ => {}
+            }
+        }
+
+        if link.info_hash.is_none() && link.info_hash_v2.is_none() {
+            return Err(MagnetError {
+                message: "Missing 'xt=urn:btih:' or 'xt=urn:btmh:' info hash".to_string(),
+            });
+        }
+        Ok(link)
+    }
+
+    fn parse_exact_topic(&mut self, topic: &str) -> Result<(), MagnetError> {
+        let invalid = || MagnetError {
+            message: format!("Invalid info hash '{}'", topic),
+        };
+        if let Some(hash) = topic.strip_prefix("urn:btih:") {
+            let bytes = match hash.len() {
+                40 => hex_decode(hash),
+                32 => base32_decode(hash),
+                _ => None,
+            };
+            self.info_hash = Some(bytes.and_then(|b| b.try_into().ok()).ok_or_else(invalid)?);
+        } else if let Some(hash) = topic.strip_prefix("urn:btmh:") {
+            let digest = hash.strip_prefix(SHA256_MULTIHASH).ok_or_else(invalid)?;
+            self.info_hash_v2 = Some(
+                hex_decode(digest)
+                    .and_then(|b| b.try_into().ok())
+                    .ok_or_else(invalid)?,
+            );
+        }
+        // Other URNs (ed2k, sha1, ...) belong to other networks
+        Ok(())
+    }
+
+    /// The link for a torrent: both info hashes of a hybrid, its name, total
+    /// size, the trackers of every tier and the BEP 19 web seeds.
+    pub fn from_torrent(torrent: &TorrentFile) -> Self {
+        let mut trackers: Vec<String> = Vec::new();
+        let tiers = torrent.announce_list.iter().flatten().flatten();
+        for url in std::iter::once(&torrent.announce).chain(tiers) {
+            if !url.is_empty() && !trackers.contains(url) {
+                trackers.push(url.clone());
+            }
+        }
+
+        MagnetLink {
+            info_hash: torrent.info.has_v1().then_some(torrent.info_hash),
+            info_hash_v2: torrent.info_hash_v2,
+            display_name: Some(torrent.info.name.clone()),
+            trackers,
+            web_seeds: torrent.web_seeds.clone(),
+            exact_length: Some(torrent.total_size()),
+        }
+    }
+
+    /// Formats the link as a `magnet:?` URI.
+    pub fn to_uri(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(ref hash) = self.info_hash {
+            params.push(format!("xt=urn:btih:{}", hex_encode(hash)));
+        }
+        if let Some(ref hash) = self.info_hash_v2 {
+            params.push(format!(
+                "xt=urn:btmh:{}{}",
+                SHA256_MULTIHASH,
+                hex_encode(hash)
+            ));
+        }
+        if let Some(ref name) = self.display_name {
+            params.push(format!("dn={}", encode_component(name)));
+        }
+        if let Some(length) = self.exact_length {
+            params.push(format!("xl={}", length));
+        }
+        for url in &self.trackers {
+            params.push(format!("tr={}", encode_component(url)));
+        }
+        for url in &self.web_seeds {
+            params.push(format!("ws={}", encode_component(url)));
+        }
+        format!("magnet:?{}", params.join("&"))
+    }
+
+    /// Builds a .torrent from the `info` dictionary fetched from peers with
+    /// `ut_metadata` (BEP 9), after checking it against the link's hashes.
+    /// Each tracker gets its own tier, since magnet links have no tiers.
+    ///
+    /// v2 `piece layers` are not part of the metadata, so a torrent made
+    /// from a v2 link only has the hashes needed to verify whole files.
+    pub fn to_torrent(&self, info_bytes: &[u8]) -> Result<Vec<u8>, MagnetError> {
+        if !self.matches(info_bytes) {
+            return Err(MagnetError {
+                message: "Metadata does not match the link's info hash".to_string(),
+            });
+        }
+
+        let mut data = b"d4:info".to_vec();
+        data.extend_from_slice(info_bytes);
+        data.push(b'e');
+        let mut editor = TorrentEditor::from_bytes(&data)?;
+        let tiers: Vec<Vec<String>> = self.trackers.iter().map(|url| vec![url.clone()]).collect();
+        editor.set_trackers(&tiers);
+        editor.set_web_seeds(&self.web_seeds);
+        Ok(editor.to_bytes())
+    }
+
+    /// Hash of the swarm to join: the v1 hash, or the truncated v2 hash for
+    /// v2-only links. `None` for a link without either, which
+    /// [`MagnetLink::parse`] never returns.
+    pub fn swarm_hash(&self) -> Option<[u8; 20]> {
+        match (self.info_hash, self.info_hash_v2) {
+            (Some(hash), _) => Some(hash),
+            (None, Some(v2)) => Some(v2[..20].try_into().expect("20 of 32 bytes")),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether `info_bytes` hash to every info hash the link has. A link
+    /// without hashes matches nothing.
+    pub fn matches(&self, info_bytes: &[u8]) -> bool {
+        let v1 = self
+            .info_hash
+            .is_none_or(|hash| <[u8; 20]>::from(Sha1::digest(info_bytes)) == hash);
+        let v2 = self
+            .info_hash_v2
+            .is_none_or(|hash| Hash256::from(Sha256::digest(info_bytes)) == hash);
+        self.swarm_hash().is_some() && v1 && v2
+    }
+}
+
+/// Percent-decodes a query value; `+` stands for a space.
+fn decode_component(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn encode_component(value: &str) -> String {
+    utf8_percent_encode(value, QUERY_VALUE).to_string()
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, as used for 20-byte info hashes.
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+
+    const HASH: [u8; 20] = [
+        0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9, 0xf5, 0x19, 0xb3, 0x35, 0xaa,
+        0x7c, 0x13, 0x67, 0xa8, 0x8a,
+    ];
+
+    #[test]
+    fn test_parse_magnet() {
+        let link = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+             &dn=Big+Buck%20Bunny&tr=udp%3A%2F%2Ftracker.example%3A80\
+             &tr.1=http://t2/announce&ws=http://seed/&xl=1234",
+        )
+        .unwrap();
+        assert_eq!(link.info_hash, Some(HASH));
+        assert_eq!(link.display_name.as_deref(), Some("Big Buck Bunny"));
+        assert_eq!(
+            link.trackers,
+            vec!["udp://tracker.example:80", "http://t2/announce"]
+        );
+        assert_eq!(link.web_seeds, vec!["http://seed/"]);
+        assert_eq!(link.exact_length, Some(1234));
+        assert_eq!(link.swarm_hash(), Some(HASH));
+    }
+
+    #[test]
+    fn test_base32_and_v2_hashes() {
+        let base32 = base32_encode(&HASH);
+        assert_eq!(base32, "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK");
+        let link = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", base32)).unwrap();
+        assert_eq!(link.info_hash, Some(HASH));
+
+        let v2 = format!("magnet:?xt=urn:btmh:1220{}", "ab".repeat(32));
+        let link = MagnetLink::parse(&v2).unwrap();
+        assert_eq!(link.info_hash, None);
+        assert_eq!(link.swarm_hash(), Some([0xab; 20]));
+        assert_eq!(MagnetLink::default().swarm_hash(), None);
+        assert!(!MagnetLink::default().matches(b"de"));
+
+        assert!(MagnetLink::parse("magnet:?dn=nothing").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(MagnetLink::parse("http://example.com").is_err());
+    }
+
+    #[test]
+    fn test_torrent_to_magnet_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("Big Buck");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("movie.mkv"), vec![7u8; 50_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root,
+            piece_length: Some(16 * 1024),
+            trackers: vec![
+                vec!["http://a/announce".to_string()],
+                vec!["udp://b:80".to_string(), "http://a/announce".to_string()],
+            ],
+            web_seeds: vec!["http://seed/files/".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let path = dir.path().join("movie.torrent");
+        std::fs::write(&path, &created.data).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+
+        let uri = MagnetLink::from_torrent(&torrent).to_uri();
+        assert_eq!(
+            uri,
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=Big%20Buck&xl=50000\
+                 &tr=http%3A%2F%2Fa%2Fannounce&tr=udp%3A%2F%2Fb%3A80\
+                 &ws=http%3A%2F%2Fseed%2Ffiles%2F",
+                hex_encode(&created.info_hash)
+            )
+        );
+        let link = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(link, MagnetLink::from_torrent(&torrent));
+
+        // The info dictionary as ut_metadata would deliver it
+        let info_bytes = crate::parser::bencode_encode(&created.info.to_bencode());
+        let data = link.to_torrent(&info_bytes).unwrap();
+        std::fs::write(&path, data).unwrap();
+        let rebuilt = parse_torrent_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(rebuilt.info_hash, created.info_hash);
+        assert_eq!(rebuilt.announce, "http://a/announce");
+        assert_eq!(rebuilt.announce_list.unwrap()[1], vec!["udp://b:80"]);
+        assert_eq!(rebuilt.web_seeds, vec!["http://seed/files/"]);
+
+        assert!(link.to_torrent(b"d4:name1:xe").is_err());
+
+        // Without trackers the torrent has no announce at all
+        let trackerless = MagnetLink {
+            trackers: Vec::new(),
+            ..link
+        };
+        std::fs::write(&path, trackerless.to_torrent(&info_bytes).unwrap()).unwrap();
+        let rebuilt = parse_torrent_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(rebuilt.announce, "");
+        assert_eq!(rebuilt.info_hash, created.info_hash);
+    }
+}
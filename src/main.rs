@@ -1,25 +1,94 @@
-use crate::download::Downloader;
-use crate::peer_manager::PeerClient;
-use crate::ui::{UI, UIEvent};
-use crate::{parser::parse_torrent_file, tracker::TrackerClient};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use il_pleut::create::{CreateOptions, create_torrent};
+use il_pleut::edit::TorrentEditor;
+use il_pleut::magnet::{MagnetLink, hex_encode};
+use il_pleut::metadata;
+use il_pleut::parser::{ParseMode, ParseOptions, TorrentFile, parse_torrent_file_with};
+use il_pleut::peer_manager::PeerConfig;
+use il_pleut::peer_scoring::PeerScores;
+use il_pleut::picker::PickStrategy;
+use il_pleut::priority::{self, FilePriorities, FileSelection};
+use il_pleut::rate_limit::{self, RateLimits, RateSchedule, ScheduleRule};
+use il_pleut::resume::ResumeData;
+use il_pleut::rpc::{RpcClient, RpcServer};
+use il_pleut::sanitize;
+use il_pleut::serve::HttpServer;
+use il_pleut::session::{AddOptions, Session, SessionConfig, SessionEvent};
+use il_pleut::stream::{self, PieceProgress};
+use il_pleut::summary::TorrentSummary;
+use il_pleut::tracker::TrackerClient;
+use il_pleut::ui::{UI, UIEvent};
+use il_pleut::verify::verify_torrent;
+use serde_json::{Value, json};
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-
-mod download;
-mod parser;
-mod peer_manager;
-mod tracker;
-mod ui;
-mod wire;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
 
 /// Il Pleut - A minimal BitTorrent client
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    download: Option<DownloadArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a .torrent file from a file or directory
+    Create(CreateArgs),
+    /// Show what a .torrent file or magnet link contains, without downloading
+    Info(InfoArgs),
+    /// Hash-check downloaded data against its torrent
+    Verify(VerifyArgs),
+    /// Change a torrent's trackers, comment or web seeds, keeping its info hash
+    Edit(EditArgs),
+    /// Print the magnet link for a torrent, or build a torrent from a magnet link
+    Magnet(MagnetArgs),
+    /// Download a torrent while serving its files over HTTP, for media players
+    Serve(ServeArgs),
+    /// Run torrents in the background, controlled through a JSON-RPC API
+    Daemon(DaemonArgs),
+    /// Change the rate limits of a running daemon
+    Limit(LimitArgs),
+}
+
+/// The daemon's files, unless given elsewhere: `$XDG_STATE_HOME/il-pleut`,
+/// or `~/.local/state/il-pleut` when that is unset.
+fn state_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("il-pleut")
+}
+
+/// Where the daemon writes its API token, and where clients read it: the
+/// given file, else next to the unix socket, else in the state directory.
+fn token_file(token_file: Option<&Path>, socket: Option<&Path>) -> PathBuf {
+    match (token_file, socket) {
+        (Some(path), _) => path.to_path_buf(),
+        (None, Some(socket)) => socket.with_extension("token"),
+        (None, None) => state_dir().join("token"),
+    }
+}
+
+/// Download a torrent (the default when no subcommand is given)
+#[derive(clap::Args, Debug)]
+struct DownloadArgs {
     /// Path to the torrent file to download
     torrent_file: String,
 
@@ -30,12 +99,580 @@ struct Args {
     /// Port to listen on for peer connections
     #[arg(short, long, default_value = "6881")]
     port: u16,
+
+    /// Seconds to wait when connecting and handshaking with a peer
+    #[arg(long, default_value = "10")]
+    connect_timeout: u64,
+
+    /// Seconds a peer may stay silent before it is disconnected
+    #[arg(long, default_value = "180")]
+    peer_timeout: u64,
+
+    /// Ban a peer after it contributed to this many pieces that failed the hash check
+    #[arg(long, default_value = "2")]
+    ban_threshold: u32,
+
+    /// Ban a peer at once when a later good copy of a piece it sent shows
+    /// its data was bad
+    #[arg(long)]
+    smart_ban: bool,
+
+    /// Global download limit in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    download_limit: u64,
+
+    /// Global upload limit in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    upload_limit: u64,
+
+    /// Download limit for this torrent in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    torrent_download_limit: u64,
+
+    /// Upload limit for this torrent in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    torrent_upload_limit: u64,
+
+    /// Time-of-day override for the global limits, as HH:MM-HH:MM=DOWN,UP in
+    /// KiB/s (0 = unlimited). May be repeated; the first matching window wins.
+    #[arg(long, value_parser = rate_limit::parse_schedule_rule)]
+    schedule: Vec<ScheduleRule>,
+
+    /// Extra tracker to announce to; may be repeated. Ignored for private torrents.
+    #[arg(long = "add-tracker")]
+    extra_trackers: Vec<String>,
+
+    /// Encoding of names that are not UTF-8 and have no .utf-8 variant,
+    /// e.g. windows-1251 or shift_jis
+    #[arg(long = "codepage", value_parser = parse_codepage)]
+    parse_options: Option<ParseOptions>,
+
+    /// Reject non-canonical bencode in the torrent and in tracker responses
+    /// instead of warning about it
+    #[arg(long)]
+    strict: bool,
+
+    /// Fast-resume file from `verify --resume-file`; its pieces are not
+    /// downloaded again if the files are unchanged since
+    #[arg(long)]
+    resume: Option<PathBuf>,
+
+    /// Download this file (0-based, in the order `info` lists files) from
+    /// its start first, so it can be played while downloading
+    #[arg(long, value_name = "FILE_INDEX")]
+    stream: Option<usize>,
+
+    /// Only download these files: indices such as 0,2-4 (in the order
+    /// `info` lists files) or a glob such as '*.mkv'. May be repeated;
+    /// priorities can be changed in the UI later
+    #[arg(long, value_name = "FILES", value_parser = priority::parse_file_selection)]
+    only: Vec<FileSelection>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CreateArgs {
+    /// File or directory to share
+    path: PathBuf,
+
+    /// Where to write the .torrent file (defaults to <name>.torrent)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Tracker tier as comma-separated announce URLs; repeat for more tiers
+    #[arg(short, long = "tracker", required = true)]
+    trackers: Vec<String>,
+
+    /// Piece length in bytes (picked from the total size if omitted)
+    #[arg(long)]
+    piece_length: Option<u32>,
+
+    /// Free-form comment stored in the torrent
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// Value of the "created by" field
+    #[arg(long, default_value = concat!("il-pleut/", env!("CARGO_PKG_VERSION")))]
+    created_by: String,
+
+    /// Mark the torrent private (BEP 27): no DHT, PEX or local discovery
+    #[arg(long)]
+    private: bool,
+
+    /// HTTP/FTP URL serving the same content (BEP 19); may be repeated
+    #[arg(long = "web-seed")]
+    web_seeds: Vec<String>,
+
+    /// Number of hashing threads (defaults to every CPU core)
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    /// Path to a .torrent file, or a magnet link
+    source: String,
+
+    /// Print JSON instead of text, for scripts
+    #[arg(long)]
+    json: bool,
+
+    /// Reject non-canonical bencode instead of warning about it
+    #[arg(long)]
+    strict: bool,
+
+    /// Encoding of names that are not UTF-8 and have no .utf-8 variant
+    #[arg(long = "codepage", value_parser = parse_codepage)]
+    parse_options: Option<ParseOptions>,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Path to the torrent file
+    torrent_file: String,
+
+    /// Directory holding the torrent's data, as given to --output when downloading
+    #[arg(short, long, default_value = ".")]
+    data_dir: PathBuf,
+
+    /// Number of hashing threads (defaults to every CPU core)
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Write a fast-resume file for `--resume` from the result
+    #[arg(long)]
+    resume_file: Option<PathBuf>,
+
+    /// Reject non-canonical bencode instead of warning about it
+    #[arg(long)]
+    strict: bool,
+
+    /// Encoding of names that are not UTF-8 and have no .utf-8 variant
+    #[arg(long = "codepage", value_parser = parse_codepage)]
+    parse_options: Option<ParseOptions>,
+}
+
+#[derive(clap::Args, Debug)]
+struct EditArgs {
+    /// Path to the torrent file to edit
+    torrent_file: PathBuf,
+
+    /// Where to write the edited torrent (defaults to overwriting the input)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Tracker tier as comma-separated announce URLs; repeat for more tiers.
+    /// Replaces every existing tracker.
+    #[arg(short, long = "tracker", conflicts_with = "clear_trackers")]
+    trackers: Vec<String>,
+
+    /// Remove every tracker
+    #[arg(long)]
+    clear_trackers: bool,
+
+    /// New comment; an empty value removes it
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// New "created by" value; an empty value removes it
+    #[arg(long)]
+    created_by: Option<String>,
+
+    /// Web seed URL (BEP 19); repeat for more. Replaces every existing one.
+    #[arg(long = "web-seed", conflicts_with = "clear_web_seeds")]
+    web_seeds: Vec<String>,
+
+    /// Remove every web seed
+    #[arg(long)]
+    clear_web_seeds: bool,
+
+    /// Set the private flag. Changes the info hash.
+    #[arg(long, conflicts_with = "public")]
+    private: bool,
+
+    /// Clear the private flag. Changes the info hash.
+    #[arg(long)]
+    public: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct MagnetArgs {
+    /// Path to a .torrent file, or a magnet link to turn into one
+    source: String,
+
+    /// The bencoded info dictionary for a magnet link, if already at hand.
+    /// Without it, the dictionary is fetched from the link's trackers' peers
+    #[arg(long)]
+    metadata: Option<PathBuf>,
+
+    /// Port announced to trackers while fetching the dictionary
+    #[arg(short, long, default_value = "6881")]
+    port: u16,
+
+    /// Where to write the torrent built from a magnet link (defaults to <name>.torrent)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Address for the HTTP server; files are at their paths under the
+    /// output directory, with a list at /
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    #[command(flatten)]
+    download: DownloadArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct DaemonArgs {
+    /// Address for the JSON-RPC API
+    #[arg(long, default_value = "127.0.0.1:6800")]
+    listen: SocketAddr,
+
+    /// Listen on this unix socket instead of TCP
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// File to write the token API clients must send with `auth`, readable
+    /// only by the current user [default: next to --socket, or
+    /// ~/.local/state/il-pleut/token]
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// Stay attached to the terminal instead of running in the background
+    #[arg(long)]
+    foreground: bool,
+
+    /// File the background daemon appends its output to [default:
+    /// ~/.local/state/il-pleut/daemon.log]
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Output directory for torrents added without one
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+
+    /// Port to listen on for peer connections
+    #[arg(short, long, default_value = "6881")]
+    port: u16,
+
+    /// Torrents downloading at once (0 = unlimited)
+    #[arg(long, default_value = "3")]
+    max_active_downloads: usize,
+
+    /// Complete torrents seeding at once (0 = unlimited)
+    #[arg(long, default_value = "5")]
+    max_active_seeds: usize,
+
+    /// Peer connections open at once across all torrents
+    #[arg(long, default_value = "200")]
+    max_connections: usize,
+
+    /// Global download limit in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    download_limit: u64,
+
+    /// Global upload limit in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    upload_limit: u64,
+
+    /// Reject non-canonical bencode in added torrents and in tracker
+    /// responses instead of warning about it
+    #[arg(long)]
+    strict: bool,
+
+    /// Stop seeding a torrent once it has uploaded this many times its size
+    #[arg(long)]
+    seed_ratio: Option<f64>,
+
+    /// Stop seeding a torrent once it has seeded this many minutes
+    #[arg(long)]
+    seed_time: Option<u64>,
+
+    /// Do not look for peers in the DHT
+    #[arg(long)]
+    no_dht: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct LimitArgs {
+    /// Download limit in KiB/s (0 = unlimited); unchanged when missing
+    #[arg(long)]
+    download: Option<u64>,
+
+    /// Upload limit in KiB/s (0 = unlimited); unchanged when missing
+    #[arg(long)]
+    upload: Option<u64>,
+
+    /// Change this torrent's limits (hex info hash) instead of the global ones
+    #[arg(long)]
+    info_hash: Option<String>,
+
+    /// Address of the daemon's JSON-RPC API
+    #[arg(long, default_value = "127.0.0.1:6800")]
+    connect: SocketAddr,
+
+    /// Reach the daemon on this unix socket instead of TCP
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// File holding the daemon's API token [default: next to --socket, or
+    /// ~/.local/state/il-pleut/token]
+    #[arg(long)]
+    token_file: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Create(args)) => run_create(args),
+        Some(Command::Info(args)) => run_info(args),
+        Some(Command::Verify(args)) => run_verify(args),
+        Some(Command::Edit(args)) => run_edit(args),
+        Some(Command::Magnet(args)) => run_magnet(args).await,
+        Some(Command::Serve(args)) => run_tui(args.download, Some(args.listen)).await,
+        Some(Command::Daemon(args)) => run_daemon(args).await,
+        Some(Command::Limit(args)) => run_limit(args).await,
+        None => {
+            // clap only leaves this empty when a subcommand was given
+            let args = cli.download.expect("download arguments are required");
+            run_tui(args, None).await
+        }
+    }
+}
+
+fn run_create(args: CreateArgs) {
+    let options = CreateOptions {
+        path: args.path,
+        piece_length: args.piece_length,
+        trackers: args
+            .trackers
+            .iter()
+            .map(|tier| tier.split(',').map(|url| url.trim().to_string()).collect())
+            .collect(),
+        comment: args.comment,
+        created_by: Some(args.created_by),
+        private: args.private,
+        web_seeds: args.web_seeds,
+        threads: args.threads,
+    };
 
+    let created = match create_torrent(&options) {
+        Ok(created) => created,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(format!("{}.torrent", created.info.name)));
+    if let Err(e) = std::fs::write(&output, &created.data) {
+        eprintln!("Error: Cannot write '{}': {}", output.display(), e);
+        std::process::exit(1);
+    }
+
+    println!("Created {}", output.display());
+    println!(
+        "  Pieces: {} x {} bytes",
+        created.info.pieces.len(),
+        created.info.piece_length
+    );
+    println!("  Info hash: {}", hex_encode(&created.info_hash));
+}
+
+fn run_info(args: InfoArgs) {
+    let summary = if args.source.starts_with("magnet:") {
+        MagnetLink::parse(&args.source)
+            .map(|link| TorrentSummary::from_magnet(&link))
+            .map_err(|e| e.to_string())
+    } else {
+        let options = args
+            .parse_options
+            .unwrap_or_default()
+            .with_mode(parse_mode(args.strict));
+        parse_torrent_file_with(&args.source, &options)
+            .map(|torrent| {
+                // Keep stdout clean for --json
+                for warning in &torrent.warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                TorrentSummary::from_torrent(&torrent)
+            })
+            .map_err(|e| e.to_string())
+    };
+    let summary = match summary {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&summary).expect("summaries serialize to JSON");
+        println!("{}", json);
+    } else {
+        print!("{}", summary);
+    }
+}
+
+/// Exits with 0 when every piece is good, 1 when some are bad or missing,
+/// and 2 when the check could not run at all.
+fn run_verify(args: VerifyArgs) {
+    let options = args
+        .parse_options
+        .unwrap_or_default()
+        .with_mode(parse_mode(args.strict));
+    let torrent = match parse_torrent_file_with(&args.torrent_file, &options) {
+        Ok(torrent) => torrent,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if !args.data_dir.is_dir() {
+        eprintln!(
+            "Error: Data directory '{}' not found",
+            args.data_dir.display()
+        );
+        std::process::exit(2);
+    }
+
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let report = verify_torrent(&torrent, &args.data_dir, threads);
+    print!("{}", report);
+
+    if let Some(ref path) = args.resume_file {
+        let resume = ResumeData::from_report(&torrent, &args.data_dir, &report);
+        if let Err(e) = resume.save(path) {
+            eprintln!("Error: Cannot write '{}': {}", path.display(), e);
+            std::process::exit(2);
+        }
+        println!("Wrote fast-resume file {}", path.display());
+    }
+
+    if !report.is_complete() {
+        std::process::exit(1);
+    }
+}
+
+fn run_edit(args: EditArgs) {
+    let mut editor = match std::fs::read(&args.torrent_file)
+        .map_err(|e| e.to_string())
+        .and_then(|data| TorrentEditor::from_bytes(&data).map_err(|e| e.to_string()))
+    {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.clear_trackers || !args.trackers.is_empty() {
+        let tiers: Vec<Vec<String>> = args
+            .trackers
+            .iter()
+            .map(|tier| tier.split(',').map(|url| url.trim().to_string()).collect())
+            .collect();
+        editor.set_trackers(&tiers);
+    }
+    if let Some(ref comment) = args.comment {
+        editor.set_comment(Some(comment.as_str()).filter(|c| !c.is_empty()));
+    }
+    if let Some(ref created_by) = args.created_by {
+        editor.set_created_by(Some(created_by.as_str()).filter(|c| !c.is_empty()));
+    }
+    if args.clear_web_seeds || !args.web_seeds.is_empty() {
+        editor.set_web_seeds(&args.web_seeds);
+    }
+    if args.private || args.public {
+        editor.set_private(args.private);
+    }
+
+    if editor.changes_info_hash() {
+        eprintln!(
+            "Warning: the info hash changes from {} to {}; the edited torrent joins a new swarm",
+            hex_encode(&editor.original_info_hash()),
+            hex_encode(&editor.info_hash())
+        );
+    }
+
+    let output = args.output.unwrap_or(args.torrent_file);
+    if let Err(e) = std::fs::write(&output, editor.to_bytes()) {
+        eprintln!("Error: Cannot write '{}': {}", output.display(), e);
+        std::process::exit(1);
+    }
+    println!("Wrote {}", output.display());
+    println!("  Info hash: {}", hex_encode(&editor.info_hash()));
+}
+
+async fn run_magnet(args: MagnetArgs) {
+    if !args.source.starts_with("magnet:") {
+        match parse_torrent_file_with(&args.source, &ParseOptions::default()) {
+            Ok(torrent) => println!("{}", MagnetLink::from_torrent(&torrent).to_uri()),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let link = match MagnetLink::parse(&args.source) {
+        Ok(link) => link,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let info_bytes = match args.metadata {
+        Some(ref metadata) => std::fs::read(metadata)
+            .map_err(|e| format!("Cannot read '{}': {}", metadata.display(), e)),
+        None => {
+            eprintln!("Fetching metadata from the swarm...");
+            metadata::fetch(
+                &link,
+                &TrackerClient::new(),
+                &PeerConfig::default(),
+                args.port,
+            )
+            .await
+            .map_err(|e| e.to_string())
+        }
+    };
+    let data = match info_bytes.and_then(|info| link.to_torrent(&info).map_err(|e| e.to_string())) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let output = args.output.unwrap_or_else(|| {
+        let name = link
+            .display_name
+            .as_deref()
+            .and_then(sanitize::sanitize_component)
+            .or_else(|| link.swarm_hash().map(|hash| hex_encode(&hash)))
+            .unwrap_or_else(|| "magnet".to_string());
+        PathBuf::from(format!("{}.torrent", name))
+    });
+    if let Err(e) = std::fs::write(&output, data) {
+        eprintln!("Error: Cannot write '{}': {}", output.display(), e);
+        std::process::exit(1);
+    }
+    println!("Created {}", output.display());
+}
+
+/// Runs a download in the terminal UI, serving its files over HTTP on
+/// `listen` if given.
+async fn run_tui(args: DownloadArgs, listen: Option<SocketAddr>) {
     // Validate torrent file exists
     if !std::path::Path::new(&args.torrent_file).exists() {
         eprintln!("Error: Torrent file '{}' not found", args.torrent_file);
@@ -43,166 +680,482 @@ async fn main() {
     }
 
     // Validate output directory
-    if args.output != "." {
-        if let Err(e) = std::fs::create_dir_all(&args.output) {
+    if args.output != "."
+        && let Err(e) = std::fs::create_dir_all(&args.output)
+    {
+        eprintln!(
+            "Error: Cannot create output directory '{}': {}",
+            args.output, e
+        );
+        std::process::exit(1);
+    }
+
+    // Parse the torrent and pick its files before the UI takes the terminal
+    let parse_options = args
+        .parse_options
+        .unwrap_or_default()
+        .with_mode(parse_mode(args.strict));
+    let torrent = match parse_torrent_file_with(&args.torrent_file, &parse_options) {
+        Ok(torrent) => torrent,
+        Err(e) => {
+            eprintln!("Error: Failed to parse torrent: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let file_priorities = match priority::select_files(&torrent, &args.only) {
+        Ok(priorities) => FilePriorities::new(priorities),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let playhead = match args.stream {
+        Some(file_index) if file_index >= torrent.files().len() => {
             eprintln!(
-                "Error: Cannot create output directory '{}': {}",
-                args.output, e
+                "Error: No file {} to stream; the torrent has {}",
+                file_index,
+                torrent.files().len()
             );
             std::process::exit(1);
         }
+        Some(file_index) => Some(torrent.pieces_for_range(file_index, 0, 0).start),
+        None => None,
+    };
+
+    // Create rate limiters shared by every peer connection
+    let global_limits = RateLimits::new(
+        rate_limit::kib_limit(args.download_limit),
+        rate_limit::kib_limit(args.upload_limit),
+    );
+    let torrent_limits = RateLimits::new(
+        rate_limit::kib_limit(args.torrent_download_limit),
+        rate_limit::kib_limit(args.torrent_upload_limit),
+    );
+    if !args.schedule.is_empty() {
+        RateSchedule {
+            default_download: rate_limit::kib_limit(args.download_limit),
+            default_upload: rate_limit::kib_limit(args.upload_limit),
+            rules: args.schedule.clone(),
+        }
+        .spawn(global_limits.clone());
     }
 
     // Create UI
-    let mut ui = match UI::new() {
-        Ok(ui) => ui,
+    let ui = match UI::new() {
+        Ok(ui) => ui
+            .with_rate_limits("Global", global_limits.clone())
+            .with_rate_limits("Torrent", torrent_limits.clone()),
         Err(e) => {
             eprintln!("Failed to create UI: {}", e);
             std::process::exit(1);
         }
     };
 
+    let mut ui = if file_priorities.len() > 1 {
+        ui.with_file_priorities(file_priorities.clone())
+    } else {
+        ui
+    };
+
     let ui_sender = ui.get_event_sender();
-    let should_stop = Arc::new(AtomicBool::new(false));
-
-    // Start download process in background thread
-    let download_sender = ui_sender.clone();
-    let stop_signal = should_stop.clone();
-    let torrent_path = args.torrent_file.clone();
-    let output_dir = args.output.clone();
-    let port = args.port;
-    let download_handle = thread::spawn(move || {
-        tokio::runtime::Runtime::new()
-            .unwrap()
-            .block_on(async move {
-                run_download(download_sender, stop_signal, torrent_path, output_dir, port).await;
-            });
-    });
 
-    // Run UI (this blocks until user quits)
-    if let Err(e) = ui.run() {
-        eprintln!("UI error: {}", e);
-    }
+    let stream = match listen {
+        Some(addr) => {
+            Some(start_http_server(&torrent, &args.output, addr, ui_sender.clone()).await)
+        }
+        None => None,
+    };
 
-    // Signal download thread to stop
-    should_stop.store(true, Ordering::Relaxed);
+    let resume = args
+        .resume
+        .as_ref()
+        .and_then(|path| match ResumeData::load(path) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                let _ = ui_sender.send(UIEvent::Error(e.to_string()));
+                None
+            }
+        });
 
-    // Wait for download thread to complete with timeout
-    let _join_handle = thread::spawn(move || {
-        let _ = download_handle.join();
+    // A session with this one torrent, its events shown in the UI
+    let session = Session::new(SessionConfig {
+        port: args.port,
+        peer_config: PeerConfig {
+            connect_timeout: Duration::from_secs(args.connect_timeout),
+            handshake_timeout: Duration::from_secs(args.connect_timeout),
+            idle_timeout: Duration::from_secs(args.peer_timeout),
+            ..PeerConfig::default()
+        },
+        rate_limits: global_limits,
+        parse_mode: parse_options.mode,
+        ..SessionConfig::default()
     });
+    if let Err(e) = session.listen().await {
+        let _ = ui_sender.send(UIEvent::Error(e.to_string()));
+    }
+    let mut events = session.subscribe();
+    let event_sender = ui_sender.clone();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(SessionEvent::Torrent(_, event)) => {
+                    let _ = event_sender.send(event);
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let strategy = if stream.is_some() || playhead.is_some() {
+        PickStrategy::streaming()
+    } else {
+        PickStrategy::default()
+    };
+    let options = AddOptions {
+        output_dir: PathBuf::from(&args.output),
+        file_priorities,
+        resume,
+        rate_limits: torrent_limits,
+        extra_trackers: args.extra_trackers.clone(),
+        peer_scores: PeerScores::new(args.ban_threshold).with_smart_ban(args.smart_ban),
+        strategy,
+        playhead,
+        stream,
+        paused: false,
+    };
+    if let Err(e) = session.add(torrent, options) {
+        let _ = ui_sender.send(UIEvent::Error(e.to_string()));
+    }
+
+    // Run UI on a blocking thread (this blocks until user quits)
+    match tokio::task::spawn_blocking(move || ui.run()).await {
+        Ok(Err(e)) => eprintln!("UI error: {}", e),
+        Err(e) => eprintln!("UI error: {}", e),
+        Ok(Ok(())) => {}
+    }
 
-    // Give it 2 seconds to shut down gracefully
-    thread::sleep(Duration::from_secs(2));
+    // Give the download 2 seconds to stop gracefully
+    let _ = tokio::time::timeout(Duration::from_secs(2), session.shutdown()).await;
 
     // If still running, the process will exit anyway
     println!("Shutting down...");
 }
 
-async fn run_download(
-    ui_sender: std::sync::mpsc::Sender<UIEvent>,
-    should_stop: Arc<AtomicBool>,
-    torrent_path: String,
-    output_dir: String,
-    port: u16,
-) {
-    // Parse torrent file
-    let torrent = match parse_torrent_file(&torrent_path) {
-        Ok(torrent) => {
-            let _ = ui_sender.send(UIEvent::TorrentParsed(torrent.clone()));
-            torrent
+async fn run_daemon(args: DaemonArgs) {
+    let token_file = token_file(args.token_file.as_deref(), args.socket.as_deref());
+    if !args.foreground {
+        let log_file = args
+            .log_file
+            .unwrap_or_else(|| state_dir().join("daemon.log"));
+        let mut child = match detach(&log_file) {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Error: Failed to start the daemon: {}", e);
+                return;
+            }
+        };
+        // A daemon that cannot listen gives up right away
+        tokio::time::sleep(DAEMON_STARTUP).await;
+        match child.try_wait() {
+            Ok(Some(status)) => eprintln!(
+                "Error: The daemon exited at startup ({}); see {}",
+                status,
+                log_file.display()
+            ),
+            _ => println!(
+                "Daemon started with pid {}; its API token is in {} and its log in {}",
+                child.id(),
+                token_file.display(),
+                log_file.display()
+            ),
         }
+        return;
+    }
+
+    // Bound before the token is written, so a second daemon failing to
+    // start leaves the running one's token alone
+    let listener = match args.socket {
+        Some(ref path) => bind_unix(path),
+        None => TcpListener::bind(args.listen).await.map(ApiListener::Tcp),
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
         Err(e) => {
-            let _ = ui_sender.send(UIEvent::Error(format!("Failed to parse torrent: {}", e)));
-            return;
+            eprintln!("Error: JSON-RPC API failed: {}", e);
+            std::process::exit(1);
         }
     };
+    let token = hex_encode(&rand::random::<[u8; 16]>());
+    if let Err(e) = write_token(&token_file, &token) {
+        eprintln!(
+            "Error: Failed to write the API token to {}: {}",
+            token_file.display(),
+            e
+        );
+        std::process::exit(1);
+    }
 
-    // Create tracker client
-    let tracker_client = TrackerClient::new();
+    let session = Session::new(SessionConfig {
+        port: args.port,
+        rate_limits: RateLimits::new(
+            rate_limit::kib_limit(args.download_limit),
+            rate_limit::kib_limit(args.upload_limit),
+        ),
+        max_connections: args.max_connections,
+        max_active_downloads: args.max_active_downloads,
+        max_active_seeds: args.max_active_seeds,
+        parse_mode: parse_mode(args.strict),
+        dht: !args.no_dht,
+        seed_ratio: args.seed_ratio,
+        seed_time: args
+            .seed_time
+            .map(|minutes| Duration::from_secs(minutes * 60)),
+        ..SessionConfig::default()
+    });
+    match session.listen().await {
+        Ok(addr) => println!("Listening for peers on {}", addr),
+        Err(e) => eprintln!("Error: {}", e),
+    }
+    let server = RpcServer::new(session.clone(), args.output).with_token(token);
 
-    // Announce to tracker
-    let response = match tracker_client.announce(&torrent).await {
-        Ok(response) => {
-            let _ = ui_sender.send(UIEvent::TrackerResponse(response.clone()));
-            response
+    let result = match listener {
+        ApiListener::Tcp(listener) => {
+            println!("JSON-RPC API listening on {}", args.listen);
+            tokio::select! {
+                result = server.run(listener) => result,
+                _ = stop_signal() => Ok(()),
+            }
         }
-        Err(e) => {
-            let _ = ui_sender.send(UIEvent::Error(format!("Tracker error: {}", e)));
-            return;
+        #[cfg(unix)]
+        ApiListener::Unix(listener, path) => {
+            println!("JSON-RPC API listening on {}", path.display());
+            let result = tokio::select! {
+                result = server.run_unix(listener) => result,
+                _ = stop_signal() => Ok(()),
+            };
+            let _ = std::fs::remove_file(&path);
+            result
         }
     };
+    if let Err(e) = result {
+        eprintln!("Error: JSON-RPC API failed: {}", e);
+    }
 
-    // Find a suitable peer and start downloading
-    let mut connected = false;
-    for peer in &response.peers {
-        // Check if we should stop
-        if should_stop.load(Ordering::Relaxed) {
-            return;
-        }
+    println!("Shutting down...");
+    let _ = std::fs::remove_file(&token_file);
+    let _ = tokio::time::timeout(Duration::from_secs(2), session.shutdown()).await;
+}
 
-        let addr = SocketAddr::new(peer.ip, peer.port);
-        let _ = ui_sender.send(UIEvent::ConnectingToPeer(addr));
-
-        match PeerClient::connect(
-            addr,
-            torrent.info_hash,
-            tracker_client.get_peer_id().clone(),
-        ) {
-            Ok(mut peer_client) => {
-                let _ = ui_sender.send(UIEvent::PeerConnected(addr));
-
-                // Create downloader and start downloading
-                let output_filename = if output_dir == "." {
-                    format!("{}.download", torrent.info.name)
-                } else {
-                    format!("{}/{}.download", output_dir, torrent.info.name)
-                };
-
-                match Downloader::new(torrent.clone(), &output_filename) {
-                    Ok(downloader) => {
-                        let mut downloader = downloader
-                            .with_ui_sender(ui_sender.clone())
-                            .with_stop_signal(should_stop.clone());
-                        match downloader.download(&mut peer_client) {
-                            Ok(()) => {
-                                connected = true;
-                                break;
-                            }
-                            Err(e) => {
-                                let _ = ui_sender
-                                    .send(UIEvent::Error(format!("Download failed: {}", e)));
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let _ = ui_sender.send(UIEvent::Error(format!(
-                            "Failed to create downloader: {}",
-                            e
-                        )));
-                    }
-                }
-            }
-            Err(e) => {
-                let _ = ui_sender.send(UIEvent::PeerConnectionFailed(addr, e.to_string()));
-                continue;
-            }
+async fn run_limit(args: LimitArgs) {
+    let result = match args.socket {
+        #[cfg(unix)]
+        Some(ref path) => set_limits(tokio::net::UnixStream::connect(path).await, &args).await,
+        #[cfg(not(unix))]
+        Some(_) => Err("unix sockets are not available on this platform".to_string()),
+        None => set_limits(tokio::net::TcpStream::connect(args.connect).await, &args).await,
+    };
+    match result {
+        Ok(limits) => {
+            let kib = |name: &str| match limits[name].as_u64() {
+                Some(0) | None => "unlimited".to_string(),
+                Some(kib) => format!("{} KiB/s", kib),
+            };
+            println!(
+                "Download limit: {}, upload limit: {}",
+                kib("download_limit"),
+                kib("upload_limit")
+            );
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
         }
+    }
+}
 
-        // Check again after each connection attempt
-        if should_stop.load(Ordering::Relaxed) {
-            let _ = ui_sender.send(UIEvent::DownloadStopped);
+/// Authenticates on the daemon connection `stream` and sends it the new
+/// limits, returning the limits now in force.
+async fn set_limits<S>(stream: std::io::Result<S>, args: &LimitArgs) -> Result<Value, String>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    let stream = stream.map_err(|e| format!("Cannot reach the daemon: {}", e))?;
+    let token_file = token_file(args.token_file.as_deref(), args.socket.as_deref());
+    let token = std::fs::read_to_string(&token_file).map_err(|e| {
+        format!(
+            "Cannot read the API token from {}: {}",
+            token_file.display(),
+            e
+        )
+    })?;
+    let mut client = RpcClient::new(stream);
+    let requests = [
+        ("auth", json!({"token": token.trim()})),
+        (
+            "set_limits",
+            json!({
+                "info_hash": args.info_hash,
+                "download_limit": args.download,
+                "upload_limit": args.upload,
+            }),
+        ),
+    ];
+    let mut result = Value::Null;
+    for (method, params) in requests {
+        result = client
+            .call(method, params)
+            .await
+            .map_err(|e| format!("Lost the connection to the daemon: {}", e))?
+            .map_err(|e| e.message)?;
+    }
+    Ok(result)
+}
+
+/// Waits for Ctrl-C or, on unix, the SIGTERM that stops a detached daemon.
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
             return;
         }
     }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// How long a starting daemon is watched for an early exit.
+const DAEMON_STARTUP: Duration = Duration::from_secs(1);
+
+/// Starts this program again with the same arguments and `--foreground`,
+/// detached from the terminal with its output appended to `log_file`.
+fn detach(log_file: &Path) -> std::io::Result<std::process::Child> {
+    if let Some(dir) = log_file.parent() {
+        create_private_dir(dir)?;
+    }
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .arg("--foreground")
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // Out of the terminal's process group, so Ctrl-C there misses it
+        command.process_group(0);
+    }
+    command.spawn()
+}
+
+/// Writes `token` to a new file only the current user can read, creating
+/// its directory if need be.
+fn write_token(path: &Path, token: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(token.as_bytes())
+}
+
+/// Creates `dir` and its parents, new ones readable only by the current user.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    if dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+/// Where the daemon answers API clients.
+enum ApiListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> std::io::Result<ApiListener> {
+    use std::os::unix::fs::FileTypeExt;
+    // A socket left behind by a daemon that died refuses connections
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if is_socket && std::os::unix::net::UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    Ok(ApiListener::Unix(listener, path.to_path_buf()))
+}
 
-    if !connected {
-        if should_stop.load(Ordering::Relaxed) {
-            let _ = ui_sender.send(UIEvent::DownloadStopped);
-        } else {
-            let _ = ui_sender.send(UIEvent::Error(
-                "Failed to connect to any peers or download failed".to_string(),
-            ));
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> std::io::Result<ApiListener> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "unix sockets are not available on this platform",
+    ))
+}
+
+/// Serves the torrent's files in `output_dir` on `addr`, returning the
+/// downloader's end of the stream. Exits if the server cannot start.
+async fn start_http_server(
+    torrent: &TorrentFile,
+    output_dir: &str,
+    addr: SocketAddr,
+    ui_sender: std::sync::mpsc::Sender<UIEvent>,
+) -> PieceProgress {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error: Cannot listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    let (progress, reader) = stream::stream(torrent, Path::new(output_dir));
+    let _ = ui_sender.send(UIEvent::ServingHttp(addr));
+    tokio::spawn(async move {
+        if let Err(e) = HttpServer::new(reader).run(listener).await {
+            let _ = ui_sender.send(UIEvent::Error(format!("HTTP server stopped: {}", e)));
         }
+    });
+    progress
+}
+
+fn parse_codepage(label: &str) -> Result<ParseOptions, String> {
+    ParseOptions::default()
+        .with_codepage(label)
+        .map_err(|e| e.to_string())
+}
+
+fn parse_mode(strict: bool) -> ParseMode {
+    if strict {
+        ParseMode::Strict
+    } else {
+        ParseMode::Lenient
     }
 }
@@ -0,0 +1,223 @@
+/// SHA-256 Merkle trees as used by BitTorrent v2 (BEP 52).
+///
+/// Every file is split into 16 KiB blocks whose hashes form the leaves of a
+/// binary tree. Leaves past the end of the file are zero, so the tree is
+/// always complete. A piece is a subtree of `piece_length / 16 KiB` leaves.
+use sha2::{Digest, Sha256};
+
+pub type Hash256 = [u8; 32];
+
+/// Size of the data covered by one leaf.
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+/// Most hashes handed out for one request, as BEP 52 allows.
+const MAX_HASH_RUN: usize = 512;
+
+pub fn hash_pair(left: &Hash256, right: &Hash256) -> Hash256 {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a subtree of `2^height` zero leaves.
+pub fn zero_subtree(height: u32) -> Hash256 {
+    (0..height).fold([0u8; 32], |node, _| hash_pair(&node, &node))
+}
+
+/// Root over `leaves`, padded to `leaf_count` (a power of two) with `pad`.
+pub fn root_from_leaves(leaves: &[Hash256], leaf_count: usize, mut pad: Hash256) -> Hash256 {
+    debug_assert!(leaf_count.is_power_of_two() && leaves.len() <= leaf_count);
+    let mut layer = leaves.to_vec();
+    let mut width = leaf_count;
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad);
+        }
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// SHA-256 of each 16 KiB block of `data`. The last block may be short.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash256> {
+    data.chunks(MERKLE_BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect()
+}
+
+fn blocks_per_piece(piece_length: u32) -> usize {
+    (piece_length as usize / MERKLE_BLOCK_SIZE).max(1)
+}
+
+/// Hash of one piece as stored in `piece layers`.
+pub fn piece_root(data: &[u8], piece_length: u32) -> Hash256 {
+    root_from_leaves(
+        &block_hashes(data),
+        blocks_per_piece(piece_length),
+        [0u8; 32],
+    )
+}
+
+/// `pieces root` of a file no larger than one piece, computed from its data.
+pub fn small_file_root(data: &[u8]) -> Hash256 {
+    let leaves = block_hashes(data);
+    root_from_leaves(&leaves, leaves.len().next_power_of_two(), [0u8; 32])
+}
+
+/// `pieces root` of a file, computed from its piece layer.
+pub fn root_from_piece_layer(layer: &[Hash256], piece_length: u32) -> Hash256 {
+    let pad = zero_subtree(blocks_per_piece(piece_length).trailing_zeros());
+    root_from_leaves(layer, layer.len().next_power_of_two(), pad)
+}
+
+/// Answers a `hash request` from a file's piece layer: the `length` hashes
+/// of layer `base_layer` starting at `index`, followed by up to
+/// `proof_layers` uncle hashes on the way to the root, as
+/// [`verify_hashes`] expects them. `None` for malformed or out-of-range
+/// requests and for layers below the piece layer, which only the data has.
+pub fn hashes_from_piece_layer(
+    piece_layer: &[Hash256],
+    piece_length: u32,
+    base_layer: u32,
+    index: u32,
+    length: u32,
+    proof_layers: u32,
+) -> Option<Vec<Hash256>> {
+    let piece_height = blocks_per_piece(piece_length).trailing_zeros();
+    let (index, length) = (index as usize, length as usize);
+    if piece_layer.is_empty()
+        || base_layer < piece_height
+        || !length.is_power_of_two()
+        || length > MAX_HASH_RUN
+        || !index.is_multiple_of(length)
+    {
+        return None;
+    }
+
+    let mut layer = piece_layer.to_vec();
+    layer.resize(
+        piece_layer.len().next_power_of_two(),
+        zero_subtree(piece_height),
+    );
+    for _ in piece_height..base_layer {
+        if layer.len() == 1 {
+            return None;
+        }
+        layer = parent_layer(&layer);
+    }
+    let mut hashes = layer.get(index..index + length)?.to_vec();
+
+    // Uncles start above the subtree the run forms
+    for _ in 0..length.trailing_zeros() {
+        layer = parent_layer(&layer);
+    }
+    let mut position = index / length;
+    for _ in 0..proof_layers {
+        if layer.len() == 1 {
+            break;
+        }
+        hashes.push(layer[position ^ 1]);
+        layer = parent_layer(&layer);
+        position /= 2;
+    }
+    Some(hashes)
+}
+
+fn parent_layer(layer: &[Hash256]) -> Vec<Hash256> {
+    layer
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], &pair[1]))
+        .collect()
+}
+
+/// Checks a run of hashes from a `hashes` message against a file's root.
+///
+/// `hashes` are consecutive nodes of one layer starting at `index`, and
+/// `proof` the uncle hashes from just above that subtree up to the root.
+pub fn verify_hashes(root: &Hash256, index: u32, hashes: &[Hash256], proof: &[Hash256]) -> bool {
+    let count = hashes.len();
+    if !count.is_power_of_two() || !(index as usize).is_multiple_of(count) {
+        return false;
+    }
+    let mut node = root_from_leaves(hashes, count, [0u8; 32]);
+    let mut position = index as usize / count;
+    for uncle in proof {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    position == 0 && node == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_layer_matches_file_root() {
+        let piece_length = 2 * MERKLE_BLOCK_SIZE as u32;
+        // Three pieces; the last one is a single short block
+        let data: Vec<u8> = (0..5 * MERKLE_BLOCK_SIZE - 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let layer: Vec<Hash256> = data
+            .chunks(piece_length as usize)
+            .map(|piece| piece_root(piece, piece_length))
+            .collect();
+
+        // The whole file as one tree, leaves padded with zeros
+        let leaves = block_hashes(&data);
+        let expected = root_from_leaves(&leaves, 8, [0u8; 32]);
+        assert_eq!(root_from_piece_layer(&layer, piece_length), expected);
+    }
+
+    #[test]
+    fn test_verify_hashes_with_proof() {
+        let leaves: Vec<Hash256> = (0..8u8).map(|i| [i; 32]).collect();
+        let root = root_from_leaves(&leaves, 8, [0u8; 32]);
+
+        let left_half = root_from_leaves(&leaves[..4], 4, [0u8; 32]);
+        let right_half = root_from_leaves(&leaves[4..], 4, [0u8; 32]);
+        let pair_01 = hash_pair(&leaves[0], &leaves[1]);
+        assert!(verify_hashes(
+            &root,
+            2,
+            &leaves[2..4],
+            &[pair_01, right_half]
+        ));
+        assert!(verify_hashes(&root, 4, &leaves[4..], &[left_half]));
+        assert!(!verify_hashes(&root, 4, &leaves[..4], &[left_half]));
+        assert!(!verify_hashes(&root, 2, &leaves[2..4], &[pair_01]));
+    }
+
+    #[test]
+    fn test_hashes_from_piece_layer() {
+        // Five pieces of two blocks: the piece layer is layer 1
+        let piece_length = 2 * MERKLE_BLOCK_SIZE as u32;
+        let layer: Vec<Hash256> = (0..5u8).map(|i| [i; 32]).collect();
+        let root = root_from_piece_layer(&layer, piece_length);
+
+        let reply = hashes_from_piece_layer(&layer, piece_length, 1, 2, 2, 8).unwrap();
+        assert_eq!(&reply[..2], &layer[2..4]);
+        assert!(verify_hashes(&root, 2, &reply[..2], &reply[2..]));
+
+        // One layer up, padded past the fifth piece
+        let reply = hashes_from_piece_layer(&layer, piece_length, 2, 2, 2, 8).unwrap();
+        assert!(verify_hashes(&root, 2, &reply[..2], &reply[2..]));
+        let reply = hashes_from_piece_layer(&layer, piece_length, 1, 4, 4, 1).unwrap();
+        assert_eq!(reply.len(), 5);
+
+        assert!(hashes_from_piece_layer(&layer, piece_length, 0, 0, 2, 0).is_none());
+        assert!(hashes_from_piece_layer(&layer, piece_length, 1, 1, 2, 0).is_none());
+        assert!(hashes_from_piece_layer(&layer, piece_length, 1, 8, 2, 0).is_none());
+    }
+}
@@ -0,0 +1,376 @@
+/// Fetches a torrent's `info` dictionary from peers for magnet links
+/// (BEP 9).
+///
+/// `ut_metadata` rides on the extension protocol (BEP 10): both sides flag
+/// it in the handshake, then swap an extended handshake naming the
+/// extensions they speak and the ids they want them sent under. The
+/// dictionary comes in 16 KiB pieces and is only trusted once it hashes to
+/// the link's info hash.
+use crate::bencode::{self, BencodeError};
+use crate::magnet::MagnetLink;
+use crate::parser::BencodeParser;
+use crate::peer_manager::{PeerClient, PeerConfig};
+use crate::tracker::TrackerClient;
+use crate::wire::{Handshake, PeerMessage, Reserved};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Size of every metadata piece but the last.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+/// Largest `info` dictionary accepted from a peer.
+pub const MAX_METADATA_LEN: usize = 16 * 1024 * 1024;
+/// Peers asked for the metadata at once.
+const PARALLEL_PEERS: usize = 8;
+/// Time one peer gets to deliver the whole dictionary.
+const PEER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Extended message id of the extended handshake.
+const EXTENDED_HANDSHAKE: u8 = 0;
+/// The id we ask peers to send `ut_metadata` messages under.
+const UT_METADATA: u8 = 1;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+#[derive(Debug)]
+pub struct MetadataError {
+    pub message: String,
+}
+
+impl std::fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Metadata error: {}", self.message)
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+impl From<io::Error> for MetadataError {
+    fn from(err: io::Error) -> Self {
+        MetadataError {
+            message: format!("IO error: {}", err),
+        }
+    }
+}
+
+impl From<BencodeError> for MetadataError {
+    fn from(err: BencodeError) -> Self {
+        MetadataError {
+            message: format!("Invalid message: {}", err),
+        }
+    }
+}
+
+/// The extended handshake, as far as `ut_metadata` needs it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names and the ids the sender wants them sent under; 0
+    /// turns an extension off.
+    #[serde(default, deserialize_with = "bencode::lenient::or_default")]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, deserialize_with = "bencode::lenient::or_default")]
+    pub metadata_size: Option<u64>,
+}
+
+/// The dictionary that starts every `ut_metadata` message. Data messages
+/// carry the piece right after it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: i64,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<u64>,
+}
+
+/// Builds the handshake that flags extension support, for connections
+/// that will fetch metadata.
+pub fn handshake(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+    Handshake::new(info_hash, peer_id).with_reserved(Reserved::default().with_extensions())
+}
+
+/// Downloads and checks the `info` dictionary from one connected peer.
+pub async fn fetch_from_peer(
+    peer: &mut PeerClient,
+    link: &MagnetLink,
+) -> Result<Vec<u8>, MetadataError> {
+    if !peer.reserved.supports_extensions() {
+        return Err(MetadataError {
+            message: "Peer does not support extensions".to_string(),
+        });
+    }
+    let ours = ExtendedHandshake {
+        m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA as i64)]),
+        metadata_size: None,
+    };
+    peer.send_message(PeerMessage::Extended {
+        id: EXTENDED_HANDSHAKE,
+        payload: bencode::to_bytes(&ours)?,
+    })
+    .await?;
+
+    // Anything the peer sends before its extended handshake is ignored
+    let theirs: ExtendedHandshake = loop {
+        if let PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE,
+            payload,
+        } = peer.receive_message().await?
+        {
+            break bencode::from_bytes(&payload)?;
+        }
+    };
+    let their_id = match theirs.m.get("ut_metadata") {
+        Some(&id) if (1..=255).contains(&id) => id as u8,
+        _ => {
+            return Err(MetadataError {
+                message: "Peer does not serve metadata".to_string(),
+            });
+        }
+    };
+    let size = match theirs.metadata_size {
+        Some(size) if size > 0 && size <= MAX_METADATA_LEN as u64 => size as usize,
+        _ => {
+            return Err(MetadataError {
+                message: format!("Unusable metadata size {:?}", theirs.metadata_size),
+            });
+        }
+    };
+
+    let pieces = size.div_ceil(METADATA_PIECE_LEN);
+    for piece in 0..pieces {
+        let request = MetadataMessage {
+            msg_type: MSG_REQUEST,
+            piece: piece as u32,
+            total_size: None,
+        };
+        peer.send_message(PeerMessage::Extended {
+            id: their_id,
+            payload: bencode::to_bytes(&request)?,
+        })
+        .await?;
+    }
+
+    let mut data = vec![0u8; size];
+    let mut received = vec![false; pieces];
+    let mut missing = pieces;
+    while missing > 0 {
+        let PeerMessage::Extended {
+            id: UT_METADATA,
+            payload,
+        } = peer.receive_message().await?
+        else {
+            continue;
+        };
+        let (message, block) = split_message(&payload)?;
+        let index = message.piece as usize;
+        match message.msg_type {
+            MSG_DATA if index < pieces => {
+                let start = index * METADATA_PIECE_LEN;
+                let end = (start + METADATA_PIECE_LEN).min(size);
+                if block.len() != end - start {
+                    return Err(MetadataError {
+                        message: format!("Metadata piece {} has the wrong size", index),
+                    });
+                }
+                data[start..end].copy_from_slice(block);
+                if !std::mem::replace(&mut received[index], true) {
+                    missing -= 1;
+                }
+            }
+            MSG_REJECT => {
+                return Err(MetadataError {
+                    message: format!("Peer rejected metadata piece {}", index),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if !link.matches(&data) {
+        return Err(MetadataError {
+            message: "Metadata does not match the link's info hash".to_string(),
+        });
+    }
+    Ok(data)
+}
+
+/// Splits a `ut_metadata` payload into its dictionary and the piece data
+/// after it.
+fn split_message(payload: &[u8]) -> Result<(MetadataMessage, &[u8]), MetadataError> {
+    let mut parser = BencodeParser::new(payload).with_spans();
+    let header = parser.parse().map_err(|e| MetadataError {
+        message: format!("Invalid message: {}", e.message),
+    })?;
+    let end = parser.span(&[]).map_or(0, |span| span.end);
+    Ok((bencode::from_value(&header)?, &payload[end..]))
+}
+
+/// Fetches the `info` dictionary for `link` from the peers its trackers
+/// know, asking a few peers at once until one delivers.
+pub async fn fetch(
+    link: &MagnetLink,
+    tracker: &TrackerClient,
+    config: &PeerConfig,
+    port: u16,
+) -> Result<Vec<u8>, MetadataError> {
+    let info_hash = link.swarm_hash().ok_or_else(|| MetadataError {
+        message: "The link has no info hash".to_string(),
+    })?;
+
+    let mut peers: Vec<SocketAddr> = Vec::new();
+    for url in &link.trackers {
+        let left = link.exact_length.unwrap_or(0);
+        if let Ok(response) = tracker.announce_hash(url, &info_hash, port, left).await {
+            for peer in response.peers {
+                let addr = SocketAddr::new(peer.ip, peer.port);
+                if !peers.contains(&addr) {
+                    peers.push(addr);
+                }
+            }
+        }
+    }
+    fetch_from_peers(link, &peers, *tracker.get_peer_id(), config).await
+}
+
+/// Asks `peers` for the metadata, [`PARALLEL_PEERS`] at a time, and returns
+/// the first dictionary that checks out.
+pub async fn fetch_from_peers(
+    link: &MagnetLink,
+    peers: &[SocketAddr],
+    peer_id: [u8; 20],
+    config: &PeerConfig,
+) -> Result<Vec<u8>, MetadataError> {
+    let info_hash = link.swarm_hash().ok_or_else(|| MetadataError {
+        message: "The link has no info hash".to_string(),
+    })?;
+    let attempt = |addr: SocketAddr| async move {
+        let handshake = handshake(info_hash, peer_id);
+        let fetch = async {
+            let mut peer = PeerClient::connect_with(addr, handshake, config.clone()).await?;
+            fetch_from_peer(&mut peer, link).await
+        };
+        tokio::time::timeout(PEER_TIMEOUT, fetch)
+            .await
+            .unwrap_or_else(|_| {
+                Err(MetadataError {
+                    message: "Timed out".to_string(),
+                })
+            })
+    };
+
+    let mut waiting = peers.iter().copied();
+    let mut running: FuturesUnordered<_> =
+        waiting.by_ref().take(PARALLEL_PEERS).map(attempt).collect();
+    let mut last_error = None;
+    while let Some(result) = running.next().await {
+        match result {
+            Ok(data) => return Ok(data),
+            Err(e) => last_error = Some(e),
+        }
+        if let Some(addr) = waiting.next() {
+            running.push(attempt(addr));
+        }
+    }
+    Err(MetadataError {
+        message: match last_error {
+            Some(e) => format!("No peer delivered the metadata (last: {})", e.message),
+            None => "No peers found".to_string(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{HandshakeCodec, MessageCodec};
+    use futures::SinkExt;
+    use sha1::{Digest, Sha1};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    /// A peer that serves `info` with `ut_metadata` under id 3.
+    async fn serve_metadata(listener: TcpListener, info: Vec<u8>) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(socket, HandshakeCodec);
+        let theirs = framed.next().await.unwrap().unwrap();
+        assert!(theirs.reserved.supports_extensions());
+        framed
+            .send(handshake(theirs.info_hash, [9; 20]))
+            .await
+            .unwrap();
+        let mut framed = framed.map_codec(|_| MessageCodec::new());
+
+        let ours = ExtendedHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), 3)]),
+            metadata_size: Some(info.len() as u64),
+        };
+        let payload = bencode::to_bytes(&ours).unwrap();
+        framed
+            .send(PeerMessage::Extended { id: 0, payload })
+            .await
+            .unwrap();
+        while let Some(Ok(message)) = framed.next().await {
+            let PeerMessage::Extended { id: 3, payload } = message else {
+                continue;
+            };
+            let (request, _) = split_message(&payload).unwrap();
+            let start = request.piece as usize * METADATA_PIECE_LEN;
+            let end = (start + METADATA_PIECE_LEN).min(info.len());
+            let header = MetadataMessage {
+                msg_type: MSG_DATA,
+                piece: request.piece,
+                total_size: Some(info.len() as u64),
+            };
+            let mut payload = bencode::to_bytes(&header).unwrap();
+            payload.extend_from_slice(&info[start..end]);
+            framed
+                .send(PeerMessage::Extended { id: 1, payload })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_from_peer() {
+        // Big enough for three pieces
+        let mut info = b"d4:name1:x6:pieces40000:".to_vec();
+        info.extend(vec![7u8; 40_000]);
+        info.push(b'e');
+        let link = MagnetLink {
+            info_hash: Some(Sha1::digest(&info).into()),
+            ..MagnetLink::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(listener, info.clone()));
+
+        // The first peer refuses connections
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let fetched =
+            fetch_from_peers(&link, &[closed_addr, addr], [1; 20], &PeerConfig::default())
+                .await
+                .unwrap();
+        assert_eq!(fetched, info);
+
+        let wrong = MagnetLink {
+            info_hash: Some([0; 20]),
+            ..MagnetLink::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metadata(listener, info));
+        let err = fetch_from_peers(&wrong, &[addr], [1; 20], &PeerConfig::default())
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("does not match"), "{}", err);
+    }
+}
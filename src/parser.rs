@@ -1,7 +1,14 @@
 /// Parses the `.torrent` file and returns a Torrent struct.
+use crate::bencode::{self, BencodeError, ByteString};
+use crate::decoder;
+use crate::merkle::{self, Hash256};
+use encoding_rs::Encoding;
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use std::collections::HashMap;
 use std::fs;
+use std::ops::Range;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum BencodeValue {
@@ -24,14 +31,116 @@ impl std::fmt::Display for ParseError {
 
 impl std::error::Error for ParseError {}
 
+impl From<BencodeError> for ParseError {
+    fn from(err: BencodeError) -> Self {
+        ParseError {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// A step on the way from the root value to a nested value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    Key(Vec<u8>),
+    Index(usize),
+}
+
+/// Input the parser accepted even though it is not canonical bencode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseWarning {
+    /// Byte offset of the offending token.
+    pub offset: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+/// What to do with input that decodes fine but is not canonical bencode:
+/// `i-0e`, `i03e`, `03:abc`, unsorted or duplicate dictionary keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Accept it and record a [`ParseWarning`].
+    #[default]
+    Lenient,
+    /// Reject it, along with data after the root value. For input that must
+    /// encode back to the same bytes, as a tracker or DHT node needs.
+    Strict,
+}
+
 pub struct BencodeParser<'a> {
     data: &'a [u8],
     position: usize,
+    path: Vec<PathSegment>,
+    spans: Option<HashMap<Vec<PathSegment>, Range<usize>>>,
+    warnings: Vec<ParseWarning>,
+    mode: ParseMode,
+    max_depth: usize,
 }
 
 impl<'a> BencodeParser<'a> {
     pub fn new(data: &'a [u8]) -> Self {
-        Self { data, position: 0 }
+        Self {
+            data,
+            position: 0,
+            path: Vec::new(),
+            spans: None,
+            warnings: Vec::new(),
+            mode: ParseMode::Lenient,
+            max_depth: decoder::DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Rejects lists and dictionaries nested deeper than `max_depth`, so
+    /// hostile input cannot overflow the stack. Defaults to
+    /// [`decoder::DEFAULT_MAX_DEPTH`].
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Records the byte range of every parsed value, so callers can get at
+    /// the original encoding (e.g. to hash the `info` dictionary).
+    pub fn with_spans(mut self) -> Self {
+        self.spans = Some(HashMap::new());
+        self
+    }
+
+    /// Byte range of the value at `path`, if spans are being recorded. The
+    /// root value has the empty path.
+    pub fn span(&self, path: &[PathSegment]) -> Option<Range<usize>> {
+        self.spans.as_ref()?.get(path).cloned()
+    }
+
+    /// Non-canonical constructs seen so far, in lenient mode.
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+
+    /// Records a warning, or fails in strict mode.
+    fn warn(&mut self, offset: usize, message: &str) -> Result<(), ParseError> {
+        let warning = ParseWarning {
+            offset,
+            message: message.to_string(),
+        };
+        match self.mode {
+            ParseMode::Lenient => {
+                self.warnings.push(warning);
+                Ok(())
+            }
+            ParseMode::Strict => Err(ParseError {
+                message: warning.to_string(),
+            }),
+        }
     }
 
     pub fn parse(&mut self) -> Result<BencodeValue, ParseError> {
@@ -41,7 +150,16 @@ impl<'a> BencodeParser<'a> {
             });
         }
 
-        match self.data[self.position] {
+        let start = self.position;
+        if matches!(self.data[start], b'l' | b'd') && self.path.len() >= self.max_depth {
+            return Err(ParseError {
+                message: format!(
+                    "Nesting deeper than {} levels at byte {}",
+                    self.max_depth, start
+                ),
+            });
+        }
+        let value = match self.data[self.position] {
             b'i' => self.parse_integer(),
             b'l' => self.parse_list(),
             b'd' => self.parse_dictionary(),
@@ -49,7 +167,19 @@ impl<'a> BencodeParser<'a> {
             _ => Err(ParseError {
                 message: format!("Unexpected character: {}", self.data[self.position] as char),
             }),
+        }?;
+
+        if let Some(ref mut spans) = self.spans {
+            spans.insert(self.path.clone(), start..self.position);
+        }
+        // Lenient parsing ignores whatever follows the root value
+        if self.mode == ParseMode::Strict && self.path.is_empty() && self.position < self.data.len()
+        {
+            return Err(ParseError {
+                message: format!("Trailing data after value at byte {}", self.position),
+            });
         }
+        Ok(value)
     }
 
     fn parse_integer(&mut self) -> Result<BencodeValue, ParseError> {
@@ -75,6 +205,10 @@ impl<'a> BencodeParser<'a> {
             message: "Invalid integer".to_string(),
         })?;
 
+        if int_str != value.to_string() {
+            self.warn(start - 1, "Non-canonical integer")?;
+        }
+
         self.position += 1; // skip 'e'
         Ok(BencodeValue::Integer(value))
     }
@@ -101,9 +235,13 @@ impl<'a> BencodeParser<'a> {
             message: "Invalid string length".to_string(),
         })?;
 
+        if length_str != length.to_string() {
+            self.warn(start, "Non-canonical string length")?;
+        }
+
         self.position += 1; // skip ':'
 
-        if self.position + length > self.data.len() {
+        if length > self.data.len() - self.position {
             return Err(ParseError {
                 message: "String longer than remaining data".to_string(),
             });
@@ -120,7 +258,10 @@ impl<'a> BencodeParser<'a> {
         let mut list = Vec::new();
 
         while self.position < self.data.len() && self.data[self.position] != b'e' {
-            list.push(self.parse()?);
+            self.path.push(PathSegment::Index(list.len()));
+            let item = self.parse();
+            self.path.pop();
+            list.push(item?);
         }
 
         if self.position >= self.data.len() {
@@ -136,10 +277,15 @@ impl<'a> BencodeParser<'a> {
     fn parse_dictionary(&mut self) -> Result<BencodeValue, ParseError> {
         self.position += 1; // skip 'd'
         let mut dict = HashMap::new();
+        let mut previous_key: Option<Vec<u8>> = None;
 
         while self.position < self.data.len() && self.data[self.position] != b'e' {
-            let key = match self.parse()? {
-                BencodeValue::String(s) => s,
+            let key_start = self.position;
+            let key = match self.data[self.position] {
+                b'0'..=b'9' => match self.parse_string()? {
+                    BencodeValue::String(s) => s,
+                    _ => unreachable!("parse_string returns strings"),
+                },
                 _ => {
                     return Err(ParseError {
                         message: "Dictionary key must be a string".to_string(),
@@ -147,8 +293,19 @@ impl<'a> BencodeParser<'a> {
                 }
             };
 
-            let value = self.parse()?;
-            dict.insert(key, value);
+            if let Some(ref previous) = previous_key {
+                if key == *previous {
+                    self.warn(key_start, "Duplicate dictionary key")?;
+                } else if key < *previous {
+                    self.warn(key_start, "Dictionary keys not sorted")?;
+                }
+            }
+
+            self.path.push(PathSegment::Key(key.clone()));
+            let value = self.parse();
+            self.path.pop();
+            dict.insert(key.clone(), value?);
+            previous_key = Some(key);
         }
 
         if self.position >= self.data.len() {
@@ -162,12 +319,30 @@ impl<'a> BencodeParser<'a> {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Serializes as a metainfo file; see `TryFrom<RawMetainfo>` for reading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "RawMetainfo", try_from = "RawMetainfo")]
 pub struct TorrentFile {
     pub announce: String,
     pub announce_list: Option<Vec<Vec<String>>>,
     pub info: TorrentInfo,
+    /// Hash identifying the swarm: SHA-1 of the info dictionary, or the
+    /// truncated SHA-256 for v2-only torrents.
     pub info_hash: [u8; 20],
+    /// SHA-256 of the info dictionary, for v2 and hybrid torrents.
+    pub info_hash_v2: Option<Hash256>,
+    /// v2 `piece layers`, keyed by each file's `pieces root`.
+    pub piece_layers: HashMap<Hash256, Vec<Hash256>>,
+    /// BEP 19 web seeds (`url-list`).
+    pub web_seeds: Vec<String>,
+    /// BEP 17 HTTP seeds (`httpseeds`).
+    pub http_seeds: Vec<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// Unix timestamp of `creation date`.
+    pub creation_date: Option<i64>,
+    /// Non-canonical encoding found while parsing the file.
+    pub warnings: Vec<ParseWarning>,
 }
 
 impl TorrentFile {
@@ -178,14 +353,273 @@ impl TorrentFile {
             TorrentFiles::Multiple { files } => files.iter().map(|f| f.length).sum(),
         }
     }
+
+    /// Info hashes of every swarm this torrent belongs to. Hybrid torrents
+    /// are in both the v1 and the v2 swarm.
+    pub fn swarm_hashes(&self) -> Vec<[u8; 20]> {
+        let mut hashes = vec![self.info_hash];
+        if let Some(v2) = self.info_hash_v2 {
+            let truncated: [u8; 20] = v2[..20].try_into().expect("20 of 32 bytes");
+            if truncated != self.info_hash {
+                hashes.push(truncated);
+            }
+        }
+        hashes
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        if self.info.has_v1() {
+            self.info.pieces.len()
+        } else {
+            self.info
+                .file_tree
+                .iter()
+                .map(|file| self.info.pieces_in(file.length))
+                .sum()
+        }
+    }
+
+    /// Size of a piece in bytes. In v2-only torrents every file starts on a
+    /// piece boundary, so the last piece of each file may be short.
+    pub fn piece_size(&self, piece_index: u32) -> u32 {
+        let piece_length = self.info.piece_length as u64;
+        if !self.info.has_v1() {
+            return match self.v2_piece(piece_index) {
+                Some((file, index)) => {
+                    (file.length - index as u64 * piece_length).min(piece_length) as u32
+                }
+                None => 0,
+            };
+        }
+
+        let start = piece_index as u64 * piece_length;
+        self.total_size().saturating_sub(start).min(piece_length) as u32
+    }
+
+    /// Checks downloaded piece data against every hash the torrent carries:
+    /// SHA-1 for v1, the Merkle tree for v2, both for hybrids.
+    pub fn verify_piece(&self, piece_index: u32, data: &[u8]) -> bool {
+        if self.info.has_v1() {
+            let Some(expected) = self.info.pieces.get(piece_index as usize) else {
+                return false;
+            };
+            if Sha1::digest(data).as_slice() != expected {
+                return false;
+            }
+        }
+        if !self.info.is_v2() {
+            return true;
+        }
+
+        let Some((file, index)) = self.v2_piece(piece_index) else {
+            // Hybrid pieces covering only padding have no v2 hash
+            return self.info.has_v1();
+        };
+        let Some(root) = file.pieces_root else {
+            return false;
+        };
+        let piece_length = self.info.piece_length as u64;
+        let file_part = (file.length - index as u64 * piece_length).min(piece_length) as usize;
+        let Some(file_data) = data.get(..file_part) else {
+            return false;
+        };
+
+        if file.length <= piece_length {
+            merkle::small_file_root(file_data) == root
+        } else {
+            self.piece_layers
+                .get(&root)
+                .and_then(|layer| layer.get(index as usize))
+                .is_some_and(|expected| {
+                    merkle::piece_root(file_data, self.info.piece_length) == *expected
+                })
+        }
+    }
+
+    /// Maps a piece to the v2 file it belongs to and its index in that file.
+    fn v2_piece(&self, piece_index: u32) -> Option<(&FileTreeEntry, u32)> {
+        let mut first = 0u64;
+        for file in &self.info.file_tree {
+            let count = self.info.pieces_in(file.length) as u64;
+            if (piece_index as u64) < first + count {
+                return Some((file, (piece_index as u64 - first) as u32));
+            }
+            first += count;
+        }
+        None
+    }
+
+    /// Every file in piece order, with its path relative to the download
+    /// directory (so the torrent name comes first).
+    pub fn files(&self) -> Vec<TorrentFileInfo> {
+        match &self.info.files {
+            TorrentFiles::Single { length } => vec![TorrentFileInfo {
+                path: vec![self.info.name.clone()],
+                raw_path: self.info.raw_name.clone().map(|name| vec![name]),
+                length: *length,
+                ..Default::default()
+            }],
+            TorrentFiles::Multiple { files } => files
+                .iter()
+                .map(|file| {
+                    let raw_path = match (&self.info.raw_name, &file.raw_path) {
+                        (None, None) => None,
+                        (name, path) => Some(
+                            std::iter::once(
+                                name.clone()
+                                    .unwrap_or_else(|| self.info.name.as_bytes().to_vec()),
+                            )
+                            .chain(path.clone().unwrap_or_else(|| {
+                                file.path.iter().map(|c| c.as_bytes().to_vec()).collect()
+                            }))
+                            .collect(),
+                        ),
+                    };
+                    TorrentFileInfo {
+                        path: std::iter::once(self.info.name.clone())
+                            .chain(file.path.iter().cloned())
+                            .collect(),
+                        raw_path,
+                        ..file.clone()
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// The parts of files a piece covers, in order. File indices refer to
+    /// [`TorrentFile::files`].
+    pub fn piece_spans(&self, piece_index: u32) -> Vec<FileSpan> {
+        let piece_length = self.info.piece_length as u64;
+        if !self.info.has_v1() {
+            // v2 pieces never cross a file boundary
+            let mut first = 0u64;
+            for (file_index, file) in self.info.file_tree.iter().enumerate() {
+                let count = self.info.pieces_in(file.length) as u64;
+                if (piece_index as u64) < first + count {
+                    let offset = (piece_index as u64 - first) * piece_length;
+                    return vec![FileSpan {
+                        file_index,
+                        offset,
+                        length: (file.length - offset).min(piece_length),
+                    }];
+                }
+                first += count;
+            }
+            return Vec::new();
+        }
+
+        let mut start = piece_index as u64 * piece_length;
+        let mut remaining = self.piece_size(piece_index) as u64;
+        let mut spans = Vec::new();
+        for (file_index, file) in self.files().iter().enumerate() {
+            if remaining == 0 {
+                break;
+            }
+            if start >= file.length {
+                start -= file.length;
+                continue;
+            }
+            let length = (file.length - start).min(remaining);
+            spans.push(FileSpan {
+                file_index,
+                offset: start,
+                length,
+            });
+            remaining -= length;
+            start = 0;
+        }
+        spans
+    }
+
+    /// The pieces holding `length` bytes at `offset` in a file, the inverse
+    /// of [`TorrentFile::piece_spans`]. Empty when `length` is 0.
+    pub fn pieces_for_range(&self, file_index: usize, offset: u64, length: u64) -> Range<u32> {
+        let piece_length = self.info.piece_length as u64;
+        let (start, first_piece) = if self.info.has_v1() {
+            let file_start: u64 = self.files()[..file_index].iter().map(|f| f.length).sum();
+            (file_start + offset, 0)
+        } else {
+            // Each v2 file starts on a piece boundary
+            let first_piece: usize = self.info.file_tree[..file_index]
+                .iter()
+                .map(|file| self.info.pieces_in(file.length))
+                .sum();
+            (offset, first_piece as u64)
+        };
+        let first = first_piece + start / piece_length;
+        let end = if length == 0 {
+            first
+        } else {
+            first_piece + (start + length).div_ceil(piece_length)
+        };
+        first as u32..end as u32
+    }
+
+    /// The pieces holding each file of [`TorrentFile::files`], in a single
+    /// pass. Empty for empty files.
+    pub fn file_pieces(&self) -> Vec<Range<u32>> {
+        let piece_length = self.info.piece_length as u64;
+        let mut ranges = Vec::new();
+        if self.info.has_v1() {
+            let mut start = 0u64;
+            for file in self.files() {
+                let first = start / piece_length;
+                let end = if file.length == 0 {
+                    first
+                } else {
+                    (start + file.length).div_ceil(piece_length)
+                };
+                ranges.push(first as u32..end as u32);
+                start += file.length;
+            }
+        } else {
+            let mut first = 0u32;
+            for file in &self.info.file_tree {
+                let end = first + self.info.pieces_in(file.length) as u32;
+                ranges.push(first..end);
+                first = end;
+            }
+        }
+        ranges
+    }
+}
+
+/// A byte range within one file of a torrent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSpan {
+    pub file_index: usize,
+    pub offset: u64,
+    pub length: u64,
 }
 
 #[derive(Debug, Clone)]
 pub struct TorrentInfo {
     pub name: String,
+    /// Original `name` bytes, when they are not the UTF-8 of `name`.
+    pub raw_name: Option<Vec<u8>>,
+    /// `name.utf-8` exactly as the metainfo had it. Re-encoding writes it
+    /// back only when present, so the info hash does not change.
+    pub name_utf8: Option<Vec<u8>>,
     pub piece_length: u32,
+    /// v1 piece hashes; empty for v2-only torrents.
     pub pieces: Vec<[u8; 20]>,
     pub files: TorrentFiles,
+    /// `meta version`, 2 for v2 and hybrid torrents.
+    pub meta_version: Option<i64>,
+    /// v2 `file tree`, flattened in tree order.
+    pub file_tree: Vec<FileTreeEntry>,
+    /// BEP 27 `private` flag: peers may only come from the metainfo's trackers.
+    pub private: bool,
+}
+
+/// A file from a v2 `file tree`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileTreeEntry {
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Merkle root of the file's blocks; absent for empty files.
+    pub pieces_root: Option<Hash256>,
 }
 
 #[derive(Debug, Clone)]
@@ -194,26 +628,243 @@ pub enum TorrentFiles {
     Multiple { files: Vec<TorrentFileInfo> },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TorrentFileInfo {
     pub path: Vec<String>,
+    /// Original `path` bytes, when they are not the UTF-8 of `path`.
+    pub raw_path: Option<Vec<Vec<u8>>>,
+    /// `path.utf-8` exactly as the metainfo had it, like
+    /// [`TorrentInfo::name_utf8`].
+    pub path_utf8: Option<Vec<Vec<u8>>>,
     pub length: u64,
+    /// BEP 47 `attr` flags.
+    pub attr: FileAttributes,
+    /// Target of a symlink (`attr` contains `l`), relative to the torrent root.
+    pub symlink_path: Option<Vec<String>>,
+    /// Optional SHA-1 of the whole file.
+    pub sha1: Option<[u8; 20]>,
 }
 
-impl BencodeValue {
-    pub(crate) fn as_string(&self) -> Result<String, ParseError> {
-        match self {
-            BencodeValue::String(bytes) => {
-                String::from_utf8(bytes.clone()).map_err(|_| ParseError {
-                    message: "Invalid UTF-8 in string".to_string(),
+/// File attributes from the BEP 47 `attr` string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// Padding inserted to align the next file to a piece boundary (`p`).
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    /// Parses an `attr` string. Unknown flags are ignored.
+    pub fn parse(attr: &str) -> Self {
+        FileAttributes {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+
+    /// The `attr` string, or an empty string when no flag is set.
+    pub fn as_attr_string(&self) -> String {
+        [
+            (self.padding, 'p'),
+            (self.executable, 'x'),
+            (self.hidden, 'h'),
+            (self.symlink, 'l'),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect()
+    }
+}
+
+impl TorrentInfo {
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    /// Whether the torrent carries v1 piece hashes (v1 or hybrid).
+    pub fn has_v1(&self) -> bool {
+        !self.pieces.is_empty() || !self.is_v2()
+    }
+
+    fn pieces_in(&self, length: u64) -> usize {
+        length.div_ceil(self.piece_length as u64) as usize
+    }
+
+    /// Builds the bencoded `info` dictionary for these fields.
+    pub fn to_bencode(&self) -> BencodeValue {
+        bencode::to_value(&self.to_raw()).expect("info fields are representable in bencode")
+    }
+
+    fn to_raw(&self) -> RawInfo {
+        let bytes = |s: &String| ByteString(s.as_bytes().to_vec());
+        let (length, files) = match &self.files {
+            TorrentFiles::Single { length } => (Some(*length), None),
+            TorrentFiles::Multiple { files } => {
+                let files = files
+                    .iter()
+                    .map(|file| {
+                        let attr = file.attr.as_attr_string();
+                        let path = match &file.raw_path {
+                            Some(raw) => raw.iter().cloned().map(ByteString).collect(),
+                            None => file.path.iter().map(bytes).collect(),
+                        };
+                        RawFile {
+                            length: file.length,
+                            path,
+                            path_utf8: file
+                                .path_utf8
+                                .as_ref()
+                                .map(|path| path.iter().cloned().map(ByteString).collect()),
+                            attr: (!attr.is_empty()).then_some(attr),
+                            symlink_path: file.symlink_path.clone(),
+                            sha1: file.sha1.map(|sha1| ByteString(sha1.to_vec())),
+                        }
+                    })
+                    .collect();
+                (None, Some(files))
+            }
+        };
+        let name = match &self.raw_name {
+            Some(raw) => ByteString(raw.clone()),
+            None => bytes(&self.name),
+        };
+
+        RawInfo {
+            name,
+            name_utf8: self.name_utf8.clone().map(ByteString),
+            piece_length: self.piece_length,
+            pieces: self.has_v1().then(|| ByteString(self.pieces.concat())),
+            length,
+            files,
+            private: self.private.then_some(1),
+            meta_version: self.meta_version,
+            file_tree: self.is_v2().then(|| encode_file_tree(&self.file_tree)),
+        }
+    }
+}
+
+fn encode_file_tree(files: &[FileTreeEntry]) -> BencodeValue {
+    let mut root = HashMap::new();
+    for file in files {
+        let mut node = &mut root;
+        for component in &file.path {
+            let child = node
+                .entry(component.as_bytes().to_vec())
+                .or_insert_with(|| BencodeValue::Dictionary(HashMap::new()));
+            let BencodeValue::Dictionary(child) = child else {
+                unreachable!("file tree nodes are dictionaries");
+            };
+            node = child;
+        }
+        let mut leaf = HashMap::new();
+        leaf.insert(
+            b"length".to_vec(),
+            BencodeValue::Integer(file.length as i64),
+        );
+        if let Some(root) = file.pieces_root {
+            leaf.insert(b"pieces root".to_vec(), BencodeValue::String(root.to_vec()));
+        }
+        node.insert(Vec::new(), BencodeValue::Dictionary(leaf));
+    }
+    BencodeValue::Dictionary(root)
+}
+
+/// Flattens a v2 `file tree` into its files, in key order.
+fn parse_file_tree(
+    node: &HashMap<Vec<u8>, BencodeValue>,
+    path: &mut Vec<String>,
+    files: &mut Vec<FileTreeEntry>,
+) -> Result<(), ParseError> {
+    let mut keys: Vec<&Vec<u8>> = node.keys().collect();
+    keys.sort();
+    for key in keys {
+        let child = node[key].as_dict()?;
+        if key.is_empty() {
+            let length = child
+                .get(b"length".as_ref())
+                .ok_or_else(|| ParseError {
+                    message: "Missing 'length' in file tree".to_string(),
+                })?
+                .as_integer()? as u64;
+            let pieces_root = child
+                .get(b"pieces root".as_ref())
+                .map(|root| {
+                    root.as_bytes()?.try_into().map_err(|_| ParseError {
+                        message: "Invalid 'pieces root' (must be 32 bytes)".to_string(),
+                    })
                 })
+                .transpose()?;
+            if length > 0 && pieces_root.is_none() {
+                return Err(ParseError {
+                    message: format!("Missing 'pieces root' for '{}'", path.join("/")),
+                });
             }
-            _ => Err(ParseError {
-                message: "Expected string".to_string(),
-            }),
+            files.push(FileTreeEntry {
+                path: path.clone(),
+                length,
+                pieces_root,
+            });
+        } else {
+            path.push(String::from_utf8(key.clone()).map_err(|_| ParseError {
+                message: "Invalid UTF-8 in file tree path".to_string(),
+            })?);
+            parse_file_tree(child, path, files)?;
+            path.pop();
         }
     }
+    Ok(())
+}
 
+/// Splits `piece layers` into per-file hash lists and checks each against
+/// the `pieces root` of a file in the tree.
+fn parse_piece_layers(
+    value: Option<&BencodeValue>,
+    info: &TorrentInfo,
+) -> Result<HashMap<Hash256, Vec<Hash256>>, ParseError> {
+    let mut layers = HashMap::new();
+    if let Some(value) = value {
+        for (root, layer) in value.as_dict()? {
+            let root: Hash256 = root.as_slice().try_into().map_err(|_| ParseError {
+                message: "Invalid piece layer key (must be 32 bytes)".to_string(),
+            })?;
+            let bytes = layer.as_bytes()?;
+            if bytes.len() % 32 != 0 {
+                return Err(ParseError {
+                    message: "Invalid piece layer length (must be multiple of 32)".to_string(),
+                });
+            }
+            let hashes: Vec<Hash256> = bytes
+                .chunks(32)
+                .map(|chunk| chunk.try_into().expect("32-byte chunk"))
+                .collect();
+            if merkle::root_from_piece_layer(&hashes, info.piece_length) != root {
+                return Err(ParseError {
+                    message: "Piece layer does not match its pieces root".to_string(),
+                });
+            }
+            layers.insert(root, hashes);
+        }
+    }
+
+    for file in &info.file_tree {
+        if file.length > info.piece_length as u64
+            && let Some(root) = file.pieces_root
+            && !layers.contains_key(&root)
+        {
+            return Err(ParseError {
+                message: format!("Missing piece layer for '{}'", file.path.join("/")),
+            });
+        }
+    }
+    Ok(layers)
+}
+
+impl BencodeValue {
     pub(crate) fn as_bytes(&self) -> Result<&[u8], ParseError> {
         match self {
             BencodeValue::String(bytes) => Ok(bytes),
@@ -232,15 +883,6 @@ impl BencodeValue {
         }
     }
 
-    pub(crate) fn as_list(&self) -> Result<&[BencodeValue], ParseError> {
-        match self {
-            BencodeValue::List(list) => Ok(list),
-            _ => Err(ParseError {
-                message: "Expected list".to_string(),
-            }),
-        }
-    }
-
     pub(crate) fn as_dict(&self) -> Result<&HashMap<Vec<u8>, BencodeValue>, ParseError> {
         match self {
             BencodeValue::Dictionary(dict) => Ok(dict),
@@ -287,144 +929,478 @@ pub fn bencode_encode(value: &BencodeValue) -> Vec<u8> {
     }
 }
 
-pub fn parse_torrent_file(filename: &str) -> Result<TorrentFile, ParseError> {
-    let data = fs::read(filename).map_err(|e| ParseError {
-        message: format!("Failed to read file: {}", e),
-    })?;
+/// Settings for reading a `.torrent` file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    /// Legacy encoding for names and paths that are neither UTF-8 nor have
+    /// a `.utf-8` variant. Without one, invalid bytes are shown as `%XX`.
+    pub codepage: Option<&'static Encoding>,
+    /// Whether non-canonical bencode is a warning or an error.
+    pub mode: ParseMode,
+}
 
-    let mut parser = BencodeParser::new(&data);
-    let root = parser.parse()?;
-    let root_dict = root.as_dict()?;
-
-    // Extract announce
-    let announce = root_dict
-        .get(b"announce".as_ref())
-        .ok_or_else(|| ParseError {
-            message: "Missing 'announce' field".to_string(),
-        })?
-        .as_string()?;
-
-    // Extract announce-list (optional)
-    let announce_list = root_dict
-        .get(b"announce-list".as_ref())
-        .map(|v| {
-            let list = v.as_list()?;
-            let mut announce_list = Vec::new();
-            for tier in list {
-                let tier_list = tier.as_list()?;
-                let mut tier_urls = Vec::new();
-                for url in tier_list {
-                    tier_urls.push(url.as_string()?);
-                }
-                announce_list.push(tier_urls);
-            }
-            Ok::<Vec<Vec<String>>, ParseError>(announce_list)
-        })
-        .transpose()?;
+impl ParseOptions {
+    pub fn with_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
 
-    // Extract info dictionary
-    let info_value = root_dict.get(b"info".as_ref()).ok_or_else(|| ParseError {
-        message: "Missing 'info' field".to_string(),
-    })?;
+    /// Sets the fallback codepage by its WHATWG label, e.g. `windows-1251`
+    /// or `shift_jis`.
+    pub fn with_codepage(mut self, label: &str) -> Result<Self, ParseError> {
+        let encoding = Encoding::for_label(label.as_bytes()).ok_or_else(|| ParseError {
+            message: format!("Unknown codepage '{}'", label),
+        })?;
+        self.codepage = Some(encoding);
+        Ok(self)
+    }
+}
 
-    let info_dict = info_value.as_dict()?;
-
-    // Calculate info hash
-    let info_encoded = bencode_encode(info_value);
-    let mut hasher = Sha1::new();
-    hasher.update(&info_encoded);
-    let info_hash: [u8; 20] = hasher.finalize().into();
-
-    // Parse info dictionary
-    let name = info_dict
-        .get(b"name".as_ref())
-        .ok_or_else(|| ParseError {
-            message: "Missing 'name' field in info".to_string(),
-        })?
-        .as_string()?;
-
-    let piece_length = info_dict
-        .get(b"piece length".as_ref())
-        .ok_or_else(|| ParseError {
-            message: "Missing 'piece length' field".to_string(),
-        })?
-        .as_integer()? as u32;
-
-    let pieces_bytes = info_dict
-        .get(b"pieces".as_ref())
-        .ok_or_else(|| ParseError {
-            message: "Missing 'pieces' field".to_string(),
-        })?
-        .as_bytes()?;
-
-    if pieces_bytes.len() % 20 != 0 {
-        return Err(ParseError {
-            message: "Invalid pieces length (must be multiple of 20)".to_string(),
-        });
+/// How a name or path component was turned into text.
+enum DecodedText {
+    /// The bytes were UTF-8, or a valid `.utf-8` variant was used.
+    Utf8(String),
+    /// Decoded with the configured codepage.
+    Codepage(String),
+    /// Invalid bytes escaped as `%XX`.
+    Escaped(String),
+}
+
+impl DecodedText {
+    fn into_string(self) -> String {
+        match self {
+            DecodedText::Utf8(text) | DecodedText::Codepage(text) | DecodedText::Escaped(text) => {
+                text
+            }
+        }
     }
+}
 
-    let mut pieces = Vec::new();
-    for chunk in pieces_bytes.chunks(20) {
-        let mut piece_hash = [0u8; 20];
-        piece_hash.copy_from_slice(chunk);
-        pieces.push(piece_hash);
+/// Turns raw name bytes into text: UTF-8 if they are, else the codepage,
+/// else UTF-8 with every invalid byte escaped.
+fn decode_text(bytes: &[u8], codepage: Option<&'static Encoding>) -> DecodedText {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return DecodedText::Utf8(text.to_string());
+    }
+    if let Some(encoding) = codepage {
+        let (text, _) = encoding.decode_without_bom_handling(bytes);
+        return DecodedText::Codepage(text.into_owned());
     }
 
-    // Determine if single or multi-file torrent
-    let files = if let Some(length_value) = info_dict.get(b"length".as_ref()) {
-        // Single file
-        let length = length_value.as_integer()? as u64;
-        TorrentFiles::Single { length }
-    } else if let Some(files_value) = info_dict.get(b"files".as_ref()) {
-        // Multi-file
-        let files_list = files_value.as_list()?;
-        let mut files = Vec::new();
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    DecodedText::Escaped(text)
+}
 
-        for file_value in files_list {
-            let file_dict = file_value.as_dict()?;
+/// Reads `name` or a `path` list, preferring its valid `.utf-8` variant.
+///
+/// Returns the text and the original bytes when they differ from it. A
+/// warning is recorded when the text had to be guessed.
+fn decode_names(
+    original: &[Vec<u8>],
+    utf8: Option<Vec<Vec<u8>>>,
+    options: &ParseOptions,
+    what: &str,
+    offset: usize,
+    warnings: &mut Vec<ParseWarning>,
+) -> (Vec<String>, Option<Vec<Vec<u8>>>) {
+    let preferred = utf8.and_then(|components| {
+        components
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+    });
+    let text = match preferred {
+        Some(text) => text,
+        None => original
+            .iter()
+            .map(|bytes| match decode_text(bytes, options.codepage) {
+                DecodedText::Utf8(text) => text,
+                DecodedText::Codepage(text) => {
+                    warnings.push(ParseWarning {
+                        offset,
+                        message: format!(
+                            "{} is not UTF-8; decoded as {}",
+                            what,
+                            options.codepage.map(|e| e.name()).unwrap_or_default()
+                        ),
+                    });
+                    text
+                }
+                escaped => {
+                    warnings.push(ParseWarning {
+                        offset,
+                        message: format!("{} is not UTF-8; invalid bytes escaped", what),
+                    });
+                    escaped.into_string()
+                }
+            })
+            .collect(),
+    };
 
-            let length = file_dict
-                .get(b"length".as_ref())
-                .ok_or_else(|| ParseError {
-                    message: "Missing 'length' in file".to_string(),
-                })?
-                .as_integer()? as u64;
+    let lossless =
+        text.len() == original.len() && text.iter().zip(original).all(|(t, o)| t.as_bytes() == o);
+    (text, (!lossless).then(|| original.to_vec()))
+}
 
-            let path_list = file_dict
-                .get(b"path".as_ref())
-                .ok_or_else(|| ParseError {
-                    message: "Missing 'path' in file".to_string(),
-                })?
-                .as_list()?;
+pub fn parse_torrent_file(filename: &str) -> Result<TorrentFile, ParseError> {
+    parse_torrent_file_with(filename, &ParseOptions::default())
+}
+
+pub fn parse_torrent_file_with(
+    filename: &str,
+    options: &ParseOptions,
+) -> Result<TorrentFile, ParseError> {
+    let data = fs::read(filename).map_err(|e| ParseError {
+        message: format!("Failed to read file: {}", e),
+    })?;
+    parse_torrent_bytes(&data, options)
+}
 
-            let mut path = Vec::new();
-            for path_component in path_list {
-                path.push(path_component.as_string()?);
+/// Parses a metainfo file already in memory, e.g. fetched over HTTP.
+pub fn parse_torrent_bytes(data: &[u8], options: &ParseOptions) -> Result<TorrentFile, ParseError> {
+    let mut parser = BencodeParser::new(data)
+        .with_spans()
+        .with_mode(options.mode);
+    let root = parser.parse()?;
+    let raw: RawMetainfo = bencode::from_value(&root)?;
+
+    // Calculate info hash over the original bytes, exactly as the swarm does
+    let info_span = parser
+        .span(&[PathSegment::Key(b"info".to_vec())])
+        .expect("spans are recorded for every parsed value");
+    TorrentFile::from_raw(
+        raw,
+        &data[info_span],
+        options,
+        &|path| parser.span(path).map_or(0, |span| span.start),
+        parser.warnings().to_vec(),
+    )
+}
+
+/// A metainfo file as laid out in bencode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RawMetainfo {
+    // Trackerless torrents, e.g. after `edit --clear-trackers`, have none
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    announce: String,
+    #[serde(rename = "announce-list")]
+    announce_list: Option<Vec<Vec<String>>>,
+    #[serde(rename = "url-list")]
+    url_list: Option<UrlList>,
+    httpseeds: Option<Vec<String>>,
+    info: RawInfo,
+    #[serde(rename = "piece layers")]
+    piece_layers: Option<BencodeValue>,
+    // Free text, so not necessarily UTF-8
+    comment: Option<ByteString>,
+    #[serde(rename = "created by")]
+    created_by: Option<ByteString>,
+    #[serde(rename = "creation date")]
+    creation_date: Option<i64>,
+}
+
+/// `url-list` may be a single URL instead of a list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// The `info` dictionary as laid out in bencode.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RawInfo {
+    name: ByteString,
+    // A malformed `name.utf-8` falls back to `name`
+    #[serde(
+        rename = "name.utf-8",
+        default,
+        deserialize_with = "bencode::lenient::or_default"
+    )]
+    name_utf8: Option<ByteString>,
+    #[serde(rename = "piece length")]
+    piece_length: u32,
+    pieces: Option<ByteString>,
+    length: Option<u64>,
+    files: Option<Vec<RawFile>>,
+    private: Option<i64>,
+    #[serde(rename = "meta version")]
+    meta_version: Option<i64>,
+    #[serde(rename = "file tree")]
+    file_tree: Option<BencodeValue>,
+}
+
+/// One entry of the v1 `files` list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RawFile {
+    length: u64,
+    path: Vec<ByteString>,
+    #[serde(
+        rename = "path.utf-8",
+        default,
+        deserialize_with = "bencode::lenient::or_default"
+    )]
+    path_utf8: Option<Vec<ByteString>>,
+    attr: Option<String>,
+    #[serde(rename = "symlink path")]
+    symlink_path: Option<Vec<String>>,
+    sha1: Option<ByteString>,
+}
+
+impl TorrentFile {
+    /// Builds a torrent from its raw layout. `info_bytes` are hashed for the
+    /// info hashes; `offset_of` locates values for warnings.
+    fn from_raw(
+        raw: RawMetainfo,
+        info_bytes: &[u8],
+        options: &ParseOptions,
+        offset_of: &dyn Fn(&[PathSegment]) -> usize,
+        mut warnings: Vec<ParseWarning>,
+    ) -> Result<TorrentFile, ParseError> {
+        let RawMetainfo {
+            announce,
+            announce_list,
+            url_list,
+            httpseeds,
+            info: raw_info,
+            piece_layers: raw_layers,
+            comment,
+            created_by,
+            creation_date,
+        } = raw;
+
+        // An empty `url-list` string means no web seeds
+        let web_seeds = match url_list {
+            Some(UrlList::One(url)) if !url.is_empty() => vec![url],
+            Some(UrlList::Many(urls)) => urls,
+            _ => Vec::new(),
+        };
+        let http_seeds = httpseeds.unwrap_or_default();
+        let text = |bytes: ByteString| decode_text(&bytes, options.codepage).into_string();
+        let comment = comment.map(text);
+        let created_by = created_by.map(text);
+        let info_key = PathSegment::Key(b"info".to_vec());
+
+        // Legacy names may not be UTF-8
+        let name_utf8 = raw_info.name_utf8.map(|name| name.0);
+        let (mut name, raw_name) = decode_names(
+            &[raw_info.name.0],
+            name_utf8.clone().map(|name| vec![name]),
+            options,
+            "'name'",
+            offset_of(&[info_key.clone(), PathSegment::Key(b"name".to_vec())]),
+            &mut warnings,
+        );
+        let name = name.remove(0);
+        let raw_name = raw_name.map(|mut raw| raw.remove(0));
+
+        let piece_length = raw_info.piece_length;
+        let private = raw_info.private == Some(1);
+        let meta_version = raw_info.meta_version;
+        let is_v2 = meta_version == Some(2);
+
+        let mut file_tree = Vec::new();
+        if is_v2 {
+            let tree = raw_info.file_tree.ok_or_else(|| ParseError {
+                message: "Missing 'file tree' field".to_string(),
+            })?;
+            parse_file_tree(tree.as_dict()?, &mut Vec::new(), &mut file_tree)?;
+            if !piece_length.is_power_of_two() || piece_length < merkle::MERKLE_BLOCK_SIZE as u32 {
+                return Err(ParseError {
+                    message: "v2 piece length must be a power of two of at least 16 KiB"
+                        .to_string(),
+                });
             }
+        }
 
-            files.push(TorrentFileInfo { path, length });
+        let pieces_bytes = match raw_info.pieces {
+            Some(pieces) => pieces.0,
+            None if is_v2 => Vec::new(),
+            None => {
+                return Err(ParseError {
+                    message: "Missing 'pieces' field".to_string(),
+                });
+            }
+        };
+        if pieces_bytes.len() % 20 != 0 {
+            return Err(ParseError {
+                message: "Invalid pieces length (must be multiple of 20)".to_string(),
+            });
         }
+        let pieces = pieces_bytes
+            .chunks(20)
+            .map(|chunk| chunk.try_into().expect("20-byte chunks"))
+            .collect();
+
+        // Determine if single or multi-file torrent
+        let files = if let Some(length) = raw_info.length {
+            TorrentFiles::Single { length }
+        } else if let Some(raw_files) = raw_info.files {
+            let mut files = Vec::new();
+            for (index, file) in raw_files.into_iter().enumerate() {
+                let path_bytes: Vec<Vec<u8>> = file.path.into_iter().map(|c| c.0).collect();
+                let path_utf8 = file
+                    .path_utf8
+                    .map(|path| path.into_iter().map(|c| c.0).collect());
+                let (path, raw_path) = decode_names(
+                    &path_bytes,
+                    path_utf8.clone(),
+                    options,
+                    &format!("Path of file {}", index),
+                    offset_of(&[
+                        info_key.clone(),
+                        PathSegment::Key(b"files".to_vec()),
+                        PathSegment::Index(index),
+                        PathSegment::Key(b"path".to_vec()),
+                    ]),
+                    &mut warnings,
+                );
+
+                let attr = file
+                    .attr
+                    .map(|attr| FileAttributes::parse(&attr))
+                    .unwrap_or_default();
+                if attr.symlink && file.symlink_path.is_none() {
+                    return Err(ParseError {
+                        message: format!("Missing 'symlink path' for '{}'", path.join("/")),
+                    });
+                }
 
-        TorrentFiles::Multiple { files }
-    } else {
-        return Err(ParseError {
-            message: "Torrent must have either 'length' or 'files' field".to_string(),
-        });
-    };
+                let sha1 = file
+                    .sha1
+                    .map(|hash| {
+                        hash.0.try_into().map_err(|_| ParseError {
+                            message: "Invalid 'sha1' in file (must be 20 bytes)".to_string(),
+                        })
+                    })
+                    .transpose()?;
+
+                files.push(TorrentFileInfo {
+                    path,
+                    raw_path,
+                    path_utf8,
+                    length: file.length,
+                    attr,
+                    symlink_path: file.symlink_path,
+                    sha1,
+                });
+            }
+            TorrentFiles::Multiple { files }
+        } else if is_v2 {
+            // v2-only: derive the file list from the tree
+            match file_tree.as_slice() {
+                [file] if file.path == [name.clone()] => TorrentFiles::Single {
+                    length: file.length,
+                },
+                _ => TorrentFiles::Multiple {
+                    files: file_tree
+                        .iter()
+                        .map(|file| TorrentFileInfo {
+                            path: file.path.clone(),
+                            length: file.length,
+                            ..Default::default()
+                        })
+                        .collect(),
+                },
+            }
+        } else {
+            return Err(ParseError {
+                message: "Torrent must have either 'length' or 'files' field".to_string(),
+            });
+        };
+
+        let info = TorrentInfo {
+            name,
+            raw_name,
+            name_utf8,
+            piece_length,
+            pieces,
+            files,
+            meta_version,
+            file_tree,
+            private,
+        };
+
+        let (info_hash_v2, piece_layers) = if is_v2 {
+            let hash: Hash256 = Sha256::digest(info_bytes).into();
+            let layers = parse_piece_layers(raw_layers.as_ref(), &info)?;
+            (Some(hash), layers)
+        } else {
+            (None, HashMap::new())
+        };
+        let info_hash: [u8; 20] = if info.has_v1() {
+            Sha1::digest(info_bytes).into()
+        } else {
+            info_hash_v2.expect("v2 torrents have a SHA-256 hash")[..20]
+                .try_into()
+                .expect("20 of 32 bytes")
+        };
+
+        Ok(TorrentFile {
+            announce,
+            announce_list,
+            info,
+            info_hash,
+            info_hash_v2,
+            piece_layers,
+            web_seeds,
+            http_seeds,
+            comment,
+            created_by,
+            creation_date,
+            warnings,
+        })
+    }
+}
 
-    let info = TorrentInfo {
-        name,
-        piece_length,
-        pieces,
-        files,
-    };
+/// Reads a torrent from a parsed value. The info hashes are taken over the
+/// re-encoded `info` dictionary, which drops keys this crate does not know;
+/// use [`parse_torrent_file`] for torrents from elsewhere.
+impl TryFrom<RawMetainfo> for TorrentFile {
+    type Error = ParseError;
+
+    fn try_from(raw: RawMetainfo) -> Result<Self, ParseError> {
+        let info_bytes = bencode::to_bytes(&raw.info)?;
+        TorrentFile::from_raw(
+            raw,
+            &info_bytes,
+            &ParseOptions::default(),
+            &|_| 0,
+            Vec::new(),
+        )
+    }
+}
 
-    Ok(TorrentFile {
-        announce,
-        announce_list,
-        info,
-        info_hash,
-    })
+impl From<TorrentFile> for RawMetainfo {
+    fn from(torrent: TorrentFile) -> Self {
+        let piece_layers = (!torrent.piece_layers.is_empty()).then(|| {
+            BencodeValue::Dictionary(
+                torrent
+                    .piece_layers
+                    .iter()
+                    .map(|(root, layer)| (root.to_vec(), BencodeValue::String(layer.concat())))
+                    .collect(),
+            )
+        });
+        RawMetainfo {
+            announce: torrent.announce,
+            announce_list: torrent.announce_list,
+            url_list: (!torrent.web_seeds.is_empty()).then_some(UrlList::Many(torrent.web_seeds)),
+            httpseeds: (!torrent.http_seeds.is_empty()).then_some(torrent.http_seeds),
+            info: torrent.info.to_raw(),
+            piece_layers,
+            comment: torrent.comment.map(|c| ByteString(c.into_bytes())),
+            created_by: torrent.created_by.map(|c| ByteString(c.into_bytes())),
+            creation_date: torrent.creation_date,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -466,4 +1442,353 @@ mod tests {
         expected.insert(b"spam".to_vec(), BencodeValue::Integer(42));
         assert_eq!(result, BencodeValue::Dictionary(expected));
     }
+
+    #[test]
+    fn test_spans() {
+        let data = b"d4:infod1:ai1ee4:listl3:abcee";
+        let mut parser = BencodeParser::new(data).with_spans();
+        parser.parse().unwrap();
+
+        let info = parser.span(&[PathSegment::Key(b"info".to_vec())]).unwrap();
+        assert_eq!(&data[info], b"d1:ai1ee");
+        let item = parser
+            .span(&[PathSegment::Key(b"list".to_vec()), PathSegment::Index(0)])
+            .unwrap();
+        assert_eq!(&data[item], b"3:abc");
+        assert_eq!(parser.span(&[]).unwrap(), 0..data.len());
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        let limit = decoder::DEFAULT_MAX_DEPTH;
+        assert!(BencodeParser::new(&nested(limit)).parse().is_ok());
+        let err = BencodeParser::new(&nested(limit + 1)).parse().unwrap_err();
+        assert_eq!(
+            err.message,
+            format!("Nesting deeper than {} levels at byte {}", limit, limit)
+        );
+        assert!(
+            BencodeParser::new(&nested(3))
+                .with_max_depth(2)
+                .parse()
+                .is_err()
+        );
+        // Deep enough to overflow the stack without the limit
+        assert!(BencodeParser::new(&nested(1_000_000)).parse().is_err());
+    }
+
+    #[test]
+    fn test_warns_on_non_canonical_input() {
+        let mut parser = BencodeParser::new(b"d1:bi03e1:ai-0e1:a02:xxe");
+        parser.parse().unwrap();
+        let messages: Vec<&str> = parser
+            .warnings()
+            .iter()
+            .map(|w| w.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Non-canonical integer",
+                "Dictionary keys not sorted",
+                "Non-canonical integer",
+                "Duplicate dictionary key",
+                "Non-canonical string length",
+            ]
+        );
+        assert_eq!(parser.warnings()[0].offset, 4);
+
+        let mut parser = BencodeParser::new(b"d1:ai1e1:bi-2ee");
+        parser.parse().unwrap();
+        assert!(parser.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_non_canonical_input() {
+        let strict = |data: &[u8]| {
+            BencodeParser::new(data)
+                .with_mode(ParseMode::Strict)
+                .parse()
+                .map_err(|e| e.message)
+        };
+        assert_eq!(
+            strict(b"i-0e").unwrap_err(),
+            "Non-canonical integer at byte 0"
+        );
+        assert_eq!(
+            strict(b"li1ei03ee").unwrap_err(),
+            "Non-canonical integer at byte 4"
+        );
+        assert_eq!(
+            strict(b"l03:abce").unwrap_err(),
+            "Non-canonical string length at byte 1"
+        );
+        assert_eq!(
+            strict(b"d1:bi1e1:ai2ee").unwrap_err(),
+            "Dictionary keys not sorted at byte 7"
+        );
+        assert_eq!(
+            strict(b"d1:ai1e1:ai2ee").unwrap_err(),
+            "Duplicate dictionary key at byte 7"
+        );
+        assert_eq!(
+            strict(b"i1ei2e").unwrap_err(),
+            "Trailing data after value at byte 3"
+        );
+        assert!(strict(b"d1:ai-1e1:bl0:i0eee").is_ok());
+
+        // Lenient mode ignores trailing data, as it always has
+        let mut parser = BencodeParser::new(b"i1ei2e");
+        assert_eq!(parser.parse().unwrap(), BencodeValue::Integer(1));
+        assert!(parser.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_info_hash_uses_original_bytes() {
+        // Keys inside `info` are deliberately unsorted; re-encoding would sort
+        // them and produce a different hash.
+        let info =
+            b"d4:name4:test6:lengthi5e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut data = b"d8:announce17:http://x/announce4:info".to_vec();
+        data.extend_from_slice(info);
+        data.push(b'e');
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unsorted.torrent");
+        fs::write(&path, &data).unwrap();
+
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(torrent.info_hash, expected);
+        assert!(!torrent.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_legacy_encodings() {
+        // "Тест" and "Привет.txt" in windows-1251
+        let name = b"\xd2\xe5\xf1\xf2".to_vec();
+        let path = b"\xcf\xf0\xe8\xe2\xe5\xf2.txt".to_vec();
+        let file = |path: Vec<u8>| {
+            let mut file = HashMap::new();
+            file.insert(b"length".to_vec(), BencodeValue::Integer(1));
+            file.insert(
+                b"path".to_vec(),
+                BencodeValue::List(vec![BencodeValue::String(path)]),
+            );
+            BencodeValue::Dictionary(file)
+        };
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), BencodeValue::String(name.clone()));
+        info.insert(
+            b"name.utf-8".to_vec(),
+            BencodeValue::String("Тест".as_bytes().to_vec()),
+        );
+        info.insert(b"piece length".to_vec(), BencodeValue::Integer(16384));
+        info.insert(b"pieces".to_vec(), BencodeValue::String(vec![0u8; 20]));
+        info.insert(
+            b"files".to_vec(),
+            BencodeValue::List(vec![file(path.clone()), file(b"ok.txt".to_vec())]),
+        );
+        let mut root = HashMap::new();
+        root.insert(
+            b"announce".to_vec(),
+            BencodeValue::String(b"http://x/announce".to_vec()),
+        );
+        root.insert(b"info".to_vec(), BencodeValue::Dictionary(info));
+
+        let dir = tempfile::tempdir().unwrap();
+        let torrent_path = dir.path().join("legacy.torrent");
+        fs::write(
+            &torrent_path,
+            bencode_encode(&BencodeValue::Dictionary(root)),
+        )
+        .unwrap();
+        let torrent_path = torrent_path.to_str().unwrap();
+
+        // Without a codepage, invalid bytes are escaped but kept
+        let torrent = parse_torrent_file(torrent_path).unwrap();
+        assert_eq!(torrent.info.name, "Тест");
+        assert_eq!(torrent.info.raw_name.as_ref(), Some(&name));
+        let files = torrent.files();
+        assert_eq!(files[0].path, vec!["Тест", "%CF%F0%E8%E2%E5%F2.txt"]);
+        assert_eq!(files[0].raw_path, Some(vec![name.clone(), path.clone()]));
+        assert_eq!(files[1].raw_path, Some(vec![name, b"ok.txt".to_vec()]));
+        assert!(
+            torrent
+                .warnings
+                .iter()
+                .any(|w| w.message.contains("Path of file 0"))
+        );
+
+        let options = ParseOptions::default()
+            .with_codepage("windows-1251")
+            .unwrap();
+        let torrent = parse_torrent_file_with(torrent_path, &options).unwrap();
+        assert_eq!(torrent.files()[0].path, vec!["Тест", "Привет.txt"]);
+
+        // Re-encoding keeps the original bytes and only the keys we read
+        let info = torrent.info.to_bencode();
+        let info_hash: [u8; 20] = Sha1::digest(bencode_encode(&info)).into();
+        assert_eq!(info_hash, torrent.info_hash);
+        let info = info.as_dict().unwrap();
+        let BencodeValue::List(files) = &info[b"files".as_ref()] else {
+            panic!("files is a list");
+        };
+        assert!(
+            !files[0]
+                .as_dict()
+                .unwrap()
+                .contains_key(b"path.utf-8".as_ref())
+        );
+        assert_eq!(
+            info[b"name".as_ref()].as_bytes().unwrap(),
+            b"\xd2\xe5\xf1\xf2"
+        );
+        assert!(ParseOptions::default().with_codepage("klingon").is_err());
+    }
+
+    #[test]
+    fn test_malformed_utf8_keys_fall_back() {
+        let data = b"d8:announce17:http://x/announce4:infod5:filesld6:lengthi1e4:pathl5:a.txte\
+            10:path.utf-8li1eeee4:name4:test10:name.utf-8i7e12:piece lengthi16384e\
+            6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = parse_torrent_bytes(data, &ParseOptions::default()).unwrap();
+        assert_eq!(torrent.info.name, "test");
+        assert_eq!(torrent.files()[0].path, vec!["test", "a.txt"]);
+    }
+
+    /// Writes a single-file v2 torrent for `data`, optionally with v1 piece
+    /// hashes too (a hybrid).
+    fn write_v2_torrent(dir: &std::path::Path, data: &[u8], hybrid: bool) -> String {
+        let piece_length = 32 * 1024;
+        let layer: Vec<Hash256> = data
+            .chunks(piece_length as usize)
+            .map(|piece| merkle::piece_root(piece, piece_length))
+            .collect();
+        let root = merkle::root_from_piece_layer(&layer, piece_length);
+
+        let info = TorrentInfo {
+            name: "video.mkv".to_string(),
+            raw_name: None,
+            name_utf8: None,
+            piece_length,
+            pieces: if hybrid {
+                data.chunks(piece_length as usize)
+                    .map(|piece| Sha1::digest(piece).into())
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            files: TorrentFiles::Single {
+                length: data.len() as u64,
+            },
+            meta_version: Some(2),
+            file_tree: vec![FileTreeEntry {
+                path: vec!["video.mkv".to_string()],
+                length: data.len() as u64,
+                pieces_root: Some(root),
+            }],
+            private: false,
+        };
+        let mut info_value = info.to_bencode();
+        if !hybrid && let BencodeValue::Dictionary(ref mut dict) = info_value {
+            dict.remove(b"length".as_ref());
+        }
+
+        let mut layers = HashMap::new();
+        layers.insert(root.to_vec(), BencodeValue::String(layer.concat()));
+        let mut root_dict = HashMap::new();
+        root_dict.insert(
+            b"announce".to_vec(),
+            BencodeValue::String(b"http://x/announce".to_vec()),
+        );
+        root_dict.insert(b"info".to_vec(), info_value);
+        root_dict.insert(b"piece layers".to_vec(), BencodeValue::Dictionary(layers));
+
+        let path = dir.join(if hybrid {
+            "hybrid.torrent"
+        } else {
+            "v2.torrent"
+        });
+        fs::write(&path, bencode_encode(&BencodeValue::Dictionary(root_dict))).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_v2_torrent_merkle_verification() {
+        let data: Vec<u8> = (0..80_000).map(|i| (i % 253) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = parse_torrent_file(&write_v2_torrent(dir.path(), &data, false)).unwrap();
+
+        assert!(torrent.info.is_v2());
+        assert!(!torrent.info.has_v1());
+        assert_eq!(torrent.total_size(), 80_000);
+        assert_eq!(torrent.num_pieces(), 3);
+        assert_eq!(torrent.piece_size(2), 80_000 - 2 * 32 * 1024);
+        assert_eq!(
+            torrent.info_hash[..],
+            torrent.info_hash_v2.unwrap()[..20],
+            "v2-only torrents use the truncated SHA-256 hash"
+        );
+        assert_eq!(torrent.swarm_hashes(), vec![torrent.info_hash]);
+
+        let piece = &data[32 * 1024..64 * 1024];
+        assert!(torrent.verify_piece(1, piece));
+        let mut corrupt = piece.to_vec();
+        corrupt[100] ^= 1;
+        assert!(!torrent.verify_piece(1, &corrupt));
+        assert!(torrent.verify_piece(2, &data[64 * 1024..]));
+    }
+
+    #[test]
+    fn test_hybrid_torrent_joins_both_swarms() {
+        let data: Vec<u8> = (0..70_000).map(|i| (i % 249) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = parse_torrent_file(&write_v2_torrent(dir.path(), &data, true)).unwrap();
+
+        assert!(torrent.info.has_v1() && torrent.info.is_v2());
+        let swarms = torrent.swarm_hashes();
+        assert_eq!(swarms.len(), 2);
+        assert_eq!(swarms[1][..], torrent.info_hash_v2.unwrap()[..20]);
+        assert!(torrent.verify_piece(0, &data[..32 * 1024]));
+    }
+
+    #[test]
+    fn test_torrent_serde_round_trip() {
+        let data: Vec<u8> = (0..70_000).map(|i| (i % 249) as u8).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_v2_torrent(dir.path(), &data, true);
+        let torrent = parse_torrent_file(&path).unwrap();
+
+        let bytes = bencode::to_bytes(&torrent).unwrap();
+        assert_eq!(bytes, fs::read(&path).unwrap());
+        let decoded: TorrentFile = bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.info_hash, torrent.info_hash);
+        assert_eq!(decoded.info_hash_v2, torrent.info_hash_v2);
+        assert_eq!(decoded.piece_layers, torrent.piece_layers);
+
+        let err =
+            bencode::from_bytes::<TorrentFile>(b"d8:announce1:x4:infod4:name1:xee").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Bencode error: missing field `piece length` at info"
+        );
+    }
+
+    #[test]
+    fn test_v2_rejects_bad_piece_layer() {
+        let data = vec![7u8; 100_000];
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_v2_torrent(dir.path(), &data, false);
+
+        // Corrupt one byte inside the piece layer, which sits at the end
+        let mut bytes = fs::read(&path).unwrap();
+        let len = bytes.len();
+        bytes[len - 5] ^= 1;
+        fs::write(&path, bytes).unwrap();
+
+        let err = parse_torrent_file(&path).unwrap_err();
+        assert!(err.message.contains("Piece layer"));
+    }
 }
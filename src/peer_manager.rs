@@ -1,46 +1,269 @@
-use crate::wire::{
-    Handshake, PeerMessage, receive_handshake, receive_message, send_handshake, send_message,
-};
+use crate::parser::TorrentFile;
+use crate::rate_limit::RateLimits;
+use crate::wire::{Handshake, HandshakeCodec, MessageCodec, PeerMessage, Reserved};
+use futures::{SinkExt, StreamExt};
 use std::io;
-use std::net::{SocketAddr, TcpStream};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio_util::codec::Framed;
+
+/// How often we send a keep-alive when we have nothing else to say.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Timeouts applied to every peer connection.
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    /// Maximum time to establish the TCP connection.
+    pub connect_timeout: Duration,
+    /// Maximum time for the peer to answer our handshake.
+    pub handshake_timeout: Duration,
+    /// Disconnect a peer that has sent nothing (not even a keep-alive) for this long.
+    pub idle_timeout: Duration,
+    /// Send a keep-alive after this long without sending anything else.
+    pub keep_alive_interval: Duration,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        PeerConfig {
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(180),
+            keep_alive_interval: KEEP_ALIVE_INTERVAL,
+        }
+    }
+}
+
+/// Where a torrent may look for peers.
+///
+/// Private torrents (BEP 27) only use the trackers in their own metainfo:
+/// the DHT stays off and trackers added by the user are ignored. Every
+/// discovery mechanism must check this before handing out or accepting
+/// peers for a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSources {
+    /// Announce URLs in the order they should be tried.
+    pub trackers: Vec<String>,
+    /// Whether the DHT may be asked for peers.
+    pub dht: bool,
+}
+
+impl PeerSources {
+    pub fn for_torrent(torrent: &TorrentFile, extra_trackers: &[String]) -> Self {
+        let public = !torrent.info.private;
+
+        let mut trackers = Vec::new();
+        let metainfo_trackers = std::iter::once(&torrent.announce)
+            .chain(torrent.announce_list.iter().flatten().flatten());
+        let extra = extra_trackers.iter().filter(|_| public);
+        for url in metainfo_trackers.chain(extra) {
+            if !url.is_empty() && !trackers.contains(url) {
+                trackers.push(url.clone());
+            }
+        }
+
+        PeerSources {
+            trackers,
+            dht: public,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct PeerClient {
     pub addr: SocketAddr,
-    pub stream: TcpStream,
+    pub stream: Framed<TcpStream, MessageCodec>,
     pub peer_id: [u8; 20],
     pub info_hash: [u8; 20],
-    // Add more state as needed (choked, bitfield, etc.)
+    /// Extensions the peer flagged in its handshake.
+    pub reserved: Reserved,
+    config: PeerConfig,
+    last_sent: Instant,
+    last_received: Instant,
+    rate_limits: Vec<RateLimits>,
 }
 
 impl PeerClient {
-    pub fn connect(addr: SocketAddr, info_hash: [u8; 20], peer_id: [u8; 20]) -> io::Result<Self> {
-        let mut stream = TcpStream::connect(addr)?;
-        let handshake = Handshake::new(info_hash, peer_id);
-        send_handshake(&mut stream, &handshake)?;
-        let peer_handshake = receive_handshake(&mut stream)?;
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        config: PeerConfig,
+    ) -> io::Result<Self> {
+        Self::connect_with(addr, Handshake::new(info_hash, peer_id), config).await
+    }
+
+    /// Like [`PeerClient::connect`], sending `handshake` as it is, e.g. with
+    /// extensions flagged in its reserved bytes.
+    pub async fn connect_with(
+        addr: SocketAddr,
+        handshake: Handshake,
+        config: PeerConfig,
+    ) -> io::Result<Self> {
+        let stream = timeout(config.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| timed_out("Timed out connecting to peer"))??;
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        let peer_handshake = timeout(config.handshake_timeout, async {
+            framed.send(handshake).await?;
+            match framed.next().await {
+                Some(handshake) => Ok(handshake?),
+                None => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed during handshake",
+                )),
+            }
+        })
+        .await
+        .map_err(|_| timed_out("Timed out waiting for peer handshake"))??;
+
+        // Any bytes the peer pipelined after its handshake stay buffered
+        // and are decoded as regular messages.
+        let stream = framed.map_codec(|_| MessageCodec::new());
+
         Ok(PeerClient {
             addr,
             stream,
             peer_id: peer_handshake.peer_id,
             info_hash: peer_handshake.info_hash,
+            reserved: peer_handshake.reserved,
+            config,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            rate_limits: Vec::new(),
+        })
+    }
+
+    /// Answers a peer that connected to us. `respond` sees the peer's
+    /// handshake and returns ours, or `None` to hang up (e.g. for a torrent
+    /// we do not have).
+    pub async fn accept(
+        stream: TcpStream,
+        addr: SocketAddr,
+        config: PeerConfig,
+        respond: impl FnOnce(&Handshake) -> Option<Handshake>,
+    ) -> io::Result<Self> {
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        let peer_handshake = timeout(config.handshake_timeout, framed.next())
+            .await
+            .map_err(|_| timed_out("Timed out waiting for peer handshake"))?
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed during handshake",
+                )
+            })??;
+        let handshake = respond(&peer_handshake).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Peer asked for an unknown torrent")
+        })?;
+        timeout(config.handshake_timeout, framed.send(handshake))
+            .await
+            .map_err(|_| timed_out("Timed out sending our handshake"))??;
+
+        Ok(PeerClient {
+            addr,
+            stream: framed.map_codec(|_| MessageCodec::new()),
+            peer_id: peer_handshake.peer_id,
+            info_hash: peer_handshake.info_hash,
+            reserved: peer_handshake.reserved,
+            config,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            rate_limits: Vec::new(),
         })
     }
 
-    pub fn send_message(&mut self, msg: &PeerMessage) -> io::Result<()> {
-        send_message(&mut self.stream, msg)
+    /// Throttles this connection with `limits`, on top of any limits already
+    /// applied. Call once for the global limits and once for the torrent's.
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits.push(limits);
+        self
     }
 
-    pub fn receive_message(&mut self) -> io::Result<PeerMessage> {
-        receive_message(&mut self.stream)
+    /// Lets the decoder validate bitfields against the torrent's piece count.
+    pub fn set_piece_count(&mut self, num_pieces: usize) {
+        self.stream.codec_mut().set_piece_count(num_pieces);
+    }
+
+    pub async fn send_message(&mut self, msg: PeerMessage) -> io::Result<()> {
+        let len = msg.encoded_len();
+        for limits in &self.rate_limits {
+            limits.upload.acquire(len).await;
+        }
+        self.stream.send(msg).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Waits for the next message from the peer.
+    ///
+    /// While waiting, a keep-alive is sent whenever we have been quiet for
+    /// `keep_alive_interval`. Fails with `TimedOut` once the peer has been
+    /// silent for longer than `idle_timeout`.
+    pub async fn receive_message(&mut self) -> io::Result<PeerMessage> {
+        loop {
+            let far = Instant::now() + self.config.idle_timeout;
+            if let Some(msg) = self.receive_message_before(far).await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Like [`PeerClient::receive_message`], giving up with `None` at
+    /// `deadline` without losing a message.
+    pub async fn receive_message_before(
+        &mut self,
+        deadline: Instant,
+    ) -> io::Result<Option<PeerMessage>> {
+        loop {
+            let idle_deadline = self.last_received + self.config.idle_timeout;
+            let keep_alive_deadline = self.last_sent + self.config.keep_alive_interval;
+            let wake = idle_deadline.min(keep_alive_deadline).min(deadline);
+
+            match timeout_at(wake, self.stream.next()).await {
+                Ok(Some(result)) => {
+                    self.last_received = Instant::now();
+                    let msg = result?;
+                    // Holding back the next read lets TCP push back on the sender
+                    for limits in &self.rate_limits {
+                        limits.download.acquire(msg.encoded_len()).await;
+                    }
+                    return Ok(Some(msg));
+                }
+                Ok(None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Peer closed the connection",
+                    ));
+                }
+                Err(_) if Instant::now() >= idle_deadline => {
+                    return Err(timed_out("Peer was silent for too long"));
+                }
+                Err(_) if Instant::now() >= deadline => return Ok(None),
+                Err(_) if Instant::now() < keep_alive_deadline => {}
+                Err(_) => self.send_message(PeerMessage::KeepAlive).await?,
+            }
+        }
     }
 }
 
+fn timed_out(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
+
 #[derive(Debug)]
 pub struct PeerManager {
     pub peers: Vec<PeerClient>,
 }
 
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PeerManager {
     pub fn new() -> Self {
         PeerManager { peers: Vec::new() }
@@ -52,3 +275,117 @@ impl PeerManager {
 
     // Add more management methods as needed (remove, broadcast, etc.)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use crate::wire::Handshake;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn short_config() -> PeerConfig {
+        PeerConfig {
+            connect_timeout: Duration::from_millis(500),
+            handshake_timeout: Duration::from_millis(100),
+            idle_timeout: Duration::from_millis(300),
+            keep_alive_interval: Duration::from_millis(100),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            // Accept but never answer the handshake
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(socket);
+        });
+
+        let err = PeerClient::connect(addr, [0u8; 20], [1u8; 20], short_config())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_then_idle_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            socket.read_exact(&mut handshake).await.unwrap();
+            let reply = Handshake::new([0u8; 20], [2u8; 20]).serialize();
+            socket.write_all(&reply).await.unwrap();
+
+            // Stay silent and collect whatever the client sends us
+            let mut received = Vec::new();
+            let mut buf = [0u8; 64];
+            while let Ok(n) = socket.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            received
+        });
+
+        let mut peer = PeerClient::connect(addr, [0u8; 20], [1u8; 20], short_config())
+            .await
+            .unwrap();
+        let err = peer.receive_message().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(peer);
+
+        let received = server.await.unwrap();
+        assert!(!received.is_empty());
+        assert!(received.chunks(4).all(|chunk| chunk == [0, 0, 0, 0]));
+    }
+
+    fn torrent(private: bool) -> TorrentFile {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("data.bin");
+        std::fs::write(&file, b"data").unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: file,
+            trackers: vec![
+                vec!["http://a/announce".to_string()],
+                vec!["http://b/announce".to_string()],
+            ],
+            private,
+            ..Default::default()
+        })
+        .unwrap();
+        let path = dir.path().join("data.torrent");
+        std::fs::write(&path, &created.data).unwrap();
+        parse_torrent_file(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_private_torrent_limits_peer_sources() {
+        let extra = vec!["http://extra/announce".to_string()];
+
+        let public = PeerSources::for_torrent(&torrent(false), &extra);
+        assert_eq!(
+            public.trackers,
+            vec![
+                "http://a/announce",
+                "http://b/announce",
+                "http://extra/announce"
+            ]
+        );
+        assert!(public.dht);
+
+        let private = PeerSources::for_torrent(&torrent(true), &extra);
+        assert_eq!(
+            private.trackers,
+            vec!["http://a/announce", "http://b/announce"]
+        );
+        assert!(!private.dht);
+    }
+}
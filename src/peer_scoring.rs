@@ -0,0 +1,193 @@
+/// Tracks peers that send corrupt data and bans repeat offenders by IP.
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// A block that was part of a piece which failed the hash check.
+#[derive(Debug, Clone)]
+struct SuspectBlock {
+    begin: u32,
+    len: usize,
+    peer: IpAddr,
+    hash: [u8; 20],
+}
+
+/// Session-wide record of hash failures per peer.
+///
+/// Every peer that contributed a block to a failed piece gets a strike; once a
+/// peer reaches `ban_threshold` strikes it is banned for the rest of the
+/// session. With smart-ban enabled, the blocks of a failed piece are
+/// remembered so that when the piece later passes, the peers whose blocks
+/// differ from the good copy are banned straight away.
+///
+/// The downloader fetches each piece from a single peer, so a failed copy
+/// has one contributor and smart-ban amounts to comparing it with the
+/// good copy another peer sends later. Block-level attribution only
+/// matters to callers that spread a piece's blocks across peers.
+#[derive(Debug)]
+pub struct PeerScores {
+    hash_failures: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
+    ban_threshold: u32,
+    smart_ban: bool,
+    suspect_blocks: HashMap<u32, Vec<SuspectBlock>>,
+}
+
+impl Default for PeerScores {
+    fn default() -> Self {
+        Self::new(2)
+    }
+}
+
+impl PeerScores {
+    pub fn new(ban_threshold: u32) -> Self {
+        PeerScores {
+            hash_failures: HashMap::new(),
+            banned: HashSet::new(),
+            ban_threshold: ban_threshold.max(1),
+            smart_ban: false,
+            suspect_blocks: HashMap::new(),
+        }
+    }
+
+    pub fn with_smart_ban(mut self, smart_ban: bool) -> Self {
+        self.smart_ban = smart_ban;
+        self
+    }
+
+    pub fn smart_ban_enabled(&self) -> bool {
+        self.smart_ban
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn hash_failures(&self, ip: &IpAddr) -> u32 {
+        self.hash_failures.get(ip).copied().unwrap_or(0)
+    }
+
+    /// Records a piece that failed its hash check.
+    ///
+    /// `blocks` lists `(begin, peer, data)` for every block of the piece.
+    /// Returns the peers that were newly banned.
+    pub fn record_hash_failure(
+        &mut self,
+        piece_index: u32,
+        blocks: &[(u32, IpAddr, &[u8])],
+    ) -> Vec<IpAddr> {
+        let contributors: HashSet<IpAddr> = blocks.iter().map(|(_, peer, _)| *peer).collect();
+
+        let mut newly_banned = Vec::new();
+        for peer in contributors {
+            let failures = self.hash_failures.entry(peer).or_insert(0);
+            *failures += 1;
+            if *failures >= self.ban_threshold && self.ban(peer) {
+                newly_banned.push(peer);
+            }
+        }
+
+        if self.smart_ban {
+            let suspects = self.suspect_blocks.entry(piece_index).or_default();
+            for (begin, peer, data) in blocks {
+                suspects.push(SuspectBlock {
+                    begin: *begin,
+                    len: data.len(),
+                    peer: *peer,
+                    hash: Sha1::digest(data).into(),
+                });
+            }
+        }
+
+        newly_banned
+    }
+
+    /// Records a piece that passed its hash check.
+    ///
+    /// With smart-ban enabled, any earlier failed copy of this piece is
+    /// compared block by block against the verified data, and the peers that
+    /// sent a differing block are banned. Returns the peers that were newly
+    /// banned.
+    pub fn record_piece_passed(&mut self, piece_index: u32, data: &[u8]) -> Vec<IpAddr> {
+        let Some(suspects) = self.suspect_blocks.remove(&piece_index) else {
+            return Vec::new();
+        };
+
+        let mut newly_banned = Vec::new();
+        for suspect in suspects {
+            let start = suspect.begin as usize;
+            let Some(good) = data.get(start..start + suspect.len) else {
+                continue;
+            };
+            let good_hash: [u8; 20] = Sha1::digest(good).into();
+            if good_hash != suspect.hash && self.ban(suspect.peer) {
+                newly_banned.push(suspect.peer);
+            }
+        }
+        newly_banned
+    }
+
+    fn ban(&mut self, peer: IpAddr) -> bool {
+        self.banned.insert(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn test_ban_after_repeated_failures() {
+        let mut scores = PeerScores::new(2);
+        let data = [0u8; 4];
+
+        assert!(
+            scores
+                .record_hash_failure(0, &[(0, ip(1), &data)])
+                .is_empty()
+        );
+        assert!(!scores.is_banned(&ip(1)));
+
+        assert_eq!(
+            scores.record_hash_failure(1, &[(0, ip(1), &data)]),
+            vec![ip(1)]
+        );
+        assert!(scores.is_banned(&ip(1)));
+        assert_eq!(scores.hash_failures(&ip(1)), 2);
+    }
+
+    #[test]
+    fn test_each_contributor_gets_one_strike_per_piece() {
+        let mut scores = PeerScores::new(2);
+        let data = [0u8; 4];
+        let blocks = [
+            (0, ip(1), &data[..]),
+            (4, ip(1), &data[..]),
+            (8, ip(2), &data[..]),
+        ];
+
+        scores.record_hash_failure(0, &blocks);
+        assert_eq!(scores.hash_failures(&ip(1)), 1);
+        assert_eq!(scores.hash_failures(&ip(2)), 1);
+    }
+
+    #[test]
+    fn test_smart_ban_finds_culprit() {
+        let mut scores = PeerScores::new(10).with_smart_ban(true);
+        let good = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        let bad_half = [9u8, 9, 9, 9];
+
+        scores.record_hash_failure(3, &[(0, ip(1), &good[..4]), (4, ip(2), &bad_half)]);
+        assert!(!scores.is_banned(&ip(2)));
+
+        // The piece is re-downloaded from a single peer and passes
+        assert_eq!(scores.record_piece_passed(3, &good), vec![ip(2)]);
+        assert!(scores.is_banned(&ip(2)));
+        assert!(!scores.is_banned(&ip(1)));
+    }
+}
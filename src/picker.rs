@@ -0,0 +1,374 @@
+/// Chooses which piece to download next.
+use crate::priority::FilePriority;
+
+/// How pieces are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickStrategy {
+    /// Lowest index first.
+    #[default]
+    Sequential,
+    /// For playing media while it downloads: the pieces right after the
+    /// playhead first, in order, then the rest of the torrent after it.
+    /// Every `rarest_every`th pick takes the rarest piece instead, so the
+    /// swarm still gets copies of pieces few peers have.
+    Streaming {
+        /// Pieces after the playhead that are fetched strictly in order.
+        window: u32,
+        rarest_every: u32,
+    },
+}
+
+impl PickStrategy {
+    pub fn streaming() -> Self {
+        PickStrategy::Streaming {
+            window: 16,
+            rarest_every: 5,
+        }
+    }
+}
+
+/// Number of [`FilePriority`] levels, `Skip` included.
+const LEVELS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    strategy: PickStrategy,
+    /// How many of the peers seen so far have each piece.
+    availability: Vec<u32>,
+    /// From the files each piece holds; see [`crate::priority::piece_priorities`].
+    priorities: Vec<FilePriority>,
+    done: Vec<bool>,
+    /// Pieces of each priority level, and how many of them are not done.
+    totals: [usize; LEVELS],
+    remaining: [usize; LEVELS],
+    /// For each level, no piece of that level below the cursor is missing.
+    cursors: [u32; LEVELS],
+    playhead: u32,
+    /// Pieces someone is waiting for, fetched before all others.
+    urgent: Vec<u32>,
+    picks: u64,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize, strategy: PickStrategy) -> Self {
+        let mut picker = PiecePicker {
+            strategy,
+            availability: vec![0; num_pieces],
+            priorities: vec![FilePriority::Normal; num_pieces],
+            done: vec![false; num_pieces],
+            totals: [0; LEVELS],
+            remaining: [0; LEVELS],
+            cursors: [0; LEVELS],
+            playhead: 0,
+            urgent: Vec::new(),
+            picks: 0,
+        };
+        picker.recount();
+        picker
+    }
+
+    pub fn strategy(&self) -> PickStrategy {
+        self.strategy
+    }
+
+    /// Changes the strategy, keeping availability and priorities.
+    pub fn set_strategy(&mut self, strategy: PickStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// Replaces the piece priorities. Higher priority pieces are picked
+    /// first, each level in the strategy's order; skipped pieces never are.
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        self.priorities = priorities;
+        self.priorities
+            .resize(self.done.len(), FilePriority::Normal);
+        self.recount();
+    }
+
+    /// Whether the piece is wanted at all.
+    pub fn is_wanted(&self, piece: u32) -> bool {
+        self.priorities
+            .get(piece as usize)
+            .is_some_and(|&priority| priority != FilePriority::Skip)
+    }
+
+    /// Records that the piece is verified; it is not picked again.
+    pub fn mark_done(&mut self, piece: u32) {
+        let Some(done) = self.done.get_mut(piece as usize) else {
+            return;
+        };
+        if *done {
+            return;
+        }
+        *done = true;
+        let level = self.priorities[piece as usize] as usize;
+        self.remaining[level] -= 1;
+        self.advance_cursor(level);
+    }
+
+    pub fn is_done(&self, piece: u32) -> bool {
+        self.done.get(piece as usize).copied().unwrap_or(false)
+    }
+
+    /// Verified and total pieces, counting only those not skipped.
+    pub fn progress(&self) -> (usize, usize) {
+        let wanted = FilePriority::Skip as usize + 1..LEVELS;
+        let total: usize = self.totals[wanted.clone()].iter().sum();
+        let remaining: usize = self.remaining[wanted].iter().sum();
+        (total - remaining, total)
+    }
+
+    /// Moves the playhead to `piece`, where playback is reading from.
+    pub fn set_playhead(&mut self, piece: u32) {
+        self.playhead = piece;
+    }
+
+    /// Sets the pieces to fetch before any other, in order, whatever the
+    /// strategy and priorities.
+    pub fn set_urgent(&mut self, pieces: Vec<u32>) {
+        self.urgent = pieces;
+    }
+
+    /// Counts a peer's bitfield (most significant bit first).
+    pub fn add_bitfield(&mut self, bitfield: &[u8]) {
+        for (index, count) in self.availability.iter_mut().enumerate() {
+            if bitfield
+                .get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+            {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Stops counting the bitfield of a peer that went away: the one it
+    /// sent with every piece it announced since.
+    pub fn remove_bitfield(&mut self, bitfield: &[u8]) {
+        for (index, count) in self.availability.iter_mut().enumerate() {
+            if bitfield
+                .get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+            {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Counts a `have` message. Callers skip pieces the peer already
+    /// announced, so each peer counts once per piece.
+    pub fn add_have(&mut self, piece: u32) {
+        if let Some(count) = self.availability.get_mut(piece as usize) {
+            *count += 1;
+        }
+    }
+
+    /// How many connected peers have the piece.
+    pub fn availability(&self, piece: u32) -> u32 {
+        self.availability.get(piece as usize).copied().unwrap_or(0)
+    }
+
+    /// The next missing piece to fetch among those `wanted` returns true
+    /// for: urgent pieces first, then by priority. Levels with nothing left
+    /// are skipped without looking at their pieces, and each level is
+    /// searched from its first missing piece.
+    pub fn pick(&mut self, wanted: impl Fn(u32) -> bool) -> Option<u32> {
+        if let Some(&piece) = self
+            .urgent
+            .iter()
+            .find(|&&i| (i as usize) < self.done.len() && !self.done[i as usize] && wanted(i))
+        {
+            return Some(piece);
+        }
+        if matches!(self.strategy, PickStrategy::Streaming { .. }) {
+            self.picks += 1;
+        }
+        (FilePriority::Skip as usize + 1..LEVELS)
+            .rev()
+            .filter(|&level| self.remaining[level] > 0)
+            .find_map(|level| self.pick_level(level, &wanted))
+    }
+
+    fn pick_level(&self, level: usize, wanted: &impl Fn(u32) -> bool) -> Option<u32> {
+        let num_pieces = self.done.len() as u32;
+        let cursor = self.cursors[level];
+        let wanted = |i: u32| {
+            self.priorities[i as usize] as usize == level && !self.done[i as usize] && wanted(i)
+        };
+
+        let (window, rarest_every) = match self.strategy {
+            PickStrategy::Sequential => return (cursor..num_pieces).find(|&i| wanted(i)),
+            PickStrategy::Streaming {
+                window,
+                rarest_every,
+            } => (window, rarest_every),
+        };
+
+        let playhead = self.playhead.min(num_pieces);
+        let window_end = playhead.saturating_add(window).min(num_pieces);
+        // Pieces past the window can wait for one rarest piece
+        let rarest_turn = rarest_every > 0 && self.picks.is_multiple_of(rarest_every as u64);
+        if rarest_turn && let Some(piece) = self.rarest(window_end..num_pieces, &wanted) {
+            return Some(piece);
+        }
+        (playhead.max(cursor)..num_pieces)
+            .find(|&i| wanted(i))
+            .or_else(|| self.rarest(cursor..playhead, &wanted))
+    }
+
+    /// Least available wanted piece in `range`, lowest index on ties.
+    fn rarest(&self, range: std::ops::Range<u32>, wanted: &impl Fn(u32) -> bool) -> Option<u32> {
+        range
+            .filter(|&i| wanted(i))
+            .min_by_key(|&i| self.availability[i as usize])
+    }
+
+    /// Rebuilds the per-level counts and cursors after priorities change.
+    fn recount(&mut self) {
+        self.totals = [0; LEVELS];
+        self.remaining = [0; LEVELS];
+        for (&priority, &done) in self.priorities.iter().zip(&self.done) {
+            self.totals[priority as usize] += 1;
+            if !done {
+                self.remaining[priority as usize] += 1;
+            }
+        }
+        self.cursors = [0; LEVELS];
+        for level in 0..LEVELS {
+            self.advance_cursor(level);
+        }
+    }
+
+    fn advance_cursor(&mut self, level: usize) {
+        let num_pieces = self.done.len() as u32;
+        let mut cursor = self.cursors[level];
+        while cursor < num_pieces
+            && (self.done[cursor as usize] || self.priorities[cursor as usize] as usize != level)
+        {
+            cursor += 1;
+        }
+        self.cursors[level] = cursor;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequential() {
+        let mut picker = PiecePicker::new(10, PickStrategy::Sequential);
+        picker.set_playhead(5);
+        assert_eq!(picker.pick(|i| i >= 3), Some(3));
+        assert_eq!(picker.pick(|_| false), None);
+    }
+
+    #[test]
+    fn test_streaming_favours_playhead_and_rarest() {
+        let mut picker = PiecePicker::new(
+            20,
+            PickStrategy::Streaming {
+                window: 3,
+                rarest_every: 3,
+            },
+        );
+        // Two peers have everything, one only the first half
+        picker.add_bitfield(&[0xff, 0xff, 0xf0]);
+        picker.add_bitfield(&[0xff, 0xff, 0xf0]);
+        picker.add_bitfield(&[0xff, 0xc0, 0x00]);
+        picker.set_playhead(8);
+
+        let mut done = [false; 20];
+        let mut order = Vec::new();
+        for _ in 0..6 {
+            let piece = picker.pick(|i| !done[i as usize]).unwrap();
+            done[piece as usize] = true;
+            order.push(piece);
+        }
+        // 8, 9 in order, the third pick is the rarest piece past the window
+        // (11..20 are on two peers), then the window and the file go on
+        assert_eq!(order, vec![8, 9, 11, 10, 12, 13]);
+
+        // Everything after the playhead done: back to the rarest before it
+        let mut picker = PiecePicker::new(4, PickStrategy::streaming());
+        picker.add_have(0);
+        picker.set_playhead(2);
+        assert_eq!(picker.pick(|i| i < 2), Some(1));
+    }
+
+    #[test]
+    fn test_availability_follows_peers() {
+        let mut picker = PiecePicker::new(10, PickStrategy::streaming());
+        picker.add_bitfield(&[0xf0, 0x00]);
+        picker.add_bitfield(&[0xff, 0xc0]);
+        picker.add_have(9);
+        assert_eq!(picker.availability(0), 2);
+        assert_eq!(picker.availability(9), 2);
+
+        // The second peer leaves with piece 9 announced on top
+        picker.remove_bitfield(&[0xff, 0xc0]);
+        assert_eq!(picker.availability(0), 1);
+        assert_eq!(picker.availability(5), 0);
+        assert_eq!(picker.availability(9), 1);
+        picker.remove_bitfield(&[0xf0, 0x00]);
+        picker.remove_bitfield(&[0xf0, 0x00]);
+        assert_eq!(picker.availability(0), 0);
+    }
+
+    #[test]
+    fn test_priorities() {
+        use FilePriority::*;
+        let mut picker = PiecePicker::new(5, PickStrategy::Sequential);
+        picker.set_priorities(vec![Skip, Low, Normal, High, Normal]);
+        let mut done = [false; 5];
+        let mut order = Vec::new();
+        while let Some(piece) = picker.pick(|i| !done[i as usize]) {
+            done[piece as usize] = true;
+            order.push(piece);
+        }
+        assert_eq!(order, vec![3, 2, 4, 1]);
+        assert!(!picker.is_wanted(0));
+    }
+
+    #[test]
+    fn test_done_pieces_and_progress() {
+        use FilePriority::*;
+        let mut picker = PiecePicker::new(6, PickStrategy::Sequential);
+        picker.set_priorities(vec![Normal, Normal, Skip, High, Normal, Low]);
+        assert_eq!(picker.progress(), (0, 5));
+
+        assert_eq!(picker.pick(|_| true), Some(3));
+        picker.mark_done(3);
+        picker.mark_done(3);
+        assert_eq!(picker.pick(|_| true), Some(0));
+        picker.mark_done(0);
+        picker.mark_done(1);
+        // Pieces the caller rules out are passed over, not lost
+        assert_eq!(picker.pick(|i| i != 4), Some(5));
+        assert_eq!(picker.pick(|_| true), Some(4));
+        assert_eq!(picker.progress(), (3, 5));
+
+        // Done pieces stay done when priorities change
+        picker.set_priorities(vec![Low; 6]);
+        assert_eq!(picker.progress(), (3, 6));
+        assert_eq!(picker.pick(|_| true), Some(2));
+        for piece in [2, 4, 5, 9] {
+            picker.mark_done(piece);
+        }
+        assert_eq!(picker.progress(), (6, 6));
+        assert_eq!(picker.pick(|_| true), None);
+    }
+
+    #[test]
+    fn test_urgent_pieces_come_first() {
+        use FilePriority::*;
+        let mut picker = PiecePicker::new(6, PickStrategy::Sequential);
+        picker.set_priorities(vec![High, High, Normal, Normal, Skip, Normal]);
+        picker.set_urgent(vec![4, 3, 9]);
+        assert_eq!(picker.pick(|_| true), Some(4));
+        picker.mark_done(4);
+        assert_eq!(picker.pick(|i| i != 3), Some(0));
+        assert_eq!(picker.pick(|_| true), Some(3));
+        picker.mark_done(3);
+        assert_eq!(picker.pick(|_| true), Some(0));
+    }
+}
@@ -0,0 +1,362 @@
+/// Choosing which files of a torrent to download, and in what order.
+use crate::parser::TorrentFile;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub struct PriorityError {
+    pub message: String,
+}
+
+impl std::fmt::Display for PriorityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Priority error: {}", self.message)
+    }
+}
+
+impl std::error::Error for PriorityError {}
+
+/// How much a file is wanted. Pieces of higher priority files are fetched
+/// first; skipped files are not downloaded at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    /// The next level up, staying at `High`.
+    pub fn raise(self) -> Self {
+        match self {
+            FilePriority::Skip => FilePriority::Low,
+            FilePriority::Low => FilePriority::Normal,
+            FilePriority::Normal | FilePriority::High => FilePriority::High,
+        }
+    }
+
+    /// The next level down, staying at `Skip`.
+    pub fn lower(self) -> Self {
+        match self {
+            FilePriority::High => FilePriority::Normal,
+            FilePriority::Normal => FilePriority::Low,
+            FilePriority::Low | FilePriority::Skip => FilePriority::Skip,
+        }
+    }
+}
+
+impl std::fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            FilePriority::Skip => "skip",
+            FilePriority::Low => "low",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high",
+        };
+        f.write_str(name)
+    }
+}
+
+impl std::str::FromStr for FilePriority {
+    type Err = PriorityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => Err(PriorityError {
+                message: format!("Unknown priority '{}' (skip, low, normal or high)", s),
+            }),
+        }
+    }
+}
+
+/// Shared per-file priorities. Clones refer to the same list, so the UI can
+/// change a priority and the downloader picks it up on its next piece.
+#[derive(Debug, Clone, Default)]
+pub struct FilePriorities {
+    priorities: Arc<Mutex<Vec<FilePriority>>>,
+    /// Bumped on every change.
+    version: Arc<AtomicU64>,
+}
+
+impl FilePriorities {
+    pub fn new(priorities: Vec<FilePriority>) -> Self {
+        FilePriorities {
+            priorities: Arc::new(Mutex::new(priorities)),
+            version: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.priorities.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The priority of file `index`; `Normal` past the end of the list.
+    pub fn get(&self, index: usize) -> FilePriority {
+        self.priorities
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, index: usize, priority: FilePriority) {
+        let mut priorities = self.priorities.lock().unwrap();
+        if let Some(current) = priorities.get_mut(index)
+            && *current != priority
+        {
+            *current = priority;
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<FilePriority> {
+        self.priorities.lock().unwrap().clone()
+    }
+
+    /// Changes whenever a priority does.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+}
+
+/// Each piece's priority: the highest of the files it holds data for.
+/// Padding files do not count, so a piece that only overlaps skipped files
+/// and padding is skipped too. Files past the end of `priorities` are
+/// `Normal`.
+pub fn piece_priorities(torrent: &TorrentFile, priorities: &[FilePriority]) -> Vec<FilePriority> {
+    let mut pieces = vec![FilePriority::Skip; torrent.num_pieces()];
+    for (index, (file, range)) in torrent
+        .files()
+        .iter()
+        .zip(torrent.file_pieces())
+        .enumerate()
+    {
+        if file.attr.padding {
+            continue;
+        }
+        let priority = priorities.get(index).copied().unwrap_or_default();
+        for piece in range {
+            if let Some(current) = pieces.get_mut(piece as usize) {
+                *current = (*current).max(priority);
+            }
+        }
+    }
+    pieces
+}
+
+/// One `--only` argument: file indices as listed by `info`, or a glob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileSelection {
+    /// e.g. `0,2-4`.
+    Indices(Vec<RangeInclusive<usize>>),
+    /// Matched against the file's path within the torrent. `*` and `?`
+    /// stop at `/`, `**` does not, and a pattern without `/` only has to
+    /// match the file name.
+    Glob(String),
+}
+
+impl FileSelection {
+    pub fn matches(&self, index: usize, path: &str) -> bool {
+        match self {
+            FileSelection::Indices(ranges) => ranges.iter().any(|range| range.contains(&index)),
+            FileSelection::Glob(pattern) => {
+                let name = if pattern.contains('/') {
+                    path
+                } else {
+                    path.rsplit('/').next().unwrap_or(path)
+                };
+                glob_match(pattern.as_bytes(), name.as_bytes())
+            }
+        }
+    }
+}
+
+/// Parses an `--only` argument. Anything made only of digits, commas and
+/// dashes is an index list; everything else is a glob.
+pub fn parse_file_selection(spec: &str) -> Result<FileSelection, PriorityError> {
+    let spec = spec.trim();
+    if spec.is_empty() {
+        return Err(PriorityError {
+            message: "Empty file selection".to_string(),
+        });
+    }
+    if !spec
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == ',' || c == '-' || c == ' ')
+    {
+        return Ok(FileSelection::Glob(spec.to_string()));
+    }
+
+    let error = || PriorityError {
+        message: format!("Invalid file index list '{}'", spec),
+    };
+    let parse_index = |s: &str| s.trim().parse::<usize>().map_err(|_| error());
+    let mut ranges = Vec::new();
+    for part in spec.split(',') {
+        let range = match part.split_once('-') {
+            Some((first, last)) => parse_index(first)?..=parse_index(last)?,
+            None => {
+                let index = parse_index(part)?;
+                index..=index
+            }
+        };
+        if range.is_empty() {
+            return Err(error());
+        }
+        ranges.push(range);
+    }
+    Ok(FileSelection::Indices(ranges))
+}
+
+/// Priorities for `--only`: `Normal` for the files any selection matches,
+/// `Skip` for the rest. No selections means every file. Fails when a
+/// selection matches no file, which is most likely a typo.
+pub fn select_files(
+    torrent: &TorrentFile,
+    selections: &[FileSelection],
+) -> Result<Vec<FilePriority>, PriorityError> {
+    let files = torrent.files();
+    if selections.is_empty() {
+        return Ok(vec![FilePriority::Normal; files.len()]);
+    }
+
+    let mut priorities = vec![FilePriority::Skip; files.len()];
+    for selection in selections {
+        let mut matched = false;
+        for (index, file) in files.iter().enumerate() {
+            if !file.attr.padding && selection.matches(index, &file.path.join("/")) {
+                priorities[index] = FilePriority::Normal;
+                matched = true;
+            }
+        }
+        if !matched {
+            return Err(PriorityError {
+                message: match selection {
+                    FileSelection::Indices(_) => {
+                        format!("No such file index; the torrent has {} files", files.len())
+                    }
+                    FileSelection::Glob(pattern) => format!("'{}' matches no file", pattern),
+                },
+            });
+        }
+    }
+    Ok(priorities)
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        [b'*', rest @ ..] => {
+            let segment = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=segment).any(|skip| glob_match(rest, &text[skip..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(text.first(), Some(&c) if c != b'/') && glob_match(rest, &text[1..])
+        }
+        [c, rest @ ..] => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use std::fs;
+
+    #[test]
+    fn test_parse_file_selection() {
+        assert_eq!(
+            parse_file_selection("0,2-4").unwrap(),
+            FileSelection::Indices(vec![0..=0, 2..=4])
+        );
+        assert_eq!(
+            parse_file_selection("*.mkv").unwrap(),
+            FileSelection::Glob("*.mkv".to_string())
+        );
+        assert!(parse_file_selection("4-2").is_err());
+        assert!(parse_file_selection("1,,2").is_err());
+        assert!(parse_file_selection(" ").is_err());
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = |pattern: &str| FileSelection::Glob(pattern.to_string());
+        assert!(glob("*.mkv").matches(0, "Season 1/e01.mkv"));
+        assert!(!glob("*.mkv").matches(0, "Season 1/e01.mkv.part"));
+        assert!(glob("Season ?/*").matches(0, "Season 1/e01.mkv"));
+        assert!(!glob("*/e01.mkv").matches(0, "a/b/e01.mkv"));
+        assert!(glob("**/e01.mkv").matches(0, "a/b/e01.mkv"));
+        assert!(FileSelection::Indices(vec![1..=2]).matches(2, "x"));
+    }
+
+    #[test]
+    fn test_select_files_and_piece_priorities() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        fs::create_dir_all(&root).unwrap();
+        // 16 KiB pieces: a.flac is in piece 0, b.flac in 0-2, c.txt in 2
+        fs::write(root.join("a.flac"), vec![1; 10_000]).unwrap();
+        fs::write(root.join("b.flac"), vec![2; 30_000]).unwrap();
+        fs::write(root.join("c.txt"), vec![3; 5_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root,
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://t/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let path = dir.path().join("album.torrent");
+        fs::write(&path, &created.data).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+
+        use FilePriority::*;
+        let only = |spec: &str| select_files(&torrent, &[parse_file_selection(spec).unwrap()]);
+        assert_eq!(only("*.txt").unwrap(), vec![Skip, Skip, Normal]);
+        assert_eq!(only("0-1").unwrap(), vec![Normal, Normal, Skip]);
+        assert!(only("*.mp3").is_err());
+        assert!(only("3").is_err());
+        assert_eq!(select_files(&torrent, &[]).unwrap(), vec![Normal; 3]);
+
+        assert_eq!(
+            piece_priorities(&torrent, &[Skip, Skip, High]),
+            vec![Skip, Skip, High]
+        );
+        assert_eq!(
+            piece_priorities(&torrent, &[High, Low, Skip]),
+            vec![High, Low, Low]
+        );
+    }
+
+    #[test]
+    fn test_priorities_handle() {
+        let priorities = FilePriorities::new(vec![FilePriority::Normal; 2]);
+        let shared = priorities.clone();
+        let version = priorities.version();
+        shared.set(1, FilePriority::Normal.raise());
+        assert_eq!(priorities.get(1), FilePriority::High);
+        assert_ne!(priorities.version(), version);
+        assert_eq!(FilePriority::Low.lower(), FilePriority::Skip);
+        assert_eq!(FilePriority::Skip.lower(), FilePriority::Skip);
+    }
+
+    #[test]
+    fn test_parse_priority() {
+        assert_eq!("High".parse::<FilePriority>().unwrap(), FilePriority::High);
+        assert_eq!("skip".parse::<FilePriority>().unwrap(), FilePriority::Skip);
+        assert!("urgent".parse::<FilePriority>().is_err());
+    }
+}
@@ -0,0 +1,307 @@
+/// Token-bucket bandwidth limiting for peer connections.
+use chrono::{Local, NaiveTime};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How many seconds of traffic a bucket may save up for a burst.
+const BURST_SECONDS: f64 = 1.0;
+
+#[derive(Debug)]
+pub struct RateLimitError {
+    pub message: String,
+}
+
+impl std::fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Rate limit error: {}", self.message)
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Bytes per second, or `None` for unlimited.
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            let capacity = rate as f64 * BURST_SECONDS;
+            self.tokens = (self.tokens + elapsed * rate as f64).min(capacity);
+        }
+        self.last_refill = now;
+    }
+}
+
+/// A shared token bucket. Clones refer to the same bucket, so one limiter can
+/// be handed to every peer connection it should cover.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `rate` bytes per second (`None` = unlimited).
+    pub fn new(rate: Option<u64>) -> Self {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                rate,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Changes the rate. Takes effect for the next transfer on every
+    /// connection sharing this limiter.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        if rate.is_none() {
+            bucket.tokens = 0.0;
+        }
+    }
+
+    /// Waits until `bytes` may be transferred.
+    ///
+    /// The bytes are taken from the bucket straight away, possibly driving it
+    /// into debt; the caller then sleeps until the debt is paid off. This keeps
+    /// transfers larger than the bucket from stalling forever.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.refill(Instant::now());
+            let Some(rate) = bucket.rate else {
+                return;
+            };
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 || rate == 0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Download and upload limiters for one scope (the whole client, or a single
+/// torrent).
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        RateLimits {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+}
+
+/// Limits that apply during part of the day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleRule {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+impl ScheduleRule {
+    /// Whether the rule covers `time`. Rules whose end is before their start
+    /// wrap around midnight.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Time-of-day overrides for a set of limits. Outside every rule, the
+/// default limits apply.
+#[derive(Debug, Clone)]
+pub struct RateSchedule {
+    pub default_download: Option<u64>,
+    pub default_upload: Option<u64>,
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl RateSchedule {
+    /// Returns the `(download, upload)` limits in force at `time`. The first
+    /// matching rule wins.
+    pub fn limits_at(&self, time: NaiveTime) -> (Option<u64>, Option<u64>) {
+        self.rules
+            .iter()
+            .find(|rule| rule.contains(time))
+            .map(|rule| (rule.download, rule.upload))
+            .unwrap_or((self.default_download, self.default_upload))
+    }
+
+    /// Applies the schedule to `limits`, checking local time once a minute.
+    ///
+    /// Limits are only written when the schedule moves into a different
+    /// window, so changes made at runtime hold until the next transition.
+    pub fn spawn(self, limits: RateLimits) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            let mut applied = None;
+            loop {
+                interval.tick().await;
+                let current = self.limits_at(Local::now().time());
+                if applied != Some(current) {
+                    limits.download.set_rate(current.0);
+                    limits.upload.set_rate(current.1);
+                    applied = Some(current);
+                }
+            }
+        })
+    }
+}
+
+/// Converts a KiB/s figure from the command line into a limit, where 0 means
+/// unlimited.
+pub fn kib_limit(kib_per_second: u64) -> Option<u64> {
+    if kib_per_second == 0 {
+        None
+    } else {
+        Some(kib_per_second.saturating_mul(1024))
+    }
+}
+
+/// Next lower step for interactive adjustment: unlimited drops to 1 MiB/s,
+/// anything else halves, down to 16 KiB/s.
+pub fn step_down(rate: Option<u64>) -> Option<u64> {
+    match rate {
+        None => Some(1024 * 1024),
+        Some(rate) => Some((rate / 2).max(16 * 1024)),
+    }
+}
+
+/// Next higher step for interactive adjustment: doubles, and lifts the limit
+/// entirely past 64 MiB/s.
+pub fn step_up(rate: Option<u64>) -> Option<u64> {
+    match rate {
+        None => None,
+        Some(rate) if rate.saturating_mul(2) > 64 * 1024 * 1024 => None,
+        Some(rate) => Some(rate * 2),
+    }
+}
+
+/// Parses a schedule rule of the form `HH:MM-HH:MM=DOWN,UP`, with limits in
+/// KiB/s and 0 meaning unlimited. For example `01:00-07:00=0,0` lifts all
+/// limits at night.
+pub fn parse_schedule_rule(rule: &str) -> Result<ScheduleRule, RateLimitError> {
+    let error = |message: &str| RateLimitError {
+        message: format!("Invalid schedule rule '{}': {}", rule, message),
+    };
+
+    let (window, limits) = rule
+        .split_once('=')
+        .ok_or_else(|| error("expected HH:MM-HH:MM=DOWN,UP"))?;
+    let (start, end) = window
+        .split_once('-')
+        .ok_or_else(|| error("expected a HH:MM-HH:MM time window"))?;
+    let parse_time =
+        |s: &str| NaiveTime::parse_from_str(s.trim(), "%H:%M").map_err(|_| error("invalid time"));
+    let (download, upload) = limits
+        .split_once(',')
+        .ok_or_else(|| error("expected DOWN,UP limits"))?;
+    let parse_limit = |s: &str| {
+        s.trim()
+            .parse::<u64>()
+            .map(kib_limit)
+            .map_err(|_| error("invalid limit"))
+    };
+
+    Ok(ScheduleRule {
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+        download: parse_limit(download)?,
+        upload: parse_limit(upload)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limiter_throttles_to_rate() {
+        let limiter = RateLimiter::new(Some(1000));
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire(500).await;
+        }
+        // 2000 bytes at 1000 B/s with an empty bucket
+        assert!(start.elapsed() >= Duration::from_millis(1900));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unlimited_does_not_wait() {
+        let limiter = RateLimiter::new(None);
+        let start = Instant::now();
+        limiter.acquire(10_000_000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limiter.set_rate(Some(100));
+        assert_eq!(limiter.rate(), Some(100));
+    }
+
+    #[test]
+    fn test_kib_limit() {
+        assert_eq!(kib_limit(0), None);
+        assert_eq!(kib_limit(100), Some(100 * 1024));
+        assert_eq!(kib_limit(u64::MAX), Some(u64::MAX));
+        assert_eq!(step_up(Some(u64::MAX)), None);
+    }
+
+    #[test]
+    fn test_parse_schedule_rule() {
+        let rule = parse_schedule_rule("22:30-07:00=0,64").unwrap();
+        assert_eq!(rule.start, time(22, 30));
+        assert_eq!(rule.end, time(7, 0));
+        assert_eq!(rule.download, None);
+        assert_eq!(rule.upload, Some(64 * 1024));
+
+        assert!(parse_schedule_rule("22:30=0,0").is_err());
+        assert!(parse_schedule_rule("25:00-07:00=0,0").is_err());
+    }
+
+    #[test]
+    fn test_schedule_wraps_midnight() {
+        let schedule = RateSchedule {
+            default_download: Some(1024),
+            default_upload: Some(512),
+            rules: vec![parse_schedule_rule("23:00-06:00=0,0").unwrap()],
+        };
+        assert_eq!(schedule.limits_at(time(2, 0)), (None, None));
+        assert_eq!(schedule.limits_at(time(23, 30)), (None, None));
+        assert_eq!(schedule.limits_at(time(12, 0)), (Some(1024), Some(512)));
+    }
+}
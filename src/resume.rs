@@ -0,0 +1,241 @@
+/// Fast-resume files: which pieces were verified, and the state of the files
+/// on disk at the time, so a later download can skip re-hashing them.
+use crate::bencode::{self, BencodeError, byte_string};
+use crate::parser::TorrentFile;
+use crate::storage::Storage;
+use crate::verify::{PieceStatus, VerifyReport};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+#[derive(Debug)]
+pub struct ResumeError {
+    pub message: String,
+}
+
+impl std::fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Resume error: {}", self.message)
+    }
+}
+
+impl std::error::Error for ResumeError {}
+
+impl From<io::Error> for ResumeError {
+    fn from(err: io::Error) -> Self {
+        ResumeError {
+            message: format!("IO error: {}", err),
+        }
+    }
+}
+
+impl From<BencodeError> for ResumeError {
+    fn from(err: BencodeError) -> Self {
+        ResumeError {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// The contents of a fast-resume file, stored as a bencoded dictionary.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash", with = "byte_string")]
+    pub info_hash: [u8; 20],
+    /// Verified pieces as a bitfield, most significant bit first, like the
+    /// wire protocol's `bitfield` message.
+    #[serde(with = "byte_string")]
+    pub pieces: Vec<u8>,
+    /// One entry per file of the torrent, in order.
+    pub files: Vec<ResumeFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeFile {
+    pub length: u64,
+    /// Modification time in seconds since the epoch; absent for padding and
+    /// symlinks, which have no data of their own.
+    #[serde(default)]
+    pub mtime: Option<i64>,
+}
+
+impl ResumeData {
+    /// Records the good pieces of `report` and the current size and
+    /// modification time of each file under `data_dir`.
+    pub fn from_report(torrent: &TorrentFile, data_dir: &Path, report: &VerifyReport) -> Self {
+        let mut pieces = vec![0u8; report.pieces.len().div_ceil(8)];
+        for (index, status) in report.pieces.iter().enumerate() {
+            if *status == PieceStatus::Good {
+                pieces[index / 8] |= 0x80 >> (index % 8);
+            }
+        }
+
+        let storage = Storage::existing(torrent, data_dir);
+        let files = torrent
+            .files()
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                if file.attr.padding || file.attr.symlink {
+                    return ResumeFile {
+                        length: file.length,
+                        mtime: None,
+                    };
+                }
+                match file_state(storage.data_path(index)) {
+                    Some((length, mtime)) => ResumeFile {
+                        length,
+                        mtime: Some(mtime),
+                    },
+                    None => ResumeFile {
+                        length: 0,
+                        mtime: None,
+                    },
+                }
+            })
+            .collect();
+
+        ResumeData {
+            info_hash: torrent.info_hash,
+            pieces,
+            files,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ResumeError> {
+        Ok(bencode::from_bytes(&fs::read(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ResumeError> {
+        fs::write(path, bencode::to_bytes(self)?)?;
+        Ok(())
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.pieces
+            .get(index / 8)
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    /// Whether this data still describes `torrent` under `data_dir`: same
+    /// info hash, and no file changed size or was touched since.
+    pub fn matches(&self, torrent: &TorrentFile, data_dir: &Path) -> bool {
+        let files = torrent.files();
+        if self.info_hash != torrent.info_hash
+            || self.files.len() != files.len()
+            || self.pieces.len() != torrent.num_pieces().div_ceil(8)
+        {
+            return false;
+        }
+        let storage = Storage::existing(torrent, data_dir);
+        files
+            .iter()
+            .zip(&self.files)
+            .enumerate()
+            .all(|(index, (file, saved))| {
+                if file.attr.padding || file.attr.symlink {
+                    return true;
+                }
+                match (file_state(storage.data_path(index)), saved.mtime) {
+                    (Some((length, mtime)), Some(saved_mtime)) => {
+                        length == saved.length && mtime == saved_mtime
+                    }
+                    // A file that was missing can only back pieces that were not good
+                    (None, None) => true,
+                    _ => false,
+                }
+            })
+    }
+}
+
+/// Size and modification time of a file, if it exists.
+fn file_state(path: &Path) -> Option<(u64, i64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    Some((metadata.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use crate::verify::verify_torrent;
+
+    #[test]
+    fn test_resume_round_trip_and_staleness() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("set");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.bin"), vec![1u8; 40_000]).unwrap();
+        fs::write(root.join("b.bin"), vec![2u8; 10_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root.clone(),
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://t/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.path().join("set.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+        let torrent = parse_torrent_file(torrent_path.to_str().unwrap()).unwrap();
+
+        fs::remove_file(root.join("b.bin")).unwrap();
+        let report = verify_torrent(&torrent, dir.path(), 2);
+        let resume = ResumeData::from_report(&torrent, dir.path(), &report);
+        assert!(resume.has_piece(0) && resume.has_piece(1));
+        // Piece 2 holds the tail of a.bin and all of b.bin
+        assert!(!resume.has_piece(2) && !resume.has_piece(3));
+        assert_eq!(resume.files[1].mtime, None);
+
+        let resume_path = dir.path().join("set.resume");
+        resume.save(&resume_path).unwrap();
+        let loaded = ResumeData::load(&resume_path).unwrap();
+        assert_eq!(loaded, resume);
+        assert!(loaded.matches(&torrent, dir.path()));
+
+        fs::write(root.join("b.bin"), vec![2u8; 10_000]).unwrap();
+        assert!(!loaded.matches(&torrent, dir.path()));
+    }
+
+    #[test]
+    fn test_resume_tracks_partfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("set");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.bin"), vec![1u8; 40_000]).unwrap();
+        fs::write(root.join("b.bin"), vec![2u8; 20_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root.clone(),
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://t/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.path().join("set.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+        let torrent = parse_torrent_file(torrent_path.to_str().unwrap()).unwrap();
+
+        let source = Storage::existing(&torrent, dir.path());
+        let out = dir.path().join("out");
+        let mut storage = Storage::new_skipping(&torrent, &out, &[false, true]).unwrap();
+        let boundary = source.read_piece(&torrent, 2).unwrap();
+        storage.write_piece(&torrent, 2, &boundary).unwrap();
+
+        let report = verify_torrent(&torrent, &out, 1);
+        let resume = ResumeData::from_report(&torrent, &out, &report);
+        assert!(resume.has_piece(2));
+        // b.bin is represented by the partfile, not as a missing file
+        assert!(resume.files[1].mtime.is_some());
+        assert!(resume.matches(&torrent, &out));
+
+        fs::write(storage.data_path(1), b"clobbered").unwrap();
+        assert!(!resume.matches(&torrent, &out));
+    }
+}
@@ -0,0 +1,1013 @@
+/// A JSON-RPC 2.0 API for controlling a [`Session`] from other programs.
+/// Requests, responses and notifications are one JSON object per line, over
+/// TCP or a unix socket.
+use crate::magnet::{MagnetLink, hex_decode, hex_encode};
+use crate::parser::{ParseOptions, TorrentFile, parse_torrent_bytes, parse_torrent_file_with};
+use crate::priority::{self, FilePriorities, FilePriority};
+use crate::rate_limit::{self, RateLimits};
+use crate::session::{
+    AddOptions, Session, SessionError, SessionEvent, TorrentState, TorrentStatus,
+};
+use crate::ui::UIEvent;
+use serde_json::{Value, json};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+    WriteHalf,
+};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// Anything the session or the torrent itself refuses.
+pub const SERVER_ERROR: i64 = -32000;
+/// The connection has not sent the server's token yet.
+pub const UNAUTHORIZED: i64 = -32001;
+
+/// Lines queued for a connection before a subscriber starts missing events.
+const OUTGOING_CAPACITY: usize = 256;
+/// Longest request line; a client sending more is hung up on.
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+/// Largest .torrent accepted from a url; bigger bodies are cut off.
+const MAX_TORRENT_BYTES: usize = 8 * 1024 * 1024;
+/// How long fetching a .torrent from a url may take in total.
+const URL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "RPC error: {}", self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<SessionError> for RpcError {
+    fn from(err: SessionError) -> Self {
+        server_error(err.message)
+    }
+}
+
+/// Answers requests against one session. Methods take named parameters:
+///
+/// - `auth`: `token`, which a server made [`RpcServer::with_token`] wants
+///   before it answers anything else on the connection
+/// - `add`: `path`, `url` or `magnet` (optionally with `metadata`, the path
+///   of its info dictionary; otherwise it is fetched from the swarm), and
+///   optionally `output_dir`, `paused`, `only` (a list
+///   of `--only` selections), `download_limit` and `upload_limit`
+/// - `list`, `stats`
+/// - `files`, `pause`, `resume`: `info_hash`
+/// - `remove`: `info_hash`, optionally `delete_data`
+/// - `set_limits`: `download_limit` and/or `upload_limit`, for one torrent
+///   if `info_hash` is given and for the session otherwise
+/// - `set_file_priorities`: `info_hash`, `priority` and optionally `files`,
+///   an `--only` selection (every file when missing)
+/// - `subscribe`, `unsubscribe`: start and stop `event` notifications
+///
+/// Info hashes are hex and limits are in KiB/s with 0 meaning unlimited.
+/// `path` and `metadata` are relative to the daemon's working directory;
+/// `output_dir` is relative to the server's output directory and may not
+/// leave it.
+#[derive(Clone)]
+pub struct RpcServer {
+    session: Session,
+    /// Where torrents are downloaded to.
+    output_dir: PathBuf,
+    http: reqwest::Client,
+    /// What clients must send with `auth`, if anything.
+    token: Option<String>,
+}
+
+impl RpcServer {
+    pub fn new(session: Session, output_dir: PathBuf) -> Self {
+        RpcServer {
+            session,
+            output_dir,
+            http: reqwest::Client::new(),
+            token: None,
+        }
+    }
+
+    /// Answers only clients that first send `token` with `auth`.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Accepts TCP connections until the listener fails.
+    pub async fn run(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (socket, _) = listener.accept().await?;
+            tokio::spawn(self.clone().handle(socket));
+        }
+    }
+
+    /// Accepts unix socket connections until the listener fails.
+    #[cfg(unix)]
+    pub async fn run_unix(self, listener: tokio::net::UnixListener) -> io::Result<()> {
+        loop {
+            let (socket, _) = listener.accept().await?;
+            tokio::spawn(self.clone().handle(socket));
+        }
+    }
+
+    /// Answers requests in order until the client hangs up.
+    async fn handle<S>(self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read_half, mut write_half) = tokio::io::split(stream);
+        let (sender, mut outgoing) = mpsc::channel::<Value>(OUTGOING_CAPACITY);
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                let mut line = message.to_string();
+                line.push('\n');
+                if write_half.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut subscription = None;
+        let mut authenticated = self.token.is_none();
+        let mut reader = BufReader::new(read_half);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let mut limited = (&mut reader).take(MAX_LINE_BYTES as u64 + 1);
+            match limited.read_until(b'\n', &mut line).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            if line.len() > MAX_LINE_BYTES {
+                let error = RpcError {
+                    code: INVALID_REQUEST,
+                    message: format!("Requests are limited to {} bytes", MAX_LINE_BYTES),
+                };
+                let _ = sender.send(error_response(Value::Null, error)).await;
+                break;
+            }
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = self
+                .respond(&line, &sender, &mut subscription, &mut authenticated)
+                .await;
+            if let Some(response) = response
+                && sender.send(response).await.is_err()
+            {
+                break;
+            }
+        }
+
+        if let Some(task) = subscription {
+            task.abort();
+        }
+        drop(sender);
+        let _ = writer.await;
+    }
+
+    /// The response to one line, or nothing for a notification.
+    async fn respond(
+        &self,
+        line: &str,
+        sender: &mpsc::Sender<Value>,
+        subscription: &mut Option<JoinHandle<()>>,
+        authenticated: &mut bool,
+    ) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = RpcError {
+                    code: PARSE_ERROR,
+                    message: format!("Invalid JSON: {}", e),
+                };
+                return Some(error_response(Value::Null, error));
+            }
+        };
+        let id = request.get("id").cloned();
+        let Some(method) = request.get("method").and_then(Value::as_str) else {
+            let error = RpcError {
+                code: INVALID_REQUEST,
+                message: "Expected an object with a 'method'".to_string(),
+            };
+            return Some(error_response(id.unwrap_or(Value::Null), error));
+        };
+
+        let result = match method {
+            "auth" => {
+                let token = request.get("params").and_then(|params| params.get("token"));
+                let sent = token.and_then(Value::as_str);
+                if self.token.as_deref().is_none_or(|expected| {
+                    sent.is_some_and(|sent| tokens_match(sent.as_bytes(), expected.as_bytes()))
+                }) {
+                    *authenticated = true;
+                    Ok(Value::Bool(true))
+                } else {
+                    Err(RpcError {
+                        code: UNAUTHORIZED,
+                        message: "Wrong token".to_string(),
+                    })
+                }
+            }
+            _ if !*authenticated => Err(RpcError {
+                code: UNAUTHORIZED,
+                message: "Send the token with 'auth' first".to_string(),
+            }),
+            "subscribe" => {
+                if subscription.is_none() {
+                    *subscription = Some(self.forward_events(sender.clone()));
+                }
+                Ok(Value::Bool(true))
+            }
+            "unsubscribe" => {
+                if let Some(task) = subscription.take() {
+                    task.abort();
+                }
+                Ok(Value::Bool(true))
+            }
+            _ => match request.get("params") {
+                None | Some(Value::Null) => self.call(method, &json!({})).await,
+                Some(params @ Value::Object(_)) => self.call(method, params).await,
+                Some(_) => Err(invalid_params("Parameters must be an object")),
+            },
+        };
+
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    /// Sends the session's events to `sender` as `event` notifications.
+    fn forward_events(&self, sender: mpsc::Sender<Value>) -> JoinHandle<()> {
+        let mut events = self.session.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let params = match events.recv().await {
+                    Ok(event) => match event_json(&event) {
+                        Some(params) => params,
+                        None => continue,
+                    },
+                    Err(RecvError::Lagged(missed)) => json!({"type": "lagged", "missed": missed}),
+                    Err(RecvError::Closed) => break,
+                };
+                let notification = json!({"jsonrpc": "2.0", "method": "event", "params": params});
+                if sender.send(notification).await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+
+    /// Runs one method. `params` is an object.
+    pub async fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "add" => self.add(params).await,
+            "list" => Ok(self
+                .session
+                .torrents()
+                .iter()
+                .map(status_json)
+                .collect::<Vec<_>>()
+                .into()),
+            "stats" => Ok(self.stats()),
+            "files" => self.files(params),
+            "pause" => {
+                self.session.pause(&info_hash_param(params)?)?;
+                Ok(Value::Null)
+            }
+            "resume" => {
+                self.session.resume(&info_hash_param(params)?)?;
+                Ok(Value::Null)
+            }
+            "remove" => {
+                let info_hash = info_hash_param(params)?;
+                if bool_param(params, "delete_data")? {
+                    self.session.remove_with_data(&info_hash).await?;
+                } else {
+                    self.session.remove(&info_hash)?;
+                }
+                Ok(Value::Null)
+            }
+            "set_limits" => self.set_limits(params),
+            "set_file_priorities" => self.set_file_priorities(params),
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("Unknown method '{}'", method),
+            }),
+        }
+    }
+
+    async fn add(&self, params: &Value) -> Result<Value, RpcError> {
+        let torrent = self.load_torrent(params).await?;
+
+        let only = match params.get("only") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(specs)) => specs
+                .iter()
+                .map(|spec| {
+                    let spec = spec
+                        .as_str()
+                        .ok_or_else(|| invalid_params("'only' must be a list of strings"))?;
+                    priority::parse_file_selection(spec).map_err(|e| invalid_params(e.message))
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(invalid_params("'only' must be a list of strings")),
+        };
+        let file_priorities =
+            priority::select_files(&torrent, &only).map_err(|e| invalid_params(e.message))?;
+
+        let output_dir = match str_param(params, "output_dir")? {
+            Some(dir) => self.output_dir.join(inner_path(dir)?),
+            None => self.output_dir.clone(),
+        };
+        let options = AddOptions {
+            output_dir,
+            file_priorities: FilePriorities::new(file_priorities),
+            rate_limits: RateLimits::new(
+                rate_limit::kib_limit(u64_param(params, "download_limit")?.unwrap_or(0)),
+                rate_limit::kib_limit(u64_param(params, "upload_limit")?.unwrap_or(0)),
+            ),
+            paused: bool_param(params, "paused")?,
+            ..AddOptions::default()
+        };
+        let info_hash = self.session.add(torrent, options)?;
+        Ok(json!({"info_hash": hex_encode(&info_hash)}))
+    }
+
+    /// The torrent named by `path`, `url` or `magnet`.
+    async fn load_torrent(&self, params: &Value) -> Result<TorrentFile, RpcError> {
+        let options = ParseOptions::default().with_mode(self.session.config().parse_mode);
+        if let Some(path) = str_param(params, "path")? {
+            return parse_torrent_file_with(path, &options).map_err(|e| server_error(e.message));
+        }
+
+        let data = if let Some(url) = str_param(params, "url")? {
+            let fetch = async {
+                let mut response = self
+                    .http
+                    .get(url)
+                    .timeout(URL_TIMEOUT)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let mut data = Vec::new();
+                while let Some(chunk) = response.chunk().await? {
+                    if data.len() + chunk.len() > MAX_TORRENT_BYTES {
+                        return Ok(None);
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                Ok::<_, reqwest::Error>(Some(data))
+            };
+            fetch
+                .await
+                .map_err(|e| server_error(format!("Failed to fetch '{}': {}", url, e)))?
+                .ok_or_else(|| {
+                    server_error(format!(
+                        "'{}' is larger than {} bytes",
+                        url, MAX_TORRENT_BYTES
+                    ))
+                })?
+        } else if let Some(uri) = str_param(params, "magnet")? {
+            let link = MagnetLink::parse(uri).map_err(|e| invalid_params(e.message))?;
+            // The info dictionary comes from the swarm unless it was given
+            let info_bytes = match str_param(params, "metadata")? {
+                Some(metadata) => std::fs::read(metadata)
+                    .map_err(|e| server_error(format!("Cannot read '{}': {}", metadata, e)))?,
+                None => self
+                    .session
+                    .fetch_metadata(&link)
+                    .await
+                    .map_err(|e| server_error(e.message))?,
+            };
+            link.to_torrent(&info_bytes)
+                .map_err(|e| server_error(e.message))?
+        } else {
+            return Err(invalid_params("Expected 'path', 'url' or 'magnet'"));
+        };
+        parse_torrent_bytes(&data, &options).map_err(|e| server_error(e.message))
+    }
+
+    fn stats(&self) -> Value {
+        let torrents = self.session.torrents();
+        let count = |wanted: fn(&TorrentState) -> bool| {
+            torrents
+                .iter()
+                .filter(|status| wanted(&status.state))
+                .count()
+        };
+        let config = self.session.config();
+        json!({
+            "torrents": torrents.len(),
+            "queued": count(|state| *state == TorrentState::Queued),
+            "downloading": count(|state| *state == TorrentState::Downloading),
+            "seeding": count(|state| *state == TorrentState::Seeding),
+            "paused": count(|state| *state == TorrentState::Paused),
+            "finished": count(|state| *state == TorrentState::Finished),
+            "errors": count(|state| matches!(state, TorrentState::Error(_))),
+            "completed_pieces": torrents.iter().map(|status| status.completed_pieces).sum::<usize>(),
+            "total_pieces": torrents.iter().map(|status| status.total_pieces).sum::<usize>(),
+            "download_limit": kib(config.rate_limits.download.rate()),
+            "upload_limit": kib(config.rate_limits.upload.rate()),
+            "max_connections": config.max_connections,
+            "max_active_downloads": config.max_active_downloads,
+            "max_active_seeds": config.max_active_seeds,
+        })
+    }
+
+    fn files(&self, params: &Value) -> Result<Value, RpcError> {
+        let info_hash = info_hash_param(params)?;
+        let (torrent, priorities) = self.torrent(&info_hash)?;
+        let files: Vec<Value> = torrent
+            .files()
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| !file.attr.padding)
+            .map(|(index, file)| {
+                json!({
+                    "index": index,
+                    "path": file.path.join("/"),
+                    "length": file.length,
+                    "priority": priorities.get(index).to_string(),
+                })
+            })
+            .collect();
+        Ok(files.into())
+    }
+
+    fn set_limits(&self, params: &Value) -> Result<Value, RpcError> {
+        let limits = match str_param(params, "info_hash")? {
+            Some(_) => {
+                let info_hash = info_hash_param(params)?;
+                self.session
+                    .rate_limits(&info_hash)
+                    .ok_or_else(|| server_error(format!("No torrent {}", hex_encode(&info_hash))))?
+            }
+            None => self.session.config().rate_limits.clone(),
+        };
+        if let Some(download) = u64_param(params, "download_limit")? {
+            limits.download.set_rate(rate_limit::kib_limit(download));
+        }
+        if let Some(upload) = u64_param(params, "upload_limit")? {
+            limits.upload.set_rate(rate_limit::kib_limit(upload));
+        }
+        Ok(json!({
+            "download_limit": kib(limits.download.rate()),
+            "upload_limit": kib(limits.upload.rate()),
+        }))
+    }
+
+    fn set_file_priorities(&self, params: &Value) -> Result<Value, RpcError> {
+        let info_hash = info_hash_param(params)?;
+        let priority: FilePriority = str_param(params, "priority")?
+            .ok_or_else(|| invalid_params("Missing 'priority'"))?
+            .parse()
+            .map_err(|e: priority::PriorityError| invalid_params(e.message))?;
+        let selection = str_param(params, "files")?
+            .map(priority::parse_file_selection)
+            .transpose()
+            .map_err(|e| invalid_params(e.message))?;
+
+        let (torrent, priorities) = self.torrent(&info_hash)?;
+        let mut changed = 0;
+        for (index, file) in torrent.files().iter().enumerate() {
+            let selected = match &selection {
+                Some(selection) => selection.matches(index, &file.path.join("/")),
+                None => true,
+            };
+            if selected && !file.attr.padding {
+                priorities.set(index, priority);
+                changed += 1;
+            }
+        }
+        if changed == 0 {
+            return Err(invalid_params("The selection matches no file"));
+        }
+        Ok(json!({"files": changed}))
+    }
+
+    fn torrent(
+        &self,
+        info_hash: &[u8; 20],
+    ) -> Result<(std::sync::Arc<TorrentFile>, FilePriorities), RpcError> {
+        self.session
+            .torrent(info_hash)
+            .zip(self.session.file_priorities(info_hash))
+            .ok_or_else(|| server_error(format!("No torrent {}", hex_encode(info_hash))))
+    }
+}
+
+/// Talks to an [`RpcServer`], one request at a time.
+pub struct RpcClient<S> {
+    reader: BufReader<ReadHalf<S>>,
+    writer: WriteHalf<S>,
+    next_id: u64,
+}
+
+impl<S: AsyncRead + AsyncWrite> RpcClient<S> {
+    pub fn new(stream: S) -> Self {
+        let (read_half, writer) = tokio::io::split(stream);
+        RpcClient {
+            reader: BufReader::new(read_half),
+            writer,
+            next_id: 0,
+        }
+    }
+
+    /// Sends a request and waits for its response, passing over any
+    /// notifications. The outer error is the connection's, the inner one
+    /// the request's.
+    pub async fn call(
+        &mut self,
+        method: &str,
+        params: Value,
+    ) -> io::Result<Result<Value, RpcError>> {
+        self.next_id += 1;
+        let id = json!(self.next_id);
+        let mut line =
+            json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}).to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The server hung up",
+                ));
+            }
+            let message: Value = serde_json::from_str(&line)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if message.get("id") != Some(&id) {
+                continue;
+            }
+            return Ok(match message.get("error") {
+                Some(error) => Err(RpcError {
+                    code: error["code"].as_i64().unwrap_or(SERVER_ERROR),
+                    message: error["message"].as_str().unwrap_or_default().to_string(),
+                }),
+                None => Ok(message.get("result").cloned().unwrap_or(Value::Null)),
+            });
+        }
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": error.code, "message": error.message},
+    })
+}
+
+fn invalid_params(message: impl Into<String>) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: message.into(),
+    }
+}
+
+/// Compares without stopping at the first differing byte, so the time taken
+/// does not tell a client how much of a guessed token was right.
+fn tokens_match(sent: &[u8], expected: &[u8]) -> bool {
+    if sent.len() != expected.len() {
+        return false;
+    }
+    sent.iter()
+        .zip(expected)
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+fn server_error(message: impl Into<String>) -> RpcError {
+    RpcError {
+        code: SERVER_ERROR,
+        message: message.into(),
+    }
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<Option<&'a str>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(invalid_params(format!("'{}' must be a string", name))),
+    }
+}
+
+fn u64_param(params: &Value, name: &str) -> Result<Option<u64>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| invalid_params(format!("'{}' must be a non-negative integer", name))),
+    }
+}
+
+fn bool_param(params: &Value, name: &str) -> Result<bool, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(value)) => Ok(*value),
+        Some(_) => Err(invalid_params(format!("'{}' must be true or false", name))),
+    }
+}
+
+/// `path` if it stays inside the directory it is relative to.
+fn inner_path(path: &str) -> Result<&Path, RpcError> {
+    let path = Path::new(path);
+    let inside = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if inside {
+        Ok(path)
+    } else {
+        Err(invalid_params(format!(
+            "'{}' must be a relative path without '..'",
+            path.display()
+        )))
+    }
+}
+
+fn info_hash_param(params: &Value) -> Result<[u8; 20], RpcError> {
+    let hex =
+        str_param(params, "info_hash")?.ok_or_else(|| invalid_params("Missing 'info_hash'"))?;
+    hex_decode(hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid_params("'info_hash' must be 40 hex digits"))
+}
+
+/// A limit in KiB/s, 0 for unlimited.
+fn kib(rate: Option<u64>) -> u64 {
+    rate.map_or(0, |rate| rate / 1024)
+}
+
+fn state_json(state: &TorrentState) -> (Value, Value) {
+    match state {
+        TorrentState::Error(message) => ("error".into(), message.as_str().into()),
+        state => (state.to_string().into(), Value::Null),
+    }
+}
+
+fn status_json(status: &TorrentStatus) -> Value {
+    let (state, error) = state_json(&status.state);
+    json!({
+        "info_hash": hex_encode(&status.info_hash),
+        "name": status.name,
+        "state": state,
+        "error": error,
+        "completed_pieces": status.completed_pieces,
+        "total_pieces": status.total_pieces,
+    })
+}
+
+/// The parameters of the `event` notification for `event`, if it is one
+/// that subscribers get.
+fn event_json(event: &SessionEvent) -> Option<Value> {
+    let (info_hash, mut params) = match event {
+        SessionEvent::Added(info_hash) => (info_hash, json!({"type": "added"})),
+        SessionEvent::Removed(info_hash) => (info_hash, json!({"type": "removed"})),
+        SessionEvent::StateChanged(info_hash, state) => {
+            let (state, error) = state_json(state);
+            (
+                info_hash,
+                json!({"type": "state", "state": state, "error": error}),
+            )
+        }
+        SessionEvent::Torrent(info_hash, event) => (info_hash, torrent_event_json(event)?),
+    };
+    params["info_hash"] = hex_encode(info_hash).into();
+    Some(params)
+}
+
+fn torrent_event_json(event: &UIEvent) -> Option<Value> {
+    Some(match event {
+        UIEvent::TrackerResponse(response) => json!({
+            "type": "tracker",
+            "seeders": response.complete,
+            "leechers": response.incomplete,
+            "peers": response.peers.len(),
+            "failure": response.failure_reason,
+        }),
+        UIEvent::PeerConnected(addr) => json!({"type": "peer_connected", "peer": addr.to_string()}),
+        UIEvent::PeerBanned(ip) => json!({"type": "peer_banned", "ip": ip.to_string()}),
+        UIEvent::PieceCompleted(piece, completed, total) => json!({
+            "type": "progress",
+            "piece": piece,
+            "completed_pieces": completed,
+            "total_pieces": total,
+        }),
+        UIEvent::PieceFailed(piece, addr) => {
+            json!({"type": "piece_failed", "piece": piece, "peer": addr.to_string()})
+        }
+        UIEvent::DownloadComplete => json!({"type": "complete"}),
+        UIEvent::Error(message) => json!({"type": "error", "message": message}),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::session::SessionConfig;
+    use std::fs;
+    use tokio::io::{BufReader, Lines};
+    use tokio::net::TcpStream;
+    use tokio::net::tcp::OwnedReadHalf;
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        write: tokio::net::tcp::OwnedWriteHalf,
+        next_id: u64,
+    }
+
+    impl Client {
+        async fn connect(addr: std::net::SocketAddr) -> Self {
+            let (read, write) = TcpStream::connect(addr).await.unwrap().into_split();
+            Client {
+                lines: BufReader::new(read).lines(),
+                write,
+                next_id: 0,
+            }
+        }
+
+        /// Sends a request and returns its response, keeping notifications
+        /// that arrive first in `events`.
+        async fn call(&mut self, method: &str, params: Value, events: &mut Vec<Value>) -> Value {
+            self.next_id += 1;
+            let request =
+                json!({"jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params});
+            self.send(&request.to_string()).await;
+            loop {
+                let message = self.next().await;
+                if message.get("id") == Some(&json!(self.next_id)) {
+                    return message;
+                }
+                events.push(message);
+            }
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.write
+                .write_all(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        async fn next(&mut self) -> Value {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&line).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_control_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.flac"), vec![1; 20_000]).unwrap();
+        fs::write(root.join("b.txt"), vec![2; 5_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root.clone(),
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://127.0.0.1:1/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.path().join("album.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+
+        let session = Session::new(SessionConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(RpcServer::new(session, dir.path().to_path_buf()).run(listener));
+        let mut client = Client::connect(addr).await;
+        let mut events = Vec::new();
+
+        let response = client.call("subscribe", json!({}), &mut events).await;
+        assert_eq!(response["result"], json!(true));
+        let response = client
+            .call(
+                "add",
+                json!({"path": torrent_path.to_str().unwrap(), "paused": true, "only": ["*.flac"]}),
+                &mut events,
+            )
+            .await;
+        let info_hash = response["result"]["info_hash"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(info_hash, hex_encode(&created.info_hash));
+
+        let list = client.call("list", json!({}), &mut events).await;
+        assert_eq!(list["result"][0]["state"], "paused");
+        let files = client
+            .call("files", json!({"info_hash": info_hash}), &mut events)
+            .await;
+        assert_eq!(files["result"][0]["priority"], "normal");
+        assert_eq!(files["result"][1]["priority"], "skip");
+
+        let response = client
+            .call(
+                "set_file_priorities",
+                json!({"info_hash": info_hash, "files": "*.txt", "priority": "high"}),
+                &mut events,
+            )
+            .await;
+        assert_eq!(response["result"]["files"], 1);
+        let files = client
+            .call("files", json!({"info_hash": info_hash}), &mut events)
+            .await;
+        assert_eq!(files["result"][1]["priority"], "high");
+
+        let limits = client
+            .call(
+                "set_limits",
+                json!({"info_hash": info_hash, "download_limit": 100}),
+                &mut events,
+            )
+            .await;
+        assert_eq!(
+            limits["result"],
+            json!({"download_limit": 100, "upload_limit": 0})
+        );
+        let stats = client.call("stats", json!({}), &mut events).await;
+        assert_eq!(stats["result"]["paused"], 1);
+
+        // Errors
+        let response = client.call("frobnicate", json!({}), &mut events).await;
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        let response = client
+            .call("pause", json!({"info_hash": "xyz"}), &mut events)
+            .await;
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = client
+            .call(
+                "add",
+                json!({"magnet": format!("magnet:?xt=urn:btih:{}", info_hash)}),
+                &mut events,
+            )
+            .await;
+        // A magnet link without trackers has nobody to fetch metadata from
+        assert_eq!(response["error"]["code"], SERVER_ERROR);
+        assert!(
+            response["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("No peers found")
+        );
+        client.send("{not json").await;
+        assert_eq!(client.next().await["error"]["code"], PARSE_ERROR);
+
+        // The data here is the torrent's own source, so removing it with
+        // its data deletes the album
+        let response = client
+            .call(
+                "remove",
+                json!({"info_hash": info_hash, "delete_data": true}),
+                &mut events,
+            )
+            .await;
+        assert_eq!(response["result"], Value::Null);
+        assert!(!root.exists());
+
+        let list = client.call("list", json!({}), &mut events).await;
+        assert_eq!(list["result"], json!([]));
+        let types: Vec<&str> = events
+            .iter()
+            .map(|event| event["params"]["type"].as_str().unwrap())
+            .collect();
+        assert!(types.contains(&"added"));
+        assert!(types.contains(&"removed"));
+        assert!(
+            events
+                .iter()
+                .all(|event| event["params"]["info_hash"] == info_hash)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_paths_and_line_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data.bin");
+        fs::write(&data, vec![3; 10_000]).unwrap();
+        let torrent_path = dir.path().join("data.torrent");
+        let created = create_torrent(&CreateOptions {
+            path: data,
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://127.0.0.1:1/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        fs::write(&torrent_path, &created.data).unwrap();
+
+        let session = Session::new(SessionConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = RpcServer::new(session, dir.path().to_path_buf()).with_token("secret");
+        tokio::spawn(server.run(listener));
+        let mut client = Client::connect(addr).await;
+        let mut events = Vec::new();
+
+        let response = client.call("list", json!({}), &mut events).await;
+        assert_eq!(response["error"]["code"], UNAUTHORIZED);
+        for guess in ["guess", "secre", "secret!", "SECRET"] {
+            let response = client
+                .call("auth", json!({"token": guess}), &mut events)
+                .await;
+            assert_eq!(response["error"]["code"], UNAUTHORIZED, "{}", guess);
+        }
+        let response = client
+            .call("auth", json!({"token": "secret"}), &mut events)
+            .await;
+        assert_eq!(response["result"], json!(true));
+
+        // Downloads stay inside the output directory
+        let add = |output_dir: &str| json!({"path": torrent_path.to_str().unwrap(), "output_dir": output_dir, "paused": true});
+        for output_dir in ["/tmp", "../elsewhere", "sub/../../elsewhere"] {
+            let response = client.call("add", add(output_dir), &mut events).await;
+            assert_eq!(response["error"]["code"], INVALID_PARAMS, "{}", output_dir);
+        }
+        let response = client.call("add", add("sub"), &mut events).await;
+        assert!(response["result"]["info_hash"].is_string());
+
+        // A line past the limit gets an error and the connection closed
+        let line = "x".repeat(MAX_LINE_BYTES + 1);
+        client.write.write_all(line.as_bytes()).await.unwrap();
+        assert_eq!(client.next().await["error"]["code"], INVALID_REQUEST);
+        assert!(client.lines.next_line().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_url_body_is_capped() {
+        // An HTTP server answering every request with an endless body
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/big.torrent", http.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = http.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await;
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                MAX_TORRENT_BYTES * 2
+            );
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(&vec![b'd'; MAX_TORRENT_BYTES * 2]).await;
+        });
+
+        let session = Session::new(SessionConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(RpcServer::new(session, ".".into()).run(listener));
+        let mut client = Client::connect(addr).await;
+        let mut events = Vec::new();
+
+        let response = client.call("add", json!({"url": url}), &mut events).await;
+        assert_eq!(response["error"]["code"], SERVER_ERROR);
+        let message = response["error"]["message"].as_str().unwrap();
+        assert!(message.contains("larger than"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_client_changes_limits() {
+        let session = Session::new(SessionConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = RpcServer::new(session.clone(), ".".into()).with_token("secret");
+        tokio::spawn(server.run(listener));
+        let mut client = RpcClient::new(TcpStream::connect(addr).await.unwrap());
+
+        let refused = client.call("set_limits", json!({"upload_limit": 5})).await;
+        assert_eq!(refused.unwrap().unwrap_err().code, UNAUTHORIZED);
+        let auth = client.call("auth", json!({"token": "secret"})).await;
+        assert_eq!(auth.unwrap().unwrap(), json!(true));
+        let limits = client
+            .call("set_limits", json!({"download_limit": 100}))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(limits, json!({"download_limit": 100, "upload_limit": 0}));
+        assert_eq!(
+            session.config().rate_limits.download.rate(),
+            Some(100 * 1024)
+        );
+    }
+}
@@ -0,0 +1,335 @@
+/// Turns file paths from untrusted torrents into safe relative paths.
+///
+/// Torrent paths are lists of components chosen by whoever made the file.
+/// Before storage touches the disk, every component is checked so that the
+/// result stays inside the output directory on any platform.
+use crate::parser::{TorrentFile, TorrentFiles};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Longest component, in bytes, that common filesystems accept.
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+/// Device names Windows reserves regardless of extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Rewrites one path component, or returns `None` if it should be dropped
+/// (empty, `.` and `..`).
+pub fn sanitize_component(component: &str) -> Option<String> {
+    if component.is_empty() || component == "." || component == ".." {
+        return None;
+    }
+
+    let mut clean: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently strips trailing dots and spaces
+    let trimmed = clean.trim_end_matches(['.', ' ']).len();
+    if trimmed < clean.len() {
+        clean.truncate(trimmed);
+        clean.push('_');
+    }
+
+    let stem = clean.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+    {
+        clean.insert(0, '_');
+    }
+
+    Some(truncate_component(&clean, MAX_COMPONENT_LEN))
+}
+
+/// Shortens `name` to at most `max` bytes, keeping a short extension.
+fn truncate_component(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+    let extension = match name.rfind('.') {
+        Some(dot) if name.len() - dot <= 16 && dot > 0 => &name[dot..],
+        _ => "",
+    };
+    let mut end = max - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+/// Sanitizes every path of a torrent, in order.
+///
+/// Paths that sanitize to nothing become `_`. When two paths end up the
+/// same, or a file would sit where another file needs a directory, the
+/// later one gets a ` (n)` suffix. Names are compared case-folded, since
+/// `Readme` and `README` are the same file on many filesystems.
+pub fn sanitize_paths(paths: &[Vec<String>]) -> Vec<PathBuf> {
+    let mut files_taken: HashSet<Vec<String>> = HashSet::new();
+    let mut dirs_taken: HashSet<Vec<String>> = HashSet::new();
+    let mut result = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        let mut clean: Vec<String> = path
+            .iter()
+            .filter_map(|component| sanitize_component(component))
+            .collect();
+        if clean.is_empty() {
+            clean.push("_".to_string());
+        }
+
+        // A directory on the way must not already be a file
+        for depth in 1..clean.len() {
+            while files_taken.contains(&folded(&clean[..depth])) {
+                clean[depth - 1] = with_suffix(&clean[depth - 1], 1);
+            }
+        }
+
+        let last = clean.len() - 1;
+        let original = clean[last].clone();
+        let mut n = 1;
+        while files_taken.contains(&folded(&clean)) || dirs_taken.contains(&folded(&clean)) {
+            clean[last] = with_suffix(&original, n);
+            n += 1;
+        }
+
+        for depth in 1..clean.len() {
+            dirs_taken.insert(folded(&clean[..depth]));
+        }
+        files_taken.insert(folded(&clean));
+        result.push(clean.iter().collect());
+    }
+    result
+}
+
+/// Case-folded components, for comparing paths.
+fn folded(path: &[String]) -> Vec<String> {
+    path.iter()
+        .map(|component| component.to_lowercase())
+        .collect()
+}
+
+/// Where a symlink `target` (relative to the torrent root) ends up once
+/// `paths` have been sanitized into `sanitized`.
+///
+/// A target naming a file, or a directory on a file's path, follows that
+/// file's collision renames. Any other target is sanitized component by
+/// component. `None` if a component sanitizes to nothing.
+pub fn sanitize_link_target(
+    paths: &[Vec<String>],
+    sanitized: &[PathBuf],
+    target: &[String],
+) -> Option<PathBuf> {
+    let clean: Vec<String> = target
+        .iter()
+        .map(|component| sanitize_component(component))
+        .collect::<Option<_>>()?;
+    // With every target component kept, the renamed path lines up with it
+    let renamed = paths
+        .iter()
+        .zip(sanitized)
+        .find(|(path, _)| path.starts_with(target));
+    match renamed {
+        Some((_, path)) => Some(path.components().take(target.len()).collect()),
+        None => Some(clean.iter().collect()),
+    }
+}
+
+/// Safe paths for every file of `torrent`, relative to the output
+/// directory, in the order of [`TorrentFile::files`].
+pub fn sanitize_torrent_paths(torrent: &TorrentFile) -> Vec<PathBuf> {
+    let name = sanitize_component(&torrent.info.name).unwrap_or_else(|| "_".to_string());
+    match &torrent.info.files {
+        TorrentFiles::Single { .. } => vec![PathBuf::from(name)],
+        TorrentFiles::Multiple { files } => {
+            let paths: Vec<Vec<String>> = files.iter().map(|file| file.path.clone()).collect();
+            sanitize_paths(&paths)
+                .into_iter()
+                .map(|path| PathBuf::from(&name).join(path))
+                .collect()
+        }
+    }
+}
+
+/// `name (n).ext`, kept within the component length limit.
+fn with_suffix(name: &str, n: usize) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    let suffix = format!(" ({}){}", n, extension);
+    format!(
+        "{}{}",
+        truncate_component(stem, MAX_COMPONENT_LEN - suffix.len()),
+        suffix
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{BencodeValue, bencode_encode, parse_torrent_file};
+    use crate::storage::Storage;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    fn string(s: &str) -> BencodeValue {
+        BencodeValue::String(s.as_bytes().to_vec())
+    }
+
+    /// Writes a multi-file .torrent with the given raw name and paths.
+    fn crafted_torrent(dir: &Path, name: &str, paths: &[&[&str]]) -> String {
+        let files = paths
+            .iter()
+            .map(|path| {
+                let mut file = HashMap::new();
+                file.insert(b"length".to_vec(), BencodeValue::Integer(1));
+                file.insert(
+                    b"path".to_vec(),
+                    BencodeValue::List(path.iter().map(|c| string(c)).collect()),
+                );
+                BencodeValue::Dictionary(file)
+            })
+            .collect();
+        let mut info = HashMap::new();
+        info.insert(b"name".to_vec(), string(name));
+        info.insert(b"piece length".to_vec(), BencodeValue::Integer(16384));
+        info.insert(b"pieces".to_vec(), BencodeValue::String(vec![0u8; 20]));
+        info.insert(b"files".to_vec(), BencodeValue::List(files));
+        let mut root = HashMap::new();
+        root.insert(b"announce".to_vec(), string("http://x/announce"));
+        root.insert(b"info".to_vec(), BencodeValue::Dictionary(info));
+
+        let path = dir.join("crafted.torrent");
+        fs::write(&path, bencode_encode(&BencodeValue::Dictionary(root))).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Every regular file below `dir`, relative to it.
+    fn files_under(dir: &Path) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.extend(
+                    files_under(&path)
+                        .into_iter()
+                        .map(|p| path.strip_prefix(dir).unwrap().join(p)),
+                );
+            } else {
+                found.push(path.strip_prefix(dir).unwrap().to_path_buf());
+            }
+        }
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn test_sanitize_component() {
+        assert_eq!(sanitize_component(".."), None);
+        assert_eq!(sanitize_component(""), None);
+        assert_eq!(sanitize_component("/etc").as_deref(), Some("_etc"));
+        assert_eq!(sanitize_component("a\0b").as_deref(), Some("a_b"));
+        assert_eq!(sanitize_component("C:").as_deref(), Some("C_"));
+        assert_eq!(sanitize_component("con.txt").as_deref(), Some("_con.txt"));
+        assert_eq!(sanitize_component("notes. ").as_deref(), Some("notes_"));
+
+        let long = format!("{}.mkv", "é".repeat(200));
+        let clean = sanitize_component(&long).unwrap();
+        assert!(clean.len() <= MAX_COMPONENT_LEN);
+        assert!(clean.ends_with(".mkv"));
+    }
+
+    #[test]
+    fn test_collisions_get_suffixes() {
+        let paths = vec![
+            vec!["a".to_string(), "x.txt".to_string()],
+            vec!["a".to_string(), "..".to_string(), "x.txt".to_string()],
+            vec!["a".to_string()],
+        ];
+        assert_eq!(
+            sanitize_paths(&paths),
+            vec![
+                PathBuf::from("a/x.txt"),
+                PathBuf::from("a/x (1).txt"),
+                PathBuf::from("a (1)"),
+            ]
+        );
+
+        let paths = vec![
+            vec!["Docs".to_string(), "README".to_string()],
+            vec!["docs".to_string(), "readme".to_string()],
+            vec!["DOCS".to_string()],
+        ];
+        assert_eq!(
+            sanitize_paths(&paths),
+            vec![
+                PathBuf::from("Docs/README"),
+                PathBuf::from("docs/readme (1)"),
+                PathBuf::from("DOCS (1)"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_link_target_follows_renames() {
+        let paths = vec![
+            vec!["a".to_string(), "x".to_string()],
+            vec!["A".to_string(), "x".to_string()],
+        ];
+        let sanitized = sanitize_paths(&paths);
+        let target = |path: &[&str]| {
+            let path: Vec<String> = path.iter().map(|c| c.to_string()).collect();
+            sanitize_link_target(&paths, &sanitized, &path)
+        };
+        assert_eq!(target(&["A", "x"]), Some(PathBuf::from("A/x (1)")));
+        assert_eq!(target(&["a"]), Some(PathBuf::from("a")));
+        assert_eq!(target(&["b:c"]), Some(PathBuf::from("b_c")));
+        assert_eq!(target(&[".."]), None);
+    }
+
+    #[test]
+    fn test_crafted_torrent_stays_in_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent_path = crafted_torrent(
+            dir.path(),
+            "../../evil",
+            &[
+                &["..", "..", "etc", "passwd"],
+                &["/tmp", "owned"],
+                &["ok\0.txt"],
+                &["", "."],
+            ],
+        );
+        let torrent = parse_torrent_file(&torrent_path).unwrap();
+
+        let out = dir.path().join("out");
+        Storage::new(&torrent, &out).unwrap();
+
+        assert_eq!(
+            files_under(dir.path().join("out").as_path()),
+            vec![
+                PathBuf::from(".._.._evil/_"),
+                PathBuf::from(".._.._evil/_tmp/owned"),
+                PathBuf::from(".._.._evil/etc/passwd"),
+                PathBuf::from(".._.._evil/ok_.txt"),
+            ]
+        );
+        // Nothing was written next to the output directory
+        let mut siblings: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        siblings.sort();
+        assert_eq!(siblings, vec!["crafted.torrent", "out"]);
+    }
+}
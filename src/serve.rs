@@ -0,0 +1,484 @@
+/// A small HTTP server exposing a torrent's files while they download, so a
+/// media player can open them by URL and seek with `Range` requests.
+use crate::sanitize;
+use crate::stream::StreamReader;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use std::io;
+use std::path::Component;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How much of a file is read (and waited for) at a time.
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Limit on the request line plus headers.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+/// Path characters left as they are in URLs.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A file of the torrent and the URL path it is served at.
+#[derive(Debug, Clone)]
+pub struct ServedFile {
+    pub index: usize,
+    /// Percent-encoded, e.g. `/Show/s01e01%20final.mkv`.
+    pub url_path: String,
+    pub length: u64,
+}
+
+/// Serves every file of a torrent at its path on disk relative to the
+/// output directory, plus an index page at `/`. A request for a range that
+/// is not downloaded yet moves the stream's playhead there and waits.
+#[derive(Debug)]
+pub struct HttpServer {
+    reader: StreamReader,
+    files: Vec<ServedFile>,
+}
+
+impl HttpServer {
+    pub fn new(reader: StreamReader) -> Self {
+        let torrent = reader.torrent();
+        let files = torrent
+            .files()
+            .iter()
+            .zip(sanitize::sanitize_torrent_paths(torrent))
+            .enumerate()
+            .filter(|(_, (file, _))| !file.attr.padding && !file.attr.symlink)
+            .map(|(index, (file, path))| ServedFile {
+                index,
+                url_path: url_path(&path),
+                length: file.length,
+            })
+            .collect();
+        HttpServer { reader, files }
+    }
+
+    pub fn files(&self) -> &[ServedFile] {
+        &self.files
+    }
+
+    /// Accepts connections until the listener fails.
+    pub async fn run(self, listener: TcpListener) -> io::Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                // The player went away or sent garbage; nothing to report
+                let _ = server.handle(socket).await;
+            });
+        }
+    }
+
+    /// Answers a single request, then closes the connection.
+    async fn handle(&self, socket: TcpStream) -> io::Result<()> {
+        let (read_half, mut write_half) = socket.into_split();
+        let request = match read_request(BufReader::new(read_half)).await? {
+            Some(request) => request,
+            None => {
+                return write_head(&mut write_half, "400 Bad Request", &[], 0).await;
+            }
+        };
+        let head_only = match request.method.as_str() {
+            "GET" => false,
+            "HEAD" => true,
+            _ => {
+                let headers = [("Allow", "GET, HEAD".to_string())];
+                return write_head(&mut write_half, "405 Method Not Allowed", &headers, 0).await;
+            }
+        };
+
+        let path = request.target.split(['?', '#']).next().unwrap_or("");
+        if path == "/" {
+            let page = self.index_page();
+            let headers = [("Content-Type", "text/html; charset=utf-8".to_string())];
+            write_head(&mut write_half, "200 OK", &headers, page.len() as u64).await?;
+            if !head_only {
+                write_half.write_all(page.as_bytes()).await?;
+            }
+            return Ok(());
+        }
+        let Some(file) = self.find(path) else {
+            return write_head(&mut write_half, "404 Not Found", &[], 0).await;
+        };
+
+        let mut headers = vec![
+            ("Content-Type", content_type(&file.url_path).to_string()),
+            ("Accept-Ranges", "bytes".to_string()),
+        ];
+        let (status, start, end) = match parse_range(request.range.as_deref(), file.length) {
+            ByteRange::Full => ("200 OK", 0, file.length),
+            ByteRange::Partial(start, end) => {
+                headers.push((
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end - 1, file.length),
+                ));
+                ("206 Partial Content", start, end)
+            }
+            ByteRange::Unsatisfiable => {
+                headers.push(("Content-Range", format!("bytes */{}", file.length)));
+                return write_head(&mut write_half, "416 Range Not Satisfiable", &headers, 0).await;
+            }
+        };
+        if head_only {
+            write_head(&mut write_half, status, &headers, end - start).await?;
+            return Ok(());
+        }
+
+        // Wait for the first chunk before answering, so a stopped download
+        // is still reported as an error rather than a truncated body
+        let mut offset = start;
+        let mut chunk = match self.read_chunk(file, offset, end).await {
+            Ok(chunk) => chunk,
+            Err(_) => {
+                return write_head(&mut write_half, "503 Service Unavailable", &[], 0).await;
+            }
+        };
+        write_head(&mut write_half, status, &headers, end - start).await?;
+        loop {
+            write_half.write_all(&chunk).await?;
+            offset += chunk.len() as u64;
+            if offset >= end {
+                break;
+            }
+            chunk = match self.read_chunk(file, offset, end).await {
+                Ok(chunk) => chunk,
+                // Headers are out; closing early is all that is left
+                Err(_) => break,
+            };
+        }
+        Ok(())
+    }
+
+    async fn read_chunk(
+        &self,
+        file: &ServedFile,
+        offset: u64,
+        end: u64,
+    ) -> Result<Vec<u8>, crate::stream::StreamError> {
+        let length = (end - offset).min(CHUNK_SIZE as u64) as usize;
+        self.reader.read(file.index, offset, length).await
+    }
+
+    fn find(&self, path: &str) -> Option<&ServedFile> {
+        let decoded = percent_decode_str(path).collect::<Vec<u8>>();
+        self.files
+            .iter()
+            .find(|file| percent_decode_str(&file.url_path).collect::<Vec<u8>>() == decoded)
+    }
+
+    fn index_page(&self) -> String {
+        let title = html_escape(&self.reader.torrent().info.name);
+        let mut page = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head>\n<body><h1>{}</h1><ul>\n",
+            title, title
+        );
+        for file in &self.files {
+            let name = percent_decode_str(&file.url_path).decode_utf8_lossy();
+            page.push_str(&format!(
+                "<li><a href=\"{}\">{}</a> ({} bytes)</li>\n",
+                file.url_path,
+                html_escape(name.trim_start_matches('/')),
+                file.length
+            ));
+        }
+        page.push_str("</ul></body></html>\n");
+        page
+    }
+}
+
+/// What a `Range` header asks for, against a file of a given length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    /// No usable range: the whole file.
+    Full,
+    /// Bytes `start..end`.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range. Multiple ranges and malformed headers
+/// are ignored, which RFC 9110 allows, and get the whole file.
+fn parse_range(header: Option<&str>, length: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|value| value.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // Suffix: the last N bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if length == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial(length.saturating_sub(suffix), length),
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let Ok(start) = first.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = if last.is_empty() {
+        length
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(length),
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= length {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    target: String,
+    range: Option<String>,
+}
+
+/// Reads the request line and headers. `None` for a malformed request, or
+/// one whose head is over [`MAX_HEADER_BYTES`].
+async fn read_request<R>(reader: BufReader<R>) -> io::Result<Option<Request>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    // Lines are cut off at the limit rather than buffered whole
+    let mut reader = reader.take(MAX_HEADER_BYTES as u64);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Ok(None);
+    };
+    if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+        return Ok(None);
+    }
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        range: None,
+    };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(Some(request));
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("range")
+        {
+            request.range = Some(value.trim().to_string());
+        }
+    }
+}
+
+async fn write_head<W>(
+    writer: &mut W,
+    status: &str,
+    headers: &[(&str, String)],
+    content_length: u64,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        content_length
+    ));
+    writer.write_all(head.as_bytes()).await
+}
+
+fn url_path(path: &std::path::Path) -> String {
+    let mut url = String::new();
+    for component in path.components() {
+        if let Component::Normal(segment) = component {
+            url.push('/');
+            url.extend(utf8_percent_encode(
+                &segment.to_string_lossy(),
+                PATH_SEGMENT,
+            ));
+        }
+    }
+    url
+}
+
+/// Guesses the MIME type players care about from the extension.
+fn content_type(path: &str) -> &'static str {
+    let extension = path
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "ts" => "video/mp2t",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "flac" => "audio/flac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "srt" => "application/x-subrip",
+        "vtt" => "text/vtt",
+        "txt" | "nfo" => "text/plain; charset=utf-8",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use crate::stream::stream;
+    use std::fs;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            parse_range(Some("bytes=0-9"), 100),
+            ByteRange::Partial(0, 10)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=90-"), 100),
+            ByteRange::Partial(90, 100)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=90-500"), 100),
+            ByteRange::Partial(90, 100)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-30"), 100),
+            ByteRange::Partial(70, 100)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-300"), 100),
+            ByteRange::Partial(0, 100)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("bytes=9-3"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 100), ByteRange::Full);
+    }
+
+    #[tokio::test]
+    async fn test_request_head_is_limited() {
+        // A request line that never ends is cut off, not buffered forever
+        let endless = BufReader::new(tokio::io::repeat(b'a'));
+        assert!(read_request(endless).await.unwrap().is_none());
+    }
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_serves_ranges_once_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("My Show");
+        fs::create_dir_all(&root).unwrap();
+        let episode: String = (0..40_000u32)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        fs::write(root.join("e01 final.mkv"), &episode).unwrap();
+        fs::write(root.join("notes.txt"), "hello").unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root,
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://t/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let path = dir.path().join("show.torrent");
+        fs::write(&path, &created.data).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+
+        let (progress, reader) = stream(&torrent, dir.path());
+        let server = HttpServer::new(reader);
+        let urls: Vec<&str> = server.files().iter().map(|f| f.url_path.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec!["/My%20Show/e01%20final.mkv", "/My%20Show/notes.txt"]
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.run(listener));
+
+        // The range is in piece 2, which is not verified yet
+        let pending = tokio::spawn(get(
+            addr,
+            "GET /My%20Show/e01%20final.mkv HTTP/1.1\r\nRange: bytes=35000-35009\r\n\r\n",
+        ));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!pending.is_finished());
+        assert_eq!(progress.playhead(), Some(2));
+        progress.piece_verified(2);
+        let response = pending.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("Content-Range: bytes 35000-35009/40000\r\n"));
+        assert!(response.contains("Content-Type: video/x-matroska\r\n"));
+        assert!(response.ends_with(&episode[35_000..35_010]));
+
+        let response = get(
+            addr,
+            "GET /My%20Show/notes.txt HTTP/1.1\r\nRange: bytes=10-\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 416 "));
+        let response = get(addr, "HEAD /My%20Show/notes.txt HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("Content-Length: 5\r\n"));
+        let response = get(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.contains("<a href=\"/My%20Show/notes.txt\">My Show/notes.txt</a>"));
+        let response = get(addr, "GET /nope HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 "));
+
+        // Once the download is gone, missing ranges fail instead of hanging
+        drop(progress);
+        let response = get(addr, "GET /My%20Show/e01%20final.mkv HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503 "));
+    }
+}
@@ -0,0 +1,1449 @@
+/// Runs many torrents in one process, sharing the peer listener, DHT,
+/// tracker client, global rate limits and a connection budget, and queueing
+/// torrents beyond the limits on active downloads and seeds.
+use crate::dht::{self, Dht};
+use crate::download::Downloader;
+use crate::magnet::{self, MagnetLink};
+use crate::metadata::{self, MetadataError};
+use crate::parser::{ParseMode, TorrentFile};
+use crate::peer_manager::{PeerClient, PeerConfig, PeerSources};
+use crate::peer_scoring::PeerScores;
+use crate::picker::PickStrategy;
+use crate::priority::{FilePriorities, FilePriority};
+use crate::rate_limit::RateLimits;
+use crate::resume::ResumeData;
+use crate::storage::Storage;
+use crate::stream::PieceProgress;
+use crate::tracker::TrackerClient;
+use crate::ui::UIEvent;
+use crate::web_seed::WebSeed;
+use crate::wire::{Handshake, Reserved};
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak, mpsc};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast};
+use tokio::task::JoinHandle;
+
+/// How often a running torrent's events are passed on.
+const EVENT_INTERVAL: Duration = Duration::from_millis(100);
+/// Events kept for subscribers that fall behind.
+const EVENT_CAPACITY: usize = 1024;
+/// How often a run waiting on its sources checks whether it was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long to wait before announcing again when no tracker answered
+/// with an interval of its own.
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(5 * 60);
+/// Peers that connected to us, waiting for their torrent's run.
+const INBOUND_CAPACITY: usize = 16;
+/// Pause after failing to accept a connection, e.g. for lack of file
+/// descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct SessionError {
+    pub message: String,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Session error: {}", self.message)
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// Settings shared by every torrent of a session.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Port announced to trackers and listened on by [`Session::listen`],
+    /// which picks a free one for 0.
+    pub port: u16,
+    pub peer_config: PeerConfig,
+    /// Limits covering every torrent, on top of each torrent's own.
+    pub rate_limits: RateLimits,
+    /// Peer connections open at once across all torrents.
+    pub max_connections: usize,
+    /// Torrents downloading at once (0 = unlimited); the rest are queued.
+    pub max_active_downloads: usize,
+    /// Complete torrents seeding at once (0 = unlimited); the rest are queued.
+    pub max_active_seeds: usize,
+    /// How strictly tracker responses are parsed.
+    pub parse_mode: ParseMode,
+    /// Join the DHT when listening, to find peers of public torrents.
+    /// Private torrents (BEP 27) never use it.
+    pub dht: bool,
+    /// `host:port` nodes DHT lookups start from.
+    pub dht_bootstrap: Vec<String>,
+    /// Stop seeding a torrent once it has uploaded this many times its size.
+    pub seed_ratio: Option<f64>,
+    /// Stop seeding a torrent once it has seeded this long in total.
+    pub seed_time: Option<Duration>,
+    /// Shortest wait between announces, whatever a tracker asks for.
+    pub min_announce_interval: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            port: 6881,
+            peer_config: PeerConfig::default(),
+            rate_limits: RateLimits::default(),
+            max_connections: 200,
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            parse_mode: ParseMode::default(),
+            dht: true,
+            dht_bootstrap: dht::DEFAULT_BOOTSTRAP
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+            seed_ratio: None,
+            seed_time: None,
+            min_announce_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How a torrent is added to a session.
+#[derive(Default)]
+pub struct AddOptions {
+    pub output_dir: PathBuf,
+    pub file_priorities: FilePriorities,
+    /// Pieces already verified; ignored unless the files still match.
+    pub resume: Option<ResumeData>,
+    /// This torrent's own limits.
+    pub rate_limits: RateLimits,
+    /// Trackers on top of the metainfo's.
+    pub extra_trackers: Vec<String>,
+    pub peer_scores: PeerScores,
+    pub strategy: PickStrategy,
+    /// First piece to fetch with [`PickStrategy::Streaming`].
+    pub playhead: Option<u32>,
+    pub stream: Option<PieceProgress>,
+    /// Add the torrent paused instead of queueing it.
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting for a download or seed slot.
+    Queued,
+    Downloading,
+    /// Complete, holding a seed slot.
+    Seeding,
+    Paused,
+    /// Complete, and done seeding: a seed limit was reached.
+    Finished,
+    /// The last run failed; resuming tries again.
+    Error(String),
+}
+
+impl std::fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TorrentState::Queued => f.write_str("queued"),
+            TorrentState::Downloading => f.write_str("downloading"),
+            TorrentState::Seeding => f.write_str("seeding"),
+            TorrentState::Paused => f.write_str("paused"),
+            TorrentState::Finished => f.write_str("finished"),
+            TorrentState::Error(message) => write!(f, "error: {}", message),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub state: TorrentState,
+    /// Verified pieces of the files not skipped, and how many there are.
+    pub completed_pieces: usize,
+    pub total_pieces: usize,
+}
+
+/// What happens in a session, for anyone following it.
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Added([u8; 20]),
+    Removed([u8; 20]),
+    StateChanged([u8; 20], TorrentState),
+    /// Progress of a running torrent, as a single-torrent UI would see it.
+    Torrent([u8; 20], UIEvent),
+}
+
+/// The torrent's downloader, built the first time the torrent starts so
+/// that queued torrents create no files.
+enum DownloaderSlot {
+    NotStarted(Box<AddOptions>),
+    Started(Box<Downloader>),
+    /// Taken by a run that has not put it back.
+    Running,
+}
+
+struct Entry {
+    torrent: Arc<TorrentFile>,
+    output_dir: PathBuf,
+    rate_limits: RateLimits,
+    /// Shared with the downloader, which picks up changes.
+    file_priorities: FilePriorities,
+    extra_trackers: Vec<String>,
+    state: TorrentState,
+    complete: bool,
+    progress: (usize, usize),
+    downloader: Arc<tokio::sync::Mutex<DownloaderSlot>>,
+    /// Stop signal of the current run.
+    stop: Arc<AtomicBool>,
+    /// Counts runs, so a superseded run does not overwrite the state.
+    run: u64,
+    task: Option<JoinHandle<()>>,
+    /// Hands peers that connected to us to the current run.
+    inbound: Option<tokio::sync::mpsc::Sender<InboundPeer>>,
+    /// Time seeded by runs that have ended, and since when the current run
+    /// has been seeding.
+    seeded: Duration,
+    seeding_since: Option<Instant>,
+}
+
+struct Inner {
+    config: SessionConfig,
+    tracker: TrackerClient,
+    connections: Arc<Semaphore>,
+    /// In queue order.
+    torrents: Mutex<Vec<Entry>>,
+    events: broadcast::Sender<SessionEvent>,
+    /// Port announced to trackers and the DHT.
+    port: AtomicU16,
+    dht: OnceLock<Dht>,
+    listener: Mutex<Option<JoinHandle<()>>>,
+    /// Removed torrents still winding down. Adding one again waits for it,
+    /// so that two runs never write the same files.
+    retiring: Mutex<HashMap<[u8; 20], JoinHandle<()>>>,
+}
+
+/// A set of torrents, each with its own state, started in the order they
+/// were added as download and seed slots allow. Clones share the session.
+///
+/// Must be used inside a Tokio runtime. Torrents find peers through
+/// trackers and web seeds and, once [`Session::listen`] is called, through
+/// the DHT and peers connecting to us. Complete torrents upload to their
+/// swarm until a seed limit is reached.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<Inner>,
+}
+
+/// What a source of a run turned up.
+#[derive(Default)]
+struct Found {
+    /// Peers to connect to, with the swarm each is in.
+    peers: Vec<(SocketAddr, [u8; 20])>,
+    /// For an announce, how long to wait before the next one.
+    reannounce: Option<Duration>,
+}
+
+/// A peer that connected to us, holding a connection from the session's
+/// budget.
+type InboundPeer = (PeerClient, OwnedSemaphorePermit);
+
+/// What a torrent run ended with.
+enum Outcome {
+    Complete,
+    Stopped,
+    SeedLimit,
+    Failed(String),
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Session {
+            inner: Arc::new(Inner {
+                connections: Arc::new(Semaphore::new(config.max_connections.max(1))),
+                tracker: TrackerClient::new().with_mode(config.parse_mode),
+                port: AtomicU16::new(config.port),
+                config,
+                torrents: Mutex::new(Vec::new()),
+                events,
+                dht: OnceLock::new(),
+                listener: Mutex::new(None),
+                retiring: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.inner.config
+    }
+
+    /// Fetches the `info` dictionary for a magnet link from its swarm, with
+    /// the session's tracker client and peer settings, asking peers from
+    /// the DHT if the link's trackers turn up none that has it.
+    pub async fn fetch_metadata(&self, link: &MagnetLink) -> Result<Vec<u8>, MetadataError> {
+        let config = &self.inner.config;
+        let port = self.inner.port.load(Ordering::Relaxed);
+        let error =
+            match metadata::fetch(link, &self.inner.tracker, &config.peer_config, port).await {
+                Ok(info) => return Ok(info),
+                Err(e) => e,
+            };
+        let (Some(dht), Some(info_hash)) = (self.inner.dht.get(), link.swarm_hash()) else {
+            return Err(error);
+        };
+        let peers = dht.get_peers(info_hash).await.peers;
+        if peers.is_empty() {
+            return Err(error);
+        }
+        let peer_id = *self.inner.tracker.get_peer_id();
+        metadata::fetch_from_peers(link, &peers, peer_id, &config.peer_config).await
+    }
+
+    /// Accepts peer connections for the running torrents on the configured
+    /// port and, unless turned off, joins the DHT on the same port. Returns
+    /// the address listened on.
+    pub async fn listen(&self) -> Result<SocketAddr, SessionError> {
+        let config = &self.inner.config;
+        let listener = TcpListener::bind(("0.0.0.0", config.port))
+            .await
+            .and_then(|listener| Ok((listener.local_addr()?, listener)));
+        let (addr, listener) = listener.map_err(|e| SessionError {
+            message: format!("Failed to listen on port {}: {}", config.port, e),
+        })?;
+        if config.dht && self.inner.dht.get().is_none() {
+            let dht_addr = SocketAddr::from(([0, 0, 0, 0], addr.port()));
+            let dht = Dht::bind(dht_addr, config.dht_bootstrap.clone())
+                .await
+                .map_err(|e| SessionError {
+                    message: format!("Failed to join the DHT: {}", e),
+                })?;
+            let _ = self.inner.dht.set(dht);
+        }
+        self.inner.port.store(addr.port(), Ordering::Relaxed);
+
+        let task = tokio::spawn(accept_peers(listener, Arc::downgrade(&self.inner)));
+        if let Some(previous) = self.inner.listener.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+        Ok(addr)
+    }
+
+    /// Follows every torrent's events from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Adds a torrent at the end of the queue and returns its info hash.
+    pub fn add(
+        &self,
+        torrent: TorrentFile,
+        mut options: AddOptions,
+    ) -> Result<[u8; 20], SessionError> {
+        let info_hash = torrent.info_hash;
+        if options.file_priorities.is_empty() {
+            // Give the torrent a list of its own so it can be edited later
+            options.file_priorities =
+                FilePriorities::new(vec![FilePriority::Normal; torrent.files().len()]);
+        }
+        {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            if torrents
+                .iter()
+                .any(|entry| entry.torrent.info_hash == info_hash)
+            {
+                return Err(SessionError {
+                    message: format!("Torrent '{}' is already in the session", torrent.info.name),
+                });
+            }
+            let state = if options.paused {
+                TorrentState::Paused
+            } else {
+                TorrentState::Queued
+            };
+            torrents.push(Entry {
+                torrent: Arc::new(torrent),
+                output_dir: options.output_dir.clone(),
+                rate_limits: options.rate_limits.clone(),
+                file_priorities: options.file_priorities.clone(),
+                extra_trackers: options.extra_trackers.clone(),
+                state,
+                complete: false,
+                progress: (0, 0),
+                downloader: Arc::new(tokio::sync::Mutex::new(DownloaderSlot::NotStarted(
+                    Box::new(options),
+                ))),
+                stop: Arc::new(AtomicBool::new(false)),
+                run: 0,
+                task: None,
+                inbound: None,
+                seeded: Duration::ZERO,
+                seeding_since: None,
+            });
+        }
+        self.emit(SessionEvent::Added(info_hash));
+        self.schedule();
+        Ok(info_hash)
+    }
+
+    /// Stops and forgets a torrent. Its files stay on disk.
+    pub fn remove(&self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        let entry = self.take(info_hash)?;
+        self.retire(entry, None);
+        Ok(())
+    }
+
+    /// Stops and forgets a torrent, then deletes its files once its
+    /// download has wound down.
+    pub async fn remove_with_data(&self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        let entry = self.take(info_hash)?;
+        let (reply, deleted) = tokio::sync::oneshot::channel();
+        self.retire(entry, Some(reply));
+        deleted.await.unwrap_or(Ok(()))
+    }
+
+    /// Waits for a removed torrent's run in the background, then deletes
+    /// its files if asked to. The torrent does not start again until then.
+    fn retire(
+        &self,
+        mut entry: Entry,
+        delete: Option<tokio::sync::oneshot::Sender<Result<(), SessionError>>>,
+    ) {
+        let info_hash = entry.torrent.info_hash;
+        let mut retiring = self.inner.retiring.lock().unwrap();
+        retiring.retain(|_, task| !task.is_finished());
+        // Removed before, re-added and removed again
+        let earlier = retiring.remove(&info_hash);
+        let task = tokio::spawn(async move {
+            if let Some(earlier) = earlier {
+                let _ = earlier.await;
+            }
+            if let Some(task) = entry.task.take() {
+                let _ = task.await;
+            }
+            if let Some(reply) = delete {
+                let result = Storage::existing(&entry.torrent, &entry.output_dir)
+                    .remove_files()
+                    .map_err(|e| SessionError {
+                        message: format!(
+                            "Failed to delete the files of '{}': {}",
+                            entry.torrent.info.name, e
+                        ),
+                    });
+                let _ = reply.send(result);
+            }
+        });
+        retiring.insert(info_hash, task);
+    }
+
+    fn take(&self, info_hash: &[u8; 20]) -> Result<Entry, SessionError> {
+        let entry = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            let index = torrents
+                .iter()
+                .position(|entry| entry.torrent.info_hash == *info_hash)
+                .ok_or_else(|| not_found(info_hash))?;
+            torrents.remove(index)
+        };
+        entry.stop.store(true, Ordering::Relaxed);
+        self.emit(SessionEvent::Removed(*info_hash));
+        self.schedule();
+        Ok(entry)
+    }
+
+    /// Pauses a torrent, freeing its slot. A running download stops after
+    /// the piece or connection attempt in progress.
+    pub fn pause(&self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        self.update(info_hash, |entry| {
+            entry.stop.store(true, Ordering::Relaxed);
+            Some(TorrentState::Paused)
+        })?;
+        self.schedule();
+        Ok(())
+    }
+
+    /// Queues a paused or failed torrent again.
+    pub fn resume(&self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        self.update(info_hash, |entry| {
+            matches!(entry.state, TorrentState::Paused | TorrentState::Error(_))
+                .then_some(TorrentState::Queued)
+        })?;
+        self.schedule();
+        Ok(())
+    }
+
+    pub fn status(&self, info_hash: &[u8; 20]) -> Option<TorrentStatus> {
+        self.with_entry(info_hash, Entry::status)
+    }
+
+    /// The torrent's metainfo.
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<Arc<TorrentFile>> {
+        self.with_entry(info_hash, |entry| entry.torrent.clone())
+    }
+
+    /// The torrent's own limits; changing their rates takes effect at once.
+    pub fn rate_limits(&self, info_hash: &[u8; 20]) -> Option<RateLimits> {
+        self.with_entry(info_hash, |entry| entry.rate_limits.clone())
+    }
+
+    /// The torrent's file priorities; changes apply from its next piece.
+    pub fn file_priorities(&self, info_hash: &[u8; 20]) -> Option<FilePriorities> {
+        self.with_entry(info_hash, |entry| entry.file_priorities.clone())
+    }
+
+    fn with_entry<T>(&self, info_hash: &[u8; 20], f: impl FnOnce(&Entry) -> T) -> Option<T> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        torrents
+            .iter()
+            .find(|entry| entry.torrent.info_hash == *info_hash)
+            .map(f)
+    }
+
+    /// Every torrent, in queue order.
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        torrents.iter().map(Entry::status).collect()
+    }
+
+    /// Stops listening and every torrent, and waits for their downloads to
+    /// wind down.
+    pub async fn shutdown(&self) {
+        if let Some(listener) = self.inner.listener.lock().unwrap().take() {
+            listener.abort();
+        }
+        let mut tasks: Vec<JoinHandle<()>> = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            torrents
+                .iter_mut()
+                .filter_map(|entry| {
+                    entry.stop.store(true, Ordering::Relaxed);
+                    entry.task.take()
+                })
+                .collect()
+        };
+        tasks.extend(
+            self.inner
+                .retiring
+                .lock()
+                .unwrap()
+                .drain()
+                .map(|(_, task)| task),
+        );
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    /// Applies `change` to a torrent; it returns the new state, if any.
+    fn update(
+        &self,
+        info_hash: &[u8; 20],
+        change: impl FnOnce(&mut Entry) -> Option<TorrentState>,
+    ) -> Result<(), SessionError> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .iter_mut()
+            .find(|entry| entry.torrent.info_hash == *info_hash)
+            .ok_or_else(|| not_found(info_hash))?;
+        if let Some(state) = change(entry) {
+            self.set_state(entry, state);
+        }
+        Ok(())
+    }
+
+    fn set_state(&self, entry: &mut Entry, state: TorrentState) {
+        if entry.state != state {
+            entry.state = state.clone();
+            self.emit(SessionEvent::StateChanged(entry.torrent.info_hash, state));
+        }
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        // Nobody listening is fine
+        let _ = self.inner.events.send(event);
+    }
+
+    /// Starts queued torrents, in order, while slots are free.
+    fn schedule(&self) {
+        let config = &self.inner.config;
+        let limit = |max: usize| if max == 0 { usize::MAX } else { max };
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let count = |state: TorrentState| torrents.iter().filter(|e| e.state == state).count();
+        let mut downloading = count(TorrentState::Downloading);
+        let mut seeding = count(TorrentState::Seeding);
+
+        for entry in torrents.iter_mut() {
+            if entry.state != TorrentState::Queued {
+                continue;
+            }
+            if entry.complete && seeding < limit(config.max_active_seeds) {
+                seeding += 1;
+                self.start(entry);
+            } else if !entry.complete && downloading < limit(config.max_active_downloads) {
+                downloading += 1;
+                self.start(entry);
+            }
+        }
+    }
+
+    /// Starts a run that downloads the torrent, or seeds it once complete.
+    fn start(&self, entry: &mut Entry) {
+        entry.run += 1;
+        entry.stop = Arc::new(AtomicBool::new(false));
+        let (sender, inbound) = tokio::sync::mpsc::channel(INBOUND_CAPACITY);
+        entry.inbound = Some(sender);
+        if entry.complete {
+            entry.seeding_since = Some(Instant::now());
+            self.set_state(entry, TorrentState::Seeding);
+        } else {
+            self.set_state(entry, TorrentState::Downloading);
+        }
+
+        let session = self.clone();
+        let info_hash = entry.torrent.info_hash;
+        let run = entry.run;
+        let retiring = self.inner.retiring.lock().unwrap().remove(&info_hash);
+        let job = Job {
+            torrent: entry.torrent.clone(),
+            output_dir: entry.output_dir.clone(),
+            rate_limits: vec![
+                self.inner.config.rate_limits.clone(),
+                entry.rate_limits.clone(),
+            ],
+            extra_trackers: entry.extra_trackers.clone(),
+            downloader: entry.downloader.clone(),
+            stop: entry.stop.clone(),
+            seeding: entry.complete,
+            seeded: entry.seeded,
+        };
+        entry.task = Some(tokio::spawn(async move {
+            // A removed copy of the torrent may still be using its files
+            if let Some(retiring) = retiring {
+                let _ = retiring.await;
+            }
+            let outcome = session.run(job, inbound).await;
+            session.finish(&info_hash, run, outcome);
+        }));
+    }
+
+    /// Records how a run ended, unless the torrent was paused, removed or
+    /// restarted in the meantime.
+    fn finish(&self, info_hash: &[u8; 20], run: u64, outcome: Outcome) {
+        let _ = self.update(info_hash, |entry| {
+            if entry.run != run {
+                return None;
+            }
+            entry.inbound = None;
+            if let Some(since) = entry.seeding_since.take() {
+                entry.seeded += since.elapsed();
+            }
+            if !matches!(
+                entry.state,
+                TorrentState::Downloading | TorrentState::Seeding
+            ) {
+                return None;
+            }
+            match outcome {
+                Outcome::Complete => {
+                    entry.complete = true;
+                    Some(TorrentState::Queued)
+                }
+                Outcome::Stopped => Some(TorrentState::Paused),
+                Outcome::SeedLimit => Some(TorrentState::Finished),
+                Outcome::Failed(message) => Some(TorrentState::Error(message)),
+            }
+        });
+        self.schedule();
+    }
+
+    /// Runs a torrent until it completes, fails, is stopped or reaches a
+    /// seed limit, passing its events on as they come.
+    async fn run(&self, job: Job, inbound: Inbound) -> Outcome {
+        let info_hash = job.torrent.info_hash;
+        let (sender, receiver) = mpsc::channel();
+        let work = self.download(job, inbound, sender);
+        tokio::pin!(work);
+        let mut interval = tokio::time::interval(EVENT_INTERVAL);
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut work => break outcome,
+                _ = interval.tick() => self.forward_events(&info_hash, &receiver),
+            }
+        };
+        self.forward_events(&info_hash, &receiver);
+        outcome
+    }
+
+    fn forward_events(&self, info_hash: &[u8; 20], receiver: &mpsc::Receiver<UIEvent>) {
+        while let Ok(event) = receiver.try_recv() {
+            if let UIEvent::PieceCompleted(_, completed, total) = event {
+                let _ = self.update(info_hash, |entry| {
+                    entry.progress = (completed, total);
+                    None
+                });
+            }
+            self.emit(SessionEvent::Torrent(*info_hash, event));
+        }
+    }
+
+    async fn download(
+        &self,
+        job: Job,
+        mut inbound: Inbound,
+        ui_sender: mpsc::Sender<UIEvent>,
+    ) -> Outcome {
+        let mut slot = job.downloader.lock().await;
+        let downloader = match std::mem::replace(&mut *slot, DownloaderSlot::Running) {
+            DownloaderSlot::Started(downloader) => *downloader,
+            DownloaderSlot::NotStarted(options) => {
+                let _ = ui_sender.send(UIEvent::TorrentParsed(Box::new((*job.torrent).clone())));
+                match build_downloader(&job, *options, &ui_sender) {
+                    Ok(downloader) => downloader,
+                    Err(message) => return Outcome::Failed(message),
+                }
+            }
+            DownloaderSlot::Running => {
+                return Outcome::Failed("Torrent state was lost by an earlier run".to_string());
+            }
+        }
+        .with_ui_sender(ui_sender.clone())
+        .with_stop_signal(job.stop.clone());
+
+        let (completed, total) = downloader.get_progress();
+        let _ = self.update(&job.torrent.info_hash, |entry| {
+            entry.progress = (completed, total);
+            None
+        });
+
+        let outcome = if job.seeding {
+            self.seed(&job, &downloader, &mut inbound, &ui_sender).await
+        } else {
+            self.fetch(&job, &downloader, &mut inbound, &ui_sender)
+                .await
+        };
+        *slot = DownloaderSlot::Started(Box::new(downloader));
+        outcome
+    }
+
+    /// Web seeds, trackers, the DHT and peers all at once, feeding one
+    /// downloader. Each peer holds a connection from the session's budget
+    /// while it is connected; the run ends as soon as the download
+    /// completes.
+    async fn fetch<'a>(
+        &'a self,
+        job: &'a Job,
+        downloader: &'a Downloader,
+        inbound: &mut Inbound,
+        ui_sender: &'a mpsc::Sender<UIEvent>,
+    ) -> Outcome {
+        let torrent = &job.torrent;
+        let stopped = || job.stop.load(Ordering::Relaxed);
+        if downloader.is_complete() {
+            let _ = ui_sender.send(UIEvent::DownloadComplete);
+            return Outcome::Complete;
+        }
+
+        let mut workers: FuturesUnordered<BoxFuture<'_, Found>> = FuturesUnordered::new();
+        let web_seeds = WebSeed::from_torrent(torrent, self.inner.tracker.http_client());
+        let have_web_seeds = !web_seeds.is_empty();
+        for seed in web_seeds {
+            workers.push(Box::pin(async move {
+                self.fetch_from_web_seed(job, downloader, seed, ui_sender)
+                    .await;
+                Found::default()
+            }));
+        }
+
+        // Announce again whenever the trackers ask us to, so peers that
+        // join later are found after the first ones leave
+        let mut announced = false;
+        let mut next_announce = Some(Instant::now());
+        let mut known_peers = Vec::new();
+        let mut poll_stop = tokio::time::interval(STOP_POLL_INTERVAL);
+        loop {
+            if next_announce.is_some_and(|at| Instant::now() >= at) {
+                next_announce = None;
+                workers.push(Box::pin(self.announce(job, ui_sender)));
+            }
+            let found = tokio::select! {
+                Some(found) = workers.next() => found,
+                Some((peer, permit)) = inbound.recv() => {
+                    workers.push(Box::pin(self.serve_inbound(job, downloader, peer, permit, ui_sender)));
+                    Found::default()
+                }
+                _ = poll_stop.tick() => Found::default(),
+            };
+            if downloader.is_complete() {
+                return Outcome::Complete;
+            }
+            if stopped() {
+                let _ = ui_sender.send(UIEvent::DownloadStopped);
+                return Outcome::Stopped;
+            }
+            if let Some(wait) = found.reannounce {
+                announced = true;
+                next_announce = Some(Instant::now() + wait);
+            }
+            for (addr, info_hash) in found.peers {
+                if known_peers.contains(&addr) || downloader.is_banned(&addr.ip()) {
+                    continue;
+                }
+                known_peers.push(addr);
+                workers.push(Box::pin(async move {
+                    self.fetch_from_peer(job, downloader, addr, info_hash, ui_sender)
+                        .await;
+                    Found::default()
+                }));
+            }
+            // Nothing to download from at all
+            if announced && known_peers.is_empty() && !have_web_seeds {
+                break;
+            }
+        }
+
+        let message = "No peers found".to_string();
+        let _ = ui_sender.send(UIEvent::Error(message.clone()));
+        Outcome::Failed(message)
+    }
+
+    /// Uploads to peers that connect to us and to those the trackers and
+    /// the DHT know of, announcing again now and then, until stopped or a
+    /// seed limit is reached.
+    async fn seed<'a>(
+        &'a self,
+        job: &'a Job,
+        downloader: &'a Downloader,
+        inbound: &mut Inbound,
+        ui_sender: &'a mpsc::Sender<UIEvent>,
+    ) -> Outcome {
+        let started = Instant::now();
+        let mut workers: FuturesUnordered<BoxFuture<'_, Found>> = FuturesUnordered::new();
+        let mut known_peers = Vec::new();
+        let mut next_announce = Some(started);
+        let mut poll_stop = tokio::time::interval(STOP_POLL_INTERVAL);
+        loop {
+            if job.stop.load(Ordering::Relaxed) {
+                return Outcome::Stopped;
+            }
+            if self.seed_limit_reached(job, downloader, started.elapsed()) {
+                return Outcome::SeedLimit;
+            }
+            if next_announce.is_some_and(|at| Instant::now() >= at) {
+                next_announce = None;
+                workers.push(Box::pin(self.announce(job, ui_sender)));
+            }
+            tokio::select! {
+                Some(found) = workers.next() => {
+                    if let Some(wait) = found.reannounce {
+                        next_announce = Some(Instant::now() + wait);
+                    }
+                    for (addr, info_hash) in found.peers {
+                        if known_peers.contains(&addr) || downloader.is_banned(&addr.ip()) {
+                            continue;
+                        }
+                        known_peers.push(addr);
+                        workers.push(Box::pin(async move {
+                            self.fetch_from_peer(job, downloader, addr, info_hash, ui_sender)
+                                .await;
+                            Found::default()
+                        }));
+                    }
+                }
+                Some((peer, permit)) = inbound.recv() => {
+                    workers.push(Box::pin(self.serve_inbound(job, downloader, peer, permit, ui_sender)));
+                }
+                _ = poll_stop.tick() => {}
+            }
+        }
+    }
+
+    /// Whether the torrent has uploaded or seeded enough, counting
+    /// `seeding` for the current run.
+    fn seed_limit_reached(&self, job: &Job, downloader: &Downloader, seeding: Duration) -> bool {
+        let config = &self.inner.config;
+        let ratio = downloader.uploaded() as f64 / job.torrent.total_size().max(1) as f64;
+        config.seed_ratio.is_some_and(|limit| ratio >= limit)
+            || config
+                .seed_time
+                .is_some_and(|limit| job.seeded + seeding >= limit)
+    }
+
+    async fn fetch_from_web_seed(
+        &self,
+        job: &Job,
+        downloader: &Downloader,
+        seed: WebSeed,
+        ui_sender: &mpsc::Sender<UIEvent>,
+    ) {
+        let _ = ui_sender.send(UIEvent::ConnectingToWebSeed(seed.url.clone()));
+        let seed = job
+            .rate_limits
+            .iter()
+            .cloned()
+            .fold(seed, WebSeed::with_rate_limits);
+        if let Err(e) = downloader.download_from_web_seed(&seed).await {
+            let _ = ui_sender.send(UIEvent::Error(format!("Web seed failed: {}", e)));
+        }
+    }
+
+    /// Announces once per swarm (hybrid torrents are in two), trying each
+    /// swarm's trackers in order until one answers and, for public
+    /// torrents, the DHT at the same time. The next announce is due after
+    /// the shortest interval the trackers that answered asked for.
+    async fn announce(&self, job: &Job, ui_sender: &mpsc::Sender<UIEvent>) -> Found {
+        let torrent = &job.torrent;
+        let sources = PeerSources::for_torrent(torrent, &job.extra_trackers);
+        let port = self.inner.port.load(Ordering::Relaxed);
+        let left = if job.seeding { 0 } else { torrent.total_size() };
+        let trackers = async {
+            let mut swarm_peers = Vec::new();
+            let mut interval: Option<Duration> = None;
+            for info_hash in torrent.swarm_hashes() {
+                for tracker in &sources.trackers {
+                    match self
+                        .inner
+                        .tracker
+                        .announce_hash(tracker, &info_hash, port, left)
+                        .await
+                    {
+                        Ok(response) => {
+                            let _ = ui_sender.send(UIEvent::TrackerResponse(response.clone()));
+                            let wait = response.interval.max(response.min_interval.unwrap_or(0));
+                            let wait = Duration::from_secs(wait.into());
+                            interval = Some(interval.map_or(wait, |interval| interval.min(wait)));
+                            for peer in response.peers {
+                                swarm_peers.push((SocketAddr::new(peer.ip, peer.port), info_hash));
+                            }
+                            break;
+                        }
+                        Err(e) => {
+                            let _ = ui_sender.send(UIEvent::Error(format!("Tracker error: {}", e)));
+                        }
+                    }
+                }
+            }
+            (swarm_peers, interval)
+        };
+        let dht = async {
+            let mut swarm_peers = Vec::new();
+            let Some(dht) = self.inner.dht.get().filter(|_| sources.dht) else {
+                return swarm_peers;
+            };
+            for info_hash in torrent.swarm_hashes() {
+                let peers = dht.announce(info_hash, port).await;
+                swarm_peers.extend(peers.into_iter().map(|addr| (addr, info_hash)));
+            }
+            swarm_peers
+        };
+        let ((mut swarm_peers, interval), dht_peers) = futures::join!(trackers, dht);
+        swarm_peers.extend(dht_peers);
+        if swarm_peers.is_empty() {
+            let _ = ui_sender.send(UIEvent::Error("No peers found".to_string()));
+        }
+        let wait = interval.unwrap_or(ANNOUNCE_RETRY);
+        Found {
+            peers: swarm_peers,
+            reannounce: Some(wait.max(self.inner.config.min_announce_interval)),
+        }
+    }
+
+    async fn fetch_from_peer(
+        &self,
+        job: &Job,
+        downloader: &Downloader,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        ui_sender: &mpsc::Sender<UIEvent>,
+    ) {
+        // Hold a connection from the session's budget for the whole peer
+        let Ok(_permit) = self.inner.connections.clone().acquire_owned().await else {
+            return;
+        };
+        if job.stop.load(Ordering::Relaxed) || (!job.seeding && downloader.is_complete()) {
+            return;
+        }
+        let _ = ui_sender.send(UIEvent::ConnectingToPeer(addr));
+        let handshake = self.handshake(&job.torrent, info_hash);
+        match PeerClient::connect_with(addr, handshake, self.inner.config.peer_config.clone()).await
+        {
+            Ok(peer_client) => {
+                let _ = ui_sender.send(UIEvent::PeerConnected(addr));
+                self.exchange(job, downloader, peer_client, ui_sender).await;
+            }
+            Err(e) => {
+                let _ = ui_sender.send(UIEvent::PeerConnectionFailed(addr, e.to_string()));
+            }
+        }
+    }
+
+    /// Runs a peer that connected to us, keeping its connection from the
+    /// session's budget until it is done.
+    async fn serve_inbound(
+        &self,
+        job: &Job,
+        downloader: &Downloader,
+        peer: PeerClient,
+        permit: OwnedSemaphorePermit,
+        ui_sender: &mpsc::Sender<UIEvent>,
+    ) -> Found {
+        let _permit = permit;
+        let _ = ui_sender.send(UIEvent::PeerConnected(peer.addr));
+        self.exchange(job, downloader, peer, ui_sender).await;
+        Found::default()
+    }
+
+    /// Downloads from the peer while uploading to it, or only uploads once
+    /// the torrent is complete.
+    async fn exchange(
+        &self,
+        job: &Job,
+        downloader: &Downloader,
+        peer: PeerClient,
+        ui_sender: &mpsc::Sender<UIEvent>,
+    ) {
+        let mut peer = job
+            .rate_limits
+            .iter()
+            .cloned()
+            .fold(peer, PeerClient::with_rate_limits);
+        let result = if job.seeding {
+            downloader.seed(&mut peer).await
+        } else {
+            downloader.download(&mut peer).await
+        };
+        if let Err(e) = result {
+            let what = if job.seeding { "Upload" } else { "Download" };
+            let _ = ui_sender.send(UIEvent::Error(format!("{} failed: {}", what, e)));
+        }
+    }
+
+    /// Hands a peer that connected to us to the run of the torrent it asks
+    /// for, if one is running and there is a connection to spare.
+    async fn accept_peer(&self, stream: TcpStream, addr: SocketAddr) {
+        let Ok(permit) = self.inner.connections.clone().try_acquire_owned() else {
+            return;
+        };
+        let mut inbound = None;
+        let config = self.inner.config.peer_config.clone();
+        let peer = PeerClient::accept(stream, addr, config, |handshake| {
+            let (sender, ours) = self.route(handshake)?;
+            inbound = Some(sender);
+            Some(ours)
+        })
+        .await;
+        if let (Ok(peer), Some(inbound)) = (peer, inbound) {
+            // A run with a full backlog turns the peer away
+            let _ = inbound.try_send((peer, permit));
+        }
+    }
+
+    /// Where to send a peer with this handshake, and our handshake for it.
+    fn route(
+        &self,
+        handshake: &Handshake,
+    ) -> Option<(tokio::sync::mpsc::Sender<InboundPeer>, Handshake)> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        let entry = torrents
+            .iter()
+            .find(|entry| entry.torrent.swarm_hashes().contains(&handshake.info_hash))?;
+        let sender = entry.inbound.clone()?;
+        Some((sender, self.handshake(&entry.torrent, handshake.info_hash)))
+    }
+
+    fn handshake(&self, torrent: &TorrentFile, info_hash: [u8; 20]) -> Handshake {
+        let mut reserved = Reserved::default();
+        if torrent.info.is_v2() {
+            reserved = reserved.with_v2();
+        }
+        Handshake::new(info_hash, *self.inner.tracker.get_peer_id()).with_reserved(reserved)
+    }
+}
+
+/// Peers that connected to us for the torrent of a run.
+type Inbound = tokio::sync::mpsc::Receiver<InboundPeer>;
+
+/// Accepts peer connections until the session is gone.
+async fn accept_peers(listener: TcpListener, inner: Weak<Inner>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => {
+                tokio::time::sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        let session = Session { inner };
+        tokio::spawn(async move { session.accept_peer(stream, addr).await });
+    }
+}
+
+/// What one run of a torrent needs from its entry.
+struct Job {
+    torrent: Arc<TorrentFile>,
+    output_dir: PathBuf,
+    /// Session limits first, then the torrent's own.
+    rate_limits: Vec<RateLimits>,
+    extra_trackers: Vec<String>,
+    downloader: Arc<tokio::sync::Mutex<DownloaderSlot>>,
+    stop: Arc<AtomicBool>,
+    /// Whether the torrent is complete and only uploads.
+    seeding: bool,
+    /// Time seeded by earlier runs.
+    seeded: Duration,
+}
+
+fn build_downloader(
+    job: &Job,
+    options: AddOptions,
+    ui_sender: &mpsc::Sender<UIEvent>,
+) -> Result<Downloader, String> {
+    // Check the resume data before the downloader preallocates anything
+    let resume = options.resume.filter(|data| {
+        let fresh = data.matches(&job.torrent, &job.output_dir);
+        if !fresh {
+            let _ = ui_sender.send(UIEvent::Error("Ignoring stale resume data".to_string()));
+        }
+        fresh
+    });
+
+    let downloader = Downloader::new_with_priorities(
+        (*job.torrent).clone(),
+        &job.output_dir,
+        options.file_priorities,
+    )
+    .map_err(|e| format!("Failed to create downloader: {}", e))?
+    .with_peer_scores(options.peer_scores)
+    .with_strategy(options.strategy);
+    let downloader = match resume {
+        Some(ref data) => downloader.with_resume(data),
+        None => downloader,
+    };
+    let downloader = match options.playhead {
+        Some(piece) => downloader.with_playhead(piece),
+        None => downloader,
+    };
+    Ok(match options.stream {
+        Some(progress) => downloader.with_stream(progress),
+        None => downloader,
+    })
+}
+
+fn not_found(info_hash: &[u8; 20]) -> SessionError {
+    SessionError {
+        message: format!(
+            "No torrent {} in the session",
+            magnet::hex_encode(info_hash)
+        ),
+    }
+}
+
+impl Entry {
+    fn status(&self) -> TorrentStatus {
+        TorrentStatus {
+            info_hash: self.torrent.info_hash,
+            name: self.torrent.info.name.clone(),
+            state: self.state.clone(),
+            completed_pieces: self.progress.0,
+            total_pieces: self.progress.1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use crate::verify::verify_torrent;
+    use std::fs;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A one-file torrent named `name` whose tracker is `tracker`.
+    fn make_torrent(dir: &Path, name: &str, tracker: &str) -> TorrentFile {
+        let data = dir.join(name);
+        fs::write(&data, name.repeat(1000)).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: data,
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec![tracker.to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let path = dir.join(format!("{}.torrent", name));
+        fs::write(&path, &created.data).unwrap();
+        parse_torrent_file(path.to_str().unwrap()).unwrap()
+    }
+
+    fn states(session: &Session) -> Vec<TorrentState> {
+        session.torrents().into_iter().map(|t| t.state).collect()
+    }
+
+    /// A tracker that answers every announce with `peer`.
+    async fn serve_tracker(peer: SocketAddr) -> String {
+        serve_tracker_in_turn(vec![peer], 1800).await
+    }
+
+    /// A tracker that answers the nth announce with the nth of `peers`,
+    /// and every later one with the last, asking for the next announce
+    /// in `interval` seconds.
+    async fn serve_tracker_in_turn(peers: Vec<SocketAddr>, interval: u32) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let bodies: Vec<Vec<u8>> = peers
+            .iter()
+            .map(|peer| {
+                let SocketAddr::V4(peer) = peer else {
+                    panic!("not an IPv4 peer");
+                };
+                let mut body = format!("d8:intervali{}e5:peers6:", interval).into_bytes();
+                body.extend(peer.ip().octets());
+                body.extend(peer.port().to_be_bytes());
+                body.push(b'e');
+                body
+            })
+            .collect();
+        tokio::spawn(async move {
+            for turn in 0.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let body = bodies[turn.min(bodies.len() - 1)].clone();
+                tokio::spawn(async move {
+                    let _ = stream.read(&mut [0; 4096]).await;
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        url
+    }
+
+    /// Waits for the session's only torrent to reach `state`.
+    async fn wait_for(session: &Session, state: TorrentState) {
+        let mut events = session.subscribe();
+        let wait = async {
+            while states(session) != [state.clone()] {
+                let _ = events.recv().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap_or_else(|_| panic!("never reached {}: {:?}", state, states(session)));
+    }
+
+    #[tokio::test]
+    async fn test_downloads_are_queued() {
+        // A tracker that accepts and never answers keeps downloads running
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(SessionConfig {
+            max_active_downloads: 1,
+            ..Default::default()
+        });
+        let options = || AddOptions {
+            output_dir: dir.path().join("out"),
+            ..Default::default()
+        };
+
+        let first = session
+            .add(make_torrent(dir.path(), "first", &tracker), options())
+            .unwrap();
+        let second = session
+            .add(make_torrent(dir.path(), "second", &tracker), options())
+            .unwrap();
+        assert!(
+            session
+                .add(make_torrent(dir.path(), "first", &tracker), options())
+                .is_err()
+        );
+        assert_eq!(
+            states(&session),
+            vec![TorrentState::Downloading, TorrentState::Queued]
+        );
+
+        session.pause(&first).unwrap();
+        assert_eq!(
+            states(&session),
+            vec![TorrentState::Paused, TorrentState::Downloading]
+        );
+        session.resume(&first).unwrap();
+        session.remove(&second).unwrap();
+        assert_eq!(states(&session), vec![TorrentState::Downloading]);
+        assert!(session.pause(&second).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_complete_torrents_take_seed_slots() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(SessionConfig {
+            max_active_downloads: 1,
+            max_active_seeds: 1,
+            ..Default::default()
+        });
+        let mut events = session.subscribe();
+
+        // Both torrents' data is already in place and verified
+        let mut hashes = Vec::new();
+        for name in ["one", "two"] {
+            let torrent = make_torrent(dir.path(), name, "http://127.0.0.1:1/announce");
+            let report = verify_torrent(&torrent, dir.path(), 1);
+            let options = AddOptions {
+                output_dir: dir.path().to_path_buf(),
+                resume: Some(ResumeData::from_report(&torrent, dir.path(), &report)),
+                ..Default::default()
+            };
+            hashes.push(session.add(torrent, options).unwrap());
+        }
+        let wait = tokio::time::timeout(Duration::from_secs(10), async {
+            while states(&session) != [TorrentState::Seeding, TorrentState::Queued] {
+                events.recv().await.unwrap();
+            }
+        });
+        wait.await.expect("torrents never settled");
+        let status = session.status(&hashes[0]).unwrap();
+        assert_eq!(status.completed_pieces, status.total_pieces);
+
+        // Pausing the seed hands its slot to the next torrent
+        session.pause(&hashes[0]).unwrap();
+        assert_eq!(
+            states(&session),
+            vec![TorrentState::Paused, TorrentState::Seeding]
+        );
+        session.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_seeds_to_peers_until_ratio() {
+        let config = SessionConfig {
+            port: 0,
+            dht: false,
+            seed_ratio: Some(1.0),
+            ..Default::default()
+        };
+        let seeder = Session::new(config.clone());
+        let port = seeder.listen().await.unwrap().port();
+        let tracker = serve_tracker(SocketAddr::from(([127, 0, 0, 1], port))).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = make_torrent(dir.path(), "shared", &tracker);
+        let report = verify_torrent(&torrent, dir.path(), 1);
+        let options = AddOptions {
+            output_dir: dir.path().to_path_buf(),
+            resume: Some(ResumeData::from_report(&torrent, dir.path(), &report)),
+            ..Default::default()
+        };
+        seeder.add(torrent.clone(), options).unwrap();
+        wait_for(&seeder, TorrentState::Seeding).await;
+
+        // The leecher finds the seeder through the tracker and downloads
+        // everything from it, which is the seeder's whole ratio
+        let leecher = Session::new(config);
+        let out = tempfile::tempdir().unwrap();
+        let options = AddOptions {
+            output_dir: out.path().to_path_buf(),
+            ..Default::default()
+        };
+        leecher.add(torrent, options).unwrap();
+        wait_for(&leecher, TorrentState::Seeding).await;
+        assert_eq!(
+            fs::read(out.path().join("shared")).unwrap(),
+            fs::read(dir.path().join("shared")).unwrap()
+        );
+        wait_for(&seeder, TorrentState::Finished).await;
+
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_reannounces_after_peers_leave() {
+        let config = SessionConfig {
+            port: 0,
+            dht: false,
+            min_announce_interval: Duration::ZERO,
+            ..Default::default()
+        };
+        let seeder = Session::new(config.clone());
+        let port = seeder.listen().await.unwrap().port();
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = make_torrent(dir.path(), "later", "http://127.0.0.1:1/announce");
+        let report = verify_torrent(&torrent, dir.path(), 1);
+        let options = AddOptions {
+            output_dir: dir.path().to_path_buf(),
+            resume: Some(ResumeData::from_report(&torrent, dir.path(), &report)),
+            ..Default::default()
+        };
+        seeder.add(torrent, options).unwrap();
+        wait_for(&seeder, TorrentState::Seeding).await;
+
+        // The first announce only finds a peer that is already gone; the
+        // seeder turns up on the next one
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let seeder_addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let tracker = serve_tracker_in_turn(vec![gone, seeder_addr], 1).await;
+        let other = tempfile::tempdir().unwrap();
+        let torrent = make_torrent(other.path(), "later", &tracker);
+        let leecher = Session::new(config);
+        let out = tempfile::tempdir().unwrap();
+        let options = AddOptions {
+            output_dir: out.path().to_path_buf(),
+            ..Default::default()
+        };
+        leecher.add(torrent, options).unwrap();
+        wait_for(&leecher, TorrentState::Seeding).await;
+        assert_eq!(
+            fs::read(out.path().join("later")).unwrap(),
+            fs::read(dir.path().join("later")).unwrap()
+        );
+
+        leecher.shutdown().await;
+        seeder.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_readded_torrent_waits_for_removed_run() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker = format!("http://{}/announce", listener.local_addr().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let session = Session::new(SessionConfig::default());
+        let torrent = make_torrent(dir.path(), "again", &tracker);
+        let options = || AddOptions {
+            output_dir: dir.path().join("out"),
+            ..Default::default()
+        };
+
+        let info_hash = session.add(torrent.clone(), options()).unwrap();
+        session.remove(&info_hash).unwrap();
+        assert!(
+            session
+                .inner
+                .retiring
+                .lock()
+                .unwrap()
+                .contains_key(&info_hash)
+        );
+
+        // The new run takes over the wait for the old one
+        session.add(torrent, options()).unwrap();
+        assert!(session.inner.retiring.lock().unwrap().is_empty());
+        assert_eq!(states(&session), vec![TorrentState::Downloading]);
+        tokio::time::timeout(Duration::from_secs(5), session.shutdown())
+            .await
+            .expect("runs never wound down");
+    }
+}
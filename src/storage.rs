@@ -0,0 +1,695 @@
+/// Writes verified pieces to the torrent's files on disk.
+use crate::magnet;
+use crate::parser::{TorrentFile, TorrentFileInfo};
+use crate::sanitize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct StorageError {
+    pub message: String,
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Storage error: {}", self.message)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        StorageError {
+            message: format!("IO error: {}", err),
+        }
+    }
+}
+
+/// The files of one torrent under an output directory.
+///
+/// Padding files (BEP 47) are never created or written; their bytes only
+/// exist inside pieces. Executable files get their mode bits when created,
+/// and symlinks are made by [`Storage::finalize`] once the download is done.
+///
+/// Skipped files are not created either. The parts of them that share a
+/// piece with a wanted file go to a hidden partfile in the output
+/// directory instead. The partfile is a list of slots, each holding one
+/// piece-sized chunk of a skipped file after a header naming the file and
+/// chunk, so it only grows by the chunks actually written.
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<TorrentFileInfo>,
+    /// Sanitized location of each file, under the output directory.
+    paths: Vec<PathBuf>,
+    /// The same paths relative to the output directory.
+    relative: Vec<PathBuf>,
+    partfile: PathBuf,
+    /// Size of the chunks the partfile holds.
+    chunk_size: u64,
+    /// Slot number of each `(file index, chunk)` in the partfile.
+    slots: HashMap<(usize, u64), u64>,
+    /// Slots in the partfile, including those of files moved out of it.
+    next_slot: u64,
+    /// Files whose data lives in the partfile.
+    in_partfile: Vec<bool>,
+}
+
+/// Bytes before each slot's data: the file index (4 bytes) and the chunk
+/// number (8 bytes), big-endian.
+const SLOT_HEADER: u64 = 12;
+
+impl Storage {
+    /// Creates the directory layout and preallocates every regular file.
+    ///
+    /// Paths from the torrent are sanitized first, so nothing is created
+    /// outside `output_dir`.
+    pub fn new(torrent: &TorrentFile, output_dir: &Path) -> Result<Self, StorageError> {
+        Storage::new_skipping(torrent, output_dir, &[])
+    }
+
+    /// Like [`Storage::new`], but files marked in `skipped` are left out
+    /// and their data goes to the partfile. Skipped files that are already
+    /// on disk are used as they are.
+    pub fn new_skipping(
+        torrent: &TorrentFile,
+        output_dir: &Path,
+        skipped: &[bool],
+    ) -> Result<Self, StorageError> {
+        let mut storage = Storage::existing(torrent, output_dir);
+        for index in 0..storage.files.len() {
+            if skipped.get(index).copied().unwrap_or(false) && !storage.paths[index].exists() {
+                storage.in_partfile[index] = true;
+            } else if storage.in_partfile[index] {
+                storage.unskip(index)?;
+            } else {
+                storage.create_file(index)?;
+            }
+        }
+
+        Ok(storage)
+    }
+
+    /// The torrent's files under `output_dir`, without creating anything.
+    /// For data that is already on disk. Files that are not on disk but
+    /// have chunks in the partfile are read from there.
+    pub fn existing(torrent: &TorrentFile, output_dir: &Path) -> Self {
+        let relative = sanitize::sanitize_torrent_paths(torrent);
+        let files = torrent.files();
+        let mut storage = Storage {
+            in_partfile: vec![false; files.len()],
+            files,
+            paths: relative.iter().map(|path| output_dir.join(path)).collect(),
+            relative,
+            partfile: output_dir.join(format!(".{}.parts", magnet::hex_encode(&torrent.info_hash))),
+            chunk_size: (torrent.info.piece_length as u64).max(1),
+            slots: HashMap::new(),
+            next_slot: 0,
+        };
+        // An unreadable partfile is as good as none: its pieces are missing
+        let _ = storage.load_slots();
+        storage
+    }
+
+    /// Reads the slot headers of the partfile.
+    fn load_slots(&mut self) -> io::Result<()> {
+        let mut parts = File::open(&self.partfile)?;
+        let slot_size = SLOT_HEADER + self.chunk_size;
+        let count = parts.metadata()?.len().div_ceil(slot_size);
+        let mut header = [0u8; SLOT_HEADER as usize];
+        for slot in 0..count {
+            parts.seek(SeekFrom::Start(slot * slot_size))?;
+            if parts.read_exact(&mut header).is_err() {
+                break;
+            }
+            self.next_slot = slot + 1;
+            let index = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+            let chunk = u64::from_be_bytes(header[4..].try_into().unwrap());
+            if index < self.files.len() && !self.paths[index].exists() {
+                self.in_partfile[index] = true;
+                self.slots.insert((index, chunk), slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the data of `chunk` of file `index` starts in the partfile,
+    /// if it has been written.
+    fn slot_offset(&self, index: usize, chunk: u64) -> Option<u64> {
+        let slot = self.slots.get(&(index, chunk))?;
+        Some(slot * (SLOT_HEADER + self.chunk_size) + SLOT_HEADER)
+    }
+
+    /// Like [`Storage::slot_offset`], adding a slot if there is none yet.
+    fn slot_offset_or_add(
+        &mut self,
+        parts: &mut File,
+        index: usize,
+        chunk: u64,
+    ) -> io::Result<u64> {
+        if let Some(offset) = self.slot_offset(index, chunk) {
+            return Ok(offset);
+        }
+        let slot = self.next_slot;
+        parts.seek(SeekFrom::Start(slot * (SLOT_HEADER + self.chunk_size)))?;
+        parts.write_all(&(index as u32).to_be_bytes())?;
+        parts.write_all(&chunk.to_be_bytes())?;
+        self.next_slot += 1;
+        self.slots.insert((index, chunk), slot);
+        Ok(self.slot_offset(index, chunk).unwrap())
+    }
+
+    /// Creates and preallocates a regular file.
+    fn create_file(&self, index: usize) -> Result<(), StorageError> {
+        let (file, path) = (&self.files[index], &self.paths[index]);
+        if file.attr.padding || file.attr.symlink {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let handle = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        handle.set_len(file.length)?;
+        if file.attr.executable {
+            set_executable(path)?;
+        }
+        Ok(())
+    }
+
+    /// Whether file `index` is kept in the partfile rather than on disk.
+    pub fn is_in_partfile(&self, index: usize) -> bool {
+        self.in_partfile[index]
+    }
+
+    /// Creates a file that was skipped, moving what the partfile holds for
+    /// it into place. The partfile is removed once no file needs it.
+    /// Files already on disk stay there even when skipped later.
+    pub fn unskip(&mut self, index: usize) -> Result<(), StorageError> {
+        if !self.in_partfile[index] {
+            return Ok(());
+        }
+        self.create_file(index)?;
+        let mut chunks: Vec<u64> = self
+            .slots
+            .keys()
+            .filter(|&&(file, _)| file == index)
+            .map(|&(_, chunk)| chunk)
+            .collect();
+        chunks.sort_unstable();
+        if !chunks.is_empty() {
+            let mut parts = File::open(&self.partfile)?;
+            let mut handle = OpenOptions::new().write(true).open(&self.paths[index])?;
+            let length = self.files[index].length;
+            for chunk in chunks {
+                let start = chunk * self.chunk_size;
+                let offset = self.slot_offset(index, chunk).unwrap();
+                parts.seek(SeekFrom::Start(offset))?;
+                handle.seek(SeekFrom::Start(start))?;
+                // The last slot may end before the chunk does
+                let wanted = self.chunk_size.min(length.saturating_sub(start));
+                io::copy(&mut (&mut parts).take(wanted), &mut handle)?;
+                self.slots.remove(&(index, chunk));
+            }
+        }
+        self.in_partfile[index] = false;
+
+        if !self.in_partfile.contains(&true) && self.partfile.exists() {
+            fs::remove_file(&self.partfile)?;
+            self.slots.clear();
+            self.next_slot = 0;
+        }
+        Ok(())
+    }
+
+    /// Where file `index` is read from to check that it is unchanged: the
+    /// file itself, or the partfile while the file is kept there.
+    pub fn data_path(&self, index: usize) -> &Path {
+        if self.in_partfile[index] {
+            &self.partfile
+        } else {
+            &self.paths[index]
+        }
+    }
+
+    /// The chunks of file `index` covering `length` bytes at `offset`, as
+    /// `(chunk, offset in chunk, length, offset in the range)`.
+    fn chunks(&self, offset: u64, length: u64) -> impl Iterator<Item = (u64, u64, u64, usize)> {
+        let chunk_size = self.chunk_size;
+        let mut position = offset;
+        let end = offset + length;
+        std::iter::from_fn(move || {
+            if position >= end {
+                return None;
+            }
+            let (chunk, within) = (position / chunk_size, position % chunk_size);
+            let length = (chunk_size - within).min(end - position);
+            let item = (chunk, within, length, (position - offset) as usize);
+            position += length;
+            Some(item)
+        })
+    }
+
+    /// Where file `index` of the torrent lives on disk.
+    pub fn path(&self, index: usize) -> &Path {
+        &self.paths[index]
+    }
+
+    /// Where file `index` lives, relative to the output directory.
+    pub fn relative_path(&self, index: usize) -> &Path {
+        &self.relative[index]
+    }
+
+    /// Reads a piece back from disk. Padding reads as zeros.
+    pub fn read_piece(
+        &self,
+        torrent: &TorrentFile,
+        piece_index: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut data = Vec::with_capacity(torrent.piece_size(piece_index) as usize);
+        for span in torrent.piece_spans(piece_index) {
+            let start = data.len();
+            data.resize(start + span.length as usize, 0);
+            self.read_into(span.file_index, span.offset, &mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Reads `length` bytes at `begin` in a piece, e.g. to answer a peer's
+    /// request. Padding reads as zeros.
+    pub fn read_block(
+        &self,
+        torrent: &TorrentFile,
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<Vec<u8>, StorageError> {
+        let (begin, end) = (begin as u64, begin as u64 + length as u64);
+        if end > torrent.piece_size(piece_index) as u64 {
+            return Err(StorageError {
+                message: format!(
+                    "Block {}+{} is past the end of piece {}",
+                    begin, length, piece_index
+                ),
+            });
+        }
+        let mut data = vec![0; length as usize];
+        let mut position = 0u64;
+        for span in torrent.piece_spans(piece_index) {
+            let (start, stop) = (position.max(begin), (position + span.length).min(end));
+            if start < stop {
+                let buffer = &mut data[(start - begin) as usize..(stop - begin) as usize];
+                self.read_into(span.file_index, span.offset + (start - position), buffer)?;
+            }
+            position += span.length;
+        }
+        Ok(data)
+    }
+
+    /// Reads `length` bytes at `offset` in file `index`. Padding reads as
+    /// zeros.
+    pub fn read_file(
+        &self,
+        index: usize,
+        offset: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, StorageError> {
+        let mut data = vec![0; length];
+        self.read_into(index, offset, &mut data)?;
+        Ok(data)
+    }
+
+    fn read_into(&self, index: usize, offset: u64, buffer: &mut [u8]) -> Result<(), StorageError> {
+        let file = &self.files[index];
+        if file.attr.padding || file.attr.symlink || buffer.is_empty() {
+            return Ok(());
+        }
+        if !self.in_partfile[index] {
+            let mut handle = File::open(&self.paths[index])?;
+            handle.seek(SeekFrom::Start(offset))?;
+            handle.read_exact(buffer)?;
+            return Ok(());
+        }
+        let mut parts = File::open(&self.partfile)?;
+        for (chunk, within, length, position) in self.chunks(offset, buffer.len() as u64) {
+            let start = self.slot_offset(index, chunk).ok_or_else(|| StorageError {
+                message: format!("Chunk {} of file {} is not in the partfile", chunk, index),
+            })?;
+            parts.seek(SeekFrom::Start(start + within))?;
+            parts.read_exact(&mut buffer[position..position + length as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Writes a verified piece into the files it covers, skipping padding.
+    pub fn write_piece(
+        &mut self,
+        torrent: &TorrentFile,
+        piece_index: u32,
+        data: &[u8],
+    ) -> Result<(), StorageError> {
+        let mut position = 0usize;
+        for span in torrent.piece_spans(piece_index) {
+            let length = span.length as usize;
+            let chunk = data
+                .get(position..position + length)
+                .ok_or_else(|| StorageError {
+                    message: format!("Piece {} is shorter than its files", piece_index),
+                })?;
+            position += length;
+
+            let file = &self.files[span.file_index];
+            if file.attr.padding || file.attr.symlink || chunk.is_empty() {
+                continue;
+            }
+            if self.in_partfile[span.file_index] {
+                self.write_partfile(span.file_index, span.offset, chunk)?;
+            } else {
+                let mut handle = OpenOptions::new()
+                    .write(true)
+                    .open(&self.paths[span.file_index])?;
+                handle.seek(SeekFrom::Start(span.offset))?;
+                handle.write_all(chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_partfile(&mut self, index: usize, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut parts = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.partfile)?;
+        let chunks: Vec<_> = self.chunks(offset, data.len() as u64).collect();
+        for (chunk, within, length, position) in chunks {
+            let start = self.slot_offset_or_add(&mut parts, index, chunk)?;
+            parts.seek(SeekFrom::Start(start + within))?;
+            parts.write_all(&data[position..position + length as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the torrent's files and partfile, then the directories they
+    /// leave empty. Files that are already gone are fine.
+    pub fn remove_files(&self) -> Result<(), StorageError> {
+        let remove = |path: &Path| match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        for (file, path) in self.files.iter().zip(&self.paths) {
+            if !file.attr.padding {
+                remove(path)?;
+            }
+        }
+        remove(&self.partfile)?;
+
+        for (path, relative) in self.paths.iter().zip(&self.relative) {
+            // Stop at the output directory or the first directory in use
+            let depth = relative.components().count().saturating_sub(1);
+            for dir in path.ancestors().skip(1).take(depth) {
+                if fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the torrent's symlinks. Targets must stay inside the torrent
+    /// directory; anything else is refused. Targets follow the renames
+    /// sanitizing made to the files they point at.
+    pub fn finalize(&self) -> Result<(), StorageError> {
+        // Paths below the torrent root, before and after sanitizing
+        let paths: Vec<Vec<String>> = self
+            .files
+            .iter()
+            .map(|file| file.path.iter().skip(1).cloned().collect())
+            .collect();
+        let inside: Vec<PathBuf> = self
+            .relative
+            .iter()
+            .map(|path| path.components().skip(1).collect())
+            .collect();
+        for ((file, path), relative) in self.files.iter().zip(&self.paths).zip(&self.relative) {
+            let Some(ref target) = file.symlink_path else {
+                continue;
+            };
+            if !file.attr.symlink {
+                continue;
+            }
+            let link =
+                relative_link_target(relative, target, &paths, &inside).ok_or_else(|| {
+                    StorageError {
+                        message: format!(
+                            "Refusing symlink '{}' -> '{}'",
+                            path.display(),
+                            target.join("/")
+                        ),
+                    }
+                })?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::symlink_metadata(path).is_ok() {
+                fs::remove_file(path)?;
+            }
+            create_symlink(&link, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns a BEP 47 `symlink path` (relative to the torrent root) into a link
+/// target relative to the link itself. `link_path` is the link's sanitized
+/// path below the output directory, starting with the torrent name;
+/// `paths` and `sanitized` are every file's path below the torrent root
+/// before and after sanitizing.
+///
+/// Target components are sanitized like file paths; a target that would
+/// need `..` or an absolute path to resolve is refused.
+fn relative_link_target(
+    link_path: &Path,
+    target: &[String],
+    paths: &[Vec<String>],
+    sanitized: &[PathBuf],
+) -> Option<PathBuf> {
+    let unsafe_component =
+        |c: &String| c.is_empty() || c == "." || c == ".." || c.contains(['/', '\\', '\0']);
+    if target.is_empty() || target.iter().any(unsafe_component) {
+        return None;
+    }
+
+    // Directories between the torrent root and the link
+    let depth = link_path.components().count().saturating_sub(2);
+    let mut link = PathBuf::new();
+    for _ in 0..depth {
+        link.push("..");
+    }
+    link.push(sanitize::sanitize_link_target(paths, sanitized, target)?);
+    Some(link)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    // Executable wherever it is readable
+    let mode = permissions.mode();
+    permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{
+        BencodeValue, FileAttributes, TorrentFiles, TorrentInfo, bencode_encode, parse_torrent_file,
+    };
+    use std::collections::HashMap;
+
+    fn file(path: &[&str], length: u64, attr: &str) -> TorrentFileInfo {
+        TorrentFileInfo {
+            path: path.iter().map(|c| c.to_string()).collect(),
+            length,
+            attr: FileAttributes::parse(attr),
+            ..Default::default()
+        }
+    }
+
+    /// Writes and parses a multi-file torrent. Piece hashes are dummies;
+    /// storage never checks them.
+    fn torrent(dir: &Path, files: Vec<TorrentFileInfo>, piece_length: u32) -> TorrentFile {
+        let total: u64 = files.iter().map(|f| f.length).sum();
+        let info = TorrentInfo {
+            name: "bundle".to_string(),
+            raw_name: None,
+            name_utf8: None,
+            piece_length,
+            pieces: vec![[0u8; 20]; total.div_ceil(piece_length as u64) as usize],
+            files: TorrentFiles::Multiple { files },
+            meta_version: None,
+            file_tree: Vec::new(),
+            private: false,
+        };
+        let mut root = HashMap::new();
+        root.insert(
+            b"announce".to_vec(),
+            BencodeValue::String(b"http://x/announce".to_vec()),
+        );
+        root.insert(b"info".to_vec(), info.to_bencode());
+        let path = dir.join("bundle.torrent");
+        fs::write(&path, bencode_encode(&BencodeValue::Dictionary(root))).unwrap();
+        parse_torrent_file(path.to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_padding_is_never_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrent(
+            dir.path(),
+            vec![
+                file(&["a.bin"], 10, ""),
+                file(&[".pad", "6"], 6, "p"),
+                file(&["b.bin"], 5, ""),
+            ],
+            16,
+        );
+        let out = dir.path().join("out");
+        let mut storage = Storage::new(&torrent, &out).unwrap();
+
+        let mut piece = vec![b'a'; 10];
+        piece.extend([0u8; 6]);
+        storage.write_piece(&torrent, 0, &piece).unwrap();
+        storage.write_piece(&torrent, 1, b"bbbbb").unwrap();
+
+        assert_eq!(fs::read(out.join("bundle/a.bin")).unwrap(), vec![b'a'; 10]);
+        assert_eq!(fs::read(out.join("bundle/b.bin")).unwrap(), b"bbbbb");
+        assert!(!out.join("bundle/.pad").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_executable_bits_and_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut link = file(&["bin", "latest"], 0, "l");
+        link.symlink_path = Some(vec!["bin".to_string(), "tool".to_string()]);
+        let torrent = torrent(dir.path(), vec![file(&["bin", "tool"], 4, "x"), link], 16);
+        assert!(torrent.files()[0].attr.executable);
+
+        let out = dir.path().join("out");
+        let mut storage = Storage::new(&torrent, &out).unwrap();
+        storage.write_piece(&torrent, 0, b"tool").unwrap();
+        storage.finalize().unwrap();
+
+        let tool = out.join("bundle/bin/tool");
+        assert_ne!(fs::metadata(&tool).unwrap().permissions().mode() & 0o111, 0);
+        let latest = out.join("bundle/bin/latest");
+        assert_eq!(fs::read_link(&latest).unwrap(), Path::new("../bin/tool"));
+        assert_eq!(fs::read(&latest).unwrap(), b"tool");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_follows_collision_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut link = file(&["link"], 0, "l");
+        link.symlink_path = Some(vec!["Tool".to_string()]);
+        let torrent = torrent(
+            dir.path(),
+            vec![file(&["tool"], 2, ""), file(&["Tool"], 2, ""), link],
+            16,
+        );
+
+        let out = dir.path().join("out");
+        let mut storage = Storage::new(&torrent, &out).unwrap();
+        storage.write_piece(&torrent, 0, b"abAB").unwrap();
+        storage.finalize().unwrap();
+
+        let link = out.join("bundle/link");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("Tool (1)"));
+        assert_eq!(fs::read(&link).unwrap(), b"AB");
+    }
+
+    #[test]
+    fn test_symlink_escaping_torrent_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut link = file(&["passwd"], 0, "l");
+        link.symlink_path = Some(vec!["..".to_string(), "etc".to_string()]);
+        let torrent = torrent(dir.path(), vec![file(&["a"], 1, ""), link], 16);
+
+        let storage = Storage::new(&torrent, &dir.path().join("out")).unwrap();
+        assert!(storage.finalize().is_err());
+        assert!(relative_link_target(Path::new("t/x"), &["/etc".into()], &[], &[]).is_none());
+    }
+
+    #[test]
+    fn test_skipped_files_go_to_partfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let torrent = torrent(
+            dir.path(),
+            vec![
+                file(&["a.bin"], 10, ""),
+                file(&["b.bin"], 12, ""),
+                file(&["c.bin"], 10, ""),
+            ],
+            16,
+        );
+        let out = dir.path().join("out");
+        let mut storage = Storage::new_skipping(&torrent, &out, &[false, true, false]).unwrap();
+        assert!(!out.join("bundle/b.bin").exists());
+
+        // Both pieces are shared with b.bin
+        let mut piece = vec![b'a'; 10];
+        piece.extend([b'b'; 6]);
+        storage.write_piece(&torrent, 0, &piece).unwrap();
+        storage
+            .write_piece(&torrent, 1, b"bbbbbbcccccccccc")
+            .unwrap();
+        assert!(!out.join("bundle/b.bin").exists());
+        assert_eq!(fs::read(out.join("bundle/c.bin")).unwrap(), b"cccccccccc");
+        assert_eq!(storage.read_piece(&torrent, 0).unwrap(), piece);
+        // Only the chunk b.bin has in those pieces, not its torrent offset
+        let partfile_length = fs::metadata(&storage.partfile).unwrap().len();
+        assert_eq!(partfile_length, SLOT_HEADER + 12);
+        // The index is read back from the slot headers
+        let reopened = Storage::existing(&torrent, &out);
+        assert!(reopened.is_in_partfile(1));
+        assert_eq!(
+            reopened.read_piece(&torrent, 1).unwrap(),
+            b"bbbbbbcccccccccc"
+        );
+
+        storage.unskip(1).unwrap();
+        assert!(!storage.is_in_partfile(1));
+        assert_eq!(fs::read(out.join("bundle/b.bin")).unwrap(), vec![b'b'; 12]);
+        assert!(!storage.partfile.exists());
+
+        storage.remove_files().unwrap();
+        assert!(!out.join("bundle").exists());
+        assert!(out.exists());
+    }
+}
@@ -0,0 +1,255 @@
+/// Reading a torrent's files while they download, e.g. to play a video
+/// before it is complete.
+use crate::parser::TorrentFile;
+use crate::storage::{Storage, StorageError};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+#[derive(Debug)]
+pub struct StreamError {
+    pub message: String,
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Stream error: {}", self.message)
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<StorageError> for StreamError {
+    fn from(err: StorageError) -> Self {
+        StreamError {
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Creates both ends of a stream over the torrent's files in `data_dir`:
+/// the downloader reports verified pieces through [`PieceProgress`], and
+/// readers wait for them on [`StreamReader`].
+pub fn stream(torrent: &TorrentFile, data_dir: &Path) -> (PieceProgress, StreamReader) {
+    let (sender, receiver) = watch::channel(vec![false; torrent.num_pieces()]);
+    let playhead = Arc::new(Mutex::new(None));
+    let requested = Arc::new(Mutex::new(Vec::new()));
+    let progress = PieceProgress {
+        sender,
+        playhead: playhead.clone(),
+        requested: requested.clone(),
+    };
+    let reader = StreamReader {
+        file_lengths: torrent.files().iter().map(|file| file.length).collect(),
+        storage: Arc::new(Storage::existing(torrent, data_dir)),
+        torrent: Arc::new(torrent.clone()),
+        pieces: receiver,
+        playhead,
+        requested,
+    };
+    (progress, reader)
+}
+
+/// The downloader's end of a stream. Dropping it wakes every waiting
+/// reader with an error.
+#[derive(Debug)]
+pub struct PieceProgress {
+    sender: watch::Sender<Vec<bool>>,
+    playhead: Arc<Mutex<Option<u32>>>,
+    requested: Arc<Mutex<Vec<Range<u32>>>>,
+}
+
+impl PieceProgress {
+    /// Marks a piece as written and verified.
+    pub fn piece_verified(&self, piece_index: u32) {
+        self.sender.send_modify(|pieces| {
+            if let Some(done) = pieces.get_mut(piece_index as usize) {
+                *done = true;
+            }
+        });
+    }
+
+    /// The first piece of the last range a reader asked for.
+    pub fn playhead(&self) -> Option<u32> {
+        *self.playhead.lock().expect("playhead lock poisoned")
+    }
+
+    /// Pieces that readers are waiting for and that are not verified yet,
+    /// oldest read first. The downloader fetches these before anything
+    /// else, whatever its strategy.
+    pub fn requested(&self) -> Vec<u32> {
+        let requested = self.requested.lock().expect("requested lock poisoned");
+        let done = self.sender.borrow();
+        let mut pieces: Vec<u32> = Vec::new();
+        for piece in requested.iter().flat_map(Range::clone) {
+            if !done.get(piece as usize).copied().unwrap_or(true) && !pieces.contains(&piece) {
+                pieces.push(piece);
+            }
+        }
+        pieces
+    }
+}
+
+/// A read waiting for its pieces, listed in [`PieceProgress::requested`]
+/// until it is dropped.
+struct PendingRead<'a> {
+    requested: &'a Mutex<Vec<Range<u32>>>,
+    pieces: Range<u32>,
+}
+
+impl Drop for PendingRead<'_> {
+    fn drop(&mut self) {
+        let mut requested = self.requested.lock().expect("requested lock poisoned");
+        if let Some(index) = requested.iter().position(|r| *r == self.pieces) {
+            requested.remove(index);
+        }
+    }
+}
+
+/// Reads byte ranges of the torrent's files, waiting until the pieces
+/// holding them have been verified. The pieces a read waits for are
+/// fetched before any others, and reading also moves the playhead, so a
+/// downloader using [`PickStrategy::Streaming`](crate::picker::PickStrategy)
+/// fetches what comes after next.
+#[derive(Debug, Clone)]
+pub struct StreamReader {
+    torrent: Arc<TorrentFile>,
+    storage: Arc<Storage>,
+    file_lengths: Vec<u64>,
+    pieces: watch::Receiver<Vec<bool>>,
+    playhead: Arc<Mutex<Option<u32>>>,
+    requested: Arc<Mutex<Vec<Range<u32>>>>,
+}
+
+impl StreamReader {
+    pub fn torrent(&self) -> &TorrentFile {
+        &self.torrent
+    }
+
+    /// Whether the range can be read without waiting.
+    pub fn is_available(&self, file_index: usize, offset: u64, length: u64) -> bool {
+        match self.file_lengths.get(file_index) {
+            Some(&file_length) if offset.saturating_add(length) <= file_length => {}
+            _ => return false,
+        }
+        let pieces = self.torrent.pieces_for_range(file_index, offset, length);
+        let done = self.pieces.borrow();
+        pieces
+            .into_iter()
+            .all(|i| done.get(i as usize).copied().unwrap_or(false))
+    }
+
+    /// Reads `length` bytes at `offset` in file `file_index` once they are
+    /// verified. Fails if the download stops before that.
+    pub async fn read(
+        &self,
+        file_index: usize,
+        offset: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, StreamError> {
+        let file_length = *self
+            .file_lengths
+            .get(file_index)
+            .ok_or_else(|| StreamError {
+                message: format!("No file {}", file_index),
+            })?;
+        if offset.saturating_add(length as u64) > file_length {
+            return Err(StreamError {
+                message: format!(
+                    "Range {}+{} is past the end of file {} ({} bytes)",
+                    offset, length, file_index, file_length
+                ),
+            });
+        }
+
+        let pieces = self
+            .torrent
+            .pieces_for_range(file_index, offset, length as u64);
+        *self.playhead.lock().expect("playhead lock poisoned") = Some(pieces.start);
+        self.requested
+            .lock()
+            .expect("requested lock poisoned")
+            .push(pieces.clone());
+        let _pending = PendingRead {
+            requested: &self.requested,
+            pieces: pieces.clone(),
+        };
+        let mut receiver = self.pieces.clone();
+        receiver
+            .wait_for(|done| pieces.clone().all(|i| done[i as usize]))
+            .await
+            .map_err(|_| StreamError {
+                message: "Download stopped before the range was verified".to_string(),
+            })?;
+
+        Ok(self.storage.read_file(file_index, offset, length)?)
+    }
+
+    /// [`StreamReader::read`] for callers outside an async runtime.
+    pub fn read_blocking(
+        &self,
+        file_index: usize,
+        offset: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, StreamError> {
+        futures::executor::block_on(self.read(file_index, offset, length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_read_waits_for_verified_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("show");
+        fs::create_dir_all(&root).unwrap();
+        let episode: Vec<u8> = (0..60_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(root.join("intro.txt"), vec![b'x'; 10_000]).unwrap();
+        fs::write(root.join("s01e01.mkv"), &episode).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root,
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://t/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let path = dir.path().join("show.torrent");
+        fs::write(&path, &created.data).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+
+        // Bytes 30000..40000 of the episode are bytes 40000..50000 of the
+        // torrent: pieces 2 and 3
+        assert_eq!(torrent.pieces_for_range(1, 30_000, 10_000), 2..4);
+        let (progress, reader) = stream(&torrent, dir.path());
+        assert!(!reader.is_available(1, 30_000, 10_000));
+        assert!(!reader.is_available(7, 0, 10));
+        assert!(!reader.is_available(1, 59_000, 2_000));
+
+        let waiting = {
+            let reader = reader.clone();
+            tokio::spawn(async move { reader.read(1, 30_000, 10_000).await })
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(progress.playhead(), Some(2));
+        assert_eq!(progress.requested(), vec![2, 3]);
+        progress.piece_verified(2);
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        assert_eq!(progress.requested(), vec![3]);
+        progress.piece_verified(3);
+        let data = waiting.await.unwrap().unwrap();
+        assert_eq!(data, &episode[30_000..40_000]);
+        assert!(progress.requested().is_empty());
+
+        assert!(reader.read(1, 59_000, 2_000).await.is_err());
+        let pending = reader.clone();
+        drop(progress);
+        assert!(pending.read_blocking(0, 0, 10).is_err());
+    }
+}
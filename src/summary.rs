@@ -0,0 +1,258 @@
+/// What `il-pleut info` reports about a torrent or magnet link.
+use crate::magnet::{MagnetLink, base32_encode, hex_encode};
+use crate::parser::{TorrentFile, TorrentFiles};
+use crate::ui::format_bytes;
+use serde::Serialize;
+use std::fmt;
+
+/// Everything known about a torrent without downloading it. Magnet links
+/// leave most fields empty until the metadata has been fetched.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentSummary {
+    pub name: Option<String>,
+    /// Swarm info hash, hex.
+    pub info_hash: String,
+    /// The same hash in base32, as some magnet links use.
+    pub info_hash_base32: String,
+    /// v2 SHA-256 info hash, hex.
+    pub info_hash_v2: Option<String>,
+    pub total_size: Option<u64>,
+    pub piece_count: Option<usize>,
+    pub piece_length: Option<u32>,
+    pub private: bool,
+    /// Announce URLs grouped by tier.
+    pub trackers: Vec<Vec<String>>,
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+    pub files: Vec<FileSummary>,
+    /// Unix timestamp.
+    pub creation_date: Option<i64>,
+    pub created_by: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileSummary {
+    /// Path inside the torrent, `/`-separated.
+    pub path: String,
+    pub length: u64,
+    /// BEP 47 `attr` flags, e.g. `p` for padding.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub attributes: String,
+}
+
+impl TorrentSummary {
+    pub fn from_torrent(torrent: &TorrentFile) -> Self {
+        let trackers = match torrent.announce_list {
+            Some(ref tiers) if !tiers.is_empty() => tiers.clone(),
+            _ if torrent.announce.is_empty() => Vec::new(),
+            _ => vec![vec![torrent.announce.clone()]],
+        };
+        let files = match &torrent.info.files {
+            TorrentFiles::Single { length } => vec![FileSummary {
+                path: torrent.info.name.clone(),
+                length: *length,
+                attributes: String::new(),
+            }],
+            TorrentFiles::Multiple { files } => files
+                .iter()
+                .map(|file| FileSummary {
+                    path: file.path.join("/"),
+                    length: file.length,
+                    attributes: file.attr.as_attr_string(),
+                })
+                .collect(),
+        };
+
+        TorrentSummary {
+            name: Some(torrent.info.name.clone()),
+            info_hash: hex_encode(&torrent.info_hash),
+            info_hash_base32: base32_encode(&torrent.info_hash),
+            info_hash_v2: torrent.info_hash_v2.map(|hash| hex_encode(&hash)),
+            total_size: Some(torrent.total_size()),
+            piece_count: Some(torrent.num_pieces()),
+            piece_length: Some(torrent.info.piece_length),
+            private: torrent.info.private,
+            trackers,
+            web_seeds: torrent.web_seeds.clone(),
+            http_seeds: torrent.http_seeds.clone(),
+            files,
+            creation_date: torrent.creation_date,
+            created_by: torrent.created_by.clone(),
+            comment: torrent.comment.clone(),
+        }
+    }
+
+    pub fn from_magnet(link: &MagnetLink) -> Self {
+        let hash = link.swarm_hash();
+        TorrentSummary {
+            name: link.display_name.clone(),
+            info_hash: hash.map(|hash| hex_encode(&hash)).unwrap_or_default(),
+            info_hash_base32: hash.map(|hash| base32_encode(&hash)).unwrap_or_default(),
+            info_hash_v2: link.info_hash_v2.map(|hash| hex_encode(&hash)),
+            total_size: link.exact_length,
+            piece_count: None,
+            piece_length: None,
+            private: false,
+            // Magnet links have no tiers; try the trackers in order
+            trackers: link.trackers.iter().map(|url| vec![url.clone()]).collect(),
+            web_seeds: link.web_seeds.clone(),
+            http_seeds: Vec::new(),
+            files: Vec::new(),
+            creation_date: None,
+            created_by: None,
+            comment: None,
+        }
+    }
+}
+
+impl fmt::Display for TorrentSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = "(unknown)";
+        writeln!(
+            f,
+            "Name:         {}",
+            self.name.as_deref().unwrap_or(unknown)
+        )?;
+        writeln!(f, "Info hash:    {}", self.info_hash)?;
+        writeln!(f, "              {} (base32)", self.info_hash_base32)?;
+        if let Some(ref v2) = self.info_hash_v2 {
+            writeln!(f, "Info hash v2: {}", v2)?;
+        }
+        match self.total_size {
+            Some(size) => writeln!(f, "Size:         {} ({} bytes)", format_bytes(size), size)?,
+            None => writeln!(f, "Size:         {}", unknown)?,
+        }
+        if let (Some(count), Some(length)) = (self.piece_count, self.piece_length) {
+            writeln!(
+                f,
+                "Pieces:       {} x {}",
+                count,
+                format_bytes(length as u64)
+            )?;
+        }
+        writeln!(
+            f,
+            "Private:      {}",
+            if self.private { "yes" } else { "no" }
+        )?;
+        if let Some(date) = self
+            .creation_date
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        {
+            writeln!(f, "Created:      {}", date.format("%Y-%m-%d %H:%M:%S UTC"))?;
+        }
+        if let Some(ref created_by) = self.created_by {
+            writeln!(f, "Created by:   {}", created_by)?;
+        }
+        if let Some(ref comment) = self.comment {
+            writeln!(f, "Comment:      {}", comment)?;
+        }
+
+        if !self.trackers.is_empty() {
+            writeln!(f, "Trackers:")?;
+            for (tier, urls) in self.trackers.iter().enumerate() {
+                for (i, url) in urls.iter().enumerate() {
+                    if i == 0 {
+                        writeln!(f, "  Tier {:<3} {}", tier + 1, url)?;
+                    } else {
+                        writeln!(f, "           {}", url)?;
+                    }
+                }
+            }
+        }
+        let seeds: Vec<&String> = self.web_seeds.iter().chain(&self.http_seeds).collect();
+        if !seeds.is_empty() {
+            writeln!(f, "Web seeds:")?;
+            for url in seeds {
+                writeln!(f, "  {}", url)?;
+            }
+        }
+        if !self.files.is_empty() {
+            writeln!(f, "Files ({}):", self.files.len())?;
+            for file in &self.files {
+                let attributes = if file.attributes.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", file.attributes)
+                };
+                writeln!(
+                    f,
+                    "  {:>10}  {}{}",
+                    format_bytes(file.length),
+                    file.path,
+                    attributes
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use std::fs;
+
+    #[test]
+    fn test_torrent_summary() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("album");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("01.flac"), vec![1u8; 30_000]).unwrap();
+        fs::write(root.join("cover.jpg"), vec![2u8; 5_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root,
+            piece_length: Some(16 * 1024),
+            trackers: vec![
+                vec![
+                    "http://a/announce".to_string(),
+                    "http://b/announce".to_string(),
+                ],
+                vec!["http://c/announce".to_string()],
+            ],
+            comment: Some("ripped from vinyl".to_string()),
+            private: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let path = dir.path().join("album.torrent");
+        fs::write(&path, &created.data).unwrap();
+        let torrent = parse_torrent_file(path.to_str().unwrap()).unwrap();
+
+        let summary = TorrentSummary::from_torrent(&torrent);
+        assert_eq!(summary.info_hash, hex_encode(&created.info_hash));
+        assert_eq!(summary.trackers.len(), 2);
+        assert_eq!(summary.files[1].path, "cover.jpg");
+        assert!(summary.creation_date.is_some());
+
+        let text = summary.to_string();
+        assert!(text.contains("Pieces:       3 x 16.0 KB"), "{}", text);
+        assert!(text.contains("Private:      yes"));
+        assert!(text.contains("Comment:      ripped from vinyl"));
+        assert!(text.contains("  Tier 1   http://a/announce\n           http://b/announce"));
+    }
+
+    #[test]
+    fn test_magnet_summary() {
+        let link = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=demo&tr=http://t/a",
+        )
+        .unwrap();
+        let summary = TorrentSummary::from_magnet(&link);
+        let text = summary.to_string();
+        assert!(text.contains("Name:         demo"));
+        assert!(text.contains("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK (base32)"));
+        assert!(text.contains("Tier 1   http://t/a"));
+
+        let json: serde_json::Value = serde_json::to_value(&summary).unwrap();
+        assert_eq!(
+            json["info_hash"],
+            "c12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+        );
+        assert_eq!(json["trackers"][0][0], "http://t/a");
+        assert!(json["total_size"].is_null());
+    }
+}
@@ -1,6 +1,8 @@
 /// Tracker client for announcing to BitTorrent trackers and parsing responses.
-use crate::parser::{BencodeParser, BencodeValue, ParseError, TorrentFile};
+use crate::bencode::{self, BencodeError};
+use crate::parser::{BencodeParser, BencodeValue, ParseError, ParseMode, TorrentFile};
 use reqwest;
+use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, Ipv4Addr};
 use url::Url;
 
@@ -33,6 +35,14 @@ impl From<ParseError> for TrackerError {
     }
 }
 
+impl From<BencodeError> for TrackerError {
+    fn from(err: BencodeError) -> Self {
+        TrackerError {
+            message: format!("Invalid response: {}", err),
+        }
+    }
+}
+
 impl From<url::ParseError> for TrackerError {
     fn from(err: url::ParseError) -> Self {
         TrackerError {
@@ -76,29 +86,79 @@ pub struct TrackerRequest {
     pub trackerid: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub ip: IpAddr,
     pub port: u16,
+    #[serde(rename = "peer id", default, with = "bencode::byte_string::option")]
     pub peer_id: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackerResponse {
+    #[serde(rename = "failure reason")]
     pub failure_reason: Option<String>,
+    #[serde(
+        rename = "warning message",
+        default,
+        deserialize_with = "bencode::lenient::text"
+    )]
     pub warning_message: Option<String>,
     pub interval: u32,
+    // Trackers get the optional fields wrong often enough that a bad one
+    // is read as missing or zero rather than failing the announce
+    #[serde(
+        rename = "min interval",
+        default,
+        deserialize_with = "bencode::lenient::or_default"
+    )]
     pub min_interval: Option<u32>,
+    #[serde(
+        rename = "tracker id",
+        default,
+        deserialize_with = "bencode::lenient::text"
+    )]
     pub tracker_id: Option<String>,
-    pub complete: u32,   // seeders
+    #[serde(default, deserialize_with = "bencode::lenient::or_default")]
+    pub complete: u32, // seeders
+    #[serde(default, deserialize_with = "bencode::lenient::or_default")]
     pub incomplete: u32, // leechers
+    #[serde(default, deserialize_with = "bencode::lenient::or_default")]
     pub downloaded: Option<u32>,
+    #[serde(default, with = "peer_list")]
     pub peers: Vec<Peer>,
 }
 
+/// `peers` is either compact (6 bytes per IPv4 peer) or a list of
+/// dictionaries. Peers are always written as dictionaries.
+mod peer_list {
+    use super::{Peer, TrackerClient};
+    use crate::parser::BencodeValue;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+    pub fn serialize<S: Serializer>(peers: &[Peer], serializer: S) -> Result<S::Ok, S::Error> {
+        peers.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Peer>, D::Error> {
+        let value = BencodeValue::deserialize(deserializer)?;
+        // Try compact format first (binary string)
+        TrackerClient::parse_compact_peers(&value)
+            .or_else(|_| TrackerClient::parse_dict_peers(&value))
+            .map_err(|e| de::Error::custom(e.message))
+    }
+}
+
 pub struct TrackerClient {
     client: reqwest::Client,
     peer_id: [u8; 20],
+    mode: ParseMode,
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TrackerClient {
@@ -111,7 +171,11 @@ impl TrackerClient {
 
         let peer_id = Self::generate_peer_id();
 
-        Self { client, peer_id }
+        Self {
+            client,
+            peer_id,
+            mode: ParseMode::default(),
+        }
     }
 
     pub fn new_with_peer_id(peer_id: [u8; 20]) -> Self {
@@ -121,7 +185,17 @@ impl TrackerClient {
             .build()
             .expect("Failed to create HTTP client");
 
-        Self { client, peer_id }
+        Self {
+            client,
+            peer_id,
+            mode: ParseMode::default(),
+        }
+    }
+
+    /// Whether non-canonical bencode in tracker responses is tolerated.
+    pub fn with_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
     }
 
     fn generate_peer_id() -> [u8; 20] {
@@ -136,8 +210,8 @@ impl TrackerClient {
         peer_id[6] = b'0';
         peer_id[7] = b'-';
 
-        for i in 8..20 {
-            peer_id[i] = rand::random::<u8>();
+        for byte in &mut peer_id[8..] {
+            *byte = rand::random::<u8>();
         }
 
         peer_id
@@ -147,15 +221,57 @@ impl TrackerClient {
         &self.peer_id
     }
 
-    pub async fn announce(&self, torrent: &TorrentFile) -> Result<TrackerResponse, TrackerError> {
-        let request = self.create_start_request(&torrent, 6881, 0, torrent.total_size());
-        let mut url = Url::parse(&torrent.announce)?;
+    /// The HTTP client used for announces, shared with web seeds.
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub async fn announce(
+        &self,
+        torrent: &TorrentFile,
+        port: u16,
+    ) -> Result<TrackerResponse, TrackerError> {
+        self.announce_to(&torrent.announce, torrent, &torrent.info_hash, port)
+            .await
+    }
+
+    /// Announces one of the torrent's swarms to a specific tracker. Hybrid
+    /// v1/v2 torrents are announced once under each of their `swarm_hashes`.
+    pub async fn announce_to(
+        &self,
+        tracker_url: &str,
+        torrent: &TorrentFile,
+        info_hash: &[u8; 20],
+        port: u16,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let request = self.start_request(*info_hash, port, 0, torrent.total_size());
+        self.send_announce(tracker_url, &request).await
+    }
+
+    /// Announces a swarm known only by its hash, as for a magnet link whose
+    /// metadata is still being fetched. `left` is the size still wanted, as
+    /// far as it is known.
+    pub async fn announce_hash(
+        &self,
+        tracker_url: &str,
+        info_hash: &[u8; 20],
+        port: u16,
+        left: u64,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let request = self.start_request(*info_hash, port, 0, left);
+        self.send_announce(tracker_url, &request).await
+    }
 
-        // Use percent-encoding for info_hash and peer_id as raw bytes
-        use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+    async fn send_announce(
+        &self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut url = Url::parse(tracker_url)?;
 
-        let info_hash_encoded = percent_encode(&request.info_hash, NON_ALPHANUMERIC).to_string();
-        let peer_id_encoded = percent_encode(&request.peer_id, NON_ALPHANUMERIC).to_string();
+        // Percent-encode info_hash and peer_id as raw bytes
+        let info_hash_encoded = Self::url_encode_bytes(&request.info_hash);
+        let peer_id_encoded = Self::url_encode_bytes(&request.peer_id);
 
         // Build query string manually to avoid double-encoding
         let mut query = format!(
@@ -172,6 +288,9 @@ impl TrackerClient {
         if request.no_peer_id {
             query.push_str("&no_peer_id=1");
         }
+        if let Some(ref event) = request.event {
+            query.push_str(&format!("&event={}", event.as_str()));
+        }
         if let Some(numwant) = request.numwant {
             query.push_str(&format!("&numwant={}", numwant));
         }
@@ -194,8 +313,12 @@ impl TrackerClient {
             });
         }
 
-        // Parse the bencode response
-        let mut parser = BencodeParser::new(&response_bytes);
+        self.parse_response(&response_bytes)
+    }
+
+    /// Reads a bencoded announce response in the client's [`ParseMode`].
+    pub fn parse_response(&self, data: &[u8]) -> Result<TrackerResponse, TrackerError> {
+        let mut parser = BencodeParser::new(data).with_mode(self.mode);
         let response_value = parser.parse()?;
         let response_dict = response_value.as_dict().map_err(|_| TrackerError {
             message: "Tracker response is not a dictionary".to_string(),
@@ -212,67 +335,7 @@ impl TrackerClient {
             });
         }
 
-        // Parse response fields
-        let warning_message = response_dict
-            .get(b"warning message".as_ref())
-            .map(|v| String::from_utf8_lossy(v.as_bytes().unwrap_or(b"")).to_string());
-
-        let interval = response_dict
-            .get(b"interval".as_ref())
-            .ok_or_else(|| TrackerError {
-                message: "Missing interval field".to_string(),
-            })?
-            .as_integer()
-            .map_err(|_| TrackerError {
-                message: "Invalid interval".to_string(),
-            })? as u32;
-
-        let min_interval = response_dict
-            .get(b"min interval".as_ref())
-            .map(|v| v.as_integer().unwrap_or(0) as u32);
-
-        let tracker_id = response_dict
-            .get(b"tracker id".as_ref())
-            .map(|v| String::from_utf8_lossy(v.as_bytes().unwrap_or(b"")).to_string());
-
-        let complete = response_dict
-            .get(b"complete".as_ref())
-            .map(|v| v.as_integer().unwrap_or(0) as u32)
-            .unwrap_or(0);
-
-        let incomplete = response_dict
-            .get(b"incomplete".as_ref())
-            .map(|v| v.as_integer().unwrap_or(0) as u32)
-            .unwrap_or(0);
-
-        let downloaded = response_dict
-            .get(b"downloaded".as_ref())
-            .map(|v| v.as_integer().unwrap_or(0) as u32);
-
-        // Parse peers - try compact first, fall back to dictionary format
-        let peers = if let Some(peers_value) = response_dict.get(b"peers".as_ref()) {
-            // Try compact format first (binary string)
-            if let Ok(compact_peers) = Self::parse_compact_peers(peers_value) {
-                compact_peers
-            } else {
-                // Fall back to dictionary format
-                Self::parse_dict_peers(peers_value)?
-            }
-        } else {
-            Vec::new()
-        };
-
-        Ok(TrackerResponse {
-            failure_reason: None,
-            warning_message,
-            interval,
-            min_interval,
-            tracker_id,
-            complete,
-            incomplete,
-            downloaded,
-            peers,
-        })
+        Ok(bencode::from_value(&response_value)?)
     }
 
     fn parse_compact_peers(peers_value: &BencodeValue) -> Result<Vec<Peer>, TrackerError> {
@@ -302,50 +365,9 @@ impl TrackerClient {
     }
 
     fn parse_dict_peers(peers_value: &BencodeValue) -> Result<Vec<Peer>, TrackerError> {
-        let peers_list = peers_value.as_list().map_err(|_| TrackerError {
-            message: "Non-compact peers must be a list".to_string(),
-        })?;
-
-        let mut peers = Vec::new();
-        for peer_value in peers_list {
-            let peer_dict = peer_value.as_dict().map_err(|_| TrackerError {
-                message: "Peer must be a dictionary".to_string(),
-            })?;
-
-            let ip_str = String::from_utf8_lossy(
-                peer_dict
-                    .get(b"ip".as_ref())
-                    .ok_or_else(|| TrackerError {
-                        message: "Missing peer IP".to_string(),
-                    })?
-                    .as_bytes()
-                    .map_err(|_| TrackerError {
-                        message: "Invalid peer IP".to_string(),
-                    })?,
-            );
-
-            let ip = ip_str.parse::<IpAddr>().map_err(|e| TrackerError {
-                message: format!("Invalid IP address '{}': {}", ip_str, e),
-            })?;
-
-            let port = peer_dict
-                .get(b"port".as_ref())
-                .ok_or_else(|| TrackerError {
-                    message: "Missing peer port".to_string(),
-                })?
-                .as_integer()
-                .map_err(|_| TrackerError {
-                    message: "Invalid peer port".to_string(),
-                })? as u16;
-
-            let peer_id = peer_dict
-                .get(b"peer id".as_ref())
-                .map(|v| v.as_bytes().unwrap_or(b"").to_vec());
-
-            peers.push(Peer { ip, port, peer_id });
-        }
-
-        Ok(peers)
+        bencode::from_value(peers_value).map_err(|e| TrackerError {
+            message: format!("Invalid peer list: {}", e),
+        })
     }
 
     fn url_encode_bytes(bytes: &[u8]) -> String {
@@ -366,15 +388,15 @@ impl TrackerClient {
     }
 
     /// Create a tracker request for starting a download
-    fn create_start_request(
+    fn start_request(
         &self,
-        torrent: &TorrentFile,
+        info_hash: [u8; 20],
         port: u16,
         downloaded: u64,
         left: u64,
     ) -> TrackerRequest {
         TrackerRequest {
-            info_hash: torrent.info_hash,
+            info_hash,
             peer_id: self.peer_id,
             port,
             uploaded: 0,
@@ -427,8 +449,8 @@ mod tests {
         let client = TrackerClient::new();
         let peer_id = client.get_peer_id();
 
-        // Should start with -RS0001-
-        assert_eq!(&peer_id[0..8], b"-RS0001-");
+        // Should start with -qB4500-
+        assert_eq!(&peer_id[0..8], b"-qB4500-");
         assert_eq!(peer_id.len(), 20);
     }
 
@@ -439,6 +461,54 @@ mod tests {
         assert_eq!(encoded, "Hello%20World%21");
     }
 
+    #[test]
+    fn test_response_deserializes_either_peer_format() {
+        let compact: TrackerResponse = bencode::from_bytes(
+            b"d8:completei3e8:intervali1800e5:peers6:\xc0\xa8\x01\x01\x1a\xe1e",
+        )
+        .unwrap();
+        assert_eq!(compact.interval, 1800);
+        assert_eq!(compact.complete, 3);
+        assert_eq!(compact.incomplete, 0);
+        assert_eq!(compact.peers[0].port, 6881);
+
+        let dict: TrackerResponse = bencode::from_bytes(
+            b"d8:intervali900e5:peersld2:ip8:10.0.0.27:peer id2:ab4:porti51413eeee",
+        )
+        .unwrap();
+        assert_eq!(dict.peers[0].ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(dict.peers[0].peer_id.as_deref(), Some(b"ab".as_ref()));
+
+        // Written back, peers are dictionaries
+        let bytes = bencode::to_bytes(&dict).unwrap();
+        let again: TrackerResponse = bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(again.peers[0].port, 51413);
+
+        // Malformed optional fields do not fail the announce
+        let sloppy: TrackerResponse = bencode::from_bytes(
+            b"d8:completei-3e10:incomplete1:x8:intervali60e12:min intervalli1ee\
+              10:tracker idi7e15:warning message2:\xffae",
+        )
+        .unwrap();
+        assert_eq!((sloppy.complete, sloppy.incomplete), (0, 0));
+        assert_eq!(sloppy.min_interval, None);
+        assert_eq!(sloppy.tracker_id.as_deref(), Some(""));
+        assert_eq!(sloppy.warning_message.as_deref(), Some("\u{fffd}a"));
+
+        let err = bencode::from_bytes::<TrackerResponse>(b"d8:intervali-1ee").unwrap_err();
+        assert!(err.to_string().ends_with(" at interval"), "{}", err);
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_non_canonical_responses() {
+        let data = b"d8:intervali0060ee";
+        let lenient = TrackerClient::new_with_peer_id([0; 20]);
+        assert_eq!(lenient.parse_response(data).unwrap().interval, 60);
+        let strict = TrackerClient::new_with_peer_id([0; 20]).with_mode(ParseMode::Strict);
+        assert!(strict.parse_response(data).is_err());
+        assert!(strict.parse_response(b"d8:intervali60ee").is_ok());
+    }
+
     #[test]
     fn test_compact_peer_parsing() {
         // Example: IP 192.168.1.1, port 6881 (0x1AE1)
@@ -1,4 +1,6 @@
 use crate::parser::TorrentFile;
+use crate::priority::{FilePriorities, FilePriority};
+use crate::rate_limit::{self, RateLimits};
 use crate::tracker::TrackerResponse;
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
@@ -15,10 +17,10 @@ use ratatui::{
 };
 use std::{
     io::{self, Stdout},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
         Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{self, Receiver, Sender},
     },
     thread,
@@ -27,13 +29,17 @@ use std::{
 
 #[derive(Debug, Clone)]
 pub enum UIEvent {
-    TorrentParsed(TorrentFile),
+    TorrentParsed(Box<TorrentFile>),
     TrackerResponse(TrackerResponse),
     ConnectingToPeer(SocketAddr),
+    ConnectingToWebSeed(String),
+    ServingHttp(SocketAddr),
     PeerConnected(SocketAddr),
     PeerConnectionFailed(SocketAddr, String),
     DownloadStarted,
     PieceCompleted(u32, usize, usize), // piece_index, completed_count, total_count
+    PieceFailed(u32, SocketAddr),      // piece_index, peer that sent it
+    PeerBanned(IpAddr),
     DownloadComplete,
     DownloadStopped,
     Error(String),
@@ -97,19 +103,10 @@ impl UIState {
         }
 
         self.last_piece_time = Some(now);
-
-        // Only log milestone pieces (every 100 pieces or multiples of 5% progress)
-        if completed == 1 || piece_index % 100 == 0 || (completed * 20) % total == 0 {
-            let _percentage = if total > 0 {
-                (completed * 100) / total
-            } else {
-                0
-            };
-        }
     }
 
     fn add_log(&mut self, message: String) {
-        self.log_messages.push(format!("{}", message));
+        self.log_messages.push(message);
         // Keep only last 50 messages
         if self.log_messages.len() > 50 {
             self.log_messages.remove(0);
@@ -131,8 +128,15 @@ pub struct UI {
     event_rx: Receiver<UIEvent>,
     event_tx: Sender<UIEvent>,
     should_quit: Arc<AtomicBool>,
+    rate_limits: Vec<(String, RateLimits)>,
+    selected_limits: Arc<AtomicUsize>,
+    file_priorities: Option<FilePriorities>,
+    selected_file: Arc<AtomicUsize>,
 }
 
+/// Files shown at once in the file list.
+const FILE_ROWS: usize = 5;
+
 impl UI {
     pub fn new() -> Result<Self, io::Error> {
         // Setup terminal
@@ -151,9 +155,27 @@ impl UI {
             event_rx,
             event_tx,
             should_quit,
+            rate_limits: Vec::new(),
+            selected_limits: Arc::new(AtomicUsize::new(0)),
+            file_priorities: None,
+            selected_file: Arc::new(AtomicUsize::new(0)),
         })
     }
 
+    /// Shows a set of rate limits and lets the user adjust them with the
+    /// keyboard. Can be called once per scope (e.g. "Global", "Torrent").
+    pub fn with_rate_limits(mut self, name: &str, limits: RateLimits) -> Self {
+        self.rate_limits.push((name.to_string(), limits));
+        self
+    }
+
+    /// Lists the torrent's files with their priorities and lets the user
+    /// change them with the keyboard.
+    pub fn with_file_priorities(mut self, priorities: FilePriorities) -> Self {
+        self.file_priorities = Some(priorities);
+        self
+    }
+
     pub fn get_event_sender(&self) -> Sender<UIEvent> {
         self.event_tx.clone()
     }
@@ -161,18 +183,68 @@ impl UI {
     pub fn run(&mut self) -> Result<(), io::Error> {
         // Start input handling thread
         let should_quit = self.should_quit.clone();
+        let rate_limits = self.rate_limits.clone();
+        let selected_limits = self.selected_limits.clone();
+        let file_priorities = self.file_priorities.clone();
+        let selected_file = self.selected_file.clone();
         thread::spawn(move || {
             loop {
                 if should_quit.load(Ordering::Relaxed) {
                     break;
                 }
 
-                if event::poll(Duration::from_millis(100)).unwrap() {
-                    if let Ok(Event::Key(key)) = event::read() {
-                        if key.code == KeyCode::Char('q') || key.code == KeyCode::Esc {
+                if event::poll(Duration::from_millis(100)).unwrap()
+                    && let Ok(Event::Key(key)) = event::read()
+                {
+                    match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => {
                             should_quit.store(true, Ordering::Relaxed);
                             break;
                         }
+                        KeyCode::Char('l') if !rate_limits.is_empty() => {
+                            let next =
+                                (selected_limits.load(Ordering::Relaxed) + 1) % rate_limits.len();
+                            selected_limits.store(next, Ordering::Relaxed);
+                        }
+                        KeyCode::Char(c @ ('d' | 'D' | 'u' | 'U')) => {
+                            let selected = selected_limits.load(Ordering::Relaxed);
+                            if let Some((_, limits)) = rate_limits.get(selected) {
+                                let limiter = if c.eq_ignore_ascii_case(&'d') {
+                                    &limits.download
+                                } else {
+                                    &limits.upload
+                                };
+                                let rate = if c.is_ascii_lowercase() {
+                                    rate_limit::step_down(limiter.rate())
+                                } else {
+                                    rate_limit::step_up(limiter.rate())
+                                };
+                                limiter.set_rate(rate);
+                            }
+                        }
+                        KeyCode::Up | KeyCode::Down if file_priorities.is_some() => {
+                            let count = file_priorities.as_ref().map_or(0, FilePriorities::len);
+                            let selected = selected_file.load(Ordering::Relaxed);
+                            let next = if key.code == KeyCode::Up {
+                                selected.saturating_sub(1)
+                            } else {
+                                (selected + 1).min(count.saturating_sub(1))
+                            };
+                            selected_file.store(next, Ordering::Relaxed);
+                        }
+                        KeyCode::Char(c @ ('+' | '=' | '-')) => {
+                            if let Some(ref priorities) = file_priorities {
+                                let selected = selected_file.load(Ordering::Relaxed);
+                                let current = priorities.get(selected);
+                                let priority = if c == '-' {
+                                    current.lower()
+                                } else {
+                                    current.raise()
+                                };
+                                priorities.set(selected, priority);
+                            }
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -197,7 +269,13 @@ impl UI {
 
             // Draw UI
             let state = self.state.lock().unwrap();
-            self.terminal.draw(|f| Self::draw_ui(f, &state))?;
+            let selected = self.selected_limits.load(Ordering::Relaxed);
+            let files = self
+                .file_priorities
+                .as_ref()
+                .map(|priorities| (priorities, self.selected_file.load(Ordering::Relaxed)));
+            self.terminal
+                .draw(|f| Self::draw_ui(f, &state, &self.rate_limits, selected, files))?;
             drop(state);
 
             // Small delay to prevent busy waiting
@@ -220,9 +298,27 @@ impl UI {
                     "  Piece length: {} bytes",
                     torrent.info.piece_length
                 ));
-                state.add_log(format!("  Number of pieces: {}", torrent.info.pieces.len()));
-                state.total_pieces = torrent.info.pieces.len();
-                state.torrent = Some(torrent);
+                state.add_log(format!("  Number of pieces: {}", torrent.num_pieces()));
+                if torrent.info.is_v2() {
+                    let version = if torrent.info.has_v1() {
+                        "hybrid v1/v2"
+                    } else {
+                        "v2"
+                    };
+                    state.add_log(format!("  Format: {}", version));
+                }
+                if torrent.info.private {
+                    state.add_log("  Private torrent: DHT, PEX and LSD disabled".to_string());
+                }
+                let seeds = torrent.web_seeds.len() + torrent.http_seeds.len();
+                if seeds > 0 {
+                    state.add_log(format!("  Web seeds: {}", seeds));
+                }
+                for warning in &torrent.warnings {
+                    state.add_log(format!("  Warning: {}", warning));
+                }
+                state.total_pieces = torrent.num_pieces();
+                state.torrent = Some(*torrent);
             }
             UIEvent::TrackerResponse(response) => {
                 state.add_log(format!(
@@ -240,6 +336,12 @@ impl UI {
                 state.add_log(format!("Connecting to peer: {}", addr));
                 state.current_peer = Some(addr);
             }
+            UIEvent::ConnectingToWebSeed(url) => {
+                state.add_log(format!("Downloading from web seed: {}", url));
+            }
+            UIEvent::ServingHttp(addr) => {
+                state.add_log(format!("Serving files at http://{}/", addr));
+            }
             UIEvent::PeerConnected(addr) => {
                 state.add_log(format!("Connected to peer: {}", addr));
                 state.connected_peer = Some(addr);
@@ -255,6 +357,15 @@ impl UI {
             UIEvent::PieceCompleted(piece_index, completed, total) => {
                 state.update_progress(piece_index, completed, total);
             }
+            UIEvent::PieceFailed(piece_index, addr) => {
+                state.add_log(format!(
+                    "Piece {} from {} failed hash check, will retry",
+                    piece_index, addr
+                ));
+            }
+            UIEvent::PeerBanned(ip) => {
+                state.add_log(format!("Banned peer {} for sending corrupt data", ip));
+            }
             UIEvent::DownloadComplete => {
                 state.add_log("Download completed successfully!".to_string());
             }
@@ -268,17 +379,26 @@ impl UI {
         }
     }
 
-    fn draw_ui(f: &mut Frame, state: &UIState) {
+    fn draw_ui(
+        f: &mut Frame,
+        state: &UIState,
+        rate_limits: &[(String, RateLimits)],
+        selected_limits: usize,
+        files: Option<(&FilePriorities, usize)>,
+    ) {
+        let file_rows = files.map_or(0, |(priorities, _)| priorities.len().min(FILE_ROWS) + 2);
         // Main layout
         let chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints([
-                Constraint::Length(3), // Title
-                Constraint::Length(8), // Torrent info
-                Constraint::Length(6), // Connection info
-                Constraint::Length(6), // Progress (increased from 4)
-                Constraint::Min(5),    // Logs
-                Constraint::Length(1), // Help
+                Constraint::Length(3),                            // Title
+                Constraint::Length(8),                            // Torrent info
+                Constraint::Length(6),                            // Connection info
+                Constraint::Length(6),                            // Progress (increased from 4)
+                Constraint::Length(rate_limits.len() as u16 + 2), // Rate limits
+                Constraint::Length(file_rows as u16),             // Files
+                Constraint::Min(5),                               // Logs
+                Constraint::Length(1),                            // Help
             ])
             .split(f.size());
 
@@ -302,14 +422,120 @@ impl UI {
         // Progress
         Self::draw_progress(f, chunks[3], state);
 
+        // Rate limits
+        Self::draw_rate_limits(f, chunks[4], rate_limits, selected_limits);
+
+        // Files
+        if let Some((priorities, selected)) = files {
+            Self::draw_files(f, chunks[5], state, priorities, selected);
+        }
+
         // Logs
-        Self::draw_logs(f, chunks[4], state);
+        Self::draw_logs(f, chunks[6], state);
 
         // Help
-        let help = Paragraph::new("Press 'q' or ESC to quit")
+        let mut help_text = if rate_limits.is_empty() {
+            "Press 'q' or ESC to quit".to_string()
+        } else {
+            "'q'/ESC quit  'l' select limits  'd'/'D' download -/+  'u'/'U' upload -/+".to_string()
+        };
+        if files.is_some() {
+            help_text.push_str("  Up/Down file  '-'/'+' priority");
+        }
+        let help = Paragraph::new(help_text)
             .style(Style::default().fg(Color::Gray))
             .alignment(Alignment::Center);
-        f.render_widget(help, chunks[5]);
+        f.render_widget(help, chunks[7]);
+    }
+
+    fn draw_rate_limits(
+        f: &mut Frame,
+        area: Rect,
+        rate_limits: &[(String, RateLimits)],
+        selected: usize,
+    ) {
+        let format_limit = |rate: Option<u64>| match rate {
+            Some(rate) => format!("{}/s", format_bytes(rate)),
+            None => "unlimited".to_string(),
+        };
+
+        let lines: Vec<Line> = rate_limits
+            .iter()
+            .enumerate()
+            .map(|(i, (name, limits))| {
+                let name_style = if i == selected {
+                    Style::default()
+                        .fg(Color::Cyan)
+                        .add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default().fg(Color::Cyan)
+                };
+                Line::from(vec![
+                    Span::styled(format!("{}: ", name), name_style),
+                    Span::styled("Down ", Style::default().fg(Color::Green)),
+                    Span::raw(format_limit(limits.download.rate())),
+                    Span::styled("  Up ", Style::default().fg(Color::Yellow)),
+                    Span::raw(format_limit(limits.upload.rate())),
+                ])
+            })
+            .collect();
+
+        let paragraph = Paragraph::new(lines)
+            .block(Block::default().title("Rate Limits").borders(Borders::ALL));
+        f.render_widget(paragraph, area);
+    }
+
+    fn draw_files(
+        f: &mut Frame,
+        area: Rect,
+        state: &UIState,
+        priorities: &FilePriorities,
+        selected: usize,
+    ) {
+        let names: Vec<String> = match state.torrent {
+            Some(ref torrent) => torrent
+                .files()
+                .iter()
+                .map(|file| file.path.join("/"))
+                .collect(),
+            None => Vec::new(),
+        };
+        let priorities = priorities.to_vec();
+        // Scroll so the selected file stays visible
+        let first = selected.saturating_sub(FILE_ROWS - 1);
+
+        let lines: Vec<Line> = priorities
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(FILE_ROWS)
+            .map(|(i, priority)| {
+                let color = match priority {
+                    FilePriority::Skip => Color::DarkGray,
+                    FilePriority::Low => Color::Blue,
+                    FilePriority::Normal => Color::Green,
+                    FilePriority::High => Color::Yellow,
+                };
+                let name = names
+                    .get(i)
+                    .cloned()
+                    .unwrap_or_else(|| format!("File {}", i));
+                let name_style = if i == selected {
+                    Style::default().add_modifier(Modifier::REVERSED)
+                } else {
+                    Style::default()
+                };
+                Line::from(vec![
+                    Span::styled(format!("{:<7}", priority), Style::default().fg(color)),
+                    Span::styled(name, name_style),
+                ])
+            })
+            .collect();
+
+        let title = format!("Files ({})", priorities.len());
+        let paragraph =
+            Paragraph::new(lines).block(Block::default().title(title).borders(Borders::ALL));
+        f.render_widget(paragraph, area);
     }
 
     fn draw_torrent_info(f: &mut Frame, area: Rect, state: &UIState) {
@@ -338,13 +564,21 @@ impl UI {
                 ]),
                 Line::from(vec![
                     Span::styled("Pieces: ", Style::default().fg(Color::Yellow)),
-                    Span::raw(format!("{}", torrent.info.pieces.len())),
+                    Span::raw(format!("{}", torrent.num_pieces())),
                     Span::styled("  Size: ", Style::default().fg(Color::Yellow)),
                     Span::raw(format_bytes(torrent.info.piece_length as u64)),
                 ]),
                 Line::from(vec![
                     Span::styled("Tracker: ", Style::default().fg(Color::Blue)),
                     Span::raw(&torrent.announce),
+                    if torrent.info.private {
+                        Span::styled(
+                            "  [private]",
+                            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        )
+                    } else {
+                        Span::raw("")
+                    },
                 ]),
                 Line::from(vec![
                     Span::styled("Info Hash: ", Style::default().fg(Color::Magenta)),
@@ -560,7 +794,7 @@ impl Drop for UI {
     }
 }
 
-fn format_bytes(bytes: u64) -> String {
+pub(crate) fn format_bytes(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
     let mut size = bytes as f64;
     let mut unit_index = 0;
@@ -0,0 +1,262 @@
+/// Hash-checks data on disk against its torrent.
+use crate::parser::TorrentFile;
+use crate::storage::Storage;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Good,
+    /// The data is there but does not match its hash.
+    Bad,
+    /// Some of the data could not be read: a file is absent or too short.
+    Missing,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    Complete,
+    /// Present, but this many of the pieces covering it are bad or missing.
+    Damaged(usize),
+    Missing,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    /// Path relative to the data directory.
+    pub path: PathBuf,
+    pub status: FileStatus,
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    pub pieces: Vec<PieceStatus>,
+    /// Every file except padding, in torrent order.
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|&status| status == PieceStatus::Good)
+    }
+
+    pub fn count(&self, status: PieceStatus) -> usize {
+        self.pieces.iter().filter(|&&s| s == status).count()
+    }
+
+    /// Indices of the pieces with `status`.
+    pub fn indices(&self, status: PieceStatus) -> Vec<usize> {
+        (0..self.pieces.len())
+            .filter(|&i| self.pieces[i] == status)
+            .collect()
+    }
+}
+
+/// Checks every piece of `torrent` against the files under `data_dir`,
+/// spreading the pieces over `threads` threads.
+pub fn verify_torrent(torrent: &TorrentFile, data_dir: &Path, threads: usize) -> VerifyReport {
+    let storage = Storage::existing(torrent, data_dir);
+    let num_pieces = torrent.num_pieces();
+    let threads = threads.clamp(1, num_pieces.max(1));
+    let pieces_per_thread = num_pieces.div_ceil(threads).max(1);
+
+    let mut pieces = vec![PieceStatus::Missing; num_pieces];
+    thread::scope(|scope| {
+        for (chunk_index, chunk) in pieces.chunks_mut(pieces_per_thread).enumerate() {
+            let storage = &storage;
+            scope.spawn(move || {
+                let first_piece = chunk_index * pieces_per_thread;
+                for (i, status) in chunk.iter_mut().enumerate() {
+                    let piece_index = (first_piece + i) as u32;
+                    *status = match storage.read_piece(torrent, piece_index) {
+                        Ok(data) if torrent.verify_piece(piece_index, &data) => PieceStatus::Good,
+                        Ok(_) => PieceStatus::Bad,
+                        Err(_) => PieceStatus::Missing,
+                    };
+                }
+            });
+        }
+    });
+
+    // Blame each bad piece on every file it covers
+    let files = torrent.files();
+    let mut damage = vec![0usize; files.len()];
+    for (piece_index, status) in pieces.iter().enumerate() {
+        if *status != PieceStatus::Good {
+            for span in torrent.piece_spans(piece_index as u32) {
+                damage[span.file_index] += 1;
+            }
+        }
+    }
+
+    let files = files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| !file.attr.padding)
+        .map(|(index, file)| {
+            let exists = file.attr.symlink || storage.path(index).exists();
+            let status = match damage[index] {
+                _ if !exists => FileStatus::Missing,
+                0 => FileStatus::Complete,
+                bad => FileStatus::Damaged(bad),
+            };
+            FileReport {
+                path: storage.relative_path(index).to_path_buf(),
+                status,
+            }
+        })
+        .collect();
+
+    VerifyReport { pieces, files }
+}
+
+/// `1-3, 7, 9-10` for `[1, 2, 3, 7, 9, 10]`.
+fn format_ranges(indices: &[usize]) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < indices.len() {
+        let start = indices[i];
+        while i + 1 < indices.len() && indices[i + 1] == indices[i] + 1 {
+            i += 1;
+        }
+        if indices[i] == start {
+            ranges.push(start.to_string());
+        } else {
+            ranges.push(format!("{}-{}", start, indices[i]));
+        }
+        i += 1;
+    }
+    ranges.join(", ")
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Checked {} pieces: {} good, {} bad, {} missing",
+            self.pieces.len(),
+            self.count(PieceStatus::Good),
+            self.count(PieceStatus::Bad),
+            self.count(PieceStatus::Missing)
+        )?;
+        for (label, status) in [("Bad", PieceStatus::Bad), ("Missing", PieceStatus::Missing)] {
+            let indices = self.indices(status);
+            if !indices.is_empty() {
+                writeln!(f, "{} pieces: {}", label, format_ranges(&indices))?;
+            }
+        }
+        writeln!(f, "Files:")?;
+        for file in &self.files {
+            match file.status {
+                FileStatus::Complete => writeln!(f, "  ok       {}", file.path.display())?,
+                FileStatus::Damaged(bad) => writeln!(
+                    f,
+                    "  damaged  {} ({} bad piece{})",
+                    file.path.display(),
+                    bad,
+                    if bad == 1 { "" } else { "s" }
+                )?,
+                FileStatus::Missing => writeln!(f, "  missing  {}", file.path.display())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::parser::parse_torrent_file;
+    use std::fs;
+
+    #[test]
+    fn test_verify_finds_bad_and_missing_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("set");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.bin"), vec![1u8; 40_000]).unwrap();
+        fs::write(root.join("b.bin"), vec![2u8; 20_000]).unwrap();
+        fs::write(root.join("c.bin"), vec![3u8; 10_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root.clone(),
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://t/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.path().join("set.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+        let torrent = parse_torrent_file(torrent_path.to_str().unwrap()).unwrap();
+
+        let report = verify_torrent(&torrent, dir.path(), 3);
+        assert!(report.is_complete());
+        assert_eq!(report.pieces.len(), 5);
+
+        // Corrupt the start of a.bin and remove c.bin
+        fs::write(root.join("a.bin"), {
+            let mut data = vec![1u8; 40_000];
+            data[10] = 0;
+            data
+        })
+        .unwrap();
+        fs::remove_file(root.join("c.bin")).unwrap();
+
+        let report = verify_torrent(&torrent, dir.path(), 2);
+        assert!(!report.is_complete());
+        assert_eq!(report.indices(PieceStatus::Bad), vec![0]);
+        // Piece 3 spans b.bin and c.bin, piece 4 is all c.bin
+        assert_eq!(report.indices(PieceStatus::Missing), vec![3, 4]);
+        assert_eq!(report.files[0].status, FileStatus::Damaged(1));
+        assert_eq!(report.files[1].status, FileStatus::Damaged(1));
+        assert_eq!(report.files[2].status, FileStatus::Missing);
+        assert_eq!(report.files[2].path, Path::new("set/c.bin"));
+
+        let text = report.to_string();
+        assert!(text.contains("Missing pieces: 3-4"), "{}", text);
+        assert!(text.contains("damaged  set/a.bin (1 bad piece)"));
+    }
+
+    #[test]
+    fn test_format_ranges() {
+        assert_eq!(format_ranges(&[1, 2, 3, 7, 9, 10]), "1-3, 7, 9-10");
+        assert_eq!(format_ranges(&[]), "");
+    }
+
+    #[test]
+    fn test_verify_reads_partfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("set");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.bin"), vec![1u8; 40_000]).unwrap();
+        fs::write(root.join("b.bin"), vec![2u8; 20_000]).unwrap();
+        fs::write(root.join("c.bin"), vec![3u8; 10_000]).unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: root.clone(),
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://t/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.path().join("set.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+        let torrent = parse_torrent_file(torrent_path.to_str().unwrap()).unwrap();
+
+        // Download pieces 2 and 3, shared by the skipped b.bin and its
+        // neighbours, into a fresh directory
+        let source = Storage::existing(&torrent, dir.path());
+        let out = dir.path().join("out");
+        let mut storage = Storage::new_skipping(&torrent, &out, &[false, true, false]).unwrap();
+        for piece in [2, 3] {
+            let data = source.read_piece(&torrent, piece).unwrap();
+            storage.write_piece(&torrent, piece, &data).unwrap();
+        }
+
+        let report = verify_torrent(&torrent, &out, 2);
+        assert_eq!(report.indices(PieceStatus::Good), vec![2, 3]);
+        assert_eq!(report.files[1].status, FileStatus::Missing);
+    }
+}
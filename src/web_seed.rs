@@ -0,0 +1,457 @@
+/// Downloads pieces over HTTP from web seeds (BEP 19) and HTTP seeds (BEP 17).
+use crate::parser::TorrentFile;
+use crate::rate_limit::RateLimits;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_encode, utf8_percent_encode};
+use reqwest::{StatusCode, header};
+
+/// Characters left as-is in a URL path segment.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug)]
+pub struct WebSeedError {
+    pub message: String,
+}
+
+impl std::fmt::Display for WebSeedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Web seed error: {}", self.message)
+    }
+}
+
+impl std::error::Error for WebSeedError {}
+
+impl From<reqwest::Error> for WebSeedError {
+    fn from(err: reqwest::Error) -> Self {
+        WebSeedError {
+            message: format!("HTTP error: {}", err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// BEP 19 `url-list`: the files themselves, fetched with Range requests.
+    Url,
+    /// BEP 17 `httpseeds`: a script serving whole pieces by index.
+    Http,
+}
+
+/// An HTTP server that can stand in for a peer.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+    client: reqwest::Client,
+    rate_limits: Vec<RateLimits>,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, kind: WebSeedKind, client: reqwest::Client) -> Self {
+        WebSeed {
+            url: url.to_string(),
+            kind,
+            client,
+            rate_limits: Vec::new(),
+        }
+    }
+
+    /// Every web seed and HTTP seed listed in `torrent`.
+    pub fn from_torrent(torrent: &TorrentFile, client: &reqwest::Client) -> Vec<WebSeed> {
+        let web_seeds = torrent
+            .web_seeds
+            .iter()
+            .map(|url| WebSeed::new(url, WebSeedKind::Url, client.clone()));
+        let http_seeds = torrent
+            .http_seeds
+            .iter()
+            .map(|url| WebSeed::new(url, WebSeedKind::Http, client.clone()));
+        web_seeds.chain(http_seeds).collect()
+    }
+
+    /// Counts downloaded bytes against `limits`, like a peer connection.
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits.push(limits);
+        self
+    }
+
+    /// URL of one of the torrent's files on a BEP 19 seed.
+    ///
+    /// A URL ending in `/` is a directory holding the torrent's content;
+    /// otherwise it names the file itself (single-file torrents only).
+    pub fn file_url(&self, torrent: &TorrentFile, file_index: usize) -> String {
+        let files = torrent.files();
+        if files.len() == 1 && !self.url.ends_with('/') {
+            return self.url.clone();
+        }
+
+        let mut url = self.url.clone();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        // The server stores the original bytes, which may not be UTF-8
+        let file = &files[file_index];
+        let path: Vec<String> = match file.raw_path {
+            Some(ref raw) => raw
+                .iter()
+                .map(|component| percent_encode(component, PATH_SEGMENT).to_string())
+                .collect(),
+            None => file
+                .path
+                .iter()
+                .map(|component| utf8_percent_encode(component, PATH_SEGMENT).to_string())
+                .collect(),
+        };
+        url.push_str(&path.join("/"));
+        url
+    }
+
+    /// Downloads one piece. The data is not verified here.
+    pub async fn fetch_piece(
+        &self,
+        torrent: &TorrentFile,
+        piece_index: u32,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let piece_size = torrent.piece_size(piece_index) as usize;
+        let data = match self.kind {
+            WebSeedKind::Url => {
+                let files = torrent.files();
+                let mut data = Vec::with_capacity(piece_size);
+                for span in torrent.piece_spans(piece_index) {
+                    if span.length == 0 {
+                        continue;
+                    }
+                    if files[span.file_index].attr.padding {
+                        // Mirrors don't host BEP 47 padding files
+                        data.resize(data.len() + span.length as usize, 0);
+                        continue;
+                    }
+                    let url = self.file_url(torrent, span.file_index);
+                    data.extend(self.fetch_range(&url, span.offset, span.length).await?);
+                }
+                data
+            }
+            WebSeedKind::Http => self.fetch_http_seed_piece(torrent, piece_index).await?,
+        };
+
+        if data.len() != piece_size {
+            return Err(WebSeedError {
+                message: format!(
+                    "Piece {} from {} is {} bytes, expected {}",
+                    piece_index,
+                    self.url,
+                    data.len(),
+                    piece_size
+                ),
+            });
+        }
+
+        for limits in &self.rate_limits {
+            limits.download.acquire(data.len()).await;
+        }
+        Ok(data)
+    }
+
+    /// Fetches `length` bytes at `offset`. A server that ignores the range
+    /// and answers with the whole file is read only up to the range's end.
+    async fn fetch_range(
+        &self,
+        url: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let mut response = self
+            .client
+            .get(url)
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", offset, offset + length - 1),
+            )
+            .send()
+            .await?;
+
+        let mut skip = match response.status() {
+            StatusCode::PARTIAL_CONTENT => 0,
+            StatusCode::OK => offset,
+            status => {
+                return Err(WebSeedError {
+                    message: format!("HTTP {} from {}", status, url),
+                });
+            }
+        };
+        let mut data = Vec::with_capacity(length as usize);
+        while (data.len() as u64) < length {
+            let Some(chunk) = response.chunk().await? else {
+                break;
+            };
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            let chunk = &chunk[skipped as usize..];
+            let wanted = (length - data.len() as u64).min(chunk.len() as u64);
+            data.extend_from_slice(&chunk[..wanted as usize]);
+        }
+        // Dropping the response closes the connection mid-body if need be
+        if (data.len() as u64) < length {
+            return Err(WebSeedError {
+                message: format!("{} is shorter than expected", url),
+            });
+        }
+        Ok(data)
+    }
+
+    async fn fetch_http_seed_piece(
+        &self,
+        torrent: &TorrentFile,
+        piece_index: u32,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}info_hash={}&piece={}",
+            self.url,
+            separator,
+            percent_encode(&torrent.info_hash, NON_ALPHANUMERIC),
+            piece_index
+        );
+
+        let response = self.client.get(&url).send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        match status {
+            StatusCode::OK => Ok(body.to_vec()),
+            StatusCode::SERVICE_UNAVAILABLE => Err(WebSeedError {
+                message: format!(
+                    "{} is busy, retry in {} seconds",
+                    self.url,
+                    String::from_utf8_lossy(&body).trim()
+                ),
+            }),
+            status => Err(WebSeedError {
+                message: format!("HTTP {} from {}", status, self.url),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{CreateOptions, create_torrent};
+    use crate::download::Downloader;
+    use crate::parser::parse_torrent_file;
+    use std::collections::HashMap;
+    use std::fs;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A minimal HTTP server serving `files` by path, honouring single
+    /// `Range: bytes=a-b` headers. The same files under `/full/` instead of
+    /// `/mirror/` ignore ranges. Returns its base URL.
+    async fn serve(files: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let files = files.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = socket.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    let range = request.lines().find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        if !name.eq_ignore_ascii_case("range") {
+                            return None;
+                        }
+                        let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+                        Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+                    });
+
+                    let full = path
+                        .strip_prefix("/full/")
+                        .and_then(|rest| files.get(&format!("/mirror/{}", rest)));
+                    let response = match (files.get(path), range) {
+                        _ if full.is_some() => {
+                            let data = full.unwrap();
+                            let mut response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                data.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(data);
+                            response
+                        }
+                        (Some(data), Some((start, end))) if end < data.len() => {
+                            let mut response = format!(
+                                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                                 Content-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                                end + 1 - start,
+                                start,
+                                end,
+                                data.len()
+                            )
+                            .into_bytes();
+                            response.extend_from_slice(&data[start..=end]);
+                            response
+                        }
+                        _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec(),
+                    };
+                    let _ = socket.write_all(&response).await;
+                });
+            }
+        });
+        format!("http://{}/mirror/", addr)
+    }
+
+    /// Creates a two-file torrent whose files share a piece, and serves the
+    /// files (after `tamper`) from a local mirror.
+    async fn mirrored_torrent(
+        dir: &std::path::Path,
+        tamper: impl Fn(&mut Vec<u8>),
+    ) -> (TorrentFile, Vec<u8>) {
+        let root = dir.join("bundle");
+        fs::create_dir_all(&root).unwrap();
+        let first: Vec<u8> = (0..40_000).map(|i| (i % 241) as u8).collect();
+        let second: Vec<u8> = (0..30_000).map(|i| (i % 239) as u8).collect();
+        fs::write(root.join("a.bin"), &first).unwrap();
+        fs::write(root.join("b.bin"), &second).unwrap();
+
+        let mut served = HashMap::new();
+        let mut served_second = second.clone();
+        tamper(&mut served_second);
+        served.insert("/mirror/bundle/a.bin".to_string(), first.clone());
+        served.insert("/mirror/bundle/b.bin".to_string(), served_second);
+        let base_url = serve(served).await;
+
+        let created = create_torrent(&CreateOptions {
+            path: root,
+            piece_length: Some(16 * 1024),
+            trackers: vec![vec!["http://tracker.invalid/announce".to_string()]],
+            web_seeds: vec![base_url],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.join("bundle.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+
+        let mut content = first;
+        content.extend(second);
+        (
+            parse_torrent_file(torrent_path.to_str().unwrap()).unwrap(),
+            content,
+        )
+    }
+
+    #[test]
+    fn test_file_url_mapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("my file.iso");
+        fs::write(&file, b"data").unwrap();
+        let created = create_torrent(&CreateOptions {
+            path: file,
+            trackers: vec![vec!["http://tracker.invalid/announce".to_string()]],
+            ..Default::default()
+        })
+        .unwrap();
+        let torrent_path = dir.path().join("single.torrent");
+        fs::write(&torrent_path, &created.data).unwrap();
+        let torrent = parse_torrent_file(torrent_path.to_str().unwrap()).unwrap();
+
+        let client = reqwest::Client::new();
+        let directory = WebSeed::new("http://mirror/pub/", WebSeedKind::Url, client.clone());
+        assert_eq!(
+            directory.file_url(&torrent, 0),
+            "http://mirror/pub/my%20file.iso"
+        );
+        let exact = WebSeed::new("http://mirror/latest.iso", WebSeedKind::Url, client);
+        assert_eq!(exact.file_url(&torrent, 0), "http://mirror/latest.iso");
+    }
+
+    #[tokio::test]
+    async fn test_download_from_web_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, content) = mirrored_torrent(dir.path(), |_| {}).await;
+        assert_eq!(torrent.web_seeds.len(), 1);
+
+        let seeds = WebSeed::from_torrent(&torrent, &reqwest::Client::new());
+        // Piece 2 straddles the two files
+        let piece = seeds[0].fetch_piece(&torrent, 2).await.unwrap();
+        assert_eq!(piece, content[2 * 16384..3 * 16384]);
+
+        let output = dir.path().join("out");
+        let downloader = Downloader::new(torrent, &output).unwrap();
+        downloader.download_from_web_seed(&seeds[0]).await.unwrap();
+        assert!(downloader.is_complete());
+        assert_eq!(
+            fs::read(output.join("bundle/a.bin")).unwrap(),
+            content[..40_000]
+        );
+        assert_eq!(
+            fs::read(output.join("bundle/b.bin")).unwrap(),
+            content[40_000..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_web_seed_data_is_hash_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, _) = mirrored_torrent(dir.path(), |data| data[20_000] ^= 0xFF).await;
+
+        let seeds = WebSeed::from_torrent(&torrent, &reqwest::Client::new());
+        let downloader = Downloader::new(torrent, &dir.path().join("out")).unwrap();
+        let err = downloader
+            .download_from_web_seed(&seeds[0])
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("hash check"));
+        assert!(!downloader.is_complete());
+    }
+
+    #[tokio::test]
+    async fn test_web_seeds_share_one_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, content) = mirrored_torrent(dir.path(), |_| {}).await;
+        let client = reqwest::Client::new();
+        let mirror = &torrent.web_seeds[0];
+        // One server ignores ranges, the other has nothing
+        let full = WebSeed::new(
+            &mirror.replace("/mirror/", "/full/"),
+            WebSeedKind::Url,
+            client.clone(),
+        );
+        let broken = WebSeed::new(
+            &mirror.replace("/mirror/", "/gone/"),
+            WebSeedKind::Url,
+            client,
+        );
+        assert_eq!(
+            full.fetch_piece(&torrent, 2).await.unwrap(),
+            content[2 * 16384..3 * 16384]
+        );
+
+        let output = dir.path().join("out");
+        let downloader = Downloader::new(torrent, &output).unwrap();
+        let (full_result, broken_result) = tokio::join!(
+            downloader.download_from_web_seed(&full),
+            downloader.download_from_web_seed(&broken)
+        );
+        full_result.unwrap();
+        assert!(broken_result.is_err());
+        assert!(downloader.is_complete());
+        assert_eq!(
+            fs::read(output.join("bundle/b.bin")).unwrap(),
+            content[40_000..]
+        );
+    }
+}
@@ -1,46 +1,118 @@
 /// Wire protocol implementation for BitTorrent
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
+use bytes::{Buf, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
 
 const BT_PROTOCOL: &str = "BitTorrent protocol";
+const HANDSHAKE_LEN: usize = 68;
+
+const MSG_CHOKE: u8 = 0;
+const MSG_UNCHOKE: u8 = 1;
+const MSG_INTERESTED: u8 = 2;
+const MSG_NOT_INTERESTED: u8 = 3;
+const MSG_HAVE: u8 = 4;
+const MSG_BITFIELD: u8 = 5;
+const MSG_REQUEST: u8 = 6;
+const MSG_PIECE: u8 = 7;
+const MSG_CANCEL: u8 = 8;
+const MSG_PORT: u8 = 9;
+const MSG_EXTENDED: u8 = 20;
+const MSG_HASH_REQUEST: u8 = 21;
+const MSG_HASHES: u8 = 22;
+const MSG_HASH_REJECT: u8 = 23;
+
+/// Body length of a `hash request` or `hash reject`, including the id byte.
+const HASH_REQUEST_LEN: usize = 1 + 32 + 16;
+/// Most hashes accepted in one `hashes` message (a 512-hash layer run plus
+/// its proof, with room to spare).
+const MAX_HASHES: usize = 1024;
+
+/// Largest block accepted in a `piece` message. We only ever request 16 KiB,
+/// but some clients serve larger blocks.
+pub const MAX_BLOCK_LEN: usize = 128 * 1024;
+/// Largest bitfield accepted before the piece count is known (2^21 pieces).
+const MAX_BITFIELD_LEN: usize = 1 << 18;
+
+/// The eight reserved handshake bytes, in which peers flag the protocol
+/// extensions they support.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    /// Flags support for the extension protocol (BEP 10).
+    pub fn with_extensions(mut self) -> Self {
+        self.0[5] |= 0x10;
+        self
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.0[5] & 0x10 != 0
+    }
+
+    /// Flags BitTorrent v2 support (BEP 52).
+    pub fn with_v2(mut self) -> Self {
+        self.0[7] |= 0x10;
+        self
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.0[7] & 0x10 != 0
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Handshake {
+    pub reserved: Reserved,
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
 
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
-        Handshake { info_hash, peer_id }
+        Handshake {
+            reserved: Reserved::default(),
+            info_hash,
+            peer_id,
+        }
     }
 
-    pub fn serialize(&self) -> [u8; 68] {
-        let mut buf = [0u8; 68];
+    pub fn with_reserved(mut self, reserved: Reserved) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    pub fn serialize(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0u8; HANDSHAKE_LEN];
         buf[0] = 19; // pstrlen
         buf[1..20].copy_from_slice(BT_PROTOCOL.as_bytes());
-        buf[20..28].copy_from_slice(&[0u8; 8]); // reserved
+        buf[20..28].copy_from_slice(&self.reserved.0);
         buf[28..48].copy_from_slice(&self.info_hash);
         buf[48..68].copy_from_slice(&self.peer_id);
         buf
     }
 
     pub fn deserialize(data: &[u8]) -> Option<Self> {
-        if data.len() != 68 || data[0] != 19 {
+        if data.len() != HANDSHAKE_LEN || data[0] != 19 {
             return None;
         }
         if &data[1..20] != BT_PROTOCOL.as_bytes() {
             return None;
         }
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&data[20..28]);
         let mut info_hash = [0u8; 20];
         info_hash.copy_from_slice(&data[28..48]);
         let mut peer_id = [0u8; 20];
         peer_id.copy_from_slice(&data[48..68]);
-        Some(Handshake { info_hash, peer_id })
+        Some(Handshake {
+            reserved: Reserved(reserved),
+            info_hash,
+            peer_id,
+        })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerMessage {
     KeepAlive,
     Choke,
@@ -65,9 +137,74 @@ pub enum PeerMessage {
         length: u32,
     },
     Port(u16),
+    HashRequest(HashRequest),
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(HashRequest),
+    /// An extension protocol message (BEP 10). Id 0 is the extended
+    /// handshake; other ids are the ones the receiver assigned.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// A run of hashes in one layer of a file's Merkle tree (BEP 52).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    /// Layer of the requested hashes; 0 is the 16 KiB block layer.
+    pub base_layer: u32,
+    /// Offset of the first hash within the layer.
+    pub index: u32,
+    /// Number of hashes requested.
+    pub length: u32,
+    /// Number of uncle-hash layers to include for verification.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    fn write(&self, v: &mut Vec<u8>) {
+        v.extend_from_slice(&self.pieces_root);
+        v.extend_from_slice(&self.base_layer.to_be_bytes());
+        v.extend_from_slice(&self.index.to_be_bytes());
+        v.extend_from_slice(&self.length.to_be_bytes());
+        v.extend_from_slice(&self.proof_layers.to_be_bytes());
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        HashRequest {
+            pieces_root: bytes[..32].try_into().expect("32-byte root"),
+            base_layer: read_u32(&bytes[32..36]),
+            index: read_u32(&bytes[36..40]),
+            length: read_u32(&bytes[40..44]),
+            proof_layers: read_u32(&bytes[44..48]),
+        }
+    }
 }
 
 impl PeerMessage {
+    /// Size of the message on the wire, including the length prefix.
+    pub fn encoded_len(&self) -> usize {
+        4 + match self {
+            PeerMessage::KeepAlive => 0,
+            PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested => 1,
+            PeerMessage::Have(_) => 5,
+            PeerMessage::Bitfield(bits) => 1 + bits.len(),
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => 13,
+            PeerMessage::Piece { block, .. } => 9 + block.len(),
+            PeerMessage::Port(_) => 3,
+            PeerMessage::HashRequest(_) | PeerMessage::HashReject(_) => HASH_REQUEST_LEN,
+            PeerMessage::Hashes { hashes, .. } => HASH_REQUEST_LEN + 32 * hashes.len(),
+            PeerMessage::Extended { payload, .. } => 2 + payload.len(),
+        }
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         match self {
             PeerMessage::KeepAlive => vec![0, 0, 0, 0],
@@ -129,114 +266,570 @@ impl PeerMessage {
                 v.extend_from_slice(&port.to_be_bytes());
                 v
             }
+            PeerMessage::HashRequest(request) | PeerMessage::HashReject(request) => {
+                let id = if matches!(self, PeerMessage::HashRequest(_)) {
+                    MSG_HASH_REQUEST
+                } else {
+                    MSG_HASH_REJECT
+                };
+                let mut v = Vec::with_capacity(4 + HASH_REQUEST_LEN);
+                v.extend_from_slice(&(HASH_REQUEST_LEN as u32).to_be_bytes());
+                v.push(id);
+                request.write(&mut v);
+                v
+            }
+            PeerMessage::Hashes { request, hashes } => {
+                let len = (HASH_REQUEST_LEN + 32 * hashes.len()) as u32;
+                let mut v = Vec::with_capacity(4 + len as usize);
+                v.extend_from_slice(&len.to_be_bytes());
+                v.push(MSG_HASHES);
+                request.write(&mut v);
+                for hash in hashes {
+                    v.extend_from_slice(hash);
+                }
+                v
+            }
+            PeerMessage::Extended { id, payload } => {
+                let len = (2 + payload.len()) as u32;
+                let mut v = Vec::with_capacity(4 + len as usize);
+                v.extend_from_slice(&len.to_be_bytes());
+                v.push(MSG_EXTENDED);
+                v.push(*id);
+                v.extend_from_slice(payload);
+                v
+            }
+        }
+    }
+
+    /// Parses a message body (everything after the 4-byte length prefix).
+    ///
+    /// `num_pieces` is the piece count of the torrent, when known; it is used
+    /// to check the exact size of a bitfield.
+    pub fn deserialize(body: &[u8], num_pieces: Option<usize>) -> Result<Self, WireError> {
+        if body.is_empty() {
+            return Ok(PeerMessage::KeepAlive);
+        }
+        let id = body[0];
+        check_body_len(id, body.len(), num_pieces)?;
+        match id {
+            MSG_CHOKE => Ok(PeerMessage::Choke),
+            MSG_UNCHOKE => Ok(PeerMessage::Unchoke),
+            MSG_INTERESTED => Ok(PeerMessage::Interested),
+            MSG_NOT_INTERESTED => Ok(PeerMessage::NotInterested),
+            MSG_HAVE => Ok(PeerMessage::Have(read_u32(&body[1..5]))),
+            MSG_BITFIELD => {
+                let bits = &body[1..];
+                if let Some(num_pieces) = num_pieces
+                    && num_pieces % 8 != 0
+                {
+                    // Bits past the last piece must be cleared
+                    let spare_mask = 0xFFu8 >> (num_pieces % 8);
+                    if bits[bits.len() - 1] & spare_mask != 0 {
+                        return Err(WireError::BitfieldSpareBits);
+                    }
+                }
+                Ok(PeerMessage::Bitfield(bits.to_vec()))
+            }
+            MSG_REQUEST => Ok(PeerMessage::Request {
+                index: read_u32(&body[1..5]),
+                begin: read_u32(&body[5..9]),
+                length: read_u32(&body[9..13]),
+            }),
+            MSG_PIECE => Ok(PeerMessage::Piece {
+                index: read_u32(&body[1..5]),
+                begin: read_u32(&body[5..9]),
+                block: body[9..].to_vec(),
+            }),
+            MSG_CANCEL => Ok(PeerMessage::Cancel {
+                index: read_u32(&body[1..5]),
+                begin: read_u32(&body[5..9]),
+                length: read_u32(&body[9..13]),
+            }),
+            MSG_PORT => Ok(PeerMessage::Port(u16::from_be_bytes([body[1], body[2]]))),
+            MSG_HASH_REQUEST => Ok(PeerMessage::HashRequest(HashRequest::read(&body[1..]))),
+            MSG_HASH_REJECT => Ok(PeerMessage::HashReject(HashRequest::read(&body[1..]))),
+            MSG_HASHES => {
+                if !(body.len() - HASH_REQUEST_LEN).is_multiple_of(32) {
+                    return Err(WireError::InvalidLength {
+                        id,
+                        len: body.len(),
+                    });
+                }
+                Ok(PeerMessage::Hashes {
+                    request: HashRequest::read(&body[1..HASH_REQUEST_LEN]),
+                    hashes: body[HASH_REQUEST_LEN..]
+                        .chunks(32)
+                        .map(|hash| hash.try_into().expect("32-byte hash"))
+                        .collect(),
+                })
+            }
+            MSG_EXTENDED => Ok(PeerMessage::Extended {
+                id: body[1],
+                payload: body[2..].to_vec(),
+            }),
+            _ => Err(WireError::UnknownMessageId(id)),
+        }
+    }
+}
+
+/// Checks a message body length (including the id byte) against the limits
+/// for its message type, before any of the body is buffered.
+fn check_body_len(id: u8, len: usize, num_pieces: Option<usize>) -> Result<(), WireError> {
+    let (min, max) = match id {
+        MSG_CHOKE | MSG_UNCHOKE | MSG_INTERESTED | MSG_NOT_INTERESTED => (1, 1),
+        MSG_HAVE => (5, 5),
+        MSG_BITFIELD => match num_pieces {
+            Some(num_pieces) => {
+                let expected = 1 + num_pieces.div_ceil(8);
+                if len != expected {
+                    return Err(WireError::BitfieldLength {
+                        expected: expected - 1,
+                        actual: len - 1,
+                    });
+                }
+                return Ok(());
+            }
+            None => (1, 1 + MAX_BITFIELD_LEN),
+        },
+        MSG_REQUEST | MSG_CANCEL => (13, 13),
+        MSG_PIECE => (9, 9 + MAX_BLOCK_LEN),
+        MSG_PORT => (3, 3),
+        MSG_HASH_REQUEST | MSG_HASH_REJECT => (HASH_REQUEST_LEN, HASH_REQUEST_LEN),
+        MSG_HASHES => (HASH_REQUEST_LEN, HASH_REQUEST_LEN + 32 * MAX_HASHES),
+        // A 16 KiB metadata piece and its header fit easily
+        MSG_EXTENDED => (2, 2 + MAX_BLOCK_LEN),
+        _ => return Err(WireError::UnknownMessageId(id)),
+    };
+    if len > max && min != max {
+        return Err(WireError::FrameTooLarge { id, len, max });
+    }
+    if len < min || len > max {
+        return Err(WireError::InvalidLength { id, len });
+    }
+    Ok(())
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Errors produced while decoding the peer wire protocol.
+#[derive(Debug)]
+pub enum WireError {
+    Io(io::Error),
+    InvalidHandshake,
+    UnknownMessageId(u8),
+    /// A variable-size message is larger than we are willing to buffer.
+    FrameTooLarge {
+        id: u8,
+        len: usize,
+        max: usize,
+    },
+    /// A message does not have the size its type requires.
+    InvalidLength {
+        id: u8,
+        len: usize,
+    },
+    BitfieldLength {
+        expected: usize,
+        actual: usize,
+    },
+    BitfieldSpareBits,
+}
+
+impl std::fmt::Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WireError::Io(err) => write!(f, "Wire error: {}", err),
+            WireError::InvalidHandshake => write!(f, "Wire error: invalid handshake"),
+            WireError::UnknownMessageId(id) => write!(f, "Wire error: unknown message id {}", id),
+            WireError::FrameTooLarge { id, len, max } => write!(
+                f,
+                "Wire error: message {} is {} bytes (max {})",
+                id, len, max
+            ),
+            WireError::InvalidLength { id, len } => {
+                write!(f, "Wire error: message {} has invalid length {}", id, len)
+            }
+            WireError::BitfieldLength { expected, actual } => write!(
+                f,
+                "Wire error: bitfield is {} bytes, expected {}",
+                actual, expected
+            ),
+            WireError::BitfieldSpareBits => write!(f, "Wire error: bitfield has spare bits set"),
         }
     }
 }
 
-pub fn send_handshake(stream: &mut TcpStream, handshake: &Handshake) -> io::Result<()> {
-    let buf = handshake.serialize();
-    stream.write_all(&buf)
+impl std::error::Error for WireError {}
+
+impl From<io::Error> for WireError {
+    fn from(err: io::Error) -> Self {
+        WireError::Io(err)
+    }
 }
 
-pub fn receive_handshake(stream: &mut TcpStream) -> io::Result<Handshake> {
-    let mut buf = [0u8; 68];
-    stream.read_exact(&mut buf)?;
-    Handshake::deserialize(&buf)
-        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid handshake"))
+impl From<WireError> for io::Error {
+    fn from(err: WireError) -> Self {
+        match err {
+            WireError::Io(err) => err,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
 }
 
-pub fn send_message(stream: &mut TcpStream, msg: &PeerMessage) -> io::Result<()> {
-    let buf = msg.serialize();
-    stream.write_all(&buf)
+/// Codec for the fixed-size handshake that opens every peer connection.
+///
+/// Once the handshake has been exchanged, switch the framed stream over to
+/// [`MessageCodec`] with `Framed::map_codec`.
+#[derive(Debug, Default)]
+pub struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+    type Error = WireError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Handshake>, WireError> {
+        if src.len() < HANDSHAKE_LEN {
+            src.reserve(HANDSHAKE_LEN - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(HANDSHAKE_LEN);
+        Handshake::deserialize(&frame)
+            .map(Some)
+            .ok_or(WireError::InvalidHandshake)
+    }
 }
 
-pub fn receive_message(stream: &mut TcpStream) -> io::Result<PeerMessage> {
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf)?;
-    let len = u32::from_be_bytes(len_buf);
-    if len == 0 {
-        return Ok(PeerMessage::KeepAlive);
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = WireError;
+
+    fn encode(&mut self, handshake: Handshake, dst: &mut BytesMut) -> Result<(), WireError> {
+        dst.extend_from_slice(&handshake.serialize());
+        Ok(())
     }
-    let mut msg_buf = vec![0u8; len as usize];
-    stream.read_exact(&mut msg_buf)?;
-    let id = msg_buf[0];
-    match id {
-        0 => Ok(PeerMessage::Choke),
-        1 => Ok(PeerMessage::Unchoke),
-        2 => Ok(PeerMessage::Interested),
-        3 => Ok(PeerMessage::NotInterested),
-        4 => {
-            if msg_buf.len() < 5 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid have message",
-                ));
+}
+
+/// Codec for length-prefixed peer messages.
+///
+/// Frame sizes are validated from the length prefix and message id alone, so
+/// a hostile length never causes a large allocation.
+#[derive(Debug, Default)]
+pub struct MessageCodec {
+    num_pieces: Option<usize>,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enables exact bitfield length checks once the piece count is known.
+    pub fn set_piece_count(&mut self, num_pieces: usize) {
+        self.num_pieces = Some(num_pieces);
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = PeerMessage;
+    type Error = WireError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PeerMessage>, WireError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = read_u32(&src[..4]) as usize;
+        if len == 0 {
+            src.advance(4);
+            return Ok(Some(PeerMessage::KeepAlive));
+        }
+        if src.len() < 5 {
+            return Ok(None);
+        }
+        check_body_len(src[4], len, self.num_pieces)?;
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let body = src.split_to(len);
+        PeerMessage::deserialize(&body, self.num_pieces).map(Some)
+    }
+}
+
+impl Encoder<PeerMessage> for MessageCodec {
+    type Error = WireError;
+
+    fn encode(&mut self, msg: PeerMessage, dst: &mut BytesMut) -> Result<(), WireError> {
+        dst.extend_from_slice(&msg.serialize());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn decode_all(codec: &mut MessageCodec, buf: &mut BytesMut) -> Vec<PeerMessage> {
+        let mut messages = Vec::new();
+        while let Some(msg) = codec.decode(buf).unwrap() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_handshake_codec_roundtrip() {
+        let handshake = Handshake::new([1u8; 20], [2u8; 20]);
+        let mut buf = BytesMut::new();
+        HandshakeCodec.encode(handshake, &mut buf).unwrap();
+
+        let decoded = HandshakeCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.info_hash, [1u8; 20]);
+        assert_eq!(decoded.peer_id, [2u8; 20]);
+        assert!(!decoded.reserved.supports_extensions());
+        assert!(buf.is_empty());
+
+        let extended = Handshake::new([1u8; 20], [2u8; 20])
+            .with_reserved(Reserved::default().with_extensions());
+        assert_eq!(extended.serialize()[25], 0x10);
+        let decoded = Handshake::deserialize(&extended.serialize()).unwrap();
+        assert!(decoded.reserved.supports_extensions());
+        assert!(!decoded.reserved.supports_v2());
+
+        let v2 = Handshake::new([1u8; 20], [2u8; 20]).with_reserved(Reserved::default().with_v2());
+        assert_eq!(v2.serialize()[27], 0x10);
+        let decoded = Handshake::deserialize(&v2.serialize()).unwrap();
+        assert!(decoded.reserved.supports_v2());
+    }
+
+    #[test]
+    fn test_message_codec_waits_for_full_frame() {
+        let encoded = PeerMessage::Have(7).serialize();
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::from(&encoded[..6]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&encoded[6..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(PeerMessage::Have(7)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_message_codec_decodes_back_to_back_frames() {
+        let piece = PeerMessage::Piece {
+            index: 1,
+            begin: 16384,
+            block: vec![9, 9, 9],
+        };
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(PeerMessage::KeepAlive, &mut buf).unwrap();
+        codec.encode(piece.clone(), &mut buf).unwrap();
+
+        assert_eq!(
+            decode_all(&mut codec, &mut buf),
+            vec![PeerMessage::KeepAlive, piece]
+        );
+    }
+
+    #[test]
+    fn test_rejects_huge_length_prefix_before_buffering() {
+        let mut buf = BytesMut::from(&[0xFF, 0xFF, 0xFF, 0xFF, MSG_PIECE][..]);
+        let err = MessageCodec::new().decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            WireError::FrameTooLarge {
+                id: MSG_PIECE,
+                max,
+                ..
+            } if max == 9 + MAX_BLOCK_LEN
+        ));
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn test_rejects_wrong_fixed_length() {
+        // A `have` message must be exactly 5 bytes
+        let mut buf = BytesMut::from(&[0, 0, 0, 2, MSG_HAVE, 0][..]);
+        let err = MessageCodec::new().decode(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            WireError::InvalidLength {
+                id: MSG_HAVE,
+                len: 2
             }
-            let idx = u32::from_be_bytes([msg_buf[1], msg_buf[2], msg_buf[3], msg_buf[4]]);
-            Ok(PeerMessage::Have(idx))
+        ));
+
+        // An `unchoke` with trailing garbage is rejected too
+        let mut buf = BytesMut::from(&[0, 0, 0, 2, MSG_UNCHOKE, 0][..]);
+        assert!(MessageCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_hashes_must_be_whole() {
+        let request = HashRequest {
+            pieces_root: [7u8; 32],
+            base_layer: 0,
+            index: 0,
+            length: 2,
+            proof_layers: 1,
+        };
+        let mut encoded = PeerMessage::Hashes {
+            request,
+            hashes: vec![[1u8; 32], [2u8; 32]],
         }
-        5 => Ok(PeerMessage::Bitfield(msg_buf[1..].to_vec())),
-        6 => {
-            if msg_buf.len() < 13 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid request message",
-                ));
+        .serialize();
+        // Drop the last byte and fix up the length prefix
+        encoded.pop();
+        let len = (encoded.len() - 4) as u32;
+        encoded[..4].copy_from_slice(&len.to_be_bytes());
+
+        let mut buf = BytesMut::from(&encoded[..]);
+        assert!(matches!(
+            MessageCodec::new().decode(&mut buf).unwrap_err(),
+            WireError::InvalidLength { id: MSG_HASHES, .. }
+        ));
+    }
+
+    #[test]
+    fn test_rejects_unknown_message_id() {
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, 200][..]);
+        let err = MessageCodec::new().decode(&mut buf).unwrap_err();
+        assert!(matches!(err, WireError::UnknownMessageId(200)));
+    }
+
+    #[test]
+    fn test_bitfield_checked_against_piece_count() {
+        let mut codec = MessageCodec::new();
+        codec.set_piece_count(10);
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(PeerMessage::Bitfield(vec![0xFF, 0xC0]), &mut buf)
+            .unwrap();
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(PeerMessage::Bitfield(vec![0xFF, 0xC0]))
+        );
+
+        codec
+            .encode(PeerMessage::Bitfield(vec![0xFF, 0xC0, 0x00]), &mut buf)
+            .unwrap();
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap_err(),
+            WireError::BitfieldLength {
+                expected: 2,
+                actual: 3
             }
-            let index = u32::from_be_bytes([msg_buf[1], msg_buf[2], msg_buf[3], msg_buf[4]]);
-            let begin = u32::from_be_bytes([msg_buf[5], msg_buf[6], msg_buf[7], msg_buf[8]]);
-            let length = u32::from_be_bytes([msg_buf[9], msg_buf[10], msg_buf[11], msg_buf[12]]);
-            Ok(PeerMessage::Request {
-                index,
-                begin,
-                length,
-            })
-        }
-        7 => {
-            if msg_buf.len() < 9 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid piece message",
-                ));
-            }
-            let index = u32::from_be_bytes([msg_buf[1], msg_buf[2], msg_buf[3], msg_buf[4]]);
-            let begin = u32::from_be_bytes([msg_buf[5], msg_buf[6], msg_buf[7], msg_buf[8]]);
-            let block = msg_buf[9..].to_vec();
-            Ok(PeerMessage::Piece {
-                index,
-                begin,
-                block,
-            })
-        }
-        8 => {
-            if msg_buf.len() < 13 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid cancel message",
-                ));
-            }
-            let index = u32::from_be_bytes([msg_buf[1], msg_buf[2], msg_buf[3], msg_buf[4]]);
-            let begin = u32::from_be_bytes([msg_buf[5], msg_buf[6], msg_buf[7], msg_buf[8]]);
-            let length = u32::from_be_bytes([msg_buf[9], msg_buf[10], msg_buf[11], msg_buf[12]]);
-            Ok(PeerMessage::Cancel {
-                index,
-                begin,
-                length,
-            })
-        }
-        9 => {
-            if msg_buf.len() < 3 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Invalid port message",
-                ));
-            }
-            let port = u16::from_be_bytes([msg_buf[1], msg_buf[2]]);
-            Ok(PeerMessage::Port(port))
-        }
-        _ => Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Unknown message id",
-        )),
+        ));
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(PeerMessage::Bitfield(vec![0xFF, 0xE0]), &mut buf)
+            .unwrap();
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap_err(),
+            WireError::BitfieldSpareBits
+        ));
+    }
+
+    fn arb_message() -> impl Strategy<Value = PeerMessage> {
+        prop_oneof![
+            Just(PeerMessage::KeepAlive),
+            Just(PeerMessage::Choke),
+            Just(PeerMessage::Unchoke),
+            Just(PeerMessage::Interested),
+            Just(PeerMessage::NotInterested),
+            any::<u32>().prop_map(PeerMessage::Have),
+            proptest::collection::vec(any::<u8>(), 0..64).prop_map(PeerMessage::Bitfield),
+            (any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(index, begin, length)| {
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                }
+            }),
+            (
+                any::<u32>(),
+                any::<u32>(),
+                proptest::collection::vec(any::<u8>(), 0..256)
+            )
+                .prop_map(|(index, begin, block)| PeerMessage::Piece {
+                    index,
+                    begin,
+                    block
+                }),
+            (any::<u32>(), any::<u32>(), any::<u32>()).prop_map(|(index, begin, length)| {
+                PeerMessage::Cancel {
+                    index,
+                    begin,
+                    length,
+                }
+            }),
+            any::<u16>().prop_map(PeerMessage::Port),
+            arb_hash_request().prop_map(PeerMessage::HashRequest),
+            (
+                arb_hash_request(),
+                proptest::collection::vec(any::<[u8; 32]>(), 0..8)
+            )
+                .prop_map(|(request, hashes)| PeerMessage::Hashes { request, hashes }),
+            arb_hash_request().prop_map(PeerMessage::HashReject),
+            (any::<u8>(), proptest::collection::vec(any::<u8>(), 0..256))
+                .prop_map(|(id, payload)| PeerMessage::Extended { id, payload }),
+        ]
+    }
+
+    fn arb_hash_request() -> impl Strategy<Value = HashRequest> {
+        (any::<[u8; 32]>(), any::<[u32; 4]>()).prop_map(|(pieces_root, fields)| HashRequest {
+            pieces_root,
+            base_layer: fields[0],
+            index: fields[1],
+            length: fields[2],
+            proof_layers: fields[3],
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn prop_decoder_never_panics(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+            let mut codec = MessageCodec::new();
+            let mut buf = BytesMut::from(&data[..]);
+            while let Ok(Some(_)) = codec.decode(&mut buf) {}
+        }
+
+        #[test]
+        fn prop_decoder_never_panics_with_piece_count(
+            data in proptest::collection::vec(any::<u8>(), 0..512),
+            num_pieces in 0usize..100,
+        ) {
+            let mut codec = MessageCodec::new();
+            codec.set_piece_count(num_pieces);
+            let mut buf = BytesMut::from(&data[..]);
+            while let Ok(Some(_)) = codec.decode(&mut buf) {}
+        }
+
+        #[test]
+        fn prop_roundtrip_with_arbitrary_chunking(
+            messages in proptest::collection::vec(arb_message(), 0..16),
+            chunk_size in 1usize..64,
+        ) {
+            let mut encoded = BytesMut::new();
+            for msg in &messages {
+                MessageCodec::new().encode(msg.clone(), &mut encoded).unwrap();
+            }
+
+            let mut codec = MessageCodec::new();
+            let mut buf = BytesMut::new();
+            let mut decoded = Vec::new();
+            for chunk in encoded.chunks(chunk_size) {
+                buf.extend_from_slice(chunk);
+                decoded.extend(decode_all(&mut codec, &mut buf));
+            }
+            prop_assert!(buf.is_empty());
+            prop_assert_eq!(encoded.len(), messages.iter().map(PeerMessage::encoded_len).sum::<usize>());
+            prop_assert_eq!(decoded, messages);
+        }
     }
 }